@@ -0,0 +1,126 @@
+//! Micro benchmarks for the hot paths of the pipeline.
+//!
+//! Run with `cargo bench --features testing`. The synthetic objects
+//! come from [`testing::synthetic_lo()`], so nothing here needs a
+//! database or a bucket; the point is to catch regressions in hashing
+//! throughput, queue overhead and committer batching before they show
+//! up as wall-clock time on a real migration.
+//!
+//! The hashing benchmarks double as the measurement for the `asm`
+//! feature: run them once plainly and once with
+//! `cargo bench --features "testing asm" hashing` to see what the
+//! assembly (SHA-NI on recent x86) implementations gain on this
+//! machine.
+//!
+//! [`testing::synthetic_lo()`]: ../lo_migrate/testing/fn.synthetic_lo.html
+
+#[macro_use]
+extern crate criterion;
+extern crate lo_migrate;
+extern crate sha2;
+
+use criterion::{Benchmark, Criterion, Throughput};
+use lo_migrate::queue::{RecvResult, SpillingWorkQueue, TwoLockWorkQueue, WorkQueue};
+use lo_migrate::testing::{synthetic_data, synthetic_lo, SizeDistribution};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+/// Size of the payload the hashing benchmarks digest.
+const PAYLOAD: usize = 1024 * 1024;
+
+/// Larger payload where the per-update overhead vanishes and only the
+/// compression function speed is left.
+const LARGE_PAYLOAD: usize = 16 * 1024 * 1024;
+
+/// Objects sent through a queue per iteration.
+const QUEUE_BATCH: usize = 256;
+
+/// Committer chunk size matching the default `--commit-chunk-size`.
+const COMMIT_CHUNK: usize = 100;
+
+fn hashing(c: &mut Criterion) {
+    let data = synthetic_data(PAYLOAD);
+    c.bench("hashing",
+            Benchmark::new("sha256_1mib", move |b| {
+                    b.iter(|| {
+                               let mut digest = Sha256::default();
+                               digest.input(&data);
+                               digest.result()
+                           })
+                })
+                .throughput(Throughput::Bytes(PAYLOAD as u32)));
+
+    let data = synthetic_data(LARGE_PAYLOAD);
+    c.bench("hashing",
+            Benchmark::new("sha256_16mib", move |b| {
+                    b.iter(|| {
+                               let mut digest = Sha256::default();
+                               digest.input(&data);
+                               digest.result()
+                           })
+                })
+                .throughput(Throughput::Bytes(LARGE_PAYLOAD as u32)));
+}
+
+/// Send and drain a batch of small objects, measuring per-item channel
+/// overhead for both queue implementations.
+fn queue_overhead(c: &mut Criterion) {
+    fn round_trip(queue: &WorkQueue, b: &mut ::criterion::Bencher) {
+        let dist = SizeDistribution::Constant(64);
+        b.iter_with_setup(|| (0..QUEUE_BATCH).map(|n| synthetic_lo(n, dist)).collect::<Vec<_>>(),
+                          |batch| {
+            let (tx, rx) = queue.channel(QUEUE_BATCH);
+            for lo in batch {
+                tx.send(lo).unwrap();
+            }
+            for _ in 0..QUEUE_BATCH {
+                match rx.recv_timeout(Duration::from_secs(1)) {
+                    RecvResult::Item(_) => (),
+                    other => panic!("queue unexpectedly empty: {:?}", other),
+                }
+            }
+        });
+    }
+
+    c.bench("queue",
+            Benchmark::new("two_lock", |b| round_trip(&TwoLockWorkQueue, b))
+                .with_function("spilling", |b| round_trip(&SpillingWorkQueue::new(), b))
+                .throughput(Throughput::Elements(QUEUE_BATCH as u32)));
+}
+
+/// Drain a queue into commit-sized chunks the way the committer does,
+/// measuring the batching overhead without a database.
+fn committer_batching(c: &mut Criterion) {
+    let dist = SizeDistribution::Constant(64);
+    c.bench("committer",
+            Benchmark::new("chunking", move |b| {
+                    b.iter_with_setup(|| {
+                        let (tx, rx) = TwoLockWorkQueue.channel(QUEUE_BATCH);
+                        for n in 0..QUEUE_BATCH {
+                            tx.send(synthetic_lo(n, dist)).unwrap();
+                        }
+                        rx
+                    },
+                                      |rx| {
+                        let mut chunks = Vec::new();
+                        loop {
+                            let mut chunk = Vec::with_capacity(COMMIT_CHUNK);
+                            while chunk.len() < COMMIT_CHUNK {
+                                match rx.recv_timeout(Duration::from_millis(1)) {
+                                    RecvResult::Item(lo) => chunk.push(lo),
+                                    _ => break,
+                                }
+                            }
+                            if chunk.is_empty() {
+                                break;
+                            }
+                            chunks.push(chunk);
+                        }
+                        chunks
+                    });
+                })
+                .throughput(Throughput::Elements(QUEUE_BATCH as u32)));
+}
+
+criterion_group!(benches, hashing, queue_overhead, committer_batching);
+criterion_main!(benches);
@@ -0,0 +1,123 @@
+//! Experimental async pipeline built on tokio (feature `async`).
+//!
+//! The threaded pipeline keeps dozens of blocking threads around that,
+//! against a high-latency S3 endpoint, spend most of their time parked
+//! in `.sync()`. This variant runs a task per object on a tokio
+//! runtime instead: rusoto's request futures are driven directly and
+//! `buffer_unordered` bounds how many objects are in flight at once.
+//!
+//! Trade-offs against the threaded pipeline, which remains the
+//! default:
+//!
+//! * objects are fetched with `lo_get()` and buffered in memory whole,
+//!   there is no temp-file spillover — keep the concurrency and the
+//!   object sizes in mind,
+//! * hashes are written back row by row after all uploads finished,
+//!   not in chunks while the run progresses,
+//! * no rate limiting, multipart uploads or progress monitor.
+
+use digest::{Digest, FixedOutput, Input};
+use error::{ErrorKind, MigrationError, Result};
+use futures::{Future, Stream, stream};
+use hex::{self, FromHex};
+use migrate::S3Config;
+use rusoto_s3::{PutObjectRequest, S3, S3Client};
+use tokio::runtime::Runtime;
+use tokio_postgres::{self, NoTls};
+
+/// Migrate all pending objects, running `concurrency` object tasks at
+/// a time. Returns the number of objects migrated.
+///
+/// Expects the schema to be prepared just like [`Migration::run()`].
+///
+/// [`Migration::run()`]: ../migrate/struct.Migration.html#method.run
+pub fn run<D>(pg_url: &str, s3: &S3Config, concurrency: usize) -> Result<u64>
+    where D: Digest + Input + FixedOutput + Default
+{
+    assert!(concurrency > 0, "at least one in-flight object is needed");
+    let client = s3.client()?;
+    let bucket = s3.bucket.clone();
+    let config: tokio_postgres::Config = pg_url
+        .parse()
+        .map_err(|e| MigrationError::from(ErrorKind::Async(format!("invalid Postgres URL: {}", e))))?;
+
+    let mut runtime = Runtime::new()?;
+
+    let pipeline = config
+        .connect(NoTls)
+        .map_err(pg_err)
+        .and_then(move |(mut pg, connection)| {
+            // the connection future does the actual I/O and has to be
+            // polled for the client to make progress
+            ::tokio::spawn(connection.map_err(|e| error!("connection failed: {}", e)));
+
+            pg.prepare("SELECT hash, lo_get(data), mime_type FROM _nice_binary \
+                        WHERE sha2 IS NULL")
+                .map_err(pg_err)
+                .and_then(move |stmt| {
+                    pg.query(&stmt, &[])
+                        .map_err(pg_err)
+                        .collect()
+                        .map(|rows| (pg, rows))
+                })
+        })
+        .and_then(move |(pg, rows)| {
+            let uploads = rows.into_iter().filter_map(move |row| {
+                let hash: String = row.get(0);
+                if Vec::from_hex(hash.trim()).map(|sha1| sha1.len()) != Ok(20) {
+                    warn!("hash {:?} in _nice_binary is not a valid sha1 hash, row skipped",
+                          hash);
+                    return None;
+                }
+                let data: Vec<u8> = row.get(1);
+                let mime_type: String = row.get(2);
+
+                let mut digest = D::default();
+                digest.process(&data);
+                let sha2 = hex::encode(digest.fixed_result());
+
+                let request = PutObjectRequest {
+                    bucket: bucket.clone(),
+                    key: sha2.clone(),
+                    body: Some(data.into()),
+                    content_type: Some(mime_type),
+                    ..Default::default()
+                };
+                Some(upload(&client, request).map(move |_| (hash, sha2)))
+            });
+
+            stream::iter_ok(uploads)
+                .buffer_unordered(concurrency)
+                .collect()
+                .map(|done| (pg, done))
+        })
+        .and_then(|(mut pg, done)| {
+            pg.prepare("UPDATE _nice_binary SET sha2 = $1 WHERE hash = $2")
+                .map_err(pg_err)
+                .and_then(move |stmt| {
+                    stream::iter_ok(done)
+                        .fold((pg, 0), move |(mut pg, count), (hash, sha2)| {
+                            pg.execute(&stmt, &[&sha2, &hash])
+                                .map_err(pg_err)
+                                .map(move |_| (pg, count + 1))
+                        })
+                })
+                .map(|(_, count)| count)
+        });
+
+    runtime.block_on(pipeline)
+}
+
+fn upload(client: &S3Client,
+          request: PutObjectRequest)
+          -> impl Future<Item = (), Error = MigrationError> {
+    let key = request.key.clone();
+    client
+        .put_object(request)
+        .map(|_| ())
+        .map_err(move |e| ErrorKind::Async(format!("PutObject of {} failed: {}", key, e)).into())
+}
+
+fn pg_err(err: tokio_postgres::Error) -> MigrationError {
+    ErrorKind::Async(format!("Postgres query failed: {}", err)).into()
+}
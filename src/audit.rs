@@ -0,0 +1,126 @@
+//! Append-only audit log with timestamps per pipeline stage.
+//!
+//! The monitor reports aggregates; when a run is slower than expected,
+//! the interesting question is usually *which* objects were slow and
+//! in which stage. With the audit log enabled, every worker stamps the
+//! [`AuditTrail`] riding on the [`Lo`] and the committers append one
+//! CSV line per migrated object — timestamps, stage durations and
+//! worker thread names — flushed line by line like the upload journal.
+//!
+//! Commit-only objects (preloaded via a resume manifest) skip the
+//! receive and store stages; their columns stay empty. Timestamps are
+//! RFC 3339, durations are seconds.
+//!
+//! [`AuditTrail`]: ../lo/struct.AuditTrail.html
+//! [`Lo`]: ../lo/struct.Lo.html
+
+use chrono::{DateTime, Utc};
+use error::Result;
+use lo::Lo;
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread;
+
+/// Column header of a freshly created log.
+const HEADER: &str = "sha1,oid,size,received_at,receive_seconds,received_by,stored_at,\
+                      store_seconds,stored_by,committed_at,committed_by";
+
+/// RFC 3339 rendering of an optional stage timestamp, empty when the
+/// stage did not run.
+fn timestamp(at: Option<DateTime<Utc>>) -> String {
+    at.map(|at| at.to_rfc3339()).unwrap_or_default()
+}
+
+/// Name of the calling worker thread.
+pub(crate) fn worker_name() -> String {
+    thread::current().name().unwrap_or("unnamed").to_string()
+}
+
+/// Appends one CSV line per migrated object.
+pub struct AuditLog {
+    file: Mutex<BufWriter<::std::fs::File>>,
+}
+
+impl AuditLog {
+    /// Open (or create) the log at `path`, appending to an existing
+    /// file so a restarted run keeps earlier lines. A freshly created
+    /// log starts with the column header.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        let mut file = BufWriter::new(file);
+        if file.get_ref().metadata()?.len() == 0 {
+            writeln!(file, "{}", HEADER)?;
+            file.flush()?;
+        }
+        Ok(AuditLog { file: Mutex::new(file) })
+    }
+
+    /// Append the line for one committed object. The line is flushed
+    /// to the OS before this returns so it survives a crash of the
+    /// process.
+    pub fn record(&self, lo: &Lo) -> Result<()> {
+        let trail = lo.audit_trail().cloned().unwrap_or_default();
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        writeln!(file,
+                 "{},{},{},{},{:.3},{},{},{:.3},{},{},{}",
+                 lo.sha1_hex(),
+                 lo.oid(),
+                 lo.size(),
+                 timestamp(trail.received_at),
+                 trail.receive_seconds,
+                 trail.received_by,
+                 timestamp(trail.stored_at),
+                 trail.store_seconds,
+                 trail.stored_by,
+                 Utc::now().to_rfc3339(),
+                 worker_name())?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn lines_are_appended_with_one_header() {
+        let path = env::temp_dir()
+            .join(format!("lo_migrate_audit_{}", ::std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        {
+            let log = AuditLog::open(&path).unwrap();
+            let mut lo = Lo::new(vec![0xab; 20], 42, 1024, "image/png".to_string());
+            {
+                let trail = lo.audit_trail_mut();
+                trail.received_at = Some(Utc.ymd(2018, 1, 1).and_hms(12, 0, 0));
+                trail.receive_seconds = 0.25;
+                trail.received_by = "receiver_0".to_string();
+            }
+            log.record(&lo).unwrap();
+        }
+        // a second run appends without a second header
+        {
+            let log = AuditLog::open(&path).unwrap();
+            log.record(&Lo::new(vec![0xcd; 20], 43, 1, "a/b".to_string()))
+                .unwrap();
+        }
+
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], HEADER);
+        assert!(lines[1].starts_with(&format!("{},42,1024,2018-01-01T12:00:00+00:00,\
+                                               0.250,receiver_0,,0.000,,",
+                                              "ab".repeat(20))));
+        // store and receive columns of the commit-only object are empty
+        assert!(lines[2].contains(",,0.000,,,0.000,,"));
+    }
+}
@@ -0,0 +1,312 @@
+//! Fault injection for integration tests.
+//!
+//! Enabled with the `chaos` feature. Wraps the backend abstractions —
+//! [`ObjectStore`], [`ConnFactory`] and plain readers — in layers that
+//! fail or stall according to configured probabilities, so the retry,
+//! reconnect and resume logic can be exercised in CI-sized tests
+//! instead of waiting for a flaky network to do it in production.
+//!
+//! The dice are a seeded xorshift*, so a failing run can be replayed
+//! exactly by reusing its seed.
+//!
+//! ```no_run
+//! use lo_migrate::chaos::{ChaosConfig, ChaosStore};
+//! use lo_migrate::object_store::MemoryObjectStore;
+//!
+//! let chaos = ChaosConfig::new(42).with_s3_error(0.05);
+//! let store = ChaosStore::new(MemoryObjectStore::new(), chaos);
+//! ```
+//!
+//! [`ObjectStore`]: ../object_store/trait.ObjectStore.html
+//! [`ConnFactory`]: ../db/trait.ConnFactory.html
+
+use db::{ConnFactory, PooledConn};
+use error::{ErrorKind, Result};
+use object_store::{ObjectStore, Part, UploadMeta, UploadOutcome};
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::Duration;
+
+/// Probabilities and seed for the injected faults.
+///
+/// All probabilities default to zero, so an unconfigured layer is a
+/// transparent pass-through.
+#[derive(Clone, Debug)]
+pub struct ChaosConfig {
+    seed: u64,
+    s3_error: f64,
+    pg_disconnect: f64,
+    slow_read: f64,
+    slow_read_delay: Duration,
+}
+
+impl ChaosConfig {
+    pub fn new(seed: u64) -> Self {
+        ChaosConfig {
+            seed: seed,
+            s3_error: 0.0,
+            pg_disconnect: 0.0,
+            slow_read: 0.0,
+            slow_read_delay: Duration::from_millis(100),
+        }
+    }
+
+    /// Probability that an [`ObjectStore`] call fails with an injected
+    /// 5xx-style error.
+    ///
+    /// [`ObjectStore`]: ../object_store/trait.ObjectStore.html
+    pub fn with_s3_error(mut self, probability: f64) -> Self {
+        assert!(probability >= 0.0 && probability <= 1.0);
+        self.s3_error = probability;
+        self
+    }
+
+    /// Probability that opening a Postgres connection fails.
+    pub fn with_pg_disconnect(mut self, probability: f64) -> Self {
+        assert!(probability >= 0.0 && probability <= 1.0);
+        self.pg_disconnect = probability;
+        self
+    }
+
+    /// Probability that a read stalls for `delay` before returning.
+    pub fn with_slow_read(mut self, probability: f64, delay: Duration) -> Self {
+        assert!(probability >= 0.0 && probability <= 1.0);
+        self.slow_read = probability;
+        self.slow_read_delay = delay;
+        self
+    }
+}
+
+/// Seeded dice shared by the chaos layers.
+///
+/// xorshift* again (cf. the synthetic data generator in [`testing`]):
+/// deterministic per seed and cheap, which is all fault injection
+/// needs.
+///
+/// [`testing`]: ../testing/index.html
+#[derive(Debug)]
+pub struct ChaosDice {
+    state: Mutex<u64>,
+}
+
+impl ChaosDice {
+    pub fn new(seed: u64) -> Self {
+        // the state must never be zero or the generator gets stuck
+        ChaosDice { state: Mutex::new(seed ^ 0x2545_f491_4f6c_dd1d) }
+    }
+
+    /// Roll once; true with the given probability.
+    pub fn roll(&self, probability: f64) -> bool {
+        if probability <= 0.0 {
+            return false;
+        }
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        *state ^= *state >> 12;
+        *state ^= *state << 25;
+        *state ^= *state >> 27;
+        let word = state.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        (word >> 11) as f64 / (1u64 << 53) as f64 <= probability
+    }
+}
+
+/// [`ObjectStore`] wrapper failing calls with injected S3 errors.
+///
+/// Every operation rolls the dice first and fails with
+/// [`ErrorKind::S3`] mimicking a 500 response; otherwise the call is
+/// passed through unchanged.
+///
+/// [`ObjectStore`]: ../object_store/trait.ObjectStore.html
+/// [`ErrorKind::S3`]: ../error/enum.ErrorKind.html
+pub struct ChaosStore<S: ObjectStore> {
+    inner: S,
+    dice: ChaosDice,
+    config: ChaosConfig,
+}
+
+impl<S: ObjectStore> ChaosStore<S> {
+    pub fn new(inner: S, config: ChaosConfig) -> Self {
+        ChaosStore {
+            inner: inner,
+            dice: ChaosDice::new(config.seed),
+            config: config,
+        }
+    }
+
+    /// The wrapped store, e.g. to inspect a [`MemoryObjectStore`] after
+    /// the run.
+    ///
+    /// [`MemoryObjectStore`]: ../object_store/struct.MemoryObjectStore.html
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn maybe_fail(&self, operation: &str) -> Result<()> {
+        if self.dice.roll(self.config.s3_error) {
+            Err(ErrorKind::S3(format!("injected 500 Internal Server Error during {}",
+                                      operation))
+                        .into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<S: ObjectStore> ObjectStore for ChaosStore<S> {
+    fn exists(&self, key: &str) -> Result<bool> {
+        self.maybe_fail("HeadObject")?;
+        self.inner.exists(key)
+    }
+
+    fn stat(&self, key: &str) -> Result<Option<u64>> {
+        self.maybe_fail("HeadObject")?;
+        self.inner.stat(key)
+    }
+
+    fn put(&self, key: &str, data: &[u8], meta: &UploadMeta) -> Result<UploadOutcome> {
+        self.maybe_fail("PutObject")?;
+        self.inner.put(key, data, meta)
+    }
+
+    fn put_shared(&self,
+                  key: &str,
+                  data: &Arc<Vec<u8>>,
+                  meta: &UploadMeta)
+                  -> Result<UploadOutcome> {
+        self.maybe_fail("PutObject")?;
+        self.inner.put_shared(key, data, meta)
+    }
+
+    fn create_multipart(&self, key: &str, meta: &UploadMeta) -> Result<String> {
+        self.maybe_fail("CreateMultipartUpload")?;
+        self.inner.create_multipart(key, meta)
+    }
+
+    fn upload_part(&self,
+                   key: &str,
+                   upload_id: &str,
+                   part_number: i64,
+                   data: &[u8],
+                   checksum_sha256: Option<&str>)
+                   -> Result<Part> {
+        self.maybe_fail("UploadPart")?;
+        self.inner.upload_part(key, upload_id, part_number, data, checksum_sha256)
+    }
+
+    fn upload_part_shared(&self,
+                          key: &str,
+                          upload_id: &str,
+                          part_number: i64,
+                          data: &Arc<Vec<u8>>,
+                          checksum_sha256: Option<&str>)
+                          -> Result<Part> {
+        self.maybe_fail("UploadPart")?;
+        self.inner.upload_part_shared(key, upload_id, part_number, data, checksum_sha256)
+    }
+
+    fn complete_multipart(&self,
+                          key: &str,
+                          upload_id: &str,
+                          parts: Vec<Part>)
+                          -> Result<UploadOutcome> {
+        self.maybe_fail("CompleteMultipartUpload")?;
+        self.inner.complete_multipart(key, upload_id, parts)
+    }
+
+    fn abort_multipart(&self, key: &str, upload_id: &str) -> Result<()> {
+        self.maybe_fail("AbortMultipartUpload")?;
+        self.inner.abort_multipart(key, upload_id)
+    }
+}
+
+/// [`ConnFactory`] wrapper simulating Postgres disconnects by failing
+/// the checkout, which is where a worker notices a dropped connection.
+///
+/// [`ConnFactory`]: ../db/trait.ConnFactory.html
+pub struct ChaosConnFactory<F: ConnFactory> {
+    inner: F,
+    dice: ChaosDice,
+    config: ChaosConfig,
+}
+
+impl<F: ConnFactory> ChaosConnFactory<F> {
+    pub fn new(inner: F, config: ChaosConfig) -> Self {
+        ChaosConnFactory {
+            inner: inner,
+            dice: ChaosDice::new(config.seed),
+            config: config,
+        }
+    }
+}
+
+impl<F: ConnFactory> ConnFactory for ChaosConnFactory<F> {
+    fn connection(&self) -> Result<PooledConn> {
+        if self.dice.roll(self.config.pg_disconnect) {
+            return Err(ErrorKind::Config("injected Postgres disconnect".to_string()).into());
+        }
+        self.inner.connection()
+    }
+}
+
+/// Reader wrapper stalling reads to simulate a slow network.
+pub struct ChaosReader<R: Read> {
+    inner: R,
+    dice: ChaosDice,
+    config: ChaosConfig,
+}
+
+impl<R: Read> ChaosReader<R> {
+    pub fn new(inner: R, config: ChaosConfig) -> Self {
+        ChaosReader {
+            inner: inner,
+            dice: ChaosDice::new(config.seed),
+            config: config,
+        }
+    }
+}
+
+impl<R: Read> Read for ChaosReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.dice.roll(self.config.slow_read) {
+            sleep(self.config.slow_read_delay);
+        }
+        self.inner.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object_store::MemoryObjectStore;
+
+    #[test]
+    fn dice_are_deterministic_per_seed() {
+        let a = ChaosDice::new(7);
+        let b = ChaosDice::new(7);
+        let rolls_a: Vec<_> = (0..100).map(|_| a.roll(0.5)).collect();
+        let rolls_b: Vec<_> = (0..100).map(|_| b.roll(0.5)).collect();
+        assert_eq!(rolls_a, rolls_b);
+        assert!(rolls_a.iter().any(|&roll| roll));
+        assert!(rolls_a.iter().any(|&roll| !roll));
+    }
+
+    #[test]
+    fn zero_probability_is_a_pass_through() {
+        let store = ChaosStore::new(MemoryObjectStore::new(), ChaosConfig::new(1));
+        let meta = UploadMeta::default();
+        for _ in 0..100 {
+            store.put("key", b"data", &meta).unwrap();
+        }
+        assert_eq!(store.inner().object_count(), 1);
+    }
+
+    #[test]
+    fn certain_probability_always_fails() {
+        let store = ChaosStore::new(MemoryObjectStore::new(),
+                                    ChaosConfig::new(1).with_s3_error(1.0));
+        let meta = UploadMeta::default();
+        assert!(store.put("key", b"data", &meta).is_err());
+        assert!(store.exists("key").is_err());
+        assert_eq!(store.inner().object_count(), 0);
+    }
+}
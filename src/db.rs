@@ -0,0 +1,758 @@
+//! Connection management, schema preparation and finalization of
+//! `_nice_binary`.
+
+use error::{ErrorKind, Result};
+use hex;
+use postgres::{Connection, TlsMode};
+use postgres::error::{DUPLICATE_COLUMN, Error, UNDEFINED_TABLE};
+use r2d2;
+use r2d2_postgres::{PostgresConnectionManager, TlsMode as PoolTlsMode};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Deref;
+use thread::ThreadStat;
+
+/// Hands out Postgres connections to the worker threads.
+///
+/// [`Migration::run()`] opens one connection per worker through this
+/// trait. The default [`UrlConnFactory`] connects to the configured URL
+/// each time; [`PooledConnFactory`] checks connections out of an r2d2
+/// pool instead, so they are reused and re-established after network
+/// hiccups. Embedders can implement the trait themselves to hand the
+/// workers prepared test connections.
+///
+/// [`Migration::run()`]: ../migrate/struct.Migration.html#method.run
+/// [`UrlConnFactory`]: struct.UrlConnFactory.html
+/// [`PooledConnFactory`]: struct.PooledConnFactory.html
+pub trait ConnFactory: Send + Sync {
+    /// Open or check out a connection.
+    fn connection(&self) -> Result<PooledConn>;
+}
+
+/// A connection obtained from a [`ConnFactory`].
+///
+/// Dereferences to [`Connection`], so the worker constructors taking
+/// `&Connection` work unchanged. Dropping it returns the connection to
+/// its pool, if it came from one.
+///
+/// [`ConnFactory`]: trait.ConnFactory.html
+/// [`Connection`]: ../../postgres/struct.Connection.html
+pub struct PooledConn(Box<Deref<Target = Connection> + Send>);
+
+impl Deref for PooledConn {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.0.deref()
+    }
+}
+
+/// [`ConnFactory`] opening a fresh connection per request.
+///
+/// [`ConnFactory`]: trait.ConnFactory.html
+#[derive(Debug)]
+pub struct UrlConnFactory {
+    url: String,
+}
+
+impl UrlConnFactory {
+    pub fn new(url: &str) -> Self {
+        UrlConnFactory { url: url.to_string() }
+    }
+}
+
+impl ConnFactory for UrlConnFactory {
+    fn connection(&self) -> Result<PooledConn> {
+        struct Owned(Connection);
+
+        impl Deref for Owned {
+            type Target = Connection;
+
+            fn deref(&self) -> &Connection {
+                &self.0
+            }
+        }
+
+        let conn = Connection::connect(&self.url[..], TlsMode::None)?;
+        Ok(PooledConn(Box::new(Owned(conn))))
+    }
+}
+
+/// [`ConnFactory`] backed by an r2d2 connection pool.
+///
+/// [`ConnFactory`]: trait.ConnFactory.html
+pub struct PooledConnFactory {
+    pool: r2d2::Pool<PostgresConnectionManager>,
+}
+
+impl PooledConnFactory {
+    /// Build a pool of up to `size` connections to `url`.
+    ///
+    /// The pool connects lazily, so this only fails on an unparsable
+    /// URL; a wrong host or password surfaces on the first checkout.
+    pub fn new(url: &str, size: u32) -> Result<Self> {
+        let manager = PostgresConnectionManager::new(url, PoolTlsMode::None)?;
+        let pool = r2d2::Pool::builder()
+            .max_size(size)
+            .min_idle(Some(0))
+            .build_unchecked(manager);
+        Ok(PooledConnFactory { pool: pool })
+    }
+}
+
+impl ConnFactory for PooledConnFactory {
+    fn connection(&self) -> Result<PooledConn> {
+        let conn = self.pool.get()?;
+        Ok(PooledConn(Box::new(conn)))
+    }
+}
+
+/// Ensure the `DeleteUnreferencedBinariesBatchJob` is disabled.
+///
+/// The batch job removes large objects that are no longer referenced; if
+/// it runs while the migration does, objects can vanish between
+/// observation and receipt. Refuse to run in that case.
+pub fn check_batch_job_is_disabled(conn: &Connection) -> Result<()> {
+    let rows = conn.query(
+        "SELECT active FROM nice_batch_job WHERE id = 'DeleteUnreferencedBinariesBatchJob'",
+        &[],
+    )?;
+    match rows.iter().next() {
+        Some(ref row) if row.get::<_, bool>(0) => Err(ErrorKind::BatchJobEnabled.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Check that the connecting role holds the privileges the migration
+/// needs, before any worker is spawned.
+///
+/// Verified are SELECT and UPDATE on `_nice_binary`, EXECUTE on
+/// `lo_open()` (large object data is read through the lo functions),
+/// and — because `ALTER TABLE` and `CREATE INDEX` require it —
+/// ownership of `_nice_binary` or superuser. Missing grants are
+/// reported together in one message instead of failing mid-run with
+/// an opaque permission error; `finalize` only sharpens that message,
+/// the ownership requirement is the same.
+pub fn check_privileges(conn: &Connection, finalize: bool) -> Result<()> {
+    let mut missing = Vec::new();
+
+    let rows = conn.query("SELECT has_table_privilege(current_user, '_nice_binary', \
+                           'SELECT'), \
+                           has_table_privilege(current_user, '_nice_binary', 'UPDATE'), \
+                           has_function_privilege(current_user, \
+                           'lo_open(oid, integer)', 'EXECUTE')",
+                          &[])?;
+    let row = rows.iter().next().expect("privilege query returns one row");
+    if !row.get::<_, bool>(0) {
+        missing.push("SELECT on _nice_binary".to_string());
+    }
+    if !row.get::<_, bool>(1) {
+        missing.push("UPDATE on _nice_binary".to_string());
+    }
+    if !row.get::<_, bool>(2) {
+        missing.push("EXECUTE on lo_open() to read large object data".to_string());
+    }
+
+    let rows = conn.query("SELECT pg_has_role(current_user, c.relowner, 'USAGE') \
+                           OR r.rolsuper \
+                           FROM pg_class c CROSS JOIN pg_roles r \
+                           WHERE c.relname = '_nice_binary' \
+                           AND r.rolname = current_user",
+                          &[])?;
+    match rows.iter().next() {
+        Some(ref row) if row.get::<_, bool>(0) => (),
+        Some(_) => {
+            missing.push(if finalize {
+                             "ownership of _nice_binary (needed for ALTER TABLE and the \
+                              indexes --finalize creates)"
+                                     .to_string()
+                         } else {
+                             "ownership of _nice_binary (needed for ALTER TABLE)".to_string()
+                         })
+        }
+        None => missing.push("table _nice_binary (not found)".to_string()),
+    }
+
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(ErrorKind::Config(format!("role lacks required privileges: {}",
+                                      missing.join(", ")))
+                    .into())
+    }
+}
+
+/// First key of the two-int advisory lock guarding a migration run;
+/// `"lomi"` in ASCII. The second key is the oid of `_nice_binary`, so
+/// the lock is scoped to the table (and thus the database) being
+/// migrated.
+const ADVISORY_LOCK_CLASS: i32 = 0x6c6f_6d69;
+
+/// Take the session-level advisory lock marking a migration run
+/// against `_nice_binary`.
+///
+/// Two operators accidentally starting the migration against the same
+/// database would double-read every object and race on the commits.
+/// The lock is held by `conn`'s session until it ends, so the guard
+/// lives exactly as long as the run; a second instance fails fast with
+/// a message instead. Deliberately concurrent runs (e.g. partitioned
+/// by a size or mime-type filter) can skip the lock via
+/// `--allow-concurrent`.
+pub fn acquire_migration_lock(conn: &Connection) -> Result<()> {
+    let rows = conn.query("SELECT pg_try_advisory_lock($1, \
+                           '_nice_binary'::regclass::oid::int)",
+                          &[&ADVISORY_LOCK_CLASS])?;
+    if rows.iter().next().map_or(false, |row| row.get::<_, bool>(0)) {
+        info!("acquired advisory lock on _nice_binary");
+        Ok(())
+    } else {
+        Err(ErrorKind::Config("another lo-migrate instance is already migrating this \
+                               database (advisory lock held); wait for it to finish \
+                               or rerun with --allow-concurrent"
+                                      .to_string())
+                    .into())
+    }
+}
+
+/// Migration-relevant schema state of `_nice_binary`, as reported at
+/// startup and consulted before reruns touch the table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SchemaState {
+    /// the `sha2` column exists
+    pub sha2_column: bool,
+    /// the column carries the finalizing NOT NULL constraint
+    pub not_null: bool,
+    /// the `_nice_binary_sha2_idx` index exists; the flag says whether
+    /// it is unique
+    pub index: Option<bool>,
+    /// whether the index is valid; a `CREATE INDEX CONCURRENTLY` that
+    /// failed partway leaves an invalid index behind
+    pub index_valid: Option<bool>,
+}
+
+impl SchemaState {
+    /// Whether [`add_constraints`] has fully finalized the table.
+    ///
+    /// [`add_constraints`]: fn.add_constraints.html
+    pub fn finalized(&self) -> bool {
+        self.not_null && self.index == Some(true) && self.index_valid == Some(true)
+    }
+}
+
+impl fmt::Display for SchemaState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.sha2_column {
+            return f.write_str("sha2 column not yet added");
+        }
+        if self.finalized() {
+            return f.write_str("finalized (sha2 NOT NULL, unique index present)");
+        }
+        write!(f, "migration in progress (sha2 column present")?;
+        if self.not_null {
+            write!(f, ", NOT NULL")?;
+        }
+        match self.index {
+            Some(true) => write!(f, ", unique index")?,
+            Some(false) => write!(f, ", non-unique index")?,
+            None => (),
+        }
+        if self.index_valid == Some(false) {
+            write!(f, ", invalid")?;
+        }
+        f.write_str(")")
+    }
+}
+
+/// Determine the current [`SchemaState`] of `_nice_binary`.
+///
+/// [`SchemaState`]: struct.SchemaState.html
+pub fn schema_state(conn: &Connection) -> Result<SchemaState> {
+    let rows = conn.query("SELECT a.attnotnull \
+                           FROM pg_attribute a JOIN pg_class c ON a.attrelid = c.oid \
+                           WHERE c.relname = '_nice_binary' AND a.attname = 'sha2' \
+                           AND NOT a.attisdropped",
+                          &[])?;
+    let (sha2_column, not_null) = match rows.iter().next() {
+        Some(ref row) => (true, row.get::<_, bool>(0)),
+        None => (false, false),
+    };
+
+    let rows = conn.query("SELECT i.indisunique, i.indisvalid \
+                           FROM pg_index i JOIN pg_class c ON i.indexrelid = c.oid \
+                           WHERE c.relname = '_nice_binary_sha2_idx'",
+                          &[])?;
+    let index = rows.iter().next();
+
+    Ok(SchemaState {
+           sha2_column: sha2_column,
+           not_null: not_null,
+           index: index.as_ref().map(|row| row.get::<_, bool>(0)),
+           index_valid: index.as_ref().map(|row| row.get::<_, bool>(1)),
+       })
+}
+
+/// Add the `sha2` column to `_nice_binary`.
+///
+/// A pre-existing column, e.g. from an earlier aborted run, is fine;
+/// rows already migrated are simply skipped by the observer. The
+/// table's current migration state is logged, and constraints left
+/// behind by an earlier `--finalize` are pointed out instead of
+/// letting the rerun misbehave quietly.
+pub fn add_sha2_column(conn: &Connection) -> Result<()> {
+    let state = schema_state(conn)?;
+    info!("_nice_binary: {}", state);
+
+    if !state.sha2_column {
+        return match conn.execute("ALTER TABLE _nice_binary ADD COLUMN sha2 varchar(64)",
+                                  &[]) {
+                   Ok(_) => {
+                       info!("sha2 column added to _nice_binary");
+                       Ok(())
+                   }
+                   // a concurrently started run added it first
+                   Err(ref err) if is_duplicate_column(err) => Ok(()),
+                   Err(err) => Err(err.into()),
+               };
+    }
+
+    if state.finalized() {
+        warn!("_nice_binary is already finalized; a completed migration leaves nothing \
+               to migrate");
+    } else if state.not_null || state.index.is_some() {
+        warn!("sha2 column already carries constraints from an earlier --finalize; \
+               leaving them untouched");
+    } else {
+        info!("sha2 column already exists, resuming earlier migration");
+    }
+    Ok(())
+}
+
+/// Add the `s3_version_id` column to `_nice_binary`.
+///
+/// Only run when `--store-version-ids` asks for the version ids of a
+/// versioning-enabled bucket to be persisted. S3 caps version ids at
+/// 1024 characters, hence the column width.
+pub fn add_version_id_column(conn: &Connection) -> Result<()> {
+    match conn.execute("ALTER TABLE _nice_binary ADD COLUMN s3_version_id varchar(1024)",
+                       &[]) {
+        Ok(_) => {
+            info!("s3_version_id column added to _nice_binary");
+            Ok(())
+        }
+        // left behind by an earlier run
+        Err(ref err) if is_duplicate_column(err) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Create the `_nice_binary_s3` mapping table.
+///
+/// Used instead of [`add_sha2_column`] when the production table must
+/// not be altered mid-migration; the committers then write (hash, sha2,
+/// key) tuples here and [`apply_mapping_table`] merges them into
+/// `_nice_binary` during the maintenance window.
+///
+/// [`add_sha2_column`]: fn.add_sha2_column.html
+/// [`apply_mapping_table`]: fn.apply_mapping_table.html
+pub fn create_mapping_table(conn: &Connection) -> Result<()> {
+    conn.batch_execute(
+        "CREATE TABLE IF NOT EXISTS _nice_binary_s3 (
+             hash varchar(40) PRIMARY KEY,
+             sha2 varchar(64) NOT NULL,
+             key varchar(64) NOT NULL
+         )",
+    )?;
+    Ok(())
+}
+
+/// Merge the hashes collected in `_nice_binary_s3` into `_nice_binary`.
+///
+/// Returns the number of rows updated. Requires the `sha2` column, so
+/// [`add_sha2_column`] is run first; this is the one ALTER that has to
+/// happen in the maintenance window.
+///
+/// [`add_sha2_column`]: fn.add_sha2_column.html
+pub fn apply_mapping_table(conn: &Connection) -> Result<u64> {
+    let updated = conn.execute(
+        "UPDATE _nice_binary b SET sha2 = m.sha2 \
+         FROM _nice_binary_s3 m WHERE b.hash = m.hash AND b.sha2 IS NULL",
+        &[],
+    )?;
+    info!("applied {} hashes from _nice_binary_s3 to _nice_binary", updated);
+    Ok(updated)
+}
+
+/// Commit sha1 -> sha2 pairs from an externally produced manifest.
+///
+/// Only the commit stage runs: rows whose `hash` matches an entry and
+/// whose `sha2` is still NULL are updated, nothing is read from or
+/// uploaded to a store. Meant for hashes obtained elsewhere — a
+/// restore, a previous run's journal, an S3 inventory joined with sha1
+/// metadata. Returns the number of rows updated; requires the `sha2`
+/// column, so [`add_sha2_column`] is run first.
+///
+/// [`add_sha2_column`]: fn.add_sha2_column.html
+pub fn apply_manifest(conn: &Connection, hashes: &HashMap<String, Vec<u8>>) -> Result<u64> {
+    let trans = conn.transaction()?;
+    let stmt = trans
+        .prepare("UPDATE _nice_binary SET sha2 = $1 WHERE hash = $2 AND sha2 IS NULL")?;
+    let mut updated = 0;
+    for (sha1, sha2) in hashes {
+        let sha2 = hex::encode(sha2);
+        updated += stmt.execute(&[&sha2, sha1])?;
+    }
+    trans.commit()?;
+    Ok(updated)
+}
+
+/// Number of distinct migrated objects and their byte total.
+///
+/// Rows sharing a sha2 hash store one object under one key, so
+/// distinct hashes are what a bucket listing should match.
+pub fn migrated_totals(conn: &Connection) -> Result<(u64, u64)> {
+    let rows = conn.query(
+        "SELECT count(*)::bigint, coalesce(sum(size), 0)::bigint \
+         FROM (SELECT DISTINCT ON (sha2) size FROM _nice_binary \
+               WHERE sha2 IS NOT NULL ORDER BY sha2) o",
+        &[],
+    )?;
+    let row = rows.get(0);
+    let objects: i64 = row.get(0);
+    let bytes: i64 = row.get(1);
+    Ok((objects as u64, bytes as u64))
+}
+
+/// A group of `_nice_binary` rows sharing identical content.
+#[derive(Debug)]
+pub struct Duplicate {
+    /// the shared sha2 hash
+    pub sha2: String,
+    /// `hash` column values of all rows carrying it
+    pub hashes: Vec<String>,
+}
+
+/// Find rows with identical content, i.e. sharing a sha2 hash.
+///
+/// Multiple rows can legitimately hold the same content under different
+/// sha1 hashes (the sha1 covers content plus legacy metadata quirks);
+/// such rows break the unique index added by [`add_constraints`].
+///
+/// [`add_constraints`]: fn.add_constraints.html
+pub fn find_duplicate_sha2(conn: &Connection) -> Result<Vec<Duplicate>> {
+    let rows = conn.query(
+        "SELECT sha2, hash FROM _nice_binary \
+         WHERE sha2 IN (SELECT sha2 FROM _nice_binary \
+                        WHERE sha2 IS NOT NULL GROUP BY sha2 HAVING count(*) > 1) \
+         ORDER BY sha2, hash",
+        &[],
+    )?;
+
+    let mut duplicates: Vec<Duplicate> = Vec::new();
+    for row in &rows {
+        let sha2: String = row.get(0);
+        let hash: String = row.get(1);
+        match duplicates.last_mut() {
+            Some(ref mut dup) if dup.sha2 == sha2 => {
+                dup.hashes.push(hash);
+                continue;
+            }
+            _ => (),
+        }
+        duplicates.push(Duplicate {
+            sha2: sha2,
+            hashes: vec![hash],
+        });
+    }
+    Ok(duplicates)
+}
+
+/// Verify that `_nice_binary` can actually be finalized, reporting a
+/// detailed diagnosis instead of letting the ALTER fail later.
+///
+/// `SET NOT NULL` fails on rows without a sha2 hash and the unique
+/// index fails on rows sharing one; both surface as a terse constraint
+/// violation at the very end of a long run. This checks for either up
+/// front and logs the affected rows. As a plausibility check the
+/// migrated row count is also compared against what the
+/// `_lo_migrate_state` table accounts for; a mismatch is only warned
+/// about, since pre-populated sha2 values or runs against a restored
+/// dump legitimately throw the bookkeeping off.
+pub fn verify_ready_to_finalize(conn: &Connection) -> Result<()> {
+    let rows = conn.query("SELECT count(*) FROM _nice_binary WHERE sha2 IS NULL", &[])?;
+    let null_rows: i64 = rows.get(0).get(0);
+    if null_rows > 0 {
+        let sample = conn.query("SELECT hash FROM _nice_binary WHERE sha2 IS NULL \
+                                 ORDER BY hash LIMIT 5",
+                                &[])?;
+        let hashes: Vec<String> = sample.iter().map(|row| row.get(0)).collect();
+        error!("{} rows still have no sha2 hash (e.g. {}); they were never migrated \
+                or their run failed",
+               null_rows,
+               hashes.join(", "));
+    }
+
+    let duplicates = find_duplicate_sha2(conn)?;
+    for dup in &duplicates {
+        warn!("rows {} share identical content (sha2 {})",
+              dup.hashes.join(", "),
+              dup.sha2);
+    }
+
+    if !duplicates.is_empty() {
+        error!("{} groups of rows with identical content found, not finalizing",
+               duplicates.len());
+        return Err(ErrorKind::DuplicateContent.into());
+    }
+    if null_rows > 0 {
+        return Err(ErrorKind::Config(format!("{} rows still have NULL sha2; migrate \
+                                              them before rerunning --finalize",
+                                             null_rows))
+                           .into());
+    }
+
+    let rows = conn.query("SELECT count(*) FROM _nice_binary", &[])?;
+    let total: i64 = rows.get(0).get(0);
+    match conn.query("SELECT coalesce(sum(committed), 0)::bigint FROM _lo_migrate_state",
+                     &[]) {
+        Ok(rows) => {
+            let committed: i64 = rows.get(0).get(0);
+            if committed == total {
+                info!("all {} rows carry a sha2 hash, matching the state table", total);
+            } else {
+                warn!("all {} rows carry a sha2 hash but _lo_migrate_state accounts \
+                       for {} committed hashes; fine if some hashes were pre-populated \
+                       or committed by a run against another copy of the database",
+                      total,
+                      committed);
+            }
+        }
+        // no state table: runs with state tracking off never create it
+        Err(ref err) if is_undefined_table(err) => {
+            info!("all {} rows carry a sha2 hash", total)
+        }
+        Err(err) => return Err(err.into()),
+    }
+    Ok(())
+}
+
+/// Finalize the migration: make `sha2` mandatory and unique.
+///
+/// Only run this once all objects have been migrated (`--finalize`).
+/// [`verify_ready_to_finalize`] vets the data first, so rows that would
+/// break the NOT NULL constraint or the unique index are reported as a
+/// detailed diagnosis up front rather than as a terse constraint
+/// violation from inside the ALTER.
+///
+/// With `concurrently` the unique index is built with `CREATE UNIQUE
+/// INDEX CONCURRENTLY`, which does not block writers on a live system
+/// but cannot run inside a transaction — `conn` must not have one open.
+/// A concurrent build that fails partway leaves an invalid index
+/// behind; rerunning `--finalize` detects it, drops it and builds the
+/// index again.
+///
+/// [`verify_ready_to_finalize`]: fn.verify_ready_to_finalize.html
+pub fn add_constraints(conn: &Connection, concurrently: bool) -> Result<()> {
+    verify_ready_to_finalize(conn)?;
+
+    let state = schema_state(conn)?;
+    if state.not_null {
+        info!("sha2 column is already NOT NULL");
+    } else {
+        conn.execute("ALTER TABLE _nice_binary ALTER COLUMN sha2 SET NOT NULL", &[])?;
+    }
+    match (state.index, state.index_valid) {
+        (Some(true), Some(true)) => info!("unique index _nice_binary_sha2_idx already exists"),
+        (Some(_), Some(false)) => {
+            // leftover of an earlier CREATE INDEX CONCURRENTLY that
+            // failed partway; drop it and build it again
+            warn!("index _nice_binary_sha2_idx is invalid (earlier concurrent build \
+                   failed partway), rebuilding it");
+            conn.execute("DROP INDEX _nice_binary_sha2_idx", &[])?;
+            create_unique_index(conn, concurrently)?;
+        }
+        (Some(false), _) => {
+            return Err(ErrorKind::Config("index _nice_binary_sha2_idx exists but is not \
+                                          unique; drop it and rerun with --finalize"
+                                                 .to_string())
+                               .into())
+        }
+        _ => create_unique_index(conn, concurrently)?,
+    }
+    info!("sha2 column finalized (NOT NULL, unique index)");
+    Ok(())
+}
+
+fn create_unique_index(conn: &Connection, concurrently: bool) -> Result<()> {
+    if concurrently {
+        match conn.execute("CREATE UNIQUE INDEX CONCURRENTLY _nice_binary_sha2_idx \
+                            ON _nice_binary (sha2)",
+                           &[]) {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                error!("concurrent index build failed and may have left an invalid \
+                        index behind; rerunning --finalize will drop and rebuild it");
+                Err(err.into())
+            }
+        }
+    } else {
+        conn.execute(
+            "CREATE UNIQUE INDEX _nice_binary_sha2_idx ON _nice_binary (sha2)",
+            &[],
+        )?;
+        Ok(())
+    }
+}
+
+/// Finalize with site-specific DDL instead of the built-in statements.
+///
+/// Different Nice versions expect specific constraint and index names
+/// and some sites index additional columns in the same maintenance
+/// window; `--finalize-sql FILE` hands them full control over the DDL.
+/// The data is vetted with [`verify_ready_to_finalize`] first, exactly
+/// as the built-in [`add_constraints`] does.
+///
+/// Statements are separated by `;` and executed one at a time outside
+/// a transaction, so `CREATE INDEX CONCURRENTLY` works; a script that
+/// wants atomicity can say `BEGIN;` and `COMMIT;` itself. `--` line
+/// comments are ignored.
+///
+/// [`verify_ready_to_finalize`]: fn.verify_ready_to_finalize.html
+/// [`add_constraints`]: fn.add_constraints.html
+pub fn run_finalize_script(conn: &Connection, sql: &str) -> Result<()> {
+    let statements = split_statements(sql);
+    if statements.is_empty() {
+        return Err(ErrorKind::Config("finalize script contains no statements".to_string())
+                       .into());
+    }
+    verify_ready_to_finalize(conn)?;
+
+    for statement in &statements {
+        info!("finalize: {}", statement);
+        conn.execute(statement, &[])?;
+    }
+    info!("executed {} finalize statements", statements.len());
+    Ok(())
+}
+
+/// Split a script into statements: `--` comments stripped, statements
+/// separated by `;`, empty ones dropped.
+///
+/// Deliberately simple — it does not know about `;` or `--` inside
+/// string literals or dollar quoting, which DDL scripts rarely need.
+fn split_statements(sql: &str) -> Vec<String> {
+    sql.lines()
+        .map(|line| match line.find("--") {
+                 Some(comment) => &line[..comment],
+                 None => line,
+             })
+        .collect::<Vec<_>>()
+        .join("\n")
+        .split(';')
+        .map(str::trim)
+        .filter(|statement| !statement.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn is_duplicate_column(err: &Error) -> bool {
+    err.code() == Some(&DUPLICATE_COLUMN)
+}
+
+fn is_undefined_table(err: &Error) -> bool {
+    err.code() == Some(&UNDEFINED_TABLE)
+}
+
+/// Handle to this run's row in the `_lo_migrate_state` table.
+///
+/// The state table gives resumes, audits and runs coordinated across
+/// several hosts an authoritative record in the target database itself:
+/// one row per run with start time, current counters, the high-water
+/// mark of committed hashes and whether the run finalized the table.
+#[derive(Debug, Clone, Copy)]
+pub struct RunState {
+    run_id: i64,
+}
+
+impl RunState {
+    /// Create the state table if needed and register a new run.
+    pub fn create(conn: &Connection) -> Result<RunState> {
+        conn.batch_execute(
+            "CREATE TABLE IF NOT EXISTS _lo_migrate_state (
+                 run_id bigserial PRIMARY KEY,
+                 started_at timestamp with time zone NOT NULL DEFAULT now(),
+                 updated_at timestamp with time zone NOT NULL DEFAULT now(),
+                 observed bigint NOT NULL DEFAULT 0,
+                 received bigint NOT NULL DEFAULT 0,
+                 stored bigint NOT NULL DEFAULT 0,
+                 committed bigint NOT NULL DEFAULT 0,
+                 failed bigint NOT NULL DEFAULT 0,
+                 last_committed_hash varchar(40),
+                 finalized boolean NOT NULL DEFAULT false
+             )",
+        )?;
+        let rows = conn.query("INSERT INTO _lo_migrate_state DEFAULT VALUES RETURNING run_id",
+                              &[])?;
+        let run_id: i64 = rows.get(0).get(0);
+        info!("registered as run {} in _lo_migrate_state", run_id);
+        Ok(RunState { run_id: run_id })
+    }
+
+    pub fn run_id(&self) -> i64 {
+        self.run_id
+    }
+
+    /// Write the current counters and high-water mark to the state row.
+    pub fn update(&self, conn: &Connection, stats: &ThreadStat) -> Result<()> {
+        conn.execute(
+            "UPDATE _lo_migrate_state
+             SET updated_at = now(), observed = $2, received = $3, stored = $4,
+                 committed = $5, failed = $6, last_committed_hash = $7
+             WHERE run_id = $1",
+            &[&self.run_id,
+              &(stats.lo_observed() as i64),
+              &(stats.lo_received() as i64),
+              &(stats.lo_stored() as i64),
+              &(stats.lo_committed() as i64),
+              &(stats.lo_failed() as i64),
+              &stats.last_committed_hash()],
+        )?;
+        Ok(())
+    }
+
+    /// Record that this run finalized `_nice_binary`.
+    pub fn set_finalized(&self, conn: &Connection) -> Result<()> {
+        conn.execute("UPDATE _lo_migrate_state SET finalized = true WHERE run_id = $1",
+                     &[&self.run_id])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_statements;
+
+    #[test]
+    fn statements_are_split_on_semicolons() {
+        let script = "ALTER TABLE _nice_binary ALTER COLUMN sha2 SET NOT NULL;\n\
+                      CREATE UNIQUE INDEX binary_sha2_uniq ON _nice_binary (sha2);\n";
+        assert_eq!(split_statements(script),
+                   vec!["ALTER TABLE _nice_binary ALTER COLUMN sha2 SET NOT NULL",
+                        "CREATE UNIQUE INDEX binary_sha2_uniq ON _nice_binary (sha2)"]);
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_ignored() {
+        let script = "-- site specific finalize DDL\n\
+                      \n\
+                      DROP INDEX old_idx; -- replaced below\n\
+                      CREATE INDEX new_idx\n\
+                      ON _nice_binary (sha2)";
+        assert_eq!(split_statements(script),
+                   vec!["DROP INDEX old_idx", "CREATE INDEX new_idx\nON _nice_binary (sha2)"]);
+    }
+
+    #[test]
+    fn an_all_comment_script_yields_no_statements() {
+        assert!(split_statements("-- nothing to do\n").is_empty());
+    }
+}
@@ -0,0 +1,346 @@
+//! Error type shared by all worker threads.
+
+use postgres;
+use r2d2;
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use std::result;
+use two_lock_queue::SendError;
+
+/// Convenience alias used throughout the crate.
+pub type Result<T> = result::Result<T, MigrationError>;
+
+/// Pipeline stage an error originated in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    /// walking `_nice_binary`
+    Observe,
+    /// reading large object data from Postgres
+    Receive,
+    /// checking already migrated objects against the bucket
+    Verify,
+    /// uploading to S3
+    Store,
+    /// writing hashes back to `_nice_binary`
+    Commit,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+                        Stage::Observe => "observer",
+                        Stage::Receive => "receiver",
+                        Stage::Verify => "verifier",
+                        Stage::Store => "storer",
+                        Stage::Commit => "committer",
+                    })
+    }
+}
+
+/// What actually went wrong; carried by [`MigrationError`].
+///
+/// [`MigrationError`]: struct.MigrationError.html
+#[derive(Debug)]
+pub enum ErrorKind {
+    /// thread has been cancelled
+    ThreadCancelled,
+    /// queue disconnected
+    QueueDisconnected,
+    /// a worker thread panicked
+    WorkerPanicked(String),
+    /// processing a single object panicked
+    ObjectPanicked(String),
+    /// invalid sha1 hash in `_nice_binary`
+    InvalidHash,
+    /// invalid configuration value
+    Config(String),
+    /// malformed resume manifest or upload journal
+    Manifest(String),
+    /// binary cleanup batch job is still enabled
+    BatchJobEnabled,
+    /// multiple rows share the same sha2 hash
+    DuplicateContent,
+    /// object exceeds an S3 size limit or the configured maximum
+    ObjectTooLarge(String),
+    /// I/O error
+    Io(io::Error),
+    /// Postgres error
+    Postgres(Box<postgres::error::Error>),
+    /// connection pool error
+    Pool(r2d2::Error),
+    /// S3 error
+    S3(String),
+    /// transient S3 error (5xx, timeout, throttling) worth retrying
+    S3Transient(String),
+    /// uploaded object failed checksum validation
+    ChecksumMismatch,
+    /// no buffered data attached to the object
+    NoDataAttached,
+    /// sha2 hash of the object has not been computed
+    Sha2NotComputed,
+    /// error in the experimental async pipeline
+    Async(String),
+    /// metrics could not be delivered, e.g. to a Pushgateway
+    Metrics(String),
+}
+
+impl ErrorKind {
+    /// Short variant name used to aggregate errors in the end-of-run
+    /// breakdown, e.g. `"ChecksumMismatch"`.
+    pub fn category(&self) -> &'static str {
+        match *self {
+            ErrorKind::ThreadCancelled => "ThreadCancelled",
+            ErrorKind::QueueDisconnected => "QueueDisconnected",
+            ErrorKind::WorkerPanicked(_) => "WorkerPanicked",
+            ErrorKind::ObjectPanicked(_) => "ObjectPanicked",
+            ErrorKind::InvalidHash => "InvalidHash",
+            ErrorKind::Config(_) => "Config",
+            ErrorKind::Manifest(_) => "Manifest",
+            ErrorKind::BatchJobEnabled => "BatchJobEnabled",
+            ErrorKind::DuplicateContent => "DuplicateContent",
+            ErrorKind::ObjectTooLarge(_) => "ObjectTooLarge",
+            ErrorKind::Io(_) => "Io",
+            ErrorKind::Postgres(_) => "Postgres",
+            ErrorKind::Pool(_) => "Pool",
+            ErrorKind::S3(_) => "S3",
+            ErrorKind::S3Transient(_) => "S3Transient",
+            ErrorKind::ChecksumMismatch => "ChecksumMismatch",
+            ErrorKind::NoDataAttached => "NoDataAttached",
+            ErrorKind::Sha2NotComputed => "Sha2NotComputed",
+            ErrorKind::Async(_) => "Async",
+            ErrorKind::Metrics(_) => "Metrics",
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorKind::ThreadCancelled => write!(f, "thread has been cancelled"),
+            ErrorKind::QueueDisconnected => write!(f, "queue disconnected"),
+            ErrorKind::WorkerPanicked(ref name) => {
+                write!(f, "worker thread {} panicked", name)
+            }
+            ErrorKind::ObjectPanicked(ref msg) => {
+                write!(f, "processing the object panicked: {}", msg)
+            }
+            ErrorKind::InvalidHash => write!(f, "invalid sha1 hash in _nice_binary"),
+            ErrorKind::Config(ref msg) => write!(f, "invalid configuration: {}", msg),
+            ErrorKind::Manifest(ref msg) => write!(f, "malformed manifest: {}", msg),
+            ErrorKind::BatchJobEnabled => {
+                write!(f, "binary cleanup batch job is still enabled")
+            }
+            ErrorKind::DuplicateContent => {
+                write!(f, "multiple rows share the same sha2 hash")
+            }
+            ErrorKind::ObjectTooLarge(ref msg) => write!(f, "object too large: {}", msg),
+            ErrorKind::Io(ref err) => write!(f, "I/O error: {}", err),
+            ErrorKind::Postgres(ref err) => write!(f, "Postgres error: {}", err),
+            ErrorKind::Pool(ref err) => write!(f, "connection pool error: {}", err),
+            ErrorKind::S3(ref msg) => write!(f, "S3 error: {}", msg),
+            ErrorKind::S3Transient(ref msg) => write!(f, "transient S3 error: {}", msg),
+            ErrorKind::ChecksumMismatch => {
+                write!(f, "uploaded object failed checksum validation")
+            }
+            ErrorKind::NoDataAttached => {
+                write!(f, "no buffered data attached to the object")
+            }
+            ErrorKind::Sha2NotComputed => {
+                write!(f, "sha2 hash of the object has not been computed")
+            }
+            ErrorKind::Async(ref msg) => write!(f, "async pipeline error: {}", msg),
+            ErrorKind::Metrics(ref msg) => write!(f, "metrics delivery failed: {}", msg),
+        }
+    }
+}
+
+/// An [`ErrorKind`] annotated with where in the pipeline it happened
+/// and, if known, which object it affected.
+///
+/// The annotations are added as the error bubbles up (see [`at()`] and
+/// [`for_object()`]); once set they stick, so the log line and the
+/// final report name the failing object and stage without digging
+/// through debug output.
+///
+/// [`ErrorKind`]: enum.ErrorKind.html
+/// [`at()`]: #method.at
+/// [`for_object()`]: #method.for_object
+#[derive(Debug)]
+pub struct MigrationError {
+    kind: ErrorKind,
+    stage: Option<Stage>,
+    oid: Option<u32>,
+    sha1: Option<String>,
+}
+
+impl MigrationError {
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    /// Whether retrying the failed operation can plausibly succeed,
+    /// e.g. a 5xx or timeout from the object store.
+    pub fn is_transient(&self) -> bool {
+        match self.kind {
+            ErrorKind::S3Transient(_) => true,
+            _ => false,
+        }
+    }
+
+    /// pipeline stage the error originated in, if annotated
+    pub fn stage(&self) -> Option<Stage> {
+        self.stage
+    }
+
+    /// oid of the affected large object, if annotated
+    pub fn oid(&self) -> Option<u32> {
+        self.oid
+    }
+
+    /// `hash` column value of the affected row, if annotated
+    pub fn sha1(&self) -> Option<&str> {
+        self.sha1.as_ref().map(|sha1| &sha1[..])
+    }
+
+    /// Annotate the stage the error originated in. An existing
+    /// annotation (from deeper in the call chain) is kept.
+    pub fn at(mut self, stage: Stage) -> Self {
+        if self.stage.is_none() {
+            self.stage = Some(stage);
+        }
+        self
+    }
+
+    /// Annotate the object the error relates to. An existing
+    /// annotation is kept.
+    pub fn for_object(mut self, oid: u32, sha1: String) -> Self {
+        if self.oid.is_none() && self.sha1.is_none() {
+            self.oid = Some(oid);
+            self.sha1 = Some(sha1);
+        }
+        self
+    }
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(stage) = self.stage {
+            write!(f, "{}: ", stage)?;
+        }
+        match (self.oid, &self.sha1) {
+            (Some(oid), &Some(ref sha1)) => {
+                write!(f, "large object {} (sha1 {}): ", oid, sha1)?
+            }
+            (Some(oid), &None) => write!(f, "large object {}: ", oid)?,
+            (None, &Some(ref sha1)) => write!(f, "object with sha1 {}: ", sha1)?,
+            (None, &None) => (),
+        }
+        self.kind.fmt(f)
+    }
+}
+
+impl StdError for MigrationError {
+    fn description(&self) -> &str {
+        match self.kind {
+            ErrorKind::ThreadCancelled => "thread has been cancelled",
+            ErrorKind::QueueDisconnected => "queue disconnected",
+            ErrorKind::WorkerPanicked(_) => "a worker thread panicked",
+            ErrorKind::ObjectPanicked(_) => "processing an object panicked",
+            ErrorKind::InvalidHash => "invalid sha1 hash in _nice_binary",
+            ErrorKind::Config(_) => "invalid configuration",
+            ErrorKind::Manifest(_) => "malformed resume manifest or upload journal",
+            ErrorKind::BatchJobEnabled => "binary cleanup batch job is still enabled",
+            ErrorKind::DuplicateContent => "multiple rows share the same sha2 hash",
+            ErrorKind::ObjectTooLarge(_) => "object too large",
+            ErrorKind::Io(_) => "I/O error",
+            ErrorKind::Postgres(_) => "Postgres error",
+            ErrorKind::Pool(_) => "connection pool error",
+            ErrorKind::S3(_) => "S3 error",
+            ErrorKind::S3Transient(_) => "transient S3 error",
+            ErrorKind::ChecksumMismatch => "uploaded object failed checksum validation",
+            ErrorKind::NoDataAttached => "no buffered data attached to the object",
+            ErrorKind::Sha2NotComputed => "sha2 hash of the object has not been computed",
+            ErrorKind::Async(_) => "async pipeline error",
+            ErrorKind::Metrics(_) => "metrics delivery failed",
+        }
+    }
+
+    fn cause(&self) -> Option<&StdError> {
+        match self.kind {
+            ErrorKind::Io(ref err) => Some(err),
+            ErrorKind::Postgres(ref err) => Some(&**err),
+            ErrorKind::Pool(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<ErrorKind> for MigrationError {
+    fn from(kind: ErrorKind) -> Self {
+        MigrationError {
+            kind: kind,
+            stage: None,
+            oid: None,
+            sha1: None,
+        }
+    }
+}
+
+impl From<io::Error> for MigrationError {
+    fn from(err: io::Error) -> Self {
+        ErrorKind::Io(err).into()
+    }
+}
+
+impl From<postgres::error::Error> for MigrationError {
+    fn from(err: postgres::error::Error) -> Self {
+        ErrorKind::Postgres(Box::new(err)).into()
+    }
+}
+
+impl From<r2d2::Error> for MigrationError {
+    fn from(err: r2d2::Error) -> Self {
+        ErrorKind::Pool(err).into()
+    }
+}
+
+impl<T> From<SendError<T>> for MigrationError {
+    fn from(_: SendError<T>) -> Self {
+        ErrorKind::QueueDisconnected.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn annotations_show_up_in_the_message() {
+        let err = MigrationError::from(ErrorKind::ChecksumMismatch)
+            .at(Stage::Store)
+            .for_object(4221, "da39a3ee".to_string());
+        assert_eq!(err.to_string(),
+                   "storer: large object 4221 (sha1 da39a3ee): uploaded object failed \
+                    checksum validation");
+        assert_eq!(err.stage(), Some(Stage::Store));
+        assert_eq!(err.oid(), Some(4221));
+        assert_eq!(err.sha1(), Some("da39a3ee"));
+    }
+
+    #[test]
+    fn earlier_annotations_stick() {
+        let err = MigrationError::from(ErrorKind::InvalidHash)
+            .at(Stage::Observe)
+            .at(Stage::Commit);
+        assert_eq!(err.stage(), Some(Stage::Observe));
+    }
+
+    #[test]
+    fn cause_chains_to_the_underlying_error() {
+        use std::error::Error;
+        use std::io;
+        let err = MigrationError::from(io::Error::new(io::ErrorKind::Other, "disk on fire"));
+        assert!(err.cause().is_some());
+    }
+}
@@ -0,0 +1,339 @@
+//! Pre-run time and resource estimator.
+//!
+//! Samples a handful of pending objects, measures how fast their data
+//! comes out of Postgres and goes into the bucket, and extrapolates
+//! total duration, peak temp-disk usage and S3 request counts from the
+//! source's totals — so an operator can size the maintenance window
+//! before committing to a run.
+//!
+//! The probe upload uses a multipart upload that is aborted afterwards,
+//! so estimating leaves nothing behind in the bucket.
+//!
+//! ```no_run
+//! # use lo_migrate::estimate::Estimator;
+//! # let conn = unimplemented!();
+//! let estimate = Estimator::new(&conn).run(None).unwrap();
+//! println!("{}", estimate);
+//! ```
+
+use config::{QueueConfig, ThreadConfig};
+use error::Result;
+use fallible_iterator::FallibleIterator;
+use lo::Lo;
+use object_store::{ObjectStore, UploadMeta};
+use postgres::Connection;
+use source::{DataFormat, LoSource, NiceBinarySource, PendingFilter, PendingLos,
+             PendingObject, SourceTotals};
+use std::fmt;
+use std::io::Read;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Key the aborted probe upload is created under.
+const PROBE_KEY: &str = "lo-migrate-estimate-probe";
+
+/// Parts uploaded (and discarded) to measure store throughput.
+const PROBE_PARTS: usize = 3;
+
+/// Samples pending objects and extrapolates a run's resource needs.
+pub struct Estimator<'a> {
+    conn: &'a Connection,
+    source: Option<Arc<LoSource>>,
+    data_format: DataFormat,
+    sample_size: usize,
+    upload_chunk_size: usize,
+    max_in_memory: i64,
+    threads: ThreadConfig,
+    queues: QueueConfig,
+}
+
+impl<'a> Estimator<'a> {
+    pub fn new(conn: &'a Connection) -> Self {
+        Estimator {
+            conn: conn,
+            source: None,
+            data_format: DataFormat::LargeObject,
+            sample_size: 100,
+            upload_chunk_size: 50 * 1024 * 1024,
+            max_in_memory: 1024 * 1024,
+            threads: ThreadConfig::default(),
+            queues: QueueConfig::default(),
+        }
+    }
+
+    /// Estimate against a custom [`LoSource`].
+    ///
+    /// [`LoSource`]: ../source/trait.LoSource.html
+    pub fn with_source(mut self, source: Arc<LoSource>) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// How the `data` column stores the binary data; see
+    /// [`DataFormat`]. Ignored when a custom source is set.
+    ///
+    /// [`DataFormat`]: ../source/enum.DataFormat.html
+    pub fn with_data_format(mut self, format: DataFormat) -> Self {
+        self.data_format = format;
+        self
+    }
+
+    /// Number of objects sampled; more objects, better numbers.
+    pub fn with_sample_size(mut self, sample_size: usize) -> Self {
+        assert!(sample_size > 0, "sample size must be positive");
+        self.sample_size = sample_size;
+        self
+    }
+
+    /// Match the run's planned multipart chunk size.
+    pub fn with_upload_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.upload_chunk_size = chunk_size;
+        self
+    }
+
+    /// Match the run's planned in-memory buffering threshold.
+    pub fn with_max_in_memory(mut self, max_in_memory: i64) -> Self {
+        self.max_in_memory = max_in_memory;
+        self
+    }
+
+    /// Match the run's planned thread counts.
+    pub fn with_threads(mut self, threads: ThreadConfig) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Match the run's planned queue capacities.
+    pub fn with_queues(mut self, queues: QueueConfig) -> Self {
+        self.queues = queues;
+        self
+    }
+
+    /// Sample, measure and extrapolate.
+    ///
+    /// Store throughput is only measured when a `store` is given;
+    /// without one the duration estimate assumes receiving is the
+    /// bottleneck.
+    pub fn run(&self, store: Option<&ObjectStore>) -> Result<Estimate> {
+        let source = self.source
+            .clone()
+            .unwrap_or_else(|| {
+                Arc::new(NiceBinarySource::new().with_data_format(self.data_format))
+            });
+        let totals = source.totals(self.conn)?;
+
+        let mut sampled = Vec::new();
+        {
+            let mut pending = PendingLos::new(self.conn, PendingFilter::default())
+                .with_data_format(self.data_format);
+            while let Some(object) = pending.next()? {
+                if sampled.len() == self.sample_size {
+                    break;
+                }
+                sampled.push(object);
+            }
+        }
+
+        let (sampled_bytes, receive_time) = self.measure_receive(&*source, &sampled)?;
+        let receive_throughput = throughput(sampled_bytes, receive_time);
+        let store_throughput = match store {
+            Some(store) => Some(self.measure_store(store)?),
+            None => None,
+        };
+
+        Ok(self.extrapolate(totals,
+                            &sampled,
+                            sampled_bytes,
+                            receive_throughput,
+                            store_throughput))
+    }
+
+    /// Stream the sampled objects out of Postgres, timing the reads.
+    fn measure_receive(&self,
+                       source: &LoSource,
+                       sampled: &[PendingObject])
+                       -> Result<(u64, Duration)> {
+        let mut bytes = 0;
+        let start = Instant::now();
+        let mut buffer = vec![0; 64 * 1024];
+        for object in sampled {
+            let lo = Lo::from_hash_hex(&object.hash,
+                                       object.oid,
+                                       object.size,
+                                       object.mime_type.clone())?;
+            let trans = self.conn.transaction()?;
+            let mut reader = source.open_data(&trans, &lo)?;
+            loop {
+                let read = reader.read(&mut buffer)?;
+                if read == 0 {
+                    break;
+                }
+                bytes += read as u64;
+            }
+        }
+        Ok((bytes, start.elapsed()))
+    }
+
+    /// Upload a few parts to the bucket and abort the upload, timing
+    /// the transfers. Nothing is left behind.
+    fn measure_store(&self, store: &ObjectStore) -> Result<f64> {
+        // incompressible-ish pattern; a part of zeros would flatter
+        // stores that compress on the wire
+        let part: Vec<u8> = (0..self.upload_chunk_size.min(5 * 1024 * 1024))
+            .map(|n| (n.wrapping_mul(2_654_435_761)) as u8)
+            .collect();
+
+        let upload_id = store.create_multipart(PROBE_KEY, &UploadMeta::default())?;
+        let start = Instant::now();
+        let mut uploaded = 0;
+        let result = (|| -> Result<()> {
+            for number in 0..PROBE_PARTS {
+                store.upload_part(PROBE_KEY, &upload_id, number as i64 + 1, &part, None)?;
+                uploaded += part.len() as u64;
+            }
+            Ok(())
+        })();
+        let elapsed = start.elapsed();
+        store.abort_multipart(PROBE_KEY, &upload_id)?;
+        result?;
+        Ok(throughput(uploaded, elapsed))
+    }
+
+    fn extrapolate(&self,
+                   totals: SourceTotals,
+                   sampled: &[PendingObject],
+                   sampled_bytes: u64,
+                   receive_throughput: f64,
+                   store_throughput: Option<f64>)
+                   -> Estimate {
+        // per-object request count: one PUT for small objects, create +
+        // parts + complete for multipart ones
+        let sample_requests: u64 = sampled
+            .iter()
+            .map(|object| if object.size as usize <= self.upload_chunk_size {
+                     1
+                 } else {
+                     2 + (object.size as u64 + self.upload_chunk_size as u64 - 1) /
+                         self.upload_chunk_size as u64
+                 })
+            .sum();
+        let s3_requests = scale(sample_requests, sampled.len() as u64, totals.remaining);
+
+        // objects over the in-memory threshold sit on disk while queued
+        // for the storers; at steady state the store queue is full
+        let disk_buffered: u64 = sampled
+            .iter()
+            .filter(|object| object.size > self.max_in_memory)
+            .map(|object| object.size as u64)
+            .sum();
+        let temp_disk_bytes = scale(disk_buffered,
+                                    sampled.len() as u64,
+                                    self.queues.store as u64 + self.threads.storers as u64);
+
+        // each stage moves every byte once; the slowest stage paces the
+        // pipeline
+        let receive_rate = receive_throughput * self.threads.receivers as f64;
+        let rate = match store_throughput {
+            Some(store) => receive_rate.min(store * self.threads.storers as f64),
+            None => receive_rate,
+        };
+        let estimated_duration = if rate > 0.0 {
+            Duration::from_secs((totals.remaining_bytes as f64 / rate) as u64)
+        } else {
+            Duration::from_secs(0)
+        };
+
+        Estimate {
+            sampled: sampled.len() as u64,
+            sampled_bytes: sampled_bytes,
+            receive_throughput: receive_throughput,
+            store_throughput: store_throughput,
+            totals: totals,
+            estimated_duration: estimated_duration,
+            temp_disk_bytes: temp_disk_bytes,
+            s3_requests: s3_requests,
+        }
+    }
+}
+
+/// Average `count / sampled` scaled up to `population`, rounding up.
+fn scale(count: u64, sampled: u64, population: u64) -> u64 {
+    if sampled == 0 {
+        return 0;
+    }
+    (count * population + sampled - 1) / sampled
+}
+
+/// bytes / duration in bytes per second
+fn throughput(bytes: u64, elapsed: Duration) -> f64 {
+    let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+    if secs > 0.0 { bytes as f64 / secs } else { 0.0 }
+}
+
+/// What a run is expected to need, extrapolated from a sample.
+#[derive(Debug)]
+pub struct Estimate {
+    /// objects actually sampled (may be fewer than requested)
+    pub sampled: u64,
+    /// bytes streamed while sampling
+    pub sampled_bytes: u64,
+    /// per-receiver-thread throughput in bytes per second
+    pub receive_throughput: f64,
+    /// per-storer-thread throughput in bytes per second, if measured
+    pub store_throughput: Option<f64>,
+    /// totals reported by the source
+    pub totals: SourceTotals,
+    /// expected wall-clock duration of the run
+    pub estimated_duration: Duration,
+    /// expected peak temporary disk usage in bytes
+    pub temp_disk_bytes: u64,
+    /// expected number of S3 requests
+    pub s3_requests: u64,
+}
+
+impl fmt::Display for Estimate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f,
+                 "sampled {} objects ({} bytes)",
+                 self.sampled,
+                 self.sampled_bytes)?;
+        writeln!(f,
+                 "receive throughput: {:.1} MiB/s per thread",
+                 self.receive_throughput / (1024.0 * 1024.0))?;
+        if let Some(store) = self.store_throughput {
+            writeln!(f,
+                     "store throughput: {:.1} MiB/s per thread",
+                     store / (1024.0 * 1024.0))?;
+        }
+        writeln!(f,
+                 "{} of {} objects remaining ({} bytes)",
+                 self.totals.remaining,
+                 self.totals.count,
+                 self.totals.remaining_bytes)?;
+        writeln!(f,
+                 "estimated duration: {} minutes",
+                 self.estimated_duration.as_secs() / 60)?;
+        writeln!(f,
+                 "estimated peak temp-disk usage: {} MiB",
+                 self.temp_disk_bytes / (1024 * 1024))?;
+        write!(f, "estimated S3 requests: {}", self.s3_requests)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scaling_rounds_up() {
+        assert_eq!(scale(3, 2, 100), 150);
+        assert_eq!(scale(1, 3, 100), 34);
+        assert_eq!(scale(0, 0, 100), 0);
+    }
+
+    #[test]
+    fn throughput_handles_zero_duration() {
+        assert_eq!(throughput(100, Duration::from_secs(0)), 0.0);
+        assert_eq!(throughput(100, Duration::from_secs(2)), 50.0);
+    }
+}
@@ -0,0 +1,238 @@
+//! Per-object event stream for downstream systems.
+//!
+//! Search indexes, CDN warmers and similar systems want to react to
+//! the migration in near real time instead of polling `_nice_binary`.
+//! [`EventStream`] publishes one small JSON event per committed object
+//! — oid, sha1, sha2, size, timestamp, status — over one of two
+//! transports:
+//!
+//! * newline-delimited JSON to a plain TCP endpoint; `kafkacat -P -t
+//!   topic` reading that stream bridges into Kafka, which keeps a
+//!   native Kafka client out of this crate's dependency budget,
+//! * STOMP `SEND` frames to an AMQP broker's STOMP listener (RabbitMQ
+//!   and ActiveMQ ship one), addressed to a configurable destination.
+//!
+//! Delivery is best effort: the committers emit events after the
+//! database transaction went through, and a broker outage must not
+//! stall the migration, so failures are logged and the events dropped.
+//! One reconnect is attempted per event, covering brokers that drop
+//! idle connections.
+//!
+//! [`EventStream`]: struct.EventStream.html
+
+use chrono::Utc;
+use error::{ErrorKind, Result};
+use lo::Lo;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How long connecting and sending one event may take.
+const EVENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The JSON line published for one object.
+fn event_json(lo: &Lo, status: &str) -> String {
+    format!("{{\"oid\":{},\"sha1\":\"{}\",\"sha2\":{},\"size\":{},\
+             \"timestamp\":\"{}\",\"status\":\"{}\"}}",
+            lo.oid(),
+            lo.sha1_hex(),
+            lo.sha2_hex()
+                .map(|sha2| format!("\"{}\"", sha2))
+                .unwrap_or_else(|| "null".to_string()),
+            lo.size(),
+            Utc::now().to_rfc3339(),
+            status)
+}
+
+/// STOMP `CONNECT` frame opening a session.
+fn connect_frame(login: Option<&(String, String)>) -> Vec<u8> {
+    let mut frame = String::from("CONNECT\naccept-version:1.0\n");
+    if let Some(&(ref login, ref passcode)) = login {
+        frame.push_str(&format!("login:{}\npasscode:{}\n", login, passcode));
+    }
+    frame.push('\n');
+    let mut frame = frame.into_bytes();
+    frame.push(0);
+    frame
+}
+
+/// STOMP `SEND` frame carrying one event.
+fn send_frame(destination: &str, body: &str) -> Vec<u8> {
+    let mut frame = format!("SEND\ndestination:{}\ncontent-type:application/json\n\
+                             content-length:{}\n\n{}",
+                            destination,
+                            body.len(),
+                            body)
+            .into_bytes();
+    frame.push(0);
+    frame
+}
+
+/// How the events leave the process.
+enum Transport {
+    /// newline-delimited JSON over a plain TCP connection
+    Tcp,
+    /// STOMP `SEND` frames to `destination`
+    Stomp {
+        destination: String,
+        login: Option<(String, String)>,
+    },
+}
+
+/// Publishes one JSON event per committed object.
+pub struct EventStream {
+    addr: String,
+    transport: Transport,
+    conn: Mutex<Option<TcpStream>>,
+}
+
+impl EventStream {
+    /// Stream to `addr` (`host:port`) as newline-delimited JSON.
+    pub fn tcp(addr: &str) -> Result<Self> {
+        Self::new(addr, Transport::Tcp)
+    }
+
+    /// Stream to the STOMP listener at `addr`, one `SEND` frame per
+    /// event addressed to `destination`, e.g. `/topic/lo-migrate`.
+    pub fn stomp(addr: &str, destination: &str, login: Option<(&str, &str)>) -> Result<Self> {
+        if destination.is_empty() {
+            return Err(ErrorKind::Config("no STOMP destination to publish to".to_string())
+                               .into());
+        }
+        Self::new(addr,
+                  Transport::Stomp {
+                      destination: destination.to_string(),
+                      login: login.map(|(login, passcode)| {
+                                           (login.to_string(), passcode.to_string())
+                                       }),
+                  })
+    }
+
+    fn new(addr: &str, transport: Transport) -> Result<Self> {
+        if addr.is_empty() || !addr.contains(':') {
+            return Err(ErrorKind::Config(format!("event stream address {:?} is not \
+                                                  host:port",
+                                                 addr))
+                               .into());
+        }
+        Ok(EventStream {
+               addr: addr.to_string(),
+               transport: transport,
+               conn: Mutex::new(None),
+           })
+    }
+
+    /// Open a connection, including the STOMP handshake.
+    fn connect(&self) -> Result<TcpStream> {
+        let mut stream = TcpStream::connect(&*self.addr)?;
+        stream.set_read_timeout(Some(EVENT_TIMEOUT))?;
+        stream.set_write_timeout(Some(EVENT_TIMEOUT))?;
+        if let Transport::Stomp { ref login, .. } = self.transport {
+            stream.write_all(&connect_frame(login.as_ref()))?;
+            let mut response = Vec::new();
+            let mut byte = [0u8; 1];
+            // frames end at a NUL byte
+            loop {
+                stream.read_exact(&mut byte)?;
+                if byte[0] == 0 {
+                    break;
+                }
+                response.push(byte[0]);
+            }
+            if !response.starts_with(b"CONNECTED") {
+                return Err(ErrorKind::Config(format!("STOMP listener at {} refused the \
+                                                      connection: {}",
+                                                     self.addr,
+                                                     String::from_utf8_lossy(&response)
+                                                         .trim()))
+                                   .into());
+            }
+        }
+        Ok(stream)
+    }
+
+    /// Write one event to an open connection.
+    fn send(&self, stream: &mut TcpStream, line: &str) -> Result<()> {
+        match self.transport {
+            Transport::Tcp => {
+                stream.write_all(line.as_bytes())?;
+                stream.write_all(b"\n")?;
+            }
+            Transport::Stomp { ref destination, .. } => {
+                stream.write_all(&send_frame(destination, line))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Publish the event for one object; call sites treat a failure as
+    /// advisory.
+    pub fn emit(&self, lo: &Lo, status: &str) -> Result<()> {
+        let line = event_json(lo, status);
+        let mut conn = self.conn.lock().unwrap_or_else(|e| e.into_inner());
+        if conn.is_none() {
+            *conn = Some(self.connect()?);
+        }
+        let sent = match *conn {
+            Some(ref mut stream) => self.send(stream, &line).is_ok(),
+            None => unreachable!(),
+        };
+        if !sent {
+            // the broker may have dropped an idle connection; one
+            // fresh attempt before the event is given up on
+            *conn = None;
+            let mut stream = self.connect()?;
+            self.send(&mut stream, &line)?;
+            *conn = Some(stream);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lo() -> Lo {
+        let mut lo = Lo::new(vec![0xab; 20], 42, 1024, "image/png".to_string());
+        lo.set_sha2(vec![0xcd; 32]);
+        lo
+    }
+
+    #[test]
+    fn events_are_valid_json() {
+        let json: ::serde_json::Value =
+            ::serde_json::from_str(&event_json(&lo(), "committed")).unwrap();
+        assert_eq!(json["oid"], 42);
+        assert_eq!(json["sha1"], "ab".repeat(20));
+        assert_eq!(json["sha2"], "cd".repeat(32));
+        assert_eq!(json["size"], 1024);
+        assert_eq!(json["status"], "committed");
+        assert!(json["timestamp"].is_string());
+
+        let lo = Lo::new(vec![0xab; 20], 42, 1024, "image/png".to_string());
+        let json: ::serde_json::Value =
+            ::serde_json::from_str(&event_json(&lo, "committed")).unwrap();
+        assert!(json["sha2"].is_null());
+    }
+
+    #[test]
+    fn stomp_frames_are_terminated() {
+        let frame = connect_frame(Some(&("guest".to_string(), "secret".to_string())));
+        assert_eq!(&frame[..],
+                   &b"CONNECT\naccept-version:1.0\nlogin:guest\npasscode:secret\n\n\0"[..]);
+
+        let frame = send_frame("/topic/lo-migrate", "{}");
+        assert_eq!(&frame[..],
+                   &b"SEND\ndestination:/topic/lo-migrate\n\
+                      content-type:application/json\ncontent-length:2\n\n{}\0"[..]);
+    }
+
+    #[test]
+    fn addresses_are_validated() {
+        assert!(EventStream::tcp("broker.internal:9000").is_ok());
+        assert!(EventStream::tcp("broker.internal").is_err());
+        assert!(EventStream::stomp("broker.internal:61613", "", None).is_err());
+    }
+}
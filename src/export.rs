@@ -0,0 +1,843 @@
+//! Export into a tar archive or a directory tree instead of a bucket.
+//!
+//! Air-gapped sites cannot reach the destination S3 from the database
+//! network; the migration instead streams every object into a tar
+//! archive ([`TarStore`]) that is carried over and imported into the
+//! bucket at the destination, or into a directory tree ([`DirStore`])
+//! that can be rsynced or served by a web server directly. Objects are
+//! named by the sha2 hash — the key an S3 upload would use — and an
+//! index file lists every object with its size and content type, so
+//! the import side can verify completeness without scanning the whole
+//! export.
+//!
+//! The archive is plain ustar, hand-rolled because a header block and
+//! zero padding per member is all this use of the format needs. It is
+//! written uncompressed so a crashed run leaves an inspectable file;
+//! compress the finished archive for transport (`gzip`, `zstd`) if the
+//! link is slow.
+//!
+//! [`DirStore`]: struct.DirStore.html
+//! [`TarStore`]: struct.TarStore.html
+
+use chrono::Utc;
+use error::{ErrorKind, Result};
+use hex;
+use md5;
+use object_store::{ObjectStore, Part, StorageBackend, UploadMeta, UploadOutcome,
+                   verify_checksum};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tempfile::{self, NamedTempFile, NamedTempFileOptions};
+
+/// Largest size a plain ustar header can express: 11 octal digits,
+/// one byte short of 8 GiB.
+const MAX_MEMBER_SIZE: u64 = 0o77_777_777_777;
+
+const ZERO_BLOCK: [u8; 512] = [0; 512];
+
+/// An [`ObjectStore`] writing objects into a local tar archive.
+///
+/// All handles share one archive; members are appended under a mutex,
+/// so uploads serialize on the archive file — the disk is the
+/// bottleneck of an export anyway. Keys are sha2 hashes, so a key that
+/// is already in the archive carries the identical bytes and is
+/// skipped rather than appended twice. ETags follow the S3 rules
+/// (MD5, composite MD5-of-MD5s for multipart) so the storers' upload
+/// validation is preserved.
+///
+/// Multipart parts are staged in an unlinked temporary file until the
+/// upload completes, because a tar header needs the member size up
+/// front; parts must arrive in ascending order, as the storers upload
+/// them.
+///
+/// [`ObjectStore`]: ../object_store/trait.ObjectStore.html
+#[derive(Clone, Debug)]
+pub struct TarStore {
+    inner: Arc<Mutex<TarInner>>,
+}
+
+#[derive(Debug)]
+struct TarInner {
+    archive: BufWriter<File>,
+    index: BufWriter<File>,
+    /// key -> size of the members already written
+    members: HashMap<String, u64>,
+    /// upload id -> pending multipart upload
+    uploads: HashMap<String, PendingUpload>,
+    next_upload_id: u64,
+    finished: bool,
+}
+
+#[derive(Debug)]
+struct PendingUpload {
+    key: String,
+    meta: UploadMeta,
+    /// parts staged so far, concatenated
+    file: File,
+    size: u64,
+    /// part number -> MD5 of the part, for the composite ETag
+    md5s: HashMap<i64, [u8; 16]>,
+    next_part: i64,
+}
+
+impl TarStore {
+    /// Create the archive at `path` and its index at `<path>.index`,
+    /// truncating existing files.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let archive = BufWriter::new(File::create(path)?);
+        let mut index_path = path.as_os_str().to_os_string();
+        index_path.push(".index");
+        let mut index = BufWriter::new(File::create(&index_path)?);
+        writeln!(index, "# lo-migrate archive index v1")?;
+        index.flush()?;
+        Ok(TarStore {
+            inner: Arc::new(Mutex::new(TarInner {
+                                           archive: archive,
+                                           index: index,
+                                           members: HashMap::new(),
+                                           uploads: HashMap::new(),
+                                           next_upload_id: 0,
+                                           finished: false,
+                                       })),
+        })
+    }
+
+    /// Number of members written so far.
+    pub fn member_count(&self) -> usize {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner()).members.len()
+    }
+
+    /// Write the end-of-archive trailer and flush archive and index.
+    ///
+    /// Runs automatically when the last handle is dropped; calling it
+    /// explicitly surfaces write errors instead of logging them.
+    pub fn finish(&self) -> Result<()> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner()).finish()
+    }
+}
+
+impl TarInner {
+    /// Append one member plus its index line and flush both files, so
+    /// everything before a crash is complete in the archive.
+    fn append<R: Read>(&mut self,
+                       key: &str,
+                       size: u64,
+                       content_type: Option<&str>,
+                       data: &mut R)
+                       -> Result<()> {
+        if self.finished {
+            return Err(ErrorKind::S3("the archive has already been finalized".to_string())
+                           .into());
+        }
+        if size > MAX_MEMBER_SIZE {
+            return Err(ErrorKind::ObjectTooLarge(format!("object {} is {} bytes, more \
+                                                          than a ustar member can hold",
+                                                         key,
+                                                         size))
+                               .into());
+        }
+        self.archive.write_all(&tar_header(key, size, Utc::now().timestamp()))?;
+        let copied = io::copy(data, &mut self.archive)?;
+        if copied != size {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      format!("staged upload of {} is truncated: {} of {} \
+                                               bytes",
+                                              key,
+                                              copied,
+                                              size))
+                               .into());
+        }
+        let padding = (512 - size % 512) % 512;
+        self.archive.write_all(&ZERO_BLOCK[..padding as usize])?;
+        self.archive.flush()?;
+        writeln!(self.index, "{} {} {}", key, size, content_type.unwrap_or("-"))?;
+        self.index.flush()?;
+        self.members.insert(key.to_string(), size);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.archive.write_all(&ZERO_BLOCK)?;
+        self.archive.write_all(&ZERO_BLOCK)?;
+        self.archive.flush()?;
+        self.index.flush()?;
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl Drop for TarInner {
+    fn drop(&mut self) {
+        if let Err(err) = self.finish() {
+            warn!("finalizing the export archive failed: {}", err);
+        }
+    }
+}
+
+/// A ustar header block for a regular file member.
+fn tar_header(name: &str, size: u64, mtime: i64) -> [u8; 512] {
+    debug_assert!(name.len() <= 100, "member name too long for ustar");
+    let mut block = [0u8; 512];
+    block[..name.len()].copy_from_slice(name.as_bytes());
+    block[100..108].copy_from_slice(b"0000644\0");
+    block[108..116].copy_from_slice(b"0000000\0");
+    block[116..124].copy_from_slice(b"0000000\0");
+    octal(&mut block[124..136], size);
+    octal(&mut block[136..148], mtime as u64);
+    // the checksum counts the checksum field itself as spaces
+    for byte in &mut block[148..156] {
+        *byte = b' ';
+    }
+    block[156] = b'0';
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+    let sum: u32 = block.iter().map(|&byte| u32::from(byte)).sum();
+    block[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+    block
+}
+
+/// Write `value` into a 12-byte octal header field.
+fn octal(field: &mut [u8], value: u64) {
+    field[..11].copy_from_slice(format!("{:011o}", value).as_bytes());
+    field[11] = 0;
+}
+
+/// The ETag S3 reports for a completed multipart upload — MD5 of the
+/// concatenated part digests, suffixed with the part count — so the
+/// storers' upload validation works against the local backends too.
+pub(crate) fn composite_etag(parts: &[Part], md5s: &HashMap<i64, [u8; 16]>) -> Result<String> {
+    let mut digests = Vec::with_capacity(parts.len() * 16);
+    for part in parts {
+        let digest = md5s
+            .get(&part.part_number)
+            .ok_or_else(|| {
+                            ErrorKind::S3(format!("part {} was never uploaded",
+                                                  part.part_number))
+                        })?;
+        digests.extend_from_slice(digest);
+    }
+    Ok(format!("{}-{}", hex::encode(&md5::compute(&digests).0), md5s.len()))
+}
+
+impl ObjectStore for TarStore {
+    fn exists(&self, key: &str) -> Result<bool> {
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(inner.members.contains_key(key))
+    }
+
+    fn stat(&self, key: &str) -> Result<Option<u64>> {
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(inner.members.get(key).cloned())
+    }
+
+    fn put(&self, key: &str, data: &[u8], meta: &UploadMeta) -> Result<UploadOutcome> {
+        verify_checksum(meta.checksum_sha256.as_ref().map(String::as_str), data)?;
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        if !inner.members.contains_key(key) {
+            let mut reader = data;
+            inner.append(key,
+                         data.len() as u64,
+                         meta.content_type.as_ref().map(String::as_str),
+                         &mut reader)?;
+        }
+        Ok(UploadOutcome {
+            e_tag: Some(hex::encode(&md5::compute(data).0)),
+            version_id: None,
+        })
+    }
+
+    fn create_multipart(&self, key: &str, meta: &UploadMeta) -> Result<String> {
+        let file = tempfile::tempfile()?;
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.next_upload_id += 1;
+        let upload_id = format!("upload-{}", inner.next_upload_id);
+        inner.uploads.insert(upload_id.clone(),
+                             PendingUpload {
+                                 key: key.to_string(),
+                                 meta: meta.clone(),
+                                 file: file,
+                                 size: 0,
+                                 md5s: HashMap::new(),
+                                 next_part: 1,
+                             });
+        Ok(upload_id)
+    }
+
+    fn upload_part(&self,
+                   key: &str,
+                   upload_id: &str,
+                   part_number: i64,
+                   data: &[u8],
+                   checksum_sha256: Option<&str>)
+                   -> Result<Part> {
+        verify_checksum(checksum_sha256, data)?;
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let upload = inner
+            .uploads
+            .get_mut(upload_id)
+            .ok_or_else(|| ErrorKind::S3(format!("unknown upload id {}", upload_id)))?;
+        if upload.key != key {
+            return Err(ErrorKind::S3(format!("upload {} belongs to key {}",
+                                             upload_id,
+                                             upload.key))
+                               .into());
+        }
+        if part_number != upload.next_part {
+            return Err(ErrorKind::S3(format!("part {} out of order, expected part {}; a \
+                                              tar member is written front to back",
+                                             part_number,
+                                             upload.next_part))
+                               .into());
+        }
+        upload.file.write_all(data)?;
+        upload.size += data.len() as u64;
+        let digest = md5::compute(data).0;
+        upload.md5s.insert(part_number, digest);
+        upload.next_part += 1;
+        Ok(Part {
+            part_number: part_number,
+            e_tag: Some(hex::encode(&digest)),
+        })
+    }
+
+    fn complete_multipart(&self,
+                          key: &str,
+                          upload_id: &str,
+                          parts: Vec<Part>)
+                          -> Result<UploadOutcome> {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let mut upload = inner
+            .uploads
+            .remove(upload_id)
+            .ok_or_else(|| ErrorKind::S3(format!("unknown upload id {}", upload_id)))?;
+
+        let e_tag = composite_etag(&parts, &upload.md5s)?;
+
+        if !inner.members.contains_key(key) {
+            upload.file.seek(SeekFrom::Start(0))?;
+            let size = upload.size;
+            inner.append(key,
+                         size,
+                         upload.meta.content_type.as_ref().map(String::as_str),
+                         &mut upload.file)?;
+        }
+        Ok(UploadOutcome {
+            e_tag: Some(e_tag),
+            version_id: None,
+        })
+    }
+
+    fn abort_multipart(&self, _key: &str, upload_id: &str) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner
+            .uploads
+            .remove(upload_id)
+            .map(|_| ())
+            .ok_or_else(|| ErrorKind::S3(format!("unknown upload id {}", upload_id)).into())
+    }
+}
+
+/// [`StorageBackend`] handing out handles to one shared [`TarStore`].
+///
+/// [`StorageBackend`]: ../object_store/trait.StorageBackend.html
+/// [`TarStore`]: struct.TarStore.html
+pub struct TarBackend {
+    store: TarStore,
+}
+
+impl TarBackend {
+    pub fn new(store: TarStore) -> Self {
+        TarBackend { store: store }
+    }
+}
+
+impl StorageBackend for TarBackend {
+    fn store(&self) -> Result<Box<ObjectStore>> {
+        Ok(Box::new(self.store.clone()))
+    }
+}
+
+/// An [`ObjectStore`] writing objects into a directory tree.
+///
+/// Objects land under a two-level fan-out, `ab/cd/<sha2>` by the first
+/// four hex digits of the key, so no directory collects millions of
+/// entries. Uploads are staged in a temporary file inside the root and
+/// renamed into place, so a crashed run never leaves a truncated
+/// object; a repeated key is already in place and skipped. An `index`
+/// file in the root lists every object, appended to across runs like
+/// the upload journal.
+///
+/// With [`with_nginx_layout`] every object is additionally hard-linked
+/// under `nginx/<sha2>`, a flat directory nginx serves with a plain
+/// `root <export>/nginx;` and no rewrite rule. The links share the
+/// bytes of the fan-out tree, so the second layout costs directory
+/// entries, not disk.
+///
+/// [`ObjectStore`]: ../object_store/trait.ObjectStore.html
+/// [`with_nginx_layout`]: #method.with_nginx_layout
+#[derive(Clone, Debug)]
+pub struct DirStore {
+    root: PathBuf,
+    nginx: bool,
+    inner: Arc<Mutex<DirInner>>,
+}
+
+#[derive(Debug)]
+struct DirInner {
+    index: BufWriter<File>,
+    /// upload id -> pending multipart upload
+    uploads: HashMap<String, DirUpload>,
+    next_upload_id: u64,
+    /// objects persisted by this run
+    written: u64,
+}
+
+#[derive(Debug)]
+struct DirUpload {
+    key: String,
+    meta: UploadMeta,
+    /// parts staged so far, concatenated
+    file: NamedTempFile,
+    size: u64,
+    /// part number -> MD5 of the part, for the composite ETag
+    md5s: HashMap<i64, [u8; 16]>,
+    next_part: i64,
+}
+
+impl DirStore {
+    /// Open (or create) the export tree rooted at `path`, appending to
+    /// an existing index so a restarted export keeps earlier records.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let root = path.as_ref().to_path_buf();
+        fs::create_dir_all(&root)?;
+        let index = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(root.join("index"))?;
+        let mut index = BufWriter::new(index);
+        if index.get_ref().metadata()?.len() == 0 {
+            writeln!(index, "# lo-migrate archive index v1")?;
+            index.flush()?;
+        }
+        Ok(DirStore {
+            root: root,
+            nginx: false,
+            inner: Arc::new(Mutex::new(DirInner {
+                                           index: index,
+                                           uploads: HashMap::new(),
+                                           next_upload_id: 0,
+                                           written: 0,
+                                       })),
+        })
+    }
+
+    /// Additionally hard-link every object under a flat `nginx/`
+    /// directory a web server can serve without a rewrite rule.
+    pub fn with_nginx_layout(mut self) -> Self {
+        self.nginx = true;
+        self
+    }
+
+    /// Number of objects persisted by this run; objects found already
+    /// in place from an earlier export do not count.
+    pub fn written(&self) -> u64 {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner()).written
+    }
+
+    /// Where an object lives in the fan-out tree.
+    fn object_path(&self, key: &str) -> PathBuf {
+        if key.len() < 4 {
+            // not a sha2 key; keep it in the root rather than panic
+            return self.root.join(key);
+        }
+        let mut path = self.root.join(&key[..2]);
+        path.push(&key[2..4]);
+        path.push(key);
+        path
+    }
+
+    fn nginx_path(&self, key: &str) -> PathBuf {
+        let mut path = self.root.join("nginx");
+        path.push(key);
+        path
+    }
+
+    /// Move a staged upload into its place in the tree, record it in
+    /// the index and maintain the nginx layout. A key that is already
+    /// in place carries the identical bytes (keys are sha2 hashes) and
+    /// only gets its missing hard link.
+    fn persist(&self,
+               key: &str,
+               file: NamedTempFile,
+               size: u64,
+               content_type: Option<&str>)
+               -> Result<()> {
+        let path = self.object_path(key);
+        // the lock spans the existence check so concurrent storers
+        // cannot both persist and index the same key
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        if !path.exists() {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            file.persist(&path).map_err(|err| err.error)?;
+            writeln!(inner.index, "{} {} {}", key, size, content_type.unwrap_or("-"))?;
+            inner.index.flush()?;
+            inner.written += 1;
+        }
+        if self.nginx {
+            let link = self.nginx_path(key);
+            if let Some(parent) = link.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            // link instead of copying: both layouts share the bytes
+            if let Err(err) = fs::hard_link(&path, &link) {
+                if err.kind() != io::ErrorKind::AlreadyExists {
+                    return Err(err.into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ObjectStore for DirStore {
+    fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.object_path(key).exists())
+    }
+
+    fn stat(&self, key: &str) -> Result<Option<u64>> {
+        match fs::metadata(self.object_path(key)) {
+            Ok(meta) => Ok(Some(meta.len())),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn put(&self, key: &str, data: &[u8], meta: &UploadMeta) -> Result<UploadOutcome> {
+        verify_checksum(meta.checksum_sha256.as_ref().map(String::as_str), data)?;
+        let mut file = NamedTempFileOptions::new()
+            .prefix("lo_migrate_export")
+            .create_in(&self.root)?;
+        file.write_all(data)?;
+        self.persist(key,
+                     file,
+                     data.len() as u64,
+                     meta.content_type.as_ref().map(String::as_str))?;
+        Ok(UploadOutcome {
+            e_tag: Some(hex::encode(&md5::compute(data).0)),
+            version_id: None,
+        })
+    }
+
+    fn create_multipart(&self, key: &str, meta: &UploadMeta) -> Result<String> {
+        let file = NamedTempFileOptions::new()
+            .prefix("lo_migrate_export")
+            .create_in(&self.root)?;
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.next_upload_id += 1;
+        let upload_id = format!("upload-{}", inner.next_upload_id);
+        inner.uploads.insert(upload_id.clone(),
+                             DirUpload {
+                                 key: key.to_string(),
+                                 meta: meta.clone(),
+                                 file: file,
+                                 size: 0,
+                                 md5s: HashMap::new(),
+                                 next_part: 1,
+                             });
+        Ok(upload_id)
+    }
+
+    fn upload_part(&self,
+                   key: &str,
+                   upload_id: &str,
+                   part_number: i64,
+                   data: &[u8],
+                   checksum_sha256: Option<&str>)
+                   -> Result<Part> {
+        verify_checksum(checksum_sha256, data)?;
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let upload = inner
+            .uploads
+            .get_mut(upload_id)
+            .ok_or_else(|| ErrorKind::S3(format!("unknown upload id {}", upload_id)))?;
+        if upload.key != key {
+            return Err(ErrorKind::S3(format!("upload {} belongs to key {}",
+                                             upload_id,
+                                             upload.key))
+                               .into());
+        }
+        if part_number != upload.next_part {
+            return Err(ErrorKind::S3(format!("part {} out of order, expected part {}; \
+                                              parts are staged front to back",
+                                             part_number,
+                                             upload.next_part))
+                               .into());
+        }
+        upload.file.write_all(data)?;
+        upload.size += data.len() as u64;
+        let digest = md5::compute(data).0;
+        upload.md5s.insert(part_number, digest);
+        upload.next_part += 1;
+        Ok(Part {
+            part_number: part_number,
+            e_tag: Some(hex::encode(&digest)),
+        })
+    }
+
+    fn complete_multipart(&self,
+                          key: &str,
+                          upload_id: &str,
+                          parts: Vec<Part>)
+                          -> Result<UploadOutcome> {
+        let upload = {
+            let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+            inner
+                .uploads
+                .remove(upload_id)
+                .ok_or_else(|| ErrorKind::S3(format!("unknown upload id {}", upload_id)))?
+        };
+        let e_tag = composite_etag(&parts, &upload.md5s)?;
+        let size = upload.size;
+        let content_type = upload.meta.content_type.clone();
+        self.persist(key,
+                     upload.file,
+                     size,
+                     content_type.as_ref().map(String::as_str))?;
+        Ok(UploadOutcome {
+            e_tag: Some(e_tag),
+            version_id: None,
+        })
+    }
+
+    fn abort_multipart(&self, _key: &str, upload_id: &str) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        // dropping the staged `NamedTempFile` removes it
+        inner
+            .uploads
+            .remove(upload_id)
+            .map(|_| ())
+            .ok_or_else(|| ErrorKind::S3(format!("unknown upload id {}", upload_id)).into())
+    }
+}
+
+/// [`StorageBackend`] handing out handles to one shared [`DirStore`].
+///
+/// [`DirStore`]: struct.DirStore.html
+/// [`StorageBackend`]: ../object_store/trait.StorageBackend.html
+pub struct DirBackend {
+    store: DirStore,
+}
+
+impl DirBackend {
+    pub fn new(store: DirStore) -> Self {
+        DirBackend { store: store }
+    }
+}
+
+impl StorageBackend for DirBackend {
+    fn store(&self) -> Result<Box<ObjectStore>> {
+        Ok(Box::new(self.store.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::str;
+    use tempfile::NamedTempFile;
+
+    /// A store writing to a fresh temporary archive, plus the paths of
+    /// the archive and its index. The `NamedTempFile` keeps the
+    /// archive alive; the index is removed by `cleanup`.
+    fn store() -> (TarStore, NamedTempFile, PathBuf, PathBuf) {
+        let file = NamedTempFile::new().unwrap();
+        let path = file.path().to_path_buf();
+        let mut index_path = path.as_os_str().to_os_string();
+        index_path.push(".index");
+        let store = TarStore::create(&path).unwrap();
+        (store, file, path, PathBuf::from(index_path))
+    }
+
+    fn parse_octal(field: &[u8]) -> u64 {
+        let text = str::from_utf8(field).unwrap();
+        u64::from_str_radix(text.trim_matches(|c| c == ' ' || c == '\0'), 8).unwrap()
+    }
+
+    #[test]
+    fn members_round_trip_through_a_tar_parser() {
+        let (store, _file, path, index_path) = store();
+        store.put("aaaa", b"first object", &UploadMeta::default()).unwrap();
+        store
+            .put("bbbb",
+                 &[0x55; 512],
+                 &UploadMeta { content_type: Some("image/png".to_string()), ..Default::default() })
+            .unwrap();
+        store.finish().unwrap();
+
+        let archive = fs::read(&path).unwrap();
+        // member 1: header, 12 bytes of data, padding to the next block
+        assert_eq!(&archive[..4], b"aaaa");
+        assert_eq!(archive[4], 0);
+        assert_eq!(parse_octal(&archive[124..136]), 12);
+        assert_eq!(&archive[257..262], b"ustar");
+        let claimed = parse_octal(&archive[148..156]);
+        let computed: u32 = archive[..512]
+            .iter()
+            .enumerate()
+            .map(|(i, &byte)| if i >= 148 && i < 156 { 32 } else { u32::from(byte) })
+            .sum();
+        assert_eq!(claimed, u64::from(computed));
+        assert_eq!(&archive[512..524], b"first object");
+        assert!(archive[524..1024].iter().all(|&byte| byte == 0));
+        // member 2 starts at the next block boundary, exactly one data
+        // block, no padding
+        assert_eq!(&archive[1024..1028], b"bbbb");
+        assert_eq!(parse_octal(&archive[1148..1160]), 512);
+        assert!(archive[1536..2048].iter().all(|&byte| byte == 0x55));
+        // end-of-archive trailer: two zero blocks
+        assert_eq!(archive.len(), 2048 + 1024);
+        assert!(archive[2048..].iter().all(|&byte| byte == 0));
+
+        let index = fs::read_to_string(&index_path).unwrap();
+        assert_eq!(index,
+                   "# lo-migrate archive index v1\naaaa 12 -\nbbbb 512 image/png\n");
+        fs::remove_file(&index_path).unwrap();
+    }
+
+    #[test]
+    fn multipart_uploads_are_assembled_into_one_member() {
+        let (store, _file, path, index_path) = store();
+        let upload_id = store.create_multipart("cccc", &UploadMeta::default()).unwrap();
+        let mut parts = Vec::new();
+        parts.push(store.upload_part("cccc", &upload_id, 1, &[1; 600], None).unwrap());
+        parts.push(store.upload_part("cccc", &upload_id, 2, &[2; 100], None).unwrap());
+        let outcome = store.complete_multipart("cccc", &upload_id, parts).unwrap();
+        assert!(outcome.e_tag.unwrap().ends_with("-2"));
+        assert_eq!(store.stat("cccc").unwrap(), Some(700));
+        store.finish().unwrap();
+
+        let archive = fs::read(&path).unwrap();
+        assert_eq!(parse_octal(&archive[124..136]), 700);
+        assert!(archive[512..1112].iter().all(|&byte| byte == 1));
+        assert!(archive[1112..1212].iter().all(|&byte| byte == 2));
+        fs::remove_file(&index_path).unwrap();
+    }
+
+    #[test]
+    fn out_of_order_parts_are_rejected() {
+        let (store, _file, _path, index_path) = store();
+        let upload_id = store.create_multipart("dddd", &UploadMeta::default()).unwrap();
+        let err = store
+            .upload_part("dddd", &upload_id, 2, b"data", None)
+            .unwrap_err();
+        assert!(format!("{}", err).contains("out of order"));
+        store.abort_multipart("dddd", &upload_id).unwrap();
+        fs::remove_file(&index_path).unwrap();
+    }
+
+    #[test]
+    fn duplicate_keys_are_written_once() {
+        let (store, _file, path, index_path) = store();
+        store.put("eeee", b"same bytes", &UploadMeta::default()).unwrap();
+        store.put("eeee", b"same bytes", &UploadMeta::default()).unwrap();
+        assert_eq!(store.member_count(), 1);
+        store.finish().unwrap();
+        // one header block, one data block, two trailer blocks
+        assert_eq!(fs::read(&path).unwrap().len(), 2048);
+        fs::remove_file(&index_path).unwrap();
+    }
+
+    /// A fresh directory for one `DirStore` test, clearing leftovers
+    /// of an aborted earlier run.
+    fn dir_root(name: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("lo_migrate_export_{}_{}",
+                                                ::std::process::id(),
+                                                name));
+        let _ = fs::remove_dir_all(&root);
+        root
+    }
+
+    #[test]
+    fn objects_land_in_the_fanout_layout() {
+        let root = dir_root("fanout");
+        let key = "0123456789abcdef".repeat(4);
+        let store = DirStore::create(&root).unwrap();
+        store.put(&key, b"fanned out", &UploadMeta::default()).unwrap();
+        store.put(&key, b"fanned out", &UploadMeta::default()).unwrap();
+        assert_eq!(store.written(), 1);
+        assert_eq!(fs::read(root.join("01").join("23").join(&key)).unwrap(),
+                   b"fanned out");
+        assert_eq!(fs::read_to_string(root.join("index")).unwrap(),
+                   format!("# lo-migrate archive index v1\n{} 10 -\n", key));
+
+        // a restarted export finds the object in place and skips it
+        let resumed = DirStore::create(&root).unwrap();
+        assert!(resumed.exists(&key).unwrap());
+        assert_eq!(resumed.stat(&key).unwrap(), Some(10));
+        resumed.put(&key, b"fanned out", &UploadMeta::default()).unwrap();
+        assert_eq!(resumed.written(), 0);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn nginx_layout_is_hard_linked() {
+        let root = dir_root("nginx");
+        let key = "fedcba9876543210".repeat(4);
+        let store = DirStore::create(&root).unwrap().with_nginx_layout();
+        store.put(&key, b"served directly", &UploadMeta::default()).unwrap();
+        let link = root.join("nginx").join(&key);
+        assert_eq!(fs::read(&link).unwrap(), b"served directly");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            // a link, not a copy: both layouts share one inode
+            assert_eq!(fs::metadata(&link).unwrap().ino(),
+                       fs::metadata(store.object_path(&key)).unwrap().ino());
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn multipart_uploads_are_assembled_into_one_file() {
+        let root = dir_root("multipart");
+        let key = "abcdef0123456789".repeat(4);
+        let store = DirStore::create(&root).unwrap();
+        let upload_id = store.create_multipart(&key, &UploadMeta::default()).unwrap();
+        let mut parts = Vec::new();
+        parts.push(store.upload_part(&key, &upload_id, 1, &[7; 300], None).unwrap());
+        parts.push(store.upload_part(&key, &upload_id, 2, &[8; 200], None).unwrap());
+        let outcome = store.complete_multipart(&key, &upload_id, parts).unwrap();
+        assert!(outcome.e_tag.unwrap().ends_with("-2"));
+        assert_eq!(store.stat(&key).unwrap(), Some(500));
+
+        // aborting drops the staged temporary file
+        let aborted = store.create_multipart(&key, &UploadMeta::default()).unwrap();
+        store.upload_part(&key, &aborted, 1, b"gone", None).unwrap();
+        store.abort_multipart(&key, &aborted).unwrap();
+        let staged = fs::read_dir(&root)
+            .unwrap()
+            .filter(|entry| {
+                        entry
+                            .as_ref()
+                            .unwrap()
+                            .file_name()
+                            .to_string_lossy()
+                            .starts_with("lo_migrate_export")
+                    })
+            .count();
+        assert_eq!(staged, 0);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
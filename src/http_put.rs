@@ -0,0 +1,341 @@
+//! Generic HTTP PUT backend.
+//!
+//! Some targets are not S3 at all — a WebDAV share, a custom binary
+//! service — but accept one plain `PUT` per object. [`HttpPutStore`]
+//! uploads every object to a templated URL, `{key}` replaced by the
+//! sha2 hash, optionally sending a fixed auth header with every
+//! request. Like the SigV2 client it speaks hand-rolled plain http
+//! over a [`TcpStream`]; services needing more than that sit behind a
+//! local proxy.
+//!
+//! Generic HTTP has no part protocol, so multipart uploads are staged
+//! in a temporary file and sent as a single `PUT` on completion; the
+//! composite ETag the storers validate is computed locally.
+//! [`HttpPutBackend`] plugs the store into the [`StorageBackend`]
+//! seam.
+//!
+//! [`HttpPutStore`]: struct.HttpPutStore.html
+//! [`HttpPutBackend`]: struct.HttpPutBackend.html
+//! [`StorageBackend`]: ../object_store/trait.StorageBackend.html
+//! [`TcpStream`]: https://doc.rust-lang.org/std/net/struct.TcpStream.html
+
+use error::{ErrorKind, MigrationError, Result};
+use export::composite_etag;
+use hex;
+use md5;
+use object_store::{ObjectStore, Part, StagedUploads, StorageBackend, UploadMeta,
+                   UploadOutcome, transient_status, verify_checksum};
+use sigv2::{HttpResponse, http_date, parse_response};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long one request may take end to end; generous because a whole
+/// staged multipart upload goes out in one request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Send one unsigned request to `addr`, streaming `body` after the
+/// headers. `auth` is a complete header line sent verbatim. Shared
+/// with the presigned-URL store, which uploads the same way but to a
+/// different URL per object.
+pub(crate) fn execute(operation: &str,
+                      addr: &str,
+                      verb: &str,
+                      path: &str,
+                      auth: Option<&str>,
+                      content_type: &str,
+                      content_length: u64,
+                      body: &mut Read)
+                      -> Result<HttpResponse> {
+    let mut request = format!("{} {} HTTP/1.0\r\n\
+                               Host: {}\r\n\
+                               Date: {}\r\n\
+                               Content-Length: {}\r\n",
+                              verb,
+                              path,
+                              addr,
+                              http_date(),
+                              content_length);
+    if let Some(auth) = auth {
+        request.push_str(auth);
+        request.push_str("\r\n");
+    }
+    if !content_type.is_empty() {
+        request.push_str(&format!("Content-Type: {}\r\n", content_type));
+    }
+    request.push_str("Connection: close\r\n\r\n");
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.write_all(request.as_bytes())?;
+    io::copy(body, &mut stream)?;
+
+    let mut raw = Vec::new();
+    stream.read_to_end(&mut raw)?;
+    parse_response(&raw)
+        .map_err(|_| ErrorKind::S3(format!("{} failed: malformed response", operation)).into())
+}
+
+/// Error for a response no caller-specific handling caught.
+pub(crate) fn status_error(operation: &str, response: &HttpResponse) -> MigrationError {
+    let msg = format!("{} failed: HTTP {}: {}",
+                      operation,
+                      response.status,
+                      response.body.trim());
+    if transient_status(response.status) {
+        ErrorKind::S3Transient(msg).into()
+    } else {
+        ErrorKind::S3(msg).into()
+    }
+}
+
+/// [`ObjectStore`] issuing one HTTP `PUT` per object.
+///
+/// [`ObjectStore`]: ../object_store/trait.ObjectStore.html
+#[derive(Clone)]
+pub struct HttpPutStore {
+    addr: String,
+    /// URL path with a `{key}` placeholder
+    template: String,
+    /// complete header line sent with every request, e.g.
+    /// `Authorization: Bearer ...`
+    auth: Option<String>,
+    uploads: Arc<Mutex<StagedUploads>>,
+}
+
+impl HttpPutStore {
+    /// Store putting objects to `template`, a plain-http URL with a
+    /// `{key}` placeholder, e.g. `http://dav.internal/binaries/{key}`.
+    /// `auth_header` is a complete header line sent with every
+    /// request. The port defaults to 80.
+    pub fn new(template: &str, auth_header: Option<&str>) -> Result<Self> {
+        let rest = match template.find("://") {
+            Some(at) if &template[..at] == "http" => &template[at + 3..],
+            Some(_) => {
+                return Err(ErrorKind::Config("the HTTP PUT backend speaks plain http \
+                                              only; put a local proxy in front of https \
+                                              targets"
+                                                     .to_string())
+                                   .into())
+            }
+            None => template,
+        };
+        let (addr, path) = match rest.find('/') {
+            Some(at) => (&rest[..at], &rest[at..]),
+            None => (rest, ""),
+        };
+        if addr.is_empty() {
+            return Err(ErrorKind::Config(format!("no host in URL template {:?}", template))
+                               .into());
+        }
+        if !path.contains("{key}") {
+            return Err(ErrorKind::Config(format!("URL template {:?} has no {{key}} \
+                                                  placeholder, e.g. \
+                                                  http://host/binaries/{{key}}",
+                                                 template))
+                               .into());
+        }
+        let auth = match auth_header {
+            Some(header) => {
+                let header = header.trim();
+                if header.find(':').map(|at| at == 0).unwrap_or(true) {
+                    return Err(ErrorKind::Config(format!("auth header {:?} is not a \
+                                                          complete header line like \
+                                                          'Authorization: Bearer ...'",
+                                                         header))
+                                       .into());
+                }
+                Some(header.to_string())
+            }
+            None => None,
+        };
+        Ok(HttpPutStore {
+               addr: if addr.contains(':') {
+                   addr.to_string()
+               } else {
+                   format!("{}:80", addr)
+               },
+               template: path.to_string(),
+               auth: auth,
+               uploads: Arc::new(Mutex::new(StagedUploads::default())),
+           })
+    }
+
+    fn path(&self, key: &str) -> String {
+        self.template.replace("{key}", key)
+    }
+
+    /// Send one request, streaming `body` after the headers.
+    fn execute(&self,
+               operation: &str,
+               verb: &str,
+               path: &str,
+               content_type: &str,
+               content_length: u64,
+               body: &mut Read)
+               -> Result<HttpResponse> {
+        execute(operation,
+                &self.addr,
+                verb,
+                path,
+                self.auth.as_ref().map(String::as_str),
+                content_type,
+                content_length,
+                body)
+    }
+}
+
+impl ObjectStore for HttpPutStore {
+    fn exists(&self, key: &str) -> Result<bool> {
+        let response = self.execute("HEAD", "HEAD", &self.path(key), "", 0, &mut io::empty())?;
+        // mirrors the S3 stores: any failure counts as "not there" and
+        // the object is uploaded again
+        Ok(response.status == 200)
+    }
+
+    fn stat(&self, key: &str) -> Result<Option<u64>> {
+        let response = self.execute("HEAD", "HEAD", &self.path(key), "", 0, &mut io::empty())?;
+        match response.status {
+            200 => {
+                Ok(response
+                       .header("Content-Length")
+                       .and_then(|length| length.parse().ok())
+                       .or(Some(0)))
+            }
+            404 => Ok(None),
+            _ => Err(status_error("HEAD", &response)),
+        }
+    }
+
+    fn put(&self, key: &str, data: &[u8], meta: &UploadMeta) -> Result<UploadOutcome> {
+        verify_checksum(meta.checksum_sha256.as_ref().map(String::as_str), data)?;
+        let content_type = meta.content_type.clone().unwrap_or_default();
+        let response = self.execute("PUT",
+                                    "PUT",
+                                    &self.path(key),
+                                    &content_type,
+                                    data.len() as u64,
+                                    &mut &data[..])?;
+        if response.status / 100 == 2 {
+            // the server's ETag (if any) follows its own scheme; report
+            // the S3-style MD5 the storers validate against
+            Ok(UploadOutcome {
+                e_tag: Some(hex::encode(&md5::compute(data).0)),
+                version_id: None,
+            })
+        } else {
+            Err(status_error("PUT", &response))
+        }
+    }
+
+    fn create_multipart(&self, key: &str, meta: &UploadMeta) -> Result<String> {
+        self.uploads
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .create(key, meta)
+    }
+
+    fn upload_part(&self,
+                   key: &str,
+                   upload_id: &str,
+                   part_number: i64,
+                   data: &[u8],
+                   checksum_sha256: Option<&str>)
+                   -> Result<Part> {
+        verify_checksum(checksum_sha256, data)?;
+        self.uploads
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .write_part(key, upload_id, part_number, data)
+    }
+
+    fn complete_multipart(&self,
+                          key: &str,
+                          upload_id: &str,
+                          parts: Vec<Part>)
+                          -> Result<UploadOutcome> {
+        let mut upload = self.uploads
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take(upload_id)?;
+        let e_tag = composite_etag(&parts, &upload.md5s)?;
+        let content_type = upload.meta.content_type.clone().unwrap_or_default();
+        upload.file.seek(SeekFrom::Start(0))?;
+        let response = self.execute("PUT",
+                                    "PUT",
+                                    &self.path(key),
+                                    &content_type,
+                                    upload.size,
+                                    &mut upload.file)?;
+        if response.status / 100 == 2 {
+            Ok(UploadOutcome {
+                e_tag: Some(e_tag),
+                version_id: None,
+            })
+        } else {
+            Err(status_error("PUT", &response))
+        }
+    }
+
+    fn abort_multipart(&self, _key: &str, upload_id: &str) -> Result<()> {
+        self.uploads
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .abort(upload_id)
+    }
+}
+
+/// [`StorageBackend`] handing every worker a clone of one
+/// [`HttpPutStore`].
+///
+/// [`HttpPutStore`]: struct.HttpPutStore.html
+/// [`StorageBackend`]: ../object_store/trait.StorageBackend.html
+pub struct HttpPutBackend {
+    store: HttpPutStore,
+}
+
+impl HttpPutBackend {
+    pub fn new(store: HttpPutStore) -> Self {
+        HttpPutBackend { store: store }
+    }
+}
+
+impl StorageBackend for HttpPutBackend {
+    fn store(&self) -> Result<Box<ObjectStore>> {
+        Ok(Box::new(self.store.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_templates_are_parsed() {
+        let store = HttpPutStore::new("http://dav.internal/binaries/{key}", None).unwrap();
+        assert_eq!(store.addr, "dav.internal:80");
+        assert_eq!(store.path("abcd"), "/binaries/abcd");
+
+        let store = HttpPutStore::new("dav.internal:8080/{key}", None).unwrap();
+        assert_eq!(store.addr, "dav.internal:8080");
+        assert_eq!(store.path("abcd"), "/abcd");
+
+        assert!(HttpPutStore::new("https://dav.internal/{key}", None).is_err());
+        assert!(HttpPutStore::new("http:///binaries/{key}", None).is_err());
+        assert!(HttpPutStore::new("http://dav.internal/binaries/", None).is_err());
+    }
+
+    #[test]
+    fn auth_headers_are_validated() {
+        let store = HttpPutStore::new("http://dav.internal/{key}",
+                                      Some("Authorization: Bearer secret "))
+                .unwrap();
+        assert_eq!(store.auth.as_ref().unwrap(), "Authorization: Bearer secret");
+
+        assert!(HttpPutStore::new("http://dav.internal/{key}", Some("Bearer secret"))
+                    .is_err());
+        assert!(HttpPutStore::new("http://dav.internal/{key}", Some(": value")).is_err());
+    }
+}
@@ -0,0 +1,126 @@
+//! JUnit-style XML report of a run.
+//!
+//! CI pipelines that gate a cutover on a verification run want a
+//! result they can consume natively instead of parsing logs. This
+//! renders the outcome in the JUnit XML dialect every CI system
+//! ingests: one passing test case per pipeline summary counter
+//! (committed, and verified for re-verify runs) and one failing test
+//! case per sampled failed object, carrying its oid, stage and error
+//! message.
+//!
+//! [`ThreadStat`] keeps at most [`ERROR_SAMPLE_CAP`] failure records,
+//! so the per-object cases are a sample; the `failures` attribute of
+//! the suite carries the exact total regardless.
+//!
+//! [`ThreadStat`]: ../thread/struct.ThreadStat.html
+//! [`ERROR_SAMPLE_CAP`]: ../thread/constant.ERROR_SAMPLE_CAP.html
+
+use error::Result;
+use migrate::MigrationReport;
+use std::io::Write;
+use thread::ThreadStat;
+
+/// Write the run as a JUnit XML test suite.
+pub fn write_report<W: Write>(mut out: W,
+                              stats: &ThreadStat,
+                              report: &MigrationReport)
+                              -> Result<()> {
+    let records = stats.failed_objects();
+    // summary cases below plus one per sampled failure
+    let tests = records.len() + if report.verified > 0 { 2 } else { 1 };
+
+    writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    writeln!(out,
+             "<testsuite name=\"lo-migrate\" tests=\"{}\" failures=\"{}\" time=\"{}\">",
+             tests,
+             report.failed,
+             report.runtime.as_secs())?;
+
+    writeln!(out,
+             "  <testcase classname=\"lo_migrate\" name=\"committed {} objects\"/>",
+             report.committed)?;
+    if report.verified > 0 {
+        writeln!(out,
+                 "  <testcase classname=\"lo_migrate\" name=\"verified {} objects intact\"/>",
+                 report.verified)?;
+    }
+
+    for record in records {
+        let classname = match record.stage {
+            Some(stage) => format!("lo_migrate.{}", stage),
+            None => "lo_migrate".to_string(),
+        };
+        let name = match record.oid {
+            Some(oid) => format!("object oid {}", oid),
+            None => record.category.to_string(),
+        };
+        writeln!(out,
+                 "  <testcase classname=\"{}\" name=\"{}\">",
+                 xml_escape(&classname),
+                 xml_escape(&name))?;
+        writeln!(out,
+                 "    <failure type=\"{}\" message=\"{}\"/>",
+                 xml_escape(record.category),
+                 xml_escape(&record.message))?;
+        writeln!(out, "  </testcase>")?;
+    }
+
+    writeln!(out, "</testsuite>")?;
+    Ok(())
+}
+
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error::{ErrorKind, MigrationError, Stage};
+
+    #[test]
+    fn report_lists_summary_and_sampled_failures() {
+        let stats = ThreadStat::new();
+        stats.record_failure(&MigrationError::from(ErrorKind::ChecksumMismatch)
+                                  .at(Stage::Store)
+                                  .for_object(42, "abcd".to_string()));
+
+        let report = MigrationReport {
+            observed: 10,
+            received: 10,
+            zero_byte: 0,
+            verified: 3,
+            stored: 9,
+            committed: 9,
+            committed_bytes: 4096,
+            failed: 1,
+            errors: vec![("ChecksumMismatch", 1)],
+            runtime: ::std::time::Duration::from_secs(5),
+        };
+
+        let mut xml = Vec::new();
+        write_report(&mut xml, &stats, &report).unwrap();
+        let xml = String::from_utf8(xml).unwrap();
+
+        assert!(xml.contains("tests=\"3\" failures=\"1\""));
+        assert!(xml.contains("name=\"committed 9 objects\""));
+        assert!(xml.contains("name=\"verified 3 objects intact\""));
+        assert!(xml.contains("classname=\"lo_migrate.storer\" name=\"object oid 42\""));
+        assert!(xml.contains("type=\"ChecksumMismatch\""));
+    }
+
+    #[test]
+    fn markup_is_escaped() {
+        assert_eq!(xml_escape("a<b>&\"c\""), "a&lt;b&gt;&amp;&quot;c&quot;");
+    }
+}
@@ -0,0 +1,92 @@
+//! Migrate large objects stored in Postgres to an S3 compatible object store.
+//!
+//! Nice2 keeps binary data in Postgres large objects, bookkept by the
+//! `_nice_binary` table. This crate moves that data to S3, writing the
+//! sha2 hash of every object back to `_nice_binary` so Nice can find the
+//! object in the store afterwards.
+//!
+//! # Architecture
+//!
+//! Work is passed along a pipeline of worker threads connected by bounded
+//! queues:
+//!
+//! 1. the *observer* walks `_nice_binary` and enqueues a [`Lo`] for every
+//!    row whose `sha2` column is still NULL,
+//! 2. *receivers* read the large object data from Postgres, hashing it on
+//!    the way, and buffer it in memory or in a temporary file,
+//! 3. *storers* upload the buffered data to S3, keyed by the sha2 hash,
+//! 4. *committers* write the sha2 hashes back to `_nice_binary` in chunks.
+//!
+//! A monitor thread periodically logs progress and queue utilization.
+//!
+//! [`Lo`]: lo/struct.Lo.html
+
+extern crate chrono;
+extern crate digest;
+extern crate fallible_iterator;
+extern crate futures;
+extern crate hex;
+#[cfg(unix)]
+extern crate libc;
+#[macro_use]
+extern crate log;
+extern crate md5;
+extern crate memmap;
+extern crate postgres;
+extern crate postgres_large_object;
+extern crate r2d2;
+extern crate r2d2_postgres;
+extern crate rusoto_core;
+extern crate rusoto_s3;
+#[cfg(feature = "sentry-report")]
+extern crate sentry;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+#[cfg(test)]
+extern crate serde_json;
+extern crate sha1;
+extern crate sha2;
+extern crate tempfile;
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(feature = "async")]
+extern crate tokio_postgres;
+extern crate two_lock_queue;
+
+#[cfg(feature = "async")]
+pub mod async_pipeline;
+pub mod audit;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod config;
+pub mod db;
+pub mod error;
+pub mod estimate;
+pub mod events;
+pub mod export;
+pub mod http_put;
+pub mod junit;
+pub mod lo;
+pub mod logging;
+pub mod manifest;
+pub mod metrics;
+pub mod migrate;
+pub mod notify;
+pub mod object_store;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod pipeline;
+pub mod prelude;
+pub mod presign;
+pub mod queue;
+#[cfg(feature = "sentry-report")]
+pub mod sentry_report;
+pub mod sigv2;
+pub mod source;
+pub mod tempfiles;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod thread;
+
+pub use migrate::run;
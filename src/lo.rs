@@ -0,0 +1,381 @@
+//! Representation of a single large object travelling through the pipeline.
+
+use chrono::{DateTime, Utc};
+use error::{ErrorKind, Result};
+use hex::{self, FromHex};
+use postgres::rows::Row;
+use std::fmt;
+use std::io::{Read, Seek, SeekFrom, Write};
+use tempfile::NamedTempFile;
+
+/// Column positions of a query producing [`Lo`]s, used by
+/// [`Lo::from_row()`].
+///
+/// The default matches the query the built-in source issues:
+/// `SELECT hash, data, size, mime_type ...`.
+///
+/// [`Lo`]: struct.Lo.html
+/// [`Lo::from_row()`]: struct.Lo.html#method.from_row
+#[derive(Debug, Clone, Copy)]
+pub struct ColumnMapping {
+    /// position of the hex-encoded sha1 hash
+    pub hash: usize,
+    /// position of the large object oid
+    pub oid: usize,
+    /// position of the object size in bytes
+    pub size: usize,
+    /// position of the mime type
+    pub mime_type: usize,
+    /// position of the original filename, if the query selects one
+    pub filename: Option<usize>,
+}
+
+impl Default for ColumnMapping {
+    fn default() -> Self {
+        ColumnMapping {
+            hash: 0,
+            oid: 1,
+            size: 2,
+            mime_type: 3,
+            filename: None,
+        }
+    }
+}
+
+/// Object data buffered in embedder-supplied storage.
+///
+/// Written once by the receiver and read back by the storer — for a
+/// multipart upload in one sequential pass per attempt. The storer
+/// seeks back to the start before reading, so the position the
+/// receiver leaves the buffer in does not matter. Implemented by every
+/// `Read + Seek + Send` type, e.g. `io::Cursor<Vec<u8>>`.
+pub trait BufferedData: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> BufferedData for T {}
+
+/// Creates scratch buffers for the receiver threads.
+///
+/// By default receivers buffer large objects in `mkstemp` temporary
+/// files; a backend lets embedders put the scratch data elsewhere,
+/// e.g. a quota'd tmpfs or encrypted scratch space. See
+/// [`Receiver::with_buffer_backend()`].
+///
+/// [`Receiver::with_buffer_backend()`]: ../thread/struct.Receiver.html#method.with_buffer_backend
+pub trait BufferBackend: Send + Sync {
+    /// Open a fresh scratch buffer for an object of `size` bytes.
+    fn create(&self, size: i64) -> Result<Box<ScratchBuffer>>;
+}
+
+/// A scratch buffer being filled by a receiver.
+pub trait ScratchBuffer: Write + Send {
+    /// Finish writing and turn the buffer into readable [`Data`],
+    /// typically [`Data::Custom`].
+    ///
+    /// Durable backends should flush to stable storage here (cf. the
+    /// fsync of the built-in temporary files), so a machine crash
+    /// cannot truncate data between receiving and uploading.
+    ///
+    /// [`Data`]: enum.Data.html
+    /// [`Data::Custom`]: enum.Data.html
+    fn into_data(self: Box<Self>) -> Result<Data>;
+}
+
+/// Buffered object data.
+///
+/// Small objects are kept in memory, larger ones are written to a
+/// temporary file by the receiver — or to a [`BufferBackend`]'s
+/// scratch space if one is configured. `None` is used before the data
+/// has been fetched and after the storer has uploaded and dropped it.
+///
+/// [`BufferBackend`]: trait.BufferBackend.html
+pub enum Data {
+    /// no data attached
+    None,
+    /// data held in memory
+    Vec(Vec<u8>),
+    /// data buffered in a temporary file
+    File(NamedTempFile),
+    /// data buffered in embedder-supplied storage
+    Custom(Box<BufferedData>),
+}
+
+impl fmt::Debug for Data {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Data::None => write!(f, "Data::None"),
+            Data::Vec(ref data) => write!(f, "Data::Vec({} bytes)", data.len()),
+            Data::File(ref file) => write!(f, "Data::File({:?})", file.path()),
+            Data::Custom(_) => write!(f, "Data::Custom(..)"),
+        }
+    }
+}
+
+/// Per-stage timestamps and worker names recorded for the audit log.
+///
+/// Only populated when the audit log is enabled; boxed so the common
+/// case does not grow [`Lo`].
+///
+/// [`Lo`]: struct.Lo.html
+#[derive(Clone, Debug, Default)]
+pub struct AuditTrail {
+    /// when the receiver finished buffering and hashing the data
+    pub received_at: Option<DateTime<Utc>>,
+    /// seconds the receiver spent on the object
+    pub receive_seconds: f64,
+    /// name of the receiver thread
+    pub received_by: String,
+    /// when the upload completed
+    pub stored_at: Option<DateTime<Utc>>,
+    /// seconds the storer spent on the object
+    pub store_seconds: f64,
+    /// name of the storer thread
+    pub stored_by: String,
+}
+
+/// A large object as bookkept by the `_nice_binary` table.
+#[derive(Debug)]
+pub struct Lo {
+    /// sha1 hash identifying the row (`hash` column), binary form
+    sha1: Vec<u8>,
+    /// oid of the large object (`data` column)
+    oid: u32,
+    /// object size in bytes (`size` column)
+    size: i64,
+    /// mime type (`mime_type` column)
+    mime_type: String,
+    /// original filename, if the source table carries one
+    filename: Option<String>,
+    /// buffered object data, fetched by the receiver
+    data: Data,
+    /// sha2 hash of the data, computed by the receiver, binary form
+    sha2: Option<Vec<u8>>,
+    /// version id the bucket assigned to the upload, if versioned
+    version_id: Option<String>,
+    /// audit timestamps, recorded only when the audit log is enabled
+    audit: Option<Box<AuditTrail>>,
+}
+
+impl Lo {
+    pub fn new(sha1: Vec<u8>, oid: u32, size: i64, mime_type: String) -> Self {
+        Lo {
+            sha1: sha1,
+            oid: oid,
+            size: size,
+            mime_type: mime_type,
+            filename: None,
+            data: Data::None,
+            sha2: None,
+            version_id: None,
+            audit: None,
+        }
+    }
+
+    /// Build a [`Lo`] from a hex-encoded sha1 hash, validating it.
+    ///
+    /// Surrounding whitespace is tolerated (the `hash` column is a
+    /// `varchar` and legacy rows carry padding); anything that does not
+    /// decode to 20 bytes fails with [`ErrorKind::InvalidHash`].
+    ///
+    /// [`Lo`]: struct.Lo.html
+    /// [`ErrorKind::InvalidHash`]: ../error/enum.ErrorKind.html
+    pub fn from_hash_hex(hash: &str, oid: u32, size: i64, mime_type: String) -> Result<Self> {
+        match Vec::from_hex(hash.trim()) {
+            Ok(ref sha1) if sha1.len() == 20 => Ok(Lo::new(sha1.clone(), oid, size, mime_type)),
+            _ => Err(ErrorKind::InvalidHash.into()),
+        }
+    }
+
+    /// Build a [`Lo`] from a query row, e.g. in a custom observer
+    /// against a different schema.
+    ///
+    /// `mapping` names the column positions; hash decoding and
+    /// validation behave as in [`from_hash_hex()`].
+    ///
+    /// [`Lo`]: struct.Lo.html
+    /// [`from_hash_hex()`]: #method.from_hash_hex
+    pub fn from_row(row: &Row, mapping: &ColumnMapping) -> Result<Self> {
+        let hash: String = row.get(mapping.hash);
+        let mime_type: Option<String> = row.get(mapping.mime_type);
+        let mut lo = Lo::from_hash_hex(&hash,
+                                       row.get(mapping.oid),
+                                       row.get(mapping.size),
+                                       mime_type.unwrap_or_default())?;
+        if let Some(filename) = mapping.filename {
+            lo.set_filename(row.get(filename));
+        }
+        Ok(lo)
+    }
+
+    /// sha1 hash of the object, binary form
+    pub fn sha1(&self) -> &[u8] {
+        &self.sha1
+    }
+
+    /// sha1 hash of the object as stored in the `hash` column
+    pub fn sha1_hex(&self) -> String {
+        hex::encode(&self.sha1)
+    }
+
+    /// oid of the underlying large object
+    pub fn oid(&self) -> u32 {
+        self.oid
+    }
+
+    /// size of the object in bytes
+    pub fn size(&self) -> i64 {
+        self.size
+    }
+
+    /// mime type of the object
+    pub fn mime_type(&self) -> &str {
+        &self.mime_type
+    }
+
+    pub(crate) fn set_mime_type(&mut self, mime_type: String) {
+        self.mime_type = mime_type;
+    }
+
+    /// original filename of the object, if one is known
+    pub fn filename(&self) -> Option<&str> {
+        self.filename.as_ref().map(|name| &name[..])
+    }
+
+    pub(crate) fn set_filename(&mut self, filename: Option<String>) {
+        self.filename = filename;
+    }
+
+    /// sha2 hash of the object data, if already computed
+    pub fn sha2(&self) -> Option<&[u8]> {
+        self.sha2.as_ref().map(|sha2| &sha2[..])
+    }
+
+    /// sha2 hash of the object data in hex, as written to `_nice_binary`
+    /// and used as S3 key
+    pub fn sha2_hex(&self) -> Option<String> {
+        self.sha2.as_ref().map(hex::encode)
+    }
+
+    pub(crate) fn set_sha2(&mut self, sha2: Vec<u8>) {
+        self.sha2 = Some(sha2);
+    }
+
+    /// version id the bucket assigned to the uploaded object; only set
+    /// after a successful upload into a versioning-enabled bucket
+    pub fn version_id(&self) -> Option<&str> {
+        self.version_id.as_ref().map(|id| &id[..])
+    }
+
+    pub(crate) fn set_version_id(&mut self, version_id: Option<String>) {
+        self.version_id = version_id;
+    }
+
+    /// audit timestamps, recorded only when the audit log is enabled
+    pub fn audit_trail(&self) -> Option<&AuditTrail> {
+        self.audit.as_ref().map(|audit| &**audit)
+    }
+
+    /// audit record to fill in, created on first use
+    pub(crate) fn audit_trail_mut(&mut self) -> &mut AuditTrail {
+        if self.audit.is_none() {
+            self.audit = Some(Box::new(AuditTrail::default()));
+        }
+        self.audit.as_mut().expect("created above")
+    }
+
+    /// buffered object data
+    pub fn data(&self) -> &Data {
+        &self.data
+    }
+
+    pub(crate) fn set_data(&mut self, data: Data) {
+        self.data = data;
+    }
+
+    /// Take the buffered data out of the object, leaving [`Data::None`]
+    /// behind. Dropping the data also removes a file-backed buffer.
+    ///
+    /// [`Data::None`]: enum.Data.html
+    pub fn take_data(&mut self) -> Data {
+        ::std::mem::replace(&mut self.data, Data::None)
+    }
+
+    /// Read up to `len` bytes from the start of the buffered data
+    /// without disturbing it, e.g. to sniff a content type.
+    pub(crate) fn peek_data(&mut self, len: usize) -> Result<Vec<u8>> {
+        match self.data {
+            Data::None => Ok(Vec::new()),
+            Data::Vec(ref data) => Ok(data[..data.len().min(len)].to_vec()),
+            Data::File(ref file) => {
+                let mut head = Vec::with_capacity(len);
+                file.reopen()?.take(len as u64).read_to_end(&mut head)?;
+                Ok(head)
+            }
+            Data::Custom(ref mut reader) => {
+                reader.seek(SeekFrom::Start(0))?;
+                let mut head = Vec::with_capacity(len);
+                reader.by_ref().take(len as u64).read_to_end(&mut head)?;
+                Ok(head)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_lo() -> Lo {
+        Lo::new(
+            vec![0xda, 0x39, 0xa3, 0xee],
+            4221,
+            1024,
+            "image/png".to_string(),
+        )
+    }
+
+    #[test]
+    fn accessors() {
+        let lo = sample_lo();
+        assert_eq!(lo.sha1_hex(), "da39a3ee");
+        assert_eq!(lo.oid(), 4221);
+        assert_eq!(lo.size(), 1024);
+        assert_eq!(lo.mime_type(), "image/png");
+        assert!(lo.sha2().is_none());
+    }
+
+    #[test]
+    fn take_data() {
+        let mut lo = sample_lo();
+        lo.set_data(Data::Vec(vec![1, 2, 3]));
+        match lo.take_data() {
+            Data::Vec(ref data) => assert_eq!(data, &[1, 2, 3]),
+            ref other => panic!("unexpected data: {:?}", other),
+        }
+        match *lo.data() {
+            Data::None => (),
+            ref other => panic!("unexpected data: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn from_hash_hex_validates() {
+        let lo = Lo::from_hash_hex(" 2aae6c35c94fcfb415dbe95f408b9ce91ee846ed ",
+                                   42,
+                                   11,
+                                   "text/plain".to_string())
+                .unwrap();
+        assert_eq!(lo.sha1_hex(), "2aae6c35c94fcfb415dbe95f408b9ce91ee846ed");
+
+        // not hex at all
+        assert!(Lo::from_hash_hex("not a hash", 42, 11, String::new()).is_err());
+        // valid hex but not 20 bytes
+        assert!(Lo::from_hash_hex("da39a3ee", 42, 11, String::new()).is_err());
+    }
+
+    #[test]
+    fn sha2_hex() {
+        let mut lo = sample_lo();
+        lo.set_sha2(vec![0xe3, 0xb0]);
+        assert_eq!(lo.sha2_hex().unwrap(), "e3b0");
+    }
+}
@@ -0,0 +1,321 @@
+//! Per-thread-group log routing.
+//!
+//! The pipeline spawns named threads (`receiver_1`, `storer_3`, ...),
+//! so the thread name identifies the group a log record comes from.
+//! [`GroupLogger`] wraps any [`Log`] implementation and applies
+//! per-group rules on top of it: a group can get its own level and
+//! optionally its own file, so one can debug the storers at TRACE
+//! without drowning in receiver output across twenty threads.
+//!
+//! ```no_run
+//! extern crate env_logger;
+//! extern crate log;
+//! # extern crate lo_migrate;
+//! use lo_migrate::logging::GroupLogger;
+//! use log::LevelFilter;
+//!
+//! let inner = env_logger::Builder::from_default_env().build();
+//! let inner_level = inner.filter();
+//! GroupLogger::new(Box::new(inner))
+//!     .with_group("storer", LevelFilter::Trace, Some("storer.log"))
+//!     .unwrap()
+//!     .init(inner_level)
+//!     .unwrap();
+//! ```
+//!
+//! [`SyslogLogger`] (unix only) writes to syslog or the systemd
+//! journal instead, for runs as a system service where stdout is not
+//! collected; it works stand-alone or as the wrapped logger of a
+//! [`GroupLogger`].
+//!
+//! [`GroupLogger`]: struct.GroupLogger.html
+//! [`SyslogLogger`]: struct.SyslogLogger.html
+//! [`Log`]: https://docs.rs/log/0.4/log/trait.Log.html
+
+use error::Result;
+use log::{self, Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Routing rule for one thread group.
+struct GroupRule {
+    /// thread group, i.e. the thread name up to a numeric suffix
+    group: String,
+    level: LevelFilter,
+    /// records go here instead of the wrapped logger, if set
+    file: Option<Mutex<File>>,
+}
+
+/// [`Log`] wrapper applying per-thread-group levels and targets.
+///
+/// Records from threads without a matching rule pass through to the
+/// wrapped logger unchanged, so the usual `RUST_LOG` filtering keeps
+/// working for everything not singled out.
+///
+/// [`Log`]: https://docs.rs/log/0.4/log/trait.Log.html
+pub struct GroupLogger {
+    inner: Box<Log>,
+    rules: Vec<GroupRule>,
+}
+
+impl GroupLogger {
+    pub fn new(inner: Box<Log>) -> Self {
+        GroupLogger {
+            inner: inner,
+            rules: Vec::new(),
+        }
+    }
+
+    /// Route the group's records: everything up to `level` is kept,
+    /// and written to `file` (appending) instead of the wrapped logger
+    /// if one is given.
+    pub fn with_group<P: AsRef<Path>>(mut self,
+                                      group: &str,
+                                      level: LevelFilter,
+                                      file: Option<P>)
+                                      -> Result<Self> {
+        let file = match file {
+            Some(path) => {
+                Some(Mutex::new(OpenOptions::new().append(true).create(true).open(path)?))
+            }
+            None => None,
+        };
+        self.rules.push(GroupRule {
+                            group: group.to_string(),
+                            level: level,
+                            file: file,
+                        });
+        Ok(self)
+    }
+
+    /// Install as the global logger.
+    ///
+    /// `inner_level` is the wrapped logger's own filter level (e.g.
+    /// `env_logger::Logger::filter()`); the global max level is the
+    /// most verbose of it and the rules, so a TRACE rule takes effect
+    /// even when the wrapped logger defaults to INFO.
+    pub fn init(self, inner_level: LevelFilter) -> ::std::result::Result<(), SetLoggerError> {
+        let level = self.rules
+            .iter()
+            .map(|rule| rule.level)
+            .max()
+            .unwrap_or(LevelFilter::Off)
+            .max(inner_level);
+        log::set_boxed_logger(Box::new(self))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+
+    /// Rule matching the current thread, if any.
+    fn rule(&self) -> Option<&GroupRule> {
+        let thread = ::std::thread::current();
+        let group = thread.name().map(group_of)?;
+        self.rules.iter().find(|rule| rule.group == group)
+    }
+}
+
+impl Log for GroupLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match self.rule() {
+            Some(rule) => metadata.level() <= rule.level,
+            None => self.inner.enabled(metadata),
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        match self.rule() {
+            Some(rule) => {
+                if record.level() > rule.level {
+                    return;
+                }
+                match rule.file {
+                    Some(ref file) => {
+                        let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+                        // keep the line format close to env_logger's
+                        let _ = writeln!(file,
+                                         "{} {} {}: {}",
+                                         format_level(record.level()),
+                                         ::chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+                                         record.target(),
+                                         record.args());
+                    }
+                    None => self.inner.log(record),
+                }
+            }
+            None => {
+                if self.inner.enabled(record.metadata()) {
+                    self.inner.log(record);
+                }
+            }
+        }
+    }
+
+    fn flush(&self) {
+        for rule in &self.rules {
+            if let Some(ref file) = rule.file {
+                let _ = file.lock().unwrap_or_else(|e| e.into_inner()).flush();
+            }
+        }
+        self.inner.flush();
+    }
+}
+
+/// [`Log`] implementation writing to the local syslog socket.
+///
+/// Messages go to `/dev/log` in RFC 3164 framing with the priority
+/// mapped from the log level, so both classic syslogd and
+/// systemd-journald (which serves the same socket) file them with
+/// proper severities. Meant for runs as a system service where stdout
+/// is not collected; usable stand-alone or as the inner logger of a
+/// [`GroupLogger`].
+///
+/// [`Log`]: https://docs.rs/log/0.4/log/trait.Log.html
+/// [`GroupLogger`]: struct.GroupLogger.html
+#[cfg(unix)]
+pub struct SyslogLogger {
+    socket: ::std::os::unix::net::UnixDatagram,
+    level: LevelFilter,
+    ident: String,
+}
+
+#[cfg(unix)]
+impl SyslogLogger {
+    /// Connect to `/dev/log`, tagging every message with `ident`.
+    pub fn new(ident: &str, level: LevelFilter) -> Result<Self> {
+        let socket = ::std::os::unix::net::UnixDatagram::unbound()?;
+        socket.connect("/dev/log")?;
+        Ok(SyslogLogger {
+               socket: socket,
+               level: level,
+               ident: ident.to_string(),
+           })
+    }
+
+    /// Install as the global logger.
+    pub fn init(self) -> ::std::result::Result<(), SetLoggerError> {
+        let level = self.level;
+        log::set_boxed_logger(Box::new(self))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+}
+
+/// Syslog priority of a level: facility daemon (3), severity per
+/// level; TRACE has no syslog equivalent and maps to debug.
+#[cfg(unix)]
+fn syslog_priority(level: Level) -> u8 {
+    let severity = match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    };
+    (3 << 3) | severity
+}
+
+#[cfg(unix)]
+impl Log for SyslogLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // syslogd adds its own timestamp and hostname
+        let message = format!("<{}>{}[{}]: {}: {}",
+                              syslog_priority(record.level()),
+                              self.ident,
+                              ::std::process::id(),
+                              record.target(),
+                              record.args());
+        let _ = self.socket.send(message.as_bytes());
+    }
+
+    fn flush(&self) {}
+}
+
+/// Thread group of a thread name: a trailing `_<number>` is stripped,
+/// so `storer_12` and `storer_3` are both group `storer`.
+pub fn group_of(name: &str) -> &str {
+    match name.rfind('_') {
+        Some(pos) if name[pos + 1..].chars().all(|c| c.is_ascii_digit()) &&
+                     pos + 1 < name.len() => &name[..pos],
+        _ => name,
+    }
+}
+
+fn format_level(level: Level) -> &'static str {
+    match level {
+        Level::Error => "ERROR",
+        Level::Warn => "WARN ",
+        Level::Info => "INFO ",
+        Level::Debug => "DEBUG",
+        Level::Trace => "TRACE",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numeric_suffixes_collapse_into_the_group() {
+        assert_eq!(group_of("storer_12"), "storer");
+        assert_eq!(group_of("receiver_1"), "receiver");
+        assert_eq!(group_of("observer"), "observer");
+        assert_eq!(group_of("not_a_number_x"), "not_a_number_x");
+        assert_eq!(group_of("trailing_"), "trailing_");
+    }
+
+    #[test]
+    fn rules_apply_to_the_matching_thread() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        struct CountingLog(Arc<AtomicUsize>);
+        impl Log for CountingLog {
+            fn enabled(&self, _: &Metadata) -> bool {
+                true
+            }
+            fn log(&self, _: &Record) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+            fn flush(&self) {}
+        }
+
+        let passed = Arc::new(AtomicUsize::new(0));
+        let logger = GroupLogger::new(Box::new(CountingLog(passed.clone())))
+            .with_group("storer", LevelFilter::Off, None::<&str>)
+            .unwrap();
+
+        let record = Record::builder()
+            .args(format_args!("hello"))
+            .level(Level::Info)
+            .build();
+
+        // unnamed test thread: no rule, passes through
+        logger.log(&record);
+        assert_eq!(passed.load(Ordering::SeqCst), 1);
+
+        // silenced group: swallowed
+        let logger = ::std::sync::Arc::new(logger);
+        let thread_logger = logger.clone();
+        ::std::thread::Builder::new()
+            .name("storer_1".to_string())
+            .spawn(move || {
+                let record = Record::builder()
+                    .args(format_args!("hello"))
+                    .level(Level::Info)
+                    .build();
+                thread_logger.log(&record);
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(passed.load(Ordering::SeqCst), 1);
+    }
+}
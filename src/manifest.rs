@@ -0,0 +1,166 @@
+//! Versioned on-disk format of resume manifests and upload journals.
+//!
+//! Both the [`UploadJournal`] and the `--resume-manifest` input share
+//! one line-oriented format:
+//!
+//! ```text
+//! #lo-migrate-manifest v1
+//! <sha1 hex> <sha2 hex> [version id]
+//! ```
+//!
+//! The optional third field is the version id a versioning-enabled
+//! bucket assigned to the upload, for reconciliation tooling that
+//! needs to reference the exact uploaded version; resuming ignores it.
+//!
+//! The header line names the format version so a multi-week phased
+//! migration can mix releases: parsing is forward compatible in that
+//! unknown trailing fields and further `#` comment lines are ignored
+//! and a newer version in the header only logs a warning, on the
+//! promise that the first two fields of an entry keep their meaning in
+//! all future versions. Headerless files — journals written before the
+//! header existed — parse as version 1.
+//!
+//! [`UploadJournal`]: ../thread/struct.UploadJournal.html
+
+use error::{ErrorKind, Result};
+use hex::FromHex;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+
+/// Version written to the header of new files.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// First field of the header line identifying our files.
+const MAGIC: &str = "#lo-migrate-manifest";
+
+/// Write the magic header naming the current format version.
+pub fn write_header<W: Write>(writer: &mut W) -> Result<()> {
+    writeln!(writer, "{} v{}", MAGIC, FORMAT_VERSION)?;
+    Ok(())
+}
+
+/// Write a whole manifest, header included.
+pub fn write<W: Write>(writer: &mut W, entries: &HashMap<String, Vec<u8>>) -> Result<()> {
+    write_header(writer)?;
+    for (sha1, sha2) in entries {
+        writeln!(writer, "{} {}", sha1, ::hex::encode(sha2))?;
+    }
+    Ok(())
+}
+
+/// Read a manifest into sha1 -> sha2 pairs, e.g. for
+/// [`MigrationBuilder::known_hashes()`].
+///
+/// [`MigrationBuilder::known_hashes()`]: ../migrate/struct.MigrationBuilder.html#method.known_hashes
+pub fn read<R: Read>(reader: R) -> Result<HashMap<String, Vec<u8>>> {
+    let mut known = HashMap::new();
+    let mut lines = BufReader::new(reader).lines().enumerate();
+
+    if let Some((_, line)) = lines.next() {
+        let line = line?;
+        if line.starts_with(MAGIC) {
+            match parse_version(&line) {
+                Some(version) if version > FORMAT_VERSION => {
+                    warn!("manifest declares format version {} but this release only \
+                           knows version {}; entries with an unexpected layout are \
+                           rejected",
+                          version,
+                          FORMAT_VERSION);
+                }
+                Some(_) => (),
+                None => {
+                    return Err(ErrorKind::Manifest(format!("malformed header: {:?}", line))
+                                   .into())
+                }
+            }
+        } else {
+            // headerless journal from an old release
+            parse_entry(&line, 1, &mut known)?;
+        }
+    }
+
+    for (no, line) in lines {
+        parse_entry(&line?, no + 1, &mut known)?;
+    }
+    Ok(known)
+}
+
+/// Read the manifest stored at `path`.
+pub fn read_file<P: AsRef<Path>>(path: P) -> Result<HashMap<String, Vec<u8>>> {
+    read(File::open(path)?)
+}
+
+/// Version number out of a header line, e.g. `v1` -> 1.
+fn parse_version(header: &str) -> Option<u32> {
+    let version = header[MAGIC.len()..].trim();
+    if version.starts_with('v') {
+        version[1..].parse().ok()
+    } else {
+        None
+    }
+}
+
+/// Parse one `<sha1> <sha2> [ignored...]` entry into `known`.
+fn parse_entry(line: &str, no: usize, known: &mut HashMap<String, Vec<u8>>) -> Result<()> {
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(());
+    }
+    let mut fields = line.split_whitespace();
+    // trailing fields a future version may add are deliberately ignored
+    match (fields.next(), fields.next().map(Vec::from_hex)) {
+        (Some(sha1), Some(Ok(sha2))) => {
+            known.insert(sha1.to_string(), sha2);
+            Ok(())
+        }
+        _ => Err(ErrorKind::Manifest(format!("malformed entry on line {}", no)).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut entries = HashMap::new();
+        entries.insert("ab".repeat(20), vec![0xcd; 32]);
+        entries.insert("12".repeat(20), vec![0x34; 32]);
+
+        let mut buffer = Vec::new();
+        write(&mut buffer, &entries).unwrap();
+        assert!(buffer.starts_with(b"#lo-migrate-manifest v1\n"));
+        assert_eq!(read(&buffer[..]).unwrap(), entries);
+    }
+
+    #[test]
+    fn headerless_journals_still_parse() {
+        let known = read(&b"abcd 0123\n"[..]).unwrap();
+        assert_eq!(known["abcd"], vec![0x01, 0x23]);
+    }
+
+    #[test]
+    fn unknown_trailing_fields_and_comments_are_ignored() {
+        let input = "#lo-migrate-manifest v1\n\
+                     # a comment a future release may write\n\
+                     abcd 0123 some-future-field\n\
+                     \n";
+        let known = read(input.as_bytes()).unwrap();
+        assert_eq!(known.len(), 1);
+        assert_eq!(known["abcd"], vec![0x01, 0x23]);
+    }
+
+    #[test]
+    fn newer_version_is_tolerated() {
+        let known = read(&b"#lo-migrate-manifest v2\nabcd 0123\n"[..]).unwrap();
+        assert_eq!(known.len(), 1);
+    }
+
+    #[test]
+    fn malformed_entries_are_rejected() {
+        assert!(read(&b"not-hex also-not-hex\n"[..]).is_err());
+        assert!(read(&b"#lo-migrate-manifest vX\n"[..]).is_err());
+        assert!(read(&b"lonely-field\n"[..]).is_err());
+    }
+}
@@ -0,0 +1,455 @@
+//! Metrics sink the workers report into.
+//!
+//! Monitoring integrations used to re-instrument the worker loops each
+//! time; instead everything now reports through one [`MetricsSink`].
+//! The [`Monitor`] publishes the pipeline counters and queue gauges on
+//! every tick, and the receive, store and commit workers record
+//! per-object timing histograms.
+//!
+//! Four implementations ship with the crate: [`NullSink`] (the
+//! default, does nothing), [`StdoutSink`] (one line per sample, for
+//! debugging), [`PrometheusSink`] (aggregates in memory and renders
+//! the text exposition format) and [`PushgatewaySink`] (pushes the
+//! rendered metrics to a Prometheus Pushgateway for hosts Prometheus
+//! cannot scrape). [`FanoutSink`] combines several of them.
+//!
+//! [`MetricsSink`]: trait.MetricsSink.html
+//! [`FanoutSink`]: struct.FanoutSink.html
+//! [`Monitor`]: ../thread/struct.Monitor.html
+//! [`NullSink`]: struct.NullSink.html
+//! [`StdoutSink`]: struct.StdoutSink.html
+//! [`PrometheusSink`]: struct.PrometheusSink.html
+//! [`PushgatewaySink`]: struct.PushgatewaySink.html
+
+use error::{ErrorKind, Result};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io::{Read, Write as IoWrite};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A duration as fractional seconds, the unit histograms use.
+pub fn seconds(elapsed: Duration) -> f64 {
+    elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9
+}
+
+/// Where the workers report their measurements.
+///
+/// Implementations must be cheap and non-blocking: these methods are
+/// called from the hot loops of twenty-odd threads, so anything slow
+/// belongs into a buffer flushed elsewhere.
+pub trait MetricsSink: Send + Sync {
+    /// A monotonically increasing count, e.g. objects stored.
+    fn counter(&self, name: &str, value: u64);
+
+    /// A point-in-time value, e.g. queue length.
+    fn gauge(&self, name: &str, value: f64);
+
+    /// One observation of a distribution, e.g. seconds per upload.
+    fn histogram(&self, name: &str, value: f64);
+}
+
+/// Discards everything; the default when no sink is configured.
+#[derive(Debug, Default)]
+pub struct NullSink;
+
+impl MetricsSink for NullSink {
+    fn counter(&self, _: &str, _: u64) {}
+    fn gauge(&self, _: &str, _: f64) {}
+    fn histogram(&self, _: &str, _: f64) {}
+}
+
+/// Prints one line per sample, mainly useful while debugging.
+#[derive(Debug, Default)]
+pub struct StdoutSink;
+
+impl MetricsSink for StdoutSink {
+    fn counter(&self, name: &str, value: u64) {
+        println!("counter {} {}", name, value);
+    }
+
+    fn gauge(&self, name: &str, value: f64) {
+        println!("gauge {} {}", name, value);
+    }
+
+    fn histogram(&self, name: &str, value: f64) {
+        println!("histogram {} {}", name, value);
+    }
+}
+
+/// Histogram state: cumulative bucket counts plus count and sum, as
+/// Prometheus expects them.
+#[derive(Debug)]
+struct Histogram {
+    /// upper bounds, shared by all histograms of the sink
+    counts: Vec<u64>,
+    count: u64,
+    sum: f64,
+}
+
+/// Aggregates samples in memory and renders the Prometheus text
+/// exposition format on demand.
+///
+/// The crate deliberately does not open an HTTP port; embedders serve
+/// [`render()`] however fits their setup (or push it, see the
+/// Pushgateway tooling).
+///
+/// [`render()`]: #method.render
+pub struct PrometheusSink {
+    prefix: String,
+    buckets: Vec<f64>,
+    counters: Mutex<BTreeMap<String, u64>>,
+    gauges: Mutex<BTreeMap<String, f64>>,
+    histograms: Mutex<BTreeMap<String, Histogram>>,
+}
+
+impl PrometheusSink {
+    /// Sink prefixing every metric with `lo_migrate_`.
+    pub fn new() -> Self {
+        // seconds-flavoured buckets; uploads of huge objects land in +Inf
+        Self::with_buckets(vec![0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 60.0])
+    }
+
+    /// Sink with custom histogram bucket upper bounds (ascending).
+    pub fn with_buckets(buckets: Vec<f64>) -> Self {
+        assert!(buckets.windows(2).all(|pair| pair[0] < pair[1]),
+                "histogram buckets must be ascending");
+        PrometheusSink {
+            prefix: "lo_migrate_".to_string(),
+            buckets: buckets,
+            counters: Mutex::new(BTreeMap::new()),
+            gauges: Mutex::new(BTreeMap::new()),
+            histograms: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Render all metrics in the text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, value) in &*self.counters.lock().unwrap_or_else(|e| e.into_inner()) {
+            writeln!(out, "# TYPE {}{} counter", self.prefix, name).unwrap();
+            writeln!(out, "{}{} {}", self.prefix, name, value).unwrap();
+        }
+        for (name, value) in &*self.gauges.lock().unwrap_or_else(|e| e.into_inner()) {
+            writeln!(out, "# TYPE {}{} gauge", self.prefix, name).unwrap();
+            writeln!(out, "{}{} {}", self.prefix, name, value).unwrap();
+        }
+        for (name, histogram) in &*self.histograms.lock().unwrap_or_else(|e| e.into_inner()) {
+            writeln!(out, "# TYPE {}{} histogram", self.prefix, name).unwrap();
+            let mut cumulative = 0;
+            for (bound, count) in self.buckets.iter().zip(&histogram.counts) {
+                cumulative += count;
+                writeln!(out,
+                         "{}{}_bucket{{le=\"{}\"}} {}",
+                         self.prefix,
+                         name,
+                         bound,
+                         cumulative)
+                        .unwrap();
+            }
+            writeln!(out,
+                     "{}{}_bucket{{le=\"+Inf\"}} {}",
+                     self.prefix,
+                     name,
+                     histogram.count)
+                    .unwrap();
+            writeln!(out, "{}{}_sum {}", self.prefix, name, histogram.sum).unwrap();
+            writeln!(out, "{}{}_count {}", self.prefix, name, histogram.count).unwrap();
+        }
+        out
+    }
+}
+
+impl Default for PrometheusSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MetricsSink for PrometheusSink {
+    fn counter(&self, name: &str, value: u64) {
+        // the workers report absolute values from ThreadStat, so the
+        // last sample wins rather than accumulating
+        self.counters
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(name.to_string(), value);
+    }
+
+    fn gauge(&self, name: &str, value: f64) {
+        self.gauges
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(name.to_string(), value);
+    }
+
+    fn histogram(&self, name: &str, value: f64) {
+        let mut histograms = self.histograms.lock().unwrap_or_else(|e| e.into_inner());
+        let buckets = self.buckets.len();
+        let histogram = histograms
+            .entry(name.to_string())
+            .or_insert_with(|| {
+                                Histogram {
+                                    counts: vec![0; buckets],
+                                    count: 0,
+                                    sum: 0.0,
+                                }
+                            });
+        if let Some(bucket) = self.buckets.iter().position(|&bound| value <= bound) {
+            histogram.counts[bucket] += 1;
+        }
+        histogram.count += 1;
+        histogram.sum += value;
+    }
+}
+
+/// How long a push may take before it is abandoned.
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Address and metric group of a Prometheus Pushgateway.
+///
+/// Speaks plain HTTP over a [`TcpStream`] — a Pushgateway sits on the
+/// same internal network as the migration host, which is the whole
+/// point of pushing — so no TLS stack is dragged in for it.
+///
+/// [`TcpStream`]: https://doc.rust-lang.org/std/net/struct.TcpStream.html
+pub struct PushgatewayClient {
+    addr: String,
+    path: String,
+}
+
+impl PushgatewayClient {
+    /// Client pushing into the group `job/{job}[/instance/{instance}]`
+    /// of the gateway at `url`, e.g. `http://pushgateway.internal:9091`.
+    /// The port defaults to 9091, the scheme must be `http`.
+    pub fn new(url: &str, job: &str, instance: Option<&str>) -> Result<Self> {
+        let rest = match url.find("://") {
+            Some(at) if &url[..at] == "http" => &url[at + 3..],
+            Some(_) => {
+                return Err(ErrorKind::Config("the pushgateway integration speaks plain http \
+                                              only"
+                                                     .to_string())
+                                   .into())
+            }
+            None => url,
+        };
+        let rest = rest.trim_end_matches('/');
+        if rest.is_empty() {
+            return Err(ErrorKind::Config(format!("no host in pushgateway url {:?}", url)).into());
+        }
+
+        let mut path = String::from("/metrics");
+        for (label, value) in Some(("job", job)).into_iter()
+                .chain(instance.map(|instance| ("instance", instance))) {
+            if value.is_empty() || value.contains('/') {
+                return Err(ErrorKind::Config(format!("invalid pushgateway {} label {:?}",
+                                                     label,
+                                                     value))
+                                   .into());
+            }
+            write!(path, "/{}/{}", label, value).unwrap();
+        }
+
+        Ok(PushgatewayClient {
+               addr: if rest.contains(':') {
+                   rest.to_string()
+               } else {
+                   format!("{}:9091", rest)
+               },
+               path: path,
+           })
+    }
+
+    /// PUT `body` — a rendering in the text exposition format —
+    /// replacing all previously pushed metrics of the group.
+    pub fn push(&self, body: &str) -> Result<()> {
+        let mut stream = TcpStream::connect(&*self.addr)?;
+        stream.set_read_timeout(Some(PUSH_TIMEOUT))?;
+        stream.set_write_timeout(Some(PUSH_TIMEOUT))?;
+        let request = format!("PUT {} HTTP/1.0\r\n\
+                               Host: {}\r\n\
+                               Content-Type: text/plain; version=0.0.4\r\n\
+                               Content-Length: {}\r\n\
+                               Connection: close\r\n\
+                               \r\n\
+                               {}",
+                              self.path,
+                              self.addr,
+                              body.len(),
+                              body);
+        stream.write_all(request.as_bytes())?;
+
+        let mut response = String::new();
+        // HTTP/1.0 with Connection: close, the gateway ends the stream
+        let _ = stream.read_to_string(&mut response);
+        let status = response.split_whitespace().nth(1).unwrap_or("");
+        if status.starts_with('2') {
+            Ok(())
+        } else {
+            Err(ErrorKind::Metrics(format!("pushgateway at {} answered with status {:?}",
+                                           self.addr,
+                                           status))
+                        .into())
+        }
+    }
+}
+
+/// [`PrometheusSink`] that pushes its rendering to a Pushgateway, for
+/// sites where Prometheus has no network path to scrape the migration
+/// host.
+///
+/// Pushes happen from the [`Monitor`] thread — triggered by its gauge
+/// updates, at most once per `interval` — so the worker hot loops
+/// never touch the network. A failed push is logged and retried on the
+/// next tick. Call [`push_now()`] after the run for the final totals.
+///
+/// [`PrometheusSink`]: struct.PrometheusSink.html
+/// [`Monitor`]: ../thread/struct.Monitor.html
+/// [`push_now()`]: #method.push_now
+pub struct PushgatewaySink {
+    inner: PrometheusSink,
+    client: PushgatewayClient,
+    interval: Duration,
+    last_push: Mutex<Option<Instant>>,
+}
+
+impl PushgatewaySink {
+    pub fn new(client: PushgatewayClient, interval: Duration) -> Self {
+        PushgatewaySink {
+            inner: PrometheusSink::new(),
+            client: client,
+            interval: interval,
+            last_push: Mutex::new(None),
+        }
+    }
+
+    /// Push the current state unconditionally, e.g. the final totals
+    /// once the run is over.
+    pub fn push_now(&self) -> Result<()> {
+        self.client.push(&self.inner.render())
+    }
+
+    fn maybe_push(&self) {
+        {
+            let mut last_push = self.last_push.lock().unwrap_or_else(|e| e.into_inner());
+            match *last_push {
+                Some(at) if at.elapsed() < self.interval => return,
+                _ => *last_push = Some(Instant::now()),
+            }
+        }
+        if let Err(err) = self.push_now() {
+            warn!("failed to push metrics: {}", err);
+        }
+    }
+}
+
+impl MetricsSink for PushgatewaySink {
+    fn counter(&self, name: &str, value: u64) {
+        self.inner.counter(name, value);
+    }
+
+    fn gauge(&self, name: &str, value: f64) {
+        self.inner.gauge(name, value);
+        self.maybe_push();
+    }
+
+    fn histogram(&self, name: &str, value: f64) {
+        self.inner.histogram(name, value);
+    }
+}
+
+/// Forwards every sample to several sinks, e.g. a Pushgateway and a
+/// trace exporter at once.
+pub struct FanoutSink {
+    sinks: Vec<Arc<MetricsSink>>,
+}
+
+impl FanoutSink {
+    pub fn new(sinks: Vec<Arc<MetricsSink>>) -> Self {
+        FanoutSink { sinks: sinks }
+    }
+}
+
+impl MetricsSink for FanoutSink {
+    fn counter(&self, name: &str, value: u64) {
+        for sink in &self.sinks {
+            sink.counter(name, value);
+        }
+    }
+
+    fn gauge(&self, name: &str, value: f64) {
+        for sink in &self.sinks {
+            sink.gauge(name, value);
+        }
+    }
+
+    fn histogram(&self, name: &str, value: f64) {
+        for sink in &self.sinks {
+            sink.histogram(name, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_report_the_latest_absolute_value() {
+        let sink = PrometheusSink::new();
+        sink.counter("lo_stored_total", 3);
+        sink.counter("lo_stored_total", 7);
+        assert!(sink.render().contains("lo_migrate_lo_stored_total 7\n"));
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let sink = PrometheusSink::with_buckets(vec![1.0, 10.0]);
+        sink.histogram("store_seconds", 0.5);
+        sink.histogram("store_seconds", 5.0);
+        sink.histogram("store_seconds", 50.0);
+
+        let rendered = sink.render();
+        assert!(rendered.contains("store_seconds_bucket{le=\"1\"} 1\n"));
+        assert!(rendered.contains("store_seconds_bucket{le=\"10\"} 2\n"));
+        assert!(rendered.contains("store_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(rendered.contains("store_seconds_count 3\n"));
+        assert!(rendered.contains("store_seconds_sum 55.5\n"));
+    }
+
+    #[test]
+    fn pushgateway_url_and_group_are_parsed() {
+        let client = PushgatewayClient::new("http://gw.internal", "lo_migrate", Some("db1"))
+            .unwrap();
+        assert_eq!(client.addr, "gw.internal:9091");
+        assert_eq!(client.path, "/metrics/job/lo_migrate/instance/db1");
+
+        let client = PushgatewayClient::new("gw.internal:9999/", "migrate", None).unwrap();
+        assert_eq!(client.addr, "gw.internal:9999");
+        assert_eq!(client.path, "/metrics/job/migrate");
+
+        assert!(PushgatewayClient::new("https://gw.internal", "job", None).is_err());
+        assert!(PushgatewayClient::new("http://", "job", None).is_err());
+        assert!(PushgatewayClient::new("http://gw.internal", "a/b", None).is_err());
+    }
+
+    #[test]
+    fn fanout_reaches_every_sink() {
+        let first = Arc::new(PrometheusSink::new());
+        let second = Arc::new(PrometheusSink::new());
+        let fanout = FanoutSink::new(vec![first.clone() as Arc<MetricsSink>,
+                                          second.clone() as Arc<MetricsSink>]);
+        fanout.counter("lo_stored_total", 5);
+        assert!(first.render().contains("lo_stored_total 5\n"));
+        assert!(second.render().contains("lo_stored_total 5\n"));
+    }
+
+    #[test]
+    fn null_sink_does_nothing() {
+        let sink = NullSink;
+        sink.counter("a", 1);
+        sink.gauge("b", 2.0);
+        sink.histogram("c", 3.0);
+    }
+}
@@ -0,0 +1,472 @@
+//! Notifications about the state of a run.
+//!
+//! Chat-ops bridges, ticketing systems and inboxes want a signal when
+//! a migration window opens or closes, not a log file. Two layers live
+//! here: the [`WebhookNotifier`] POSTs a machine-readable JSON summary
+//! to one URL when the run ends, and the [`Notifier`] trait carries
+//! human-readable messages over pluggable channels — [`SmtpNotifier`]
+//! for mail via a relay, [`SlackNotifier`] for Slack-compatible
+//! webhooks — fanned out by a [`NotifierSet`] on run start, completion
+//! and failure-threshold breach. The channels are configured through
+//! [`NotifyConfig`] in the config file.
+//!
+//! Everything speaks plain TCP/HTTP over a [`TcpStream`]; relays and
+//! hook bridges sit on the internal network.
+//!
+//! [`WebhookNotifier`]: struct.WebhookNotifier.html
+//! [`Notifier`]: trait.Notifier.html
+//! [`SmtpNotifier`]: struct.SmtpNotifier.html
+//! [`SlackNotifier`]: struct.SlackNotifier.html
+//! [`NotifierSet`]: struct.NotifierSet.html
+//! [`NotifyConfig`]: ../config/struct.NotifyConfig.html
+//! [`TcpStream`]: https://doc.rust-lang.org/std/net/struct.TcpStream.html
+
+use error::{ErrorKind, Result};
+use migrate::MigrationReport;
+use std::fmt::Write as FmtWrite;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// How long a notification delivery may take before it is abandoned.
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How a run ended, as reported in the `status` field of the payload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RunStatus {
+    /// all observed objects were processed, none failed
+    Completed,
+    /// the run finished, but some objects failed to migrate
+    CompletedWithFailures,
+    /// the run was cancelled, e.g. by a signal or the deadline
+    Cancelled,
+}
+
+impl RunStatus {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            RunStatus::Completed => "completed",
+            RunStatus::CompletedWithFailures => "completed_with_failures",
+            RunStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// POSTs a JSON run summary to a webhook URL.
+pub struct WebhookNotifier {
+    addr: String,
+    path: String,
+}
+
+/// Split a plain-http URL into `host:port` and path; the port defaults
+/// to 80, the scheme must be `http`.
+fn parse_http_url(url: &str) -> Result<(String, String)> {
+    let rest = match url.find("://") {
+        Some(at) if &url[..at] == "http" => &url[at + 3..],
+        Some(_) => {
+            return Err(ErrorKind::Config("the notifiers speak plain http only; point them \
+                                          at an internal relay or bridge"
+                                                 .to_string())
+                               .into())
+        }
+        None => url,
+    };
+    let (host, path) = match rest.find('/') {
+        Some(at) => (&rest[..at], &rest[at..]),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return Err(ErrorKind::Config(format!("no host in url {:?}", url)).into());
+    }
+    let addr = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+    Ok((addr, path.to_string()))
+}
+
+/// POST `body` and fail unless the response is a 2xx.
+fn http_post(addr: &str, path: &str, body: &str) -> Result<()> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(NOTIFY_TIMEOUT))?;
+    stream.set_write_timeout(Some(NOTIFY_TIMEOUT))?;
+    let request = format!("POST {} HTTP/1.0\r\n\
+                           Host: {}\r\n\
+                           Content-Type: application/json\r\n\
+                           Content-Length: {}\r\n\
+                           Connection: close\r\n\
+                           \r\n\
+                           {}",
+                          path,
+                          addr,
+                          body.len(),
+                          body);
+    stream.write_all(request.as_bytes())?;
+
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response);
+    let status = response.split_whitespace().nth(1).unwrap_or("");
+    if status.starts_with('2') {
+        Ok(())
+    } else {
+        Err(ErrorKind::Config(format!("webhook at {} answered with status {:?}", addr, status))
+                    .into())
+    }
+}
+
+impl WebhookNotifier {
+    /// Notifier for `url`, e.g. `http://hooks.internal/lo-migrate`.
+    pub fn new(url: &str) -> Result<Self> {
+        let (addr, path) = parse_http_url(url)?;
+        Ok(WebhookNotifier {
+               addr: addr,
+               path: path,
+           })
+    }
+
+    /// Deliver the summary of a finished run.
+    pub fn notify(&self, status: RunStatus, report: &MigrationReport) -> Result<()> {
+        self.post(&payload(status, report))
+    }
+
+    /// Deliver a `"status": "failed"` notification for a run that
+    /// aborted with `message` before producing a report.
+    pub fn notify_error(&self, message: &str) -> Result<()> {
+        self.post(&format!("{{\"status\":\"failed\",\"error\":\"{}\"}}",
+                           json_escape(message)))
+    }
+
+    fn post(&self, body: &str) -> Result<()> {
+        http_post(&self.addr, &self.path, body)
+    }
+}
+
+/// The summary as JSON. Counter names and error categories are plain
+/// identifiers, so only the hand-written strings need escaping.
+fn payload(status: RunStatus, report: &MigrationReport) -> String {
+    let mut errors = String::new();
+    for (i, &(category, count)) in report.errors.iter().enumerate() {
+        if i > 0 {
+            errors.push(',');
+        }
+        write!(errors, "\"{}\":{}", category, count).unwrap();
+    }
+    format!("{{\"status\":\"{}\",\"observed\":{},\"received\":{},\"verified\":{},\
+             \"stored\":{},\"committed\":{},\"committed_bytes\":{},\"failed\":{},\
+             \"runtime_seconds\":{},\"errors\":{{{}}}}}",
+            status.as_str(),
+            report.observed,
+            report.received,
+            report.verified,
+            report.stored,
+            report.committed,
+            report.committed_bytes,
+            report.failed,
+            report.runtime.as_secs(),
+            errors)
+}
+
+fn json_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                write!(escaped, "\\u{:04x}", c as u32).unwrap();
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A channel human-readable run notifications go out on.
+///
+/// Implementations deliver a short subject/message pair; formatting
+/// and trigger logic live in [`NotifierSet`], so a new channel only
+/// has to move bytes.
+///
+/// [`NotifierSet`]: struct.NotifierSet.html
+pub trait Notifier: Send + Sync {
+    /// Channel name for log messages, e.g. `"smtp"`.
+    fn name(&self) -> &'static str;
+
+    /// Deliver one notification.
+    fn send(&self, subject: &str, message: &str) -> Result<()>;
+}
+
+/// [`Notifier`] delivering mail through an SMTP relay.
+///
+/// Speaks just enough RFC 5321 for an internal relay that accepts
+/// unauthenticated mail from the server — the usual setup for cron
+/// and service jobs. No auth, no STARTTLS; anything fancier should go
+/// through a local forwarder.
+///
+/// [`Notifier`]: trait.Notifier.html
+pub struct SmtpNotifier {
+    addr: String,
+    from: String,
+    to: Vec<String>,
+}
+
+impl SmtpNotifier {
+    /// Notifier sending via `relay` (`host[:port]`, port defaulting to
+    /// 25) from `from` to the `to` addresses.
+    pub fn new(relay: &str, from: &str, to: Vec<String>) -> Result<Self> {
+        if relay.is_empty() || from.is_empty() || to.is_empty() {
+            return Err(ErrorKind::Config("smtp notifications need a relay, a sender and at \
+                                          least one recipient"
+                                                 .to_string())
+                               .into());
+        }
+        Ok(SmtpNotifier {
+               addr: if relay.contains(':') {
+                   relay.to_string()
+               } else {
+                   format!("{}:25", relay)
+               },
+               from: from.to_string(),
+               to: to,
+           })
+    }
+}
+
+/// Read one SMTP reply — skipping `250-`-style continuation lines —
+/// and fail unless it carries the expected code.
+fn smtp_expect(reader: &mut ::std::io::BufRead, code: &str) -> Result<()> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(ErrorKind::Config("smtp relay closed the connection".to_string())
+                           .into());
+        }
+        if line.len() >= 4 && &line[3..4] == "-" {
+            continue;
+        }
+        if line.starts_with(code) {
+            return Ok(());
+        }
+        return Err(ErrorKind::Config(format!("smtp relay answered {:?}", line.trim()))
+                       .into());
+    }
+}
+
+impl Notifier for SmtpNotifier {
+    fn name(&self) -> &'static str {
+        "smtp"
+    }
+
+    fn send(&self, subject: &str, message: &str) -> Result<()> {
+        let stream = TcpStream::connect(&*self.addr)?;
+        stream.set_read_timeout(Some(NOTIFY_TIMEOUT))?;
+        stream.set_write_timeout(Some(NOTIFY_TIMEOUT))?;
+        let mut reader = ::std::io::BufReader::new(stream.try_clone()?);
+        let mut stream = stream;
+
+        smtp_expect(&mut reader, "220")?;
+        write!(stream, "HELO lo-migrate\r\n")?;
+        smtp_expect(&mut reader, "250")?;
+        write!(stream, "MAIL FROM:<{}>\r\n", self.from)?;
+        smtp_expect(&mut reader, "250")?;
+        for recipient in &self.to {
+            write!(stream, "RCPT TO:<{}>\r\n", recipient)?;
+            smtp_expect(&mut reader, "250")?;
+        }
+        write!(stream, "DATA\r\n")?;
+        smtp_expect(&mut reader, "354")?;
+
+        write!(stream,
+               "From: <{}>\r\nTo: <{}>\r\nSubject: {}\r\n\r\n",
+               self.from,
+               self.to.join(">, <"),
+               subject)?;
+        for line in message.lines() {
+            // dot-stuffing, RFC 5321 section 4.5.2
+            if line.starts_with('.') {
+                write!(stream, ".")?;
+            }
+            write!(stream, "{}\r\n", line)?;
+        }
+        write!(stream, ".\r\n")?;
+        smtp_expect(&mut reader, "250")?;
+        write!(stream, "QUIT\r\n")?;
+        Ok(())
+    }
+}
+
+/// [`Notifier`] posting to a Slack-compatible incoming webhook.
+///
+/// The payload is the plain `{"text": ...}` format, which Mattermost
+/// and Rocket.Chat accept as well. Slack's own hosted hooks are https
+/// only, so this usually points at an internal bridge.
+///
+/// [`Notifier`]: trait.Notifier.html
+pub struct SlackNotifier {
+    addr: String,
+    path: String,
+}
+
+impl SlackNotifier {
+    pub fn new(webhook_url: &str) -> Result<Self> {
+        let (addr, path) = parse_http_url(webhook_url)?;
+        Ok(SlackNotifier {
+               addr: addr,
+               path: path,
+           })
+    }
+}
+
+impl Notifier for SlackNotifier {
+    fn name(&self) -> &'static str {
+        "slack"
+    }
+
+    fn send(&self, subject: &str, message: &str) -> Result<()> {
+        http_post(&self.addr,
+                  &self.path,
+                  &format!("{{\"text\":\"*{}*\\n{}\"}}",
+                           json_escape(subject),
+                           json_escape(message)))
+    }
+}
+
+/// Fans run events out over the configured [`Notifier`] channels.
+///
+/// Delivery failures are logged per channel and never affect the run.
+/// Call [`run_started()`] before spawning the pipeline,
+/// [`check_failure_threshold()`] periodically (e.g. once per monitor
+/// interval) and [`run_finished()`] with the report; the threshold
+/// notification goes out at most once per run.
+///
+/// [`Notifier`]: trait.Notifier.html
+/// [`run_started()`]: #method.run_started
+/// [`check_failure_threshold()`]: #method.check_failure_threshold
+/// [`run_finished()`]: #method.run_finished
+pub struct NotifierSet {
+    channels: Vec<Box<Notifier>>,
+    failure_threshold: Option<u64>,
+    threshold_notified: ::std::sync::atomic::AtomicBool,
+}
+
+impl NotifierSet {
+    pub fn new(channels: Vec<Box<Notifier>>) -> Self {
+        NotifierSet {
+            channels: channels,
+            failure_threshold: None,
+            threshold_notified: ::std::sync::atomic::AtomicBool::new(false),
+        }
+    }
+
+    /// Notify as soon as this many objects have failed, instead of
+    /// only with the final report.
+    pub fn with_failure_threshold(mut self, threshold: Option<u64>) -> Self {
+        self.failure_threshold = threshold;
+        self
+    }
+
+    /// Whether any channels are configured at all.
+    pub fn is_empty(&self) -> bool {
+        self.channels.is_empty()
+    }
+
+    pub fn run_started(&self, description: &str) {
+        self.broadcast("lo-migrate run started", description);
+    }
+
+    /// Check `failed` against the threshold; sends the breach
+    /// notification the first time it is reached.
+    pub fn check_failure_threshold(&self, failed: u64) {
+        use std::sync::atomic::Ordering;
+
+        let threshold = match self.failure_threshold {
+            Some(threshold) if failed >= threshold => threshold,
+            _ => return,
+        };
+        if self.threshold_notified.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        self.broadcast("lo-migrate failure threshold breached",
+                       &format!("{} objects have failed to migrate (threshold: {}); the run \
+                                 continues, see the log for details",
+                                failed,
+                                threshold));
+    }
+
+    pub fn run_finished(&self, status: RunStatus, report: &MigrationReport) {
+        let message = format!("status: {}\n{} objects committed, {} failed, {} verified \
+                               intact\nruntime: {}s\nfailure breakdown: {}",
+                              status.as_str(),
+                              report.committed,
+                              report.failed,
+                              report.verified,
+                              report.runtime.as_secs(),
+                              if report.failed == 0 {
+                                  "none".to_string()
+                              } else {
+                                  report.error_summary()
+                              });
+        self.broadcast("lo-migrate run finished", &message);
+    }
+
+    fn broadcast(&self, subject: &str, message: &str) {
+        for channel in &self.channels {
+            if let Err(err) = channel.send(subject, message) {
+                warn!("failed to notify the {} channel: {}", channel.name(), err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn webhook_url_is_parsed() {
+        let notifier = WebhookNotifier::new("http://hooks.internal/lo-migrate").unwrap();
+        assert_eq!(notifier.addr, "hooks.internal:80");
+        assert_eq!(notifier.path, "/lo-migrate");
+
+        let notifier = WebhookNotifier::new("hooks.internal:8080").unwrap();
+        assert_eq!(notifier.addr, "hooks.internal:8080");
+        assert_eq!(notifier.path, "/");
+
+        assert!(WebhookNotifier::new("https://hooks.internal/x").is_err());
+        assert!(WebhookNotifier::new("http:///x").is_err());
+    }
+
+    #[test]
+    fn payload_is_valid_json() {
+        use migrate::MigrationReport;
+        use std::time::Duration;
+
+        let report = MigrationReport {
+            observed: 10,
+            received: 9,
+            zero_byte: 1,
+            verified: 0,
+            stored: 8,
+            committed: 8,
+            committed_bytes: 4096,
+            failed: 2,
+            errors: vec![("ChecksumMismatch", 2)],
+            runtime: Duration::from_secs(61),
+        };
+        let json: ::serde_json::Value =
+            ::serde_json::from_str(&payload(RunStatus::CompletedWithFailures, &report)).unwrap();
+        assert_eq!(json["status"], "completed_with_failures");
+        assert_eq!(json["committed"], 8);
+        assert_eq!(json["runtime_seconds"], 61);
+        assert_eq!(json["errors"]["ChecksumMismatch"], 2);
+    }
+
+    #[test]
+    fn error_messages_are_escaped() {
+        assert_eq!(json_escape("a \"b\"\nc\\"), "a \\\"b\\\"\\nc\\\\");
+    }
+}
@@ -0,0 +1,1050 @@
+//! Abstraction over the bucket the storers upload into.
+//!
+//! The storers only talk to the bucket through the [`ObjectStore`]
+//! trait: [`S3ObjectStore`] wraps a rusoto client for production, and
+//! [`MemoryObjectStore`] keeps everything in a `HashMap` so the upload
+//! logic (multipart chunking, abort handling, ETag validation) can be
+//! unit-tested without a running fake-s3 server. The pipeline obtains
+//! its per-thread stores through a [`StorageBackend`] factory, the
+//! seam an alternative client stack plugs into.
+//!
+//! Bucket maintenance that is inherently S3-specific — listing and
+//! aborting stale multipart uploads — stays outside the trait, see
+//! [`abort_stale_uploads()`].
+//!
+//! [`ObjectStore`]: trait.ObjectStore.html
+//! [`S3ObjectStore`]: struct.S3ObjectStore.html
+//! [`MemoryObjectStore`]: struct.MemoryObjectStore.html
+//! [`StorageBackend`]: trait.StorageBackend.html
+//! [`abort_stale_uploads()`]: ../thread/fn.abort_stale_uploads.html
+
+use chrono::{DateTime, Utc};
+use error::{ErrorKind, Result};
+use futures::{Async, Poll, Stream};
+use hex;
+use md5;
+use rusoto_core::ByteStream;
+use rusoto_core::request::BufferedHttpResponse;
+use rusoto_s3::{AbortMultipartUploadError, AbortMultipartUploadRequest,
+                CompleteMultipartUploadError, CompleteMultipartUploadRequest,
+                CompletedMultipartUpload, CompletedPart, CreateMultipartUploadError,
+                CreateMultipartUploadRequest, HeadObjectError, HeadObjectRequest,
+                PutObjectError, PutObjectRequest, S3, UploadPartError, UploadPartRequest};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use tempfile;
+
+/// Headers attached to an uploaded object.
+#[derive(Clone, Debug, Default)]
+pub struct UploadMeta {
+    pub content_type: Option<String>,
+    pub content_disposition: Option<String>,
+    pub cache_control: Option<String>,
+    pub expires: Option<String>,
+    /// base64 of the raw sha256 of the object data, for stores that
+    /// verify uploads server-side against an `x-amz-checksum-sha256`
+    /// header (single-request uploads only; multipart parts carry
+    /// their own checksum, see [`upload_part`])
+    ///
+    /// [`upload_part`]: trait.ObjectStore.html#tymethod.upload_part
+    pub checksum_sha256: Option<String>,
+}
+
+/// Handle of a successfully uploaded multipart part, echoed back on
+/// completion.
+#[derive(Clone, Debug)]
+pub struct Part {
+    pub part_number: i64,
+    pub e_tag: Option<String>,
+}
+
+/// What the store reported about a finished upload.
+#[derive(Clone, Debug, Default)]
+pub struct UploadOutcome {
+    /// ETag of the final object, if the store reports one
+    pub e_tag: Option<String>,
+    /// version id assigned by a versioning-enabled bucket
+    pub version_id: Option<String>,
+}
+
+/// A bucket objects can be uploaded into.
+///
+/// Implementations are scoped to one bucket; multipart uploads follow
+/// the S3 model of create / upload parts / complete-or-abort. Errors
+/// the caller may retry (5xx, timeouts, throttling) are reported as
+/// [`ErrorKind::S3Transient`], everything fatal (denied access,
+/// missing bucket, malformed requests) as [`ErrorKind::S3`].
+///
+/// [`ErrorKind::S3`]: ../error/enum.ErrorKind.html
+/// [`ErrorKind::S3Transient`]: ../error/enum.ErrorKind.html
+pub trait ObjectStore: Send {
+    /// Whether an object with this key already exists.
+    fn exists(&self, key: &str) -> Result<bool>;
+
+    /// Size in bytes of the object stored under `key`, or `None` if
+    /// there is none.
+    fn stat(&self, key: &str) -> Result<Option<u64>>;
+
+    /// Upload an object in one request.
+    fn put(&self, key: &str, data: &[u8], meta: &UploadMeta) -> Result<UploadOutcome>;
+
+    /// Upload an object in one request out of a buffer shared with the
+    /// caller.
+    ///
+    /// The default implementation borrows the buffer and delegates to
+    /// [`put()`]; backends whose transport takes ownership of the body
+    /// override it so the object data is not copied into the request.
+    ///
+    /// [`put()`]: #tymethod.put
+    fn put_shared(&self,
+                  key: &str,
+                  data: &Arc<Vec<u8>>,
+                  meta: &UploadMeta)
+                  -> Result<UploadOutcome> {
+        self.put(key, &data[..], meta)
+    }
+
+    /// Start a multipart upload, returning its upload id.
+    fn create_multipart(&self, key: &str, meta: &UploadMeta) -> Result<String>;
+
+    /// Upload one part of a multipart upload. Part numbers start at 1.
+    ///
+    /// `checksum_sha256` is the base64 raw sha256 of `data`, if the
+    /// caller wants the store to verify the part server-side; backends
+    /// unable to express it fall back to the strongest verification
+    /// their protocol offers.
+    fn upload_part(&self,
+                   key: &str,
+                   upload_id: &str,
+                   part_number: i64,
+                   data: &[u8],
+                   checksum_sha256: Option<&str>)
+                   -> Result<Part>;
+
+    /// [`upload_part()`] out of a buffer shared with the caller; same
+    /// default and override rationale as [`put_shared()`].
+    ///
+    /// [`upload_part()`]: #tymethod.upload_part
+    /// [`put_shared()`]: #method.put_shared
+    fn upload_part_shared(&self,
+                          key: &str,
+                          upload_id: &str,
+                          part_number: i64,
+                          data: &Arc<Vec<u8>>,
+                          checksum_sha256: Option<&str>)
+                          -> Result<Part> {
+        self.upload_part(key, upload_id, part_number, &data[..], checksum_sha256)
+    }
+
+    /// Assemble the uploaded parts into the final object, returning the
+    /// ETag of the result and, on a versioning-enabled bucket, the
+    /// version id.
+    fn complete_multipart(&self,
+                          key: &str,
+                          upload_id: &str,
+                          parts: Vec<Part>)
+                          -> Result<UploadOutcome>;
+
+    /// Abort a multipart upload, discarding the uploaded parts.
+    fn abort_multipart(&self, key: &str, upload_id: &str) -> Result<()>;
+}
+
+/// Produces the per-thread bucket handles the pipeline uploads
+/// through.
+///
+/// The pipeline builds one [`ObjectStore`] per storer and verifier
+/// thread; routing that through a factory is the seam a different
+/// client stack plugs into — the maintained AWS SDK once the pinned
+/// toolchain allows it, or a plain-HTTP store — while the rusoto path
+/// (see [`RusotoBackend`]) stays the default during the transition.
+///
+/// [`ObjectStore`]: trait.ObjectStore.html
+/// [`RusotoBackend`]: ../migrate/struct.RusotoBackend.html
+pub trait StorageBackend: Send + Sync {
+    /// A store handle owned by one worker thread.
+    fn store(&self) -> Result<Box<ObjectStore>>;
+}
+
+/// Standard base64 without padding stripped or line breaks, as the
+/// `Content-MD5` and `x-amz-checksum-*` headers expect it. Hand-rolled
+/// for the handful of encode calls so no codec crate is pulled in.
+pub fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let group = (u32::from(chunk[0]) << 16) |
+                    (chunk.get(1).map(|&b| u32::from(b) << 8).unwrap_or(0)) |
+                    (chunk.get(2).map(|&b| u32::from(b)).unwrap_or(0));
+        encoded.push(ALPHABET[(group >> 18) as usize & 0x3f] as char);
+        encoded.push(ALPHABET[(group >> 12) as usize & 0x3f] as char);
+        encoded.push(if chunk.len() > 1 {
+                         ALPHABET[(group >> 6) as usize & 0x3f] as char
+                     } else {
+                         '='
+                     });
+        encoded.push(if chunk.len() > 2 {
+                         ALPHABET[group as usize & 0x3f] as char
+                     } else {
+                         '='
+                     });
+    }
+    encoded
+}
+
+/// base64 raw sha256 of `data`, the value `x-amz-checksum-sha256`
+/// carries.
+pub fn sha256_checksum(data: &[u8]) -> String {
+    base64(&Sha256::digest(data))
+}
+
+/// Whether an HTTP status hints at a condition retrying can fix: a
+/// server-side failure (5xx, e.g. a restarting store), a timed out
+/// request or throttling.
+pub(crate) fn transient_status(status: u16) -> bool {
+    status >= 500 || status == 429 || status == 408
+}
+
+/// Classify an error response S3 answered with.
+///
+/// `RequestTimeTooSkewed` gets special treatment: requests are signed
+/// with the local time, so a skewed VM clock makes *every* upload fail
+/// with what otherwise looks like an inscrutable signature error. The
+/// skew is measured against the `ServerTime` the store reports and the
+/// error tells the operator to sync the clock; rusoto offers no way to
+/// adjust the signing time, so aborting with an actionable message is
+/// the best we can do.
+fn classify_response(operation: &str, response: &BufferedHttpResponse) -> ErrorKind {
+    let body = String::from_utf8_lossy(&response.body);
+    if let Some(skew) = clock_skew_message(&body) {
+        return ErrorKind::S3(format!("{} failed: {}", operation, skew));
+    }
+    let status = response.status.as_u16();
+    let msg = format!("{} failed: HTTP {}: {}", operation, status, body.trim());
+    if transient_status(status) {
+        ErrorKind::S3Transient(msg)
+    } else {
+        ErrorKind::S3(msg)
+    }
+}
+
+/// Actionable message for a `RequestTimeTooSkewed` error body, naming
+/// the measured skew if the store reported its time.
+fn clock_skew_message(body: &str) -> Option<String> {
+    if !body.contains("RequestTimeTooSkewed") {
+        return None;
+    }
+    let skew = xml_field(body, "ServerTime")
+        .and_then(|time| DateTime::parse_from_rfc3339(&time).ok())
+        .map(|server| server.with_timezone(&Utc).signed_duration_since(Utc::now()));
+    let detail = match skew {
+        Some(skew) => {
+            let seconds = skew.num_seconds();
+            format!("the local clock is about {} seconds {} the server's",
+                    seconds.abs(),
+                    if seconds > 0 { "behind" } else { "ahead of" })
+        }
+        None => "the local clock is too far off from the server's".to_string(),
+    };
+    Some(format!("request rejected as RequestTimeTooSkewed; {}. Requests are \
+                  signed with the local time, so every request will fail until \
+                  the clock is synced — check NTP on this machine",
+                 detail))
+}
+
+/// Content of the first `<name>...</name>` element in an XML body.
+pub(crate) fn xml_field(body: &str, name: &str) -> Option<String> {
+    let open = format!("<{}>", name);
+    let close = format!("</{}>", name);
+    let start = body.find(&open)? + open.len();
+    let end = body[start..].find(&close)? + start;
+    Some(body[start..end].to_string())
+}
+
+/// Classify a rusoto error: a failed dispatch (connection refused or
+/// timed out while the store restarts) is transient, an error response
+/// is classified by [`classify_response`]; credential and validation
+/// errors stay fatal.
+///
+/// [`classify_response`]: fn.classify_response.html
+macro_rules! classify_err {
+    ($ty:ident, $operation:expr, $err:expr) => {
+        match *$err {
+            $ty::HttpDispatch(_) => {
+                ErrorKind::S3Transient(format!("{} failed: {}", $operation, $err))
+            }
+            $ty::Unknown(ref response) => classify_response($operation, response),
+            _ => ErrorKind::S3(format!("{} failed: {}", $operation, $err)),
+        }
+    };
+}
+
+/// Chunk size of a [`SharedBody`] request body.
+///
+/// [`SharedBody`]: struct.SharedBody.html
+const BODY_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Streams a buffer shared with the caller into a request body.
+///
+/// rusoto's `ByteStream` takes ownership of its bytes, so handing a
+/// whole buffered object over copies it and doubles the memory held
+/// per in-flight upload. Streaming bounded chunks out of the shared
+/// buffer caps the extra memory at [`BODY_CHUNK_SIZE`] per request
+/// instead.
+///
+/// [`BODY_CHUNK_SIZE`]: constant.BODY_CHUNK_SIZE.html
+struct SharedBody {
+    data: Arc<Vec<u8>>,
+    offset: usize,
+}
+
+impl Stream for SharedBody {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Vec<u8>>, io::Error> {
+        if self.offset >= self.data.len() {
+            return Ok(Async::Ready(None));
+        }
+        let end = (self.offset + BODY_CHUNK_SIZE).min(self.data.len());
+        let chunk = self.data[self.offset..end].to_vec();
+        self.offset = end;
+        Ok(Async::Ready(Some(chunk)))
+    }
+}
+
+/// Request body streaming chunks out of `data`.
+fn shared_body(data: &Arc<Vec<u8>>) -> ByteStream {
+    ByteStream::new(SharedBody {
+                        data: data.clone(),
+                        offset: 0,
+                    })
+}
+
+/// [`ObjectStore`] uploading to one S3 bucket through a rusoto client.
+///
+/// [`ObjectStore`]: trait.ObjectStore.html
+pub struct S3ObjectStore<S> {
+    client: S,
+    bucket: String,
+}
+
+impl<S: S3 + Send> S3ObjectStore<S> {
+    pub fn new(client: S, bucket: &str) -> Self {
+        S3ObjectStore {
+            client: client,
+            bucket: bucket.to_string(),
+        }
+    }
+
+    /// Shared plumbing of [`put()`] and [`put_shared()`]: everything
+    /// but the body is identical.
+    ///
+    /// `content_length` must be given for streamed bodies, rusoto only
+    /// infers it for bodies built from a buffer.
+    ///
+    /// [`put()`]: trait.ObjectStore.html#tymethod.put
+    /// [`put_shared()`]: trait.ObjectStore.html#method.put_shared
+    fn put_body(&self,
+                key: &str,
+                body: ByteStream,
+                content_length: Option<i64>,
+                content_md5: Option<String>,
+                meta: &UploadMeta)
+                -> Result<UploadOutcome> {
+        let request = PutObjectRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            body: Some(body),
+            content_length: content_length,
+            content_type: meta.content_type.clone(),
+            content_disposition: meta.content_disposition.clone(),
+            cache_control: meta.cache_control.clone(),
+            expires: meta.expires.clone(),
+            content_md5: content_md5,
+            ..Default::default()
+        };
+        let output = self.client
+            .put_object(request)
+            .sync()
+            .map_err(|e| classify_err!(PutObjectError, "PutObject", &e))?;
+        Ok(UploadOutcome {
+            e_tag: output.e_tag,
+            version_id: output.version_id,
+        })
+    }
+
+    /// Shared plumbing of [`upload_part()`] and [`upload_part_shared()`],
+    /// cf. [`put_body()`].
+    ///
+    /// [`upload_part()`]: trait.ObjectStore.html#tymethod.upload_part
+    /// [`upload_part_shared()`]: trait.ObjectStore.html#method.upload_part_shared
+    /// [`put_body()`]: #method.put_body
+    fn upload_part_body(&self,
+                        key: &str,
+                        upload_id: &str,
+                        part_number: i64,
+                        body: ByteStream,
+                        content_length: Option<i64>,
+                        content_md5: Option<String>)
+                        -> Result<Part> {
+        let request = UploadPartRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            upload_id: upload_id.to_string(),
+            part_number: part_number,
+            body: Some(body),
+            content_length: content_length,
+            content_md5: content_md5,
+            ..Default::default()
+        };
+        let output = self.client
+            .upload_part(request)
+            .sync()
+            .map_err(|e| {
+                         classify_err!(UploadPartError,
+                                       &format!("UploadPart {}", part_number),
+                                       &e)
+                     })?;
+        Ok(Part {
+            part_number: part_number,
+            e_tag: output.e_tag,
+        })
+    }
+}
+
+impl<S: S3 + Send> ObjectStore for S3ObjectStore<S> {
+    fn exists(&self, key: &str) -> Result<bool> {
+        let request = HeadObjectRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            ..Default::default()
+        };
+        match self.client.head_object(request).sync() {
+            Ok(_) => Ok(true),
+            // rusoto reports a missing key as an unknown error, so any
+            // failure is treated as "not there" and the object is
+            // simply uploaded again
+            Err(_) => Ok(false),
+        }
+    }
+
+    fn stat(&self, key: &str) -> Result<Option<u64>> {
+        let request = HeadObjectRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            ..Default::default()
+        };
+        match self.client.head_object(request).sync() {
+            Ok(output) => Ok(Some(output.content_length.unwrap_or(0) as u64)),
+            Err(HeadObjectError::NoSuchKey(_)) => Ok(None),
+            // a missing key surfaces as a plain 404 with most stores
+            Err(HeadObjectError::Unknown(ref response)) if response.status.as_u16() == 404 => {
+                Ok(None)
+            }
+            Err(ref err) => Err(classify_err!(HeadObjectError, "HeadObject", err).into()),
+        }
+    }
+
+    fn put(&self, key: &str, data: &[u8], meta: &UploadMeta) -> Result<UploadOutcome> {
+        // the pinned rusoto predates the x-amz-checksum-* headers;
+        // Content-MD5 is the server-side verification it can express
+        let content_md5 = meta.checksum_sha256
+            .as_ref()
+            .map(|_| base64(&md5::compute(data).0));
+        self.put_body(key, data.to_vec().into(), None, content_md5, meta)
+    }
+
+    fn put_shared(&self,
+                  key: &str,
+                  data: &Arc<Vec<u8>>,
+                  meta: &UploadMeta)
+                  -> Result<UploadOutcome> {
+        let content_md5 = meta.checksum_sha256
+            .as_ref()
+            .map(|_| base64(&md5::compute(&data[..]).0));
+        self.put_body(key,
+                      shared_body(data),
+                      Some(data.len() as i64),
+                      content_md5,
+                      meta)
+    }
+
+    fn create_multipart(&self, key: &str, meta: &UploadMeta) -> Result<String> {
+        let request = CreateMultipartUploadRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            content_type: meta.content_type.clone(),
+            content_disposition: meta.content_disposition.clone(),
+            cache_control: meta.cache_control.clone(),
+            expires: meta.expires.clone(),
+            ..Default::default()
+        };
+        let upload = self.client
+            .create_multipart_upload(request)
+            .sync()
+            .map_err(|e| classify_err!(CreateMultipartUploadError, "CreateMultipartUpload", &e))?;
+        upload
+            .upload_id
+            .ok_or_else(|| ErrorKind::S3("no upload id returned".to_string()).into())
+    }
+
+    fn upload_part(&self,
+                   key: &str,
+                   upload_id: &str,
+                   part_number: i64,
+                   data: &[u8],
+                   checksum_sha256: Option<&str>)
+                   -> Result<Part> {
+        // see put(): Content-MD5 stands in for x-amz-checksum-sha256
+        let content_md5 = checksum_sha256.map(|_| base64(&md5::compute(data).0));
+        self.upload_part_body(key,
+                              upload_id,
+                              part_number,
+                              data.to_vec().into(),
+                              None,
+                              content_md5)
+    }
+
+    fn upload_part_shared(&self,
+                          key: &str,
+                          upload_id: &str,
+                          part_number: i64,
+                          data: &Arc<Vec<u8>>,
+                          checksum_sha256: Option<&str>)
+                          -> Result<Part> {
+        let content_md5 = checksum_sha256.map(|_| base64(&md5::compute(&data[..]).0));
+        self.upload_part_body(key,
+                              upload_id,
+                              part_number,
+                              shared_body(data),
+                              Some(data.len() as i64),
+                              content_md5)
+    }
+
+    fn complete_multipart(&self,
+                          key: &str,
+                          upload_id: &str,
+                          parts: Vec<Part>)
+                          -> Result<UploadOutcome> {
+        let parts = parts
+            .into_iter()
+            .map(|part| {
+                     CompletedPart {
+                         e_tag: part.e_tag,
+                         part_number: Some(part.part_number),
+                     }
+                 })
+            .collect();
+        let request = CompleteMultipartUploadRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            upload_id: upload_id.to_string(),
+            multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+            ..Default::default()
+        };
+        let output = self.client
+            .complete_multipart_upload(request)
+            .sync()
+            .map_err(|e| {
+                         classify_err!(CompleteMultipartUploadError,
+                                       "CompleteMultipartUpload",
+                                       &e)
+                     })?;
+        Ok(UploadOutcome {
+            e_tag: output.e_tag,
+            version_id: output.version_id,
+        })
+    }
+
+    fn abort_multipart(&self, key: &str, upload_id: &str) -> Result<()> {
+        let request = AbortMultipartUploadRequest {
+            bucket: self.bucket.clone(),
+            key: key.to_string(),
+            upload_id: upload_id.to_string(),
+            ..Default::default()
+        };
+        self.client
+            .abort_multipart_upload(request)
+            .sync()
+            .map_err(|e| {
+                         classify_err!(AbortMultipartUploadError, "AbortMultipartUpload", &e)
+                     })?;
+        Ok(())
+    }
+}
+
+/// In-memory [`ObjectStore`] for tests.
+///
+/// Objects and pending multipart uploads live in `HashMap`s behind a
+/// mutex. ETags follow the S3 rules — MD5 of the data for plain
+/// uploads, composite MD5-of-MD5s for multipart uploads — so the
+/// storers' checksum validation is exercised for real. A part number
+/// can be marked as failing to test the abort path.
+///
+/// [`ObjectStore`]: trait.ObjectStore.html
+#[derive(Debug, Default)]
+pub struct MemoryObjectStore {
+    inner: Mutex<MemoryInner>,
+    failing_part: Option<i64>,
+    versioning: bool,
+}
+
+#[derive(Debug, Default)]
+struct MemoryInner {
+    objects: HashMap<String, StoredObject>,
+    /// upload id -> pending upload
+    uploads: HashMap<String, PendingUpload>,
+    next_upload_id: u64,
+    next_version_id: u64,
+}
+
+/// An object that made it into the fake bucket.
+#[derive(Clone, Debug)]
+pub struct StoredObject {
+    pub data: Vec<u8>,
+    pub meta: UploadMeta,
+}
+
+#[derive(Debug)]
+struct PendingUpload {
+    key: String,
+    meta: UploadMeta,
+    /// part number -> data
+    parts: HashMap<i64, Vec<u8>>,
+}
+
+impl MemoryObjectStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fail every upload of the part with this number, e.g. to test
+    /// that a failed multipart upload is aborted.
+    pub fn with_failing_part(mut self, part_number: i64) -> Self {
+        self.failing_part = Some(part_number);
+        self
+    }
+
+    /// Behave like a versioning-enabled bucket: every finished upload
+    /// is assigned a version id, as S3 reports it in `x-amz-version-id`.
+    pub fn with_versioning(mut self) -> Self {
+        self.versioning = true;
+        self
+    }
+
+    /// The object stored under `key`, if any.
+    pub fn object(&self, key: &str) -> Option<StoredObject> {
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.objects.get(key).cloned()
+    }
+
+    /// Number of objects in the fake bucket.
+    pub fn object_count(&self) -> usize {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner()).objects.len()
+    }
+
+    /// Number of multipart uploads that were started but neither
+    /// completed nor aborted — a non-zero count after a failed upload
+    /// means the abort handling is broken.
+    pub fn pending_uploads(&self) -> usize {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner()).uploads.len()
+    }
+}
+
+/// Next version id of the fake bucket, or `None` when versioning is
+/// off, as a real bucket omits the `x-amz-version-id` header.
+fn next_version_id(inner: &mut MemoryInner, versioning: bool) -> Option<String> {
+    if !versioning {
+        return None;
+    }
+    inner.next_version_id += 1;
+    Some(format!("version-{}", inner.next_version_id))
+}
+
+/// Store-side checksum validation for the local backends: reject an
+/// upload whose claimed sha256 does not match the data, as a checksum
+/// supporting S3 store answers with `BadDigest`.
+pub(crate) fn verify_checksum(claimed: Option<&str>, data: &[u8]) -> Result<()> {
+    match claimed {
+        Some(claimed) if claimed != sha256_checksum(data) => {
+            Err(ErrorKind::S3(format!("BadDigest: claimed checksum {} does not match the data",
+                                      claimed))
+                        .into())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Part bookkeeping for the backends without a part protocol (the
+/// plain HTTP PUT and presigned-URL stores): parts are staged front to
+/// back into an anonymous temporary file and the assembled object goes
+/// out in one request on completion. The caller computes the S3-style
+/// composite ETag from the recorded part MD5s.
+#[derive(Default)]
+pub(crate) struct StagedUploads {
+    /// upload id -> pending multipart upload
+    uploads: HashMap<String, StagedUpload>,
+    next_upload_id: u64,
+}
+
+/// One multipart upload staged by [`StagedUploads`].
+///
+/// [`StagedUploads`]: struct.StagedUploads.html
+pub(crate) struct StagedUpload {
+    pub(crate) meta: UploadMeta,
+    /// parts staged so far, concatenated
+    pub(crate) file: File,
+    pub(crate) size: u64,
+    /// part number -> MD5 of the part, for the composite ETag
+    pub(crate) md5s: HashMap<i64, [u8; 16]>,
+    key: String,
+    next_part: i64,
+}
+
+impl StagedUploads {
+    pub(crate) fn create(&mut self, key: &str, meta: &UploadMeta) -> Result<String> {
+        let file = tempfile::tempfile()?;
+        self.next_upload_id += 1;
+        let upload_id = format!("upload-{}", self.next_upload_id);
+        self.uploads.insert(upload_id.clone(),
+                            StagedUpload {
+                                meta: meta.clone(),
+                                file: file,
+                                size: 0,
+                                md5s: HashMap::new(),
+                                key: key.to_string(),
+                                next_part: 1,
+                            });
+        Ok(upload_id)
+    }
+
+    pub(crate) fn write_part(&mut self,
+                             key: &str,
+                             upload_id: &str,
+                             part_number: i64,
+                             data: &[u8])
+                             -> Result<Part> {
+        let upload = self.uploads
+            .get_mut(upload_id)
+            .ok_or_else(|| ErrorKind::S3(format!("unknown upload id {}", upload_id)))?;
+        if upload.key != key {
+            return Err(ErrorKind::S3(format!("upload {} belongs to key {}",
+                                             upload_id,
+                                             upload.key))
+                               .into());
+        }
+        if part_number != upload.next_part {
+            return Err(ErrorKind::S3(format!("part {} out of order, expected part {}; \
+                                              parts are staged front to back",
+                                             part_number,
+                                             upload.next_part))
+                               .into());
+        }
+        upload.file.write_all(data)?;
+        upload.size += data.len() as u64;
+        let digest = md5::compute(data).0;
+        upload.md5s.insert(part_number, digest);
+        upload.next_part += 1;
+        Ok(Part {
+            part_number: part_number,
+            e_tag: Some(hex::encode(&digest)),
+        })
+    }
+
+    /// Hand the finished upload to the caller for sending.
+    pub(crate) fn take(&mut self, upload_id: &str) -> Result<StagedUpload> {
+        self.uploads
+            .remove(upload_id)
+            .ok_or_else(|| ErrorKind::S3(format!("unknown upload id {}", upload_id)).into())
+    }
+
+    /// Drop the staged file; nothing was sent yet, so that is the
+    /// whole abort.
+    pub(crate) fn abort(&mut self, upload_id: &str) -> Result<()> {
+        self.take(upload_id).map(|_| ())
+    }
+}
+
+impl ObjectStore for MemoryObjectStore {
+    fn exists(&self, key: &str) -> Result<bool> {
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(inner.objects.contains_key(key))
+    }
+
+    fn stat(&self, key: &str) -> Result<Option<u64>> {
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(inner.objects.get(key).map(|object| object.data.len() as u64))
+    }
+
+    fn put(&self, key: &str, data: &[u8], meta: &UploadMeta) -> Result<UploadOutcome> {
+        verify_checksum(meta.checksum_sha256.as_ref().map(String::as_str), data)?;
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.objects.insert(key.to_string(),
+                             StoredObject {
+                                 data: data.to_vec(),
+                                 meta: meta.clone(),
+                             });
+        Ok(UploadOutcome {
+            e_tag: Some(hex::encode(&md5::compute(data).0)),
+            version_id: next_version_id(&mut inner, self.versioning),
+        })
+    }
+
+    fn create_multipart(&self, key: &str, meta: &UploadMeta) -> Result<String> {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.next_upload_id += 1;
+        let upload_id = format!("upload-{}", inner.next_upload_id);
+        inner.uploads.insert(upload_id.clone(),
+                             PendingUpload {
+                                 key: key.to_string(),
+                                 meta: meta.clone(),
+                                 parts: HashMap::new(),
+                             });
+        Ok(upload_id)
+    }
+
+    fn upload_part(&self,
+                   key: &str,
+                   upload_id: &str,
+                   part_number: i64,
+                   data: &[u8],
+                   checksum_sha256: Option<&str>)
+                   -> Result<Part> {
+        if self.failing_part == Some(part_number) {
+            return Err(ErrorKind::S3(format!("injected failure of part {}", part_number))
+                           .into());
+        }
+        verify_checksum(checksum_sha256, data)?;
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let upload = inner
+            .uploads
+            .get_mut(upload_id)
+            .ok_or_else(|| ErrorKind::S3(format!("unknown upload id {}", upload_id)))?;
+        if upload.key != key {
+            return Err(ErrorKind::S3(format!("upload {} belongs to key {}",
+                                             upload_id,
+                                             upload.key))
+                               .into());
+        }
+        upload.parts.insert(part_number, data.to_vec());
+        Ok(Part {
+            part_number: part_number,
+            e_tag: Some(hex::encode(&md5::compute(data).0)),
+        })
+    }
+
+    fn complete_multipart(&self,
+                          key: &str,
+                          upload_id: &str,
+                          parts: Vec<Part>)
+                          -> Result<UploadOutcome> {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let upload = inner
+            .uploads
+            .remove(upload_id)
+            .ok_or_else(|| ErrorKind::S3(format!("unknown upload id {}", upload_id)))?;
+
+        let mut data = Vec::new();
+        let mut digests = Vec::new();
+        for part in parts {
+            let bytes = upload
+                .parts
+                .get(&part.part_number)
+                .ok_or_else(|| {
+                                ErrorKind::S3(format!("part {} was never uploaded",
+                                                      part.part_number))
+                            })?;
+            data.extend_from_slice(bytes);
+            digests.extend_from_slice(&md5::compute(bytes).0);
+        }
+        let e_tag = format!("{}-{}",
+                            hex::encode(&md5::compute(&digests).0),
+                            upload.parts.len());
+
+        inner.objects.insert(key.to_string(),
+                             StoredObject {
+                                 data: data,
+                                 meta: upload.meta,
+                             });
+        Ok(UploadOutcome {
+            e_tag: Some(e_tag),
+            version_id: next_version_id(&mut inner, self.versioning),
+        })
+    }
+
+    fn abort_multipart(&self, _key: &str, upload_id: &str) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner
+            .uploads
+            .remove(upload_id)
+            .ok_or_else(|| ErrorKind::S3(format!("unknown upload id {}", upload_id)))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn put_and_exists() {
+        let store = MemoryObjectStore::new();
+        assert!(!store.exists("key").unwrap());
+
+        let meta = UploadMeta {
+            content_type: Some("text/plain".to_string()),
+            ..Default::default()
+        };
+        store.put("key", b"data", &meta).unwrap();
+        assert!(store.exists("key").unwrap());
+
+        let object = store.object("key").unwrap();
+        assert_eq!(&object.data[..], b"data");
+        assert_eq!(object.meta.content_type.as_ref().unwrap(), "text/plain");
+    }
+
+    #[test]
+    fn multipart_upload_assembles_the_parts() {
+        let store = MemoryObjectStore::new();
+        let upload_id = store.create_multipart("key", &UploadMeta::default()).unwrap();
+
+        let first = store.upload_part("key", &upload_id, 1, b"hello ", None).unwrap();
+        let second = store.upload_part("key", &upload_id, 2, b"world", None).unwrap();
+        let outcome = store
+            .complete_multipart("key", &upload_id, vec![first, second])
+            .unwrap();
+
+        assert!(outcome.e_tag.unwrap().ends_with("-2"));
+        assert!(outcome.version_id.is_none());
+        assert_eq!(&store.object("key").unwrap().data[..], b"hello world");
+        assert_eq!(store.pending_uploads(), 0);
+    }
+
+    #[test]
+    fn abort_discards_the_upload() {
+        let store = MemoryObjectStore::new();
+        let upload_id = store.create_multipart("key", &UploadMeta::default()).unwrap();
+        store.upload_part("key", &upload_id, 1, b"data", None).unwrap();
+
+        store.abort_multipart("key", &upload_id).unwrap();
+        assert_eq!(store.pending_uploads(), 0);
+        assert!(!store.exists("key").unwrap());
+        assert!(store.upload_part("key", &upload_id, 2, b"more", None).is_err());
+    }
+
+    #[test]
+    fn shared_bodies_reach_the_store() {
+        let store = MemoryObjectStore::new();
+        let data = Arc::new(b"shared body".to_vec());
+        store.put_shared("key", &data, &UploadMeta::default()).unwrap();
+        assert_eq!(&store.object("key").unwrap().data[..], b"shared body");
+
+        let upload_id = store.create_multipart("parts", &UploadMeta::default()).unwrap();
+        let part = store
+            .upload_part_shared("parts", &upload_id, 1, &data, None)
+            .unwrap();
+        store.complete_multipart("parts", &upload_id, vec![part]).unwrap();
+        assert_eq!(&store.object("parts").unwrap().data[..], b"shared body");
+
+        // the caller keeps its reference throughout
+        assert_eq!(Arc::strong_count(&data), 1);
+    }
+
+    #[test]
+    fn shared_body_streams_in_bounded_chunks() {
+        let data = Arc::new(vec![7u8; BODY_CHUNK_SIZE + 10]);
+        let chunks = SharedBody {
+                data: data.clone(),
+                offset: 0,
+            }
+            .wait()
+            .collect::<::std::result::Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].len(), BODY_CHUNK_SIZE);
+        assert_eq!(chunks[1].len(), 10);
+        assert!(chunks.iter().all(|chunk| chunk[..].iter().all(|&byte| byte == 7)));
+    }
+
+    #[test]
+    fn base64_matches_the_reference_vectors() {
+        // RFC 4648 test vectors
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn claimed_checksums_are_validated() {
+        let store = MemoryObjectStore::new();
+        let good = sha256_checksum(b"data");
+        let meta = UploadMeta {
+            checksum_sha256: Some(good.clone()),
+            ..Default::default()
+        };
+        store.put("key", b"data", &meta).unwrap();
+        assert!(store.put("key", b"tampered", &meta).is_err());
+
+        let upload_id = store.create_multipart("key", &UploadMeta::default()).unwrap();
+        assert!(store.upload_part("key", &upload_id, 1, b"data", Some(&good)).is_ok());
+        assert!(store.upload_part("key", &upload_id, 2, b"tampered", Some(&good)).is_err());
+    }
+
+    #[test]
+    fn versioning_assigns_distinct_version_ids() {
+        let store = MemoryObjectStore::new().with_versioning();
+        let first = store.put("key", b"data", &UploadMeta::default()).unwrap();
+        let second = store.put("key", b"data", &UploadMeta::default()).unwrap();
+        assert!(first.version_id.is_some());
+        assert_ne!(first.version_id, second.version_id);
+
+        let upload_id = store.create_multipart("other", &UploadMeta::default()).unwrap();
+        let part = store.upload_part("other", &upload_id, 1, b"data", None).unwrap();
+        let outcome = store.complete_multipart("other", &upload_id, vec![part]).unwrap();
+        assert!(outcome.version_id.is_some());
+
+        // unversioned buckets keep omitting the header
+        let plain = MemoryObjectStore::new();
+        assert!(plain.put("key", b"data", &UploadMeta::default())
+                    .unwrap()
+                    .version_id
+                    .is_none());
+    }
+
+    #[test]
+    fn clock_skew_is_reported_with_the_measured_offset() {
+        let body = format!("<Error><Code>RequestTimeTooSkewed</Code>\
+                            <ServerTime>{}</ServerTime></Error>",
+                           (Utc::now() - ::chrono::Duration::seconds(900)).to_rfc3339());
+        let msg = clock_skew_message(&body).unwrap();
+        assert!(msg.contains("RequestTimeTooSkewed"), "{}", msg);
+        assert!(msg.contains("ahead of the server's"), "{}", msg);
+        assert!(msg.contains("NTP"), "{}", msg);
+
+        // no ServerTime: still actionable, just without the offset
+        let msg = clock_skew_message("<Code>RequestTimeTooSkewed</Code>").unwrap();
+        assert!(msg.contains("too far off"), "{}", msg);
+
+        assert!(clock_skew_message("<Code>AccessDenied</Code>").is_none());
+    }
+
+    #[test]
+    fn xml_fields_are_extracted() {
+        assert_eq!(xml_field("<a><Code>boom</Code></a>", "Code").unwrap(), "boom");
+        assert!(xml_field("<Code>boom</Code>", "ServerTime").is_none());
+    }
+
+    #[test]
+    fn injected_part_failure() {
+        let store = MemoryObjectStore::new().with_failing_part(2);
+        let upload_id = store.create_multipart("key", &UploadMeta::default()).unwrap();
+        assert!(store.upload_part("key", &upload_id, 1, b"ok", None).is_ok());
+        assert!(store.upload_part("key", &upload_id, 2, b"boom", None).is_err());
+    }
+}
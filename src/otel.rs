@@ -0,0 +1,303 @@
+//! OpenTelemetry trace export, behind the `otel` feature.
+//!
+//! Operators who already collect database and object-store telemetry
+//! in a tracing backend want the migration's load in the same place.
+//! The [`OtlpExporter`] turns the per-object timing samples the
+//! workers already report — receive, store and commit — into spans of
+//! one run-wide trace and ships them to an OTLP/HTTP collector
+//! (`/v1/traces`, JSON encoding), batched on a background thread so
+//! the worker hot loops only pay for a channel send.
+//!
+//! The exporter plugs in as a [`MetricsSink`] (combine it with other
+//! sinks through [`FanoutSink`]); call [`shutdown()`] after the run to
+//! flush the remaining spans and close the run span.
+//!
+//! Like the Pushgateway and webhook deliveries this speaks plain HTTP;
+//! collectors sit on the internal network.
+//!
+//! [`OtlpExporter`]: struct.OtlpExporter.html
+//! [`MetricsSink`]: ../metrics/trait.MetricsSink.html
+//! [`FanoutSink`]: ../metrics/struct.FanoutSink.html
+//! [`shutdown()`]: struct.OtlpExporter.html#method.shutdown
+
+use error::{ErrorKind, Result};
+use metrics::MetricsSink;
+use std::fmt::Write as FmtWrite;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Spans per OTLP request; also the flush granularity.
+const SPAN_BATCH: usize = 512;
+
+/// How long one export request may take before it is abandoned.
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// One finished span, as queued for the exporter thread.
+struct SpanRecord {
+    name: &'static str,
+    start_nanos: u64,
+    end_nanos: u64,
+}
+
+/// Exports one trace per run to an OTLP/HTTP collector.
+///
+/// See the [module documentation](index.html) for the overall picture.
+pub struct OtlpExporter {
+    tx: Mutex<Option<Sender<SpanRecord>>>,
+    exporter: Mutex<Option<JoinHandle<u64>>>,
+}
+
+impl OtlpExporter {
+    /// Exporter shipping to the collector at `url`, e.g.
+    /// `http://otel-collector.internal:4318`.
+    pub fn new(url: &str) -> Result<Self> {
+        let (addr, path) = parse_collector_url(url)?;
+        let (tx, rx) = channel();
+        let exporter = ::std::thread::Builder::new()
+            .name("otel_export".to_string())
+            .spawn(move || exporter_thread(&addr, &path, &rx))?;
+        Ok(OtlpExporter {
+               tx: Mutex::new(Some(tx)),
+               exporter: Mutex::new(Some(exporter)),
+           })
+    }
+
+    /// Flush the remaining spans, close the run span and stop the
+    /// exporter thread. Returns the number of spans exported.
+    pub fn shutdown(&self) -> Result<u64> {
+        // dropping the sender disconnects the channel; the exporter
+        // thread flushes and exits
+        self.tx.lock().unwrap_or_else(|e| e.into_inner()).take();
+        let exporter = self.exporter
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+        match exporter {
+            Some(exporter) => {
+                exporter
+                    .join()
+                    .map_err(|_| ErrorKind::WorkerPanicked("otel_export".to_string()).into())
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn record(&self, name: &'static str, duration_seconds: f64) {
+        let end_nanos = unix_nanos(SystemTime::now());
+        let record = SpanRecord {
+            name: name,
+            start_nanos: end_nanos.saturating_sub((duration_seconds * 1e9) as u64),
+            end_nanos: end_nanos,
+        };
+        if let Some(ref tx) = *self.tx.lock().unwrap_or_else(|e| e.into_inner()) {
+            let _ = tx.send(record);
+        }
+    }
+}
+
+impl MetricsSink for OtlpExporter {
+    fn counter(&self, _: &str, _: u64) {}
+
+    fn gauge(&self, _: &str, _: f64) {}
+
+    fn histogram(&self, name: &str, value: f64) {
+        // the per-object timing samples become the spans; everything
+        // else the collector gets from the metrics side
+        let span = match name {
+            "receive_seconds" => "receive",
+            "store_seconds" => "store",
+            "commit_seconds" => "commit",
+            _ => return,
+        };
+        self.record(span, value);
+    }
+}
+
+/// Batches spans from the channel and ships them until the channel
+/// disconnects; the run span covering the whole trace goes last.
+fn exporter_thread(addr: &str, path: &str, rx: &Receiver<SpanRecord>) -> u64 {
+    let run_started = unix_nanos(SystemTime::now());
+    let trace_id = random_id(32, run_started);
+    let run_span_id = random_id(16, run_started ^ 0x5851_f42d_4c95_7f2d);
+
+    let mut exported = 0;
+    let mut batch = Vec::with_capacity(SPAN_BATCH);
+    loop {
+        let disconnected = match rx.recv() {
+            Ok(span) => {
+                batch.push(span);
+                while batch.len() < SPAN_BATCH {
+                    match rx.try_recv() {
+                        Ok(span) => batch.push(span),
+                        Err(_) => break,
+                    }
+                }
+                false
+            }
+            Err(_) => true,
+        };
+
+        if batch.len() >= SPAN_BATCH || disconnected {
+            if !batch.is_empty() {
+                export_batch(addr, path, &trace_id, Some(run_span_id.as_str()), &batch);
+                exported += batch.len() as u64;
+                batch.clear();
+            }
+        }
+        if disconnected {
+            break;
+        }
+    }
+
+    let run_span = SpanRecord {
+        name: "run",
+        start_nanos: run_started,
+        end_nanos: unix_nanos(SystemTime::now()),
+    };
+    export_batch(addr, path, &trace_id, None, &[run_span]);
+    exported + 1
+}
+
+/// Ship one batch; export failures are logged and the spans dropped,
+/// tracing never affects the migration.
+fn export_batch(addr: &str,
+                path: &str,
+                trace_id: &str,
+                parent: Option<&str>,
+                batch: &[SpanRecord]) {
+    let mut spans = String::new();
+    for (i, span) in batch.iter().enumerate() {
+        if i > 0 {
+            spans.push(',');
+        }
+        let span_id = random_id(16, span.start_nanos ^ span.end_nanos ^ i as u64);
+        write!(spans,
+               "{{\"traceId\":\"{}\",\"spanId\":\"{}\",{}\"name\":\"{}\",\"kind\":1,\
+                \"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\"}}",
+               trace_id,
+               span_id,
+               match parent {
+                   Some(parent) => format!("\"parentSpanId\":\"{}\",", parent),
+                   None => String::new(),
+               },
+               span.name,
+               span.start_nanos,
+               span.end_nanos)
+                .unwrap();
+    }
+    let body = format!("{{\"resourceSpans\":[{{\"resource\":{{\"attributes\":[{{\"key\":\
+                        \"service.name\",\"value\":{{\"stringValue\":\"lo-migrate\"}}}}]}},\
+                        \"scopeSpans\":[{{\"scope\":{{\"name\":\"lo-migrate\"}},\
+                        \"spans\":[{}]}}]}}]}}",
+                       spans);
+
+    if let Err(err) = post_traces(addr, path, &body) {
+        warn!("failed to export {} spans: {}", batch.len(), err);
+    }
+}
+
+fn post_traces(addr: &str, path: &str, body: &str) -> Result<()> {
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(EXPORT_TIMEOUT))?;
+    stream.set_write_timeout(Some(EXPORT_TIMEOUT))?;
+    let request = format!("POST {} HTTP/1.0\r\n\
+                           Host: {}\r\n\
+                           Content-Type: application/json\r\n\
+                           Content-Length: {}\r\n\
+                           Connection: close\r\n\
+                           \r\n\
+                           {}",
+                          path,
+                          addr,
+                          body.len(),
+                          body);
+    stream.write_all(request.as_bytes())?;
+
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response);
+    let status = response.split_whitespace().nth(1).unwrap_or("");
+    if status.starts_with('2') {
+        Ok(())
+    } else {
+        Err(ErrorKind::Metrics(format!("collector at {} answered with status {:?}",
+                                       addr,
+                                       status))
+                    .into())
+    }
+}
+
+/// Split a plain-http collector URL into `host:port` and path; the
+/// port defaults to 4318 (OTLP/HTTP), the path to `/v1/traces`.
+fn parse_collector_url(url: &str) -> Result<(String, String)> {
+    let rest = match url.find("://") {
+        Some(at) if &url[..at] == "http" => &url[at + 3..],
+        Some(_) => {
+            return Err(ErrorKind::Config("the otel exporter speaks plain http only"
+                                             .to_string())
+                               .into())
+        }
+        None => url,
+    };
+    let (host, path) = match rest.find('/') {
+        Some(at) => (&rest[..at], &rest[at..]),
+        None => (rest, "/v1/traces"),
+    };
+    if host.is_empty() {
+        return Err(ErrorKind::Config(format!("no host in collector url {:?}", url)).into());
+    }
+    let addr = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:4318", host)
+    };
+    Ok((addr, path.to_string()))
+}
+
+fn unix_nanos(at: SystemTime) -> u64 {
+    let elapsed = at.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    elapsed.as_secs().wrapping_mul(1_000_000_000) + u64::from(elapsed.subsec_nanos())
+}
+
+/// `digits` lower-case hex digits from a seeded xorshift*, as OTLP
+/// trace and span ids; uniqueness per run is all the format needs.
+fn random_id(digits: usize, seed: u64) -> String {
+    let mut state = seed | 1;
+    let mut id = String::with_capacity(digits);
+    while id.len() < digits {
+        state ^= state >> 12;
+        state ^= state << 25;
+        state ^= state >> 27;
+        let word = state.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        write!(id, "{:016x}", word).unwrap();
+    }
+    id.truncate(digits);
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collector_urls_are_parsed() {
+        assert_eq!(parse_collector_url("http://otel.internal").unwrap(),
+                   ("otel.internal:4318".to_string(), "/v1/traces".to_string()));
+        assert_eq!(parse_collector_url("otel.internal:4319/custom").unwrap(),
+                   ("otel.internal:4319".to_string(), "/custom".to_string()));
+        assert!(parse_collector_url("https://otel.internal").is_err());
+    }
+
+    #[test]
+    fn ids_have_the_requested_width() {
+        let trace = random_id(32, 42);
+        let span = random_id(16, 42);
+        assert_eq!(trace.len(), 32);
+        assert_eq!(span.len(), 16);
+        assert!(trace.bytes().all(|b| b.is_ascii_hexdigit()));
+        assert_ne!(random_id(16, 42), random_id(16, 43));
+    }
+}
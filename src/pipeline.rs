@@ -0,0 +1,197 @@
+//! Coordinator owning the worker threads of a running migration.
+//!
+//! [`Migration::start()`] spawns every worker through a [`Pipeline`]
+//! and hands it to the caller; [`join()`] then waits for the threads
+//! in spawn order and collects a [`ThreadResult`] per worker. The
+//! first failing or panicking worker cancels the run, so the remaining
+//! threads stop at the next object boundary instead of waiting on
+//! disconnected queues forever.
+//!
+//! [`Migration::start()`]: ../migrate/struct.Migration.html#method.start
+//! [`Pipeline`]: struct.Pipeline.html
+//! [`join()`]: struct.Pipeline.html#method.join
+//! [`ThreadResult`]: struct.ThreadResult.html
+
+use error::{ErrorKind, Result};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use thread::{CancelReason, ThreadStat};
+
+/// How a single worker thread ended.
+#[derive(Debug)]
+pub struct ThreadResult {
+    /// thread name, e.g. `observer` or `storer_2`
+    pub name: String,
+    /// objects processed, or why the worker stopped; a panicking
+    /// worker is reported as [`ErrorKind::WorkerPanicked`]
+    ///
+    /// [`ErrorKind::WorkerPanicked`]: ../error/enum.ErrorKind.html
+    pub result: Result<u64>,
+}
+
+/// Owns the spawned worker threads of a migration run.
+///
+/// Dropping a `Pipeline` without calling [`join()`] detaches the
+/// threads; they keep the shared [`ThreadStat`] alive and finish (or
+/// get cancelled) on their own.
+///
+/// [`join()`]: #method.join
+/// [`ThreadStat`]: ../thread/struct.ThreadStat.html
+pub struct Pipeline {
+    stats: Arc<ThreadStat>,
+    threads: Vec<(String, JoinHandle<Result<u64>>)>,
+}
+
+impl Pipeline {
+    pub fn new(stats: Arc<ThreadStat>) -> Self {
+        Pipeline {
+            stats: stats,
+            threads: Vec::new(),
+        }
+    }
+
+    /// The statistics shared by all workers of this pipeline.
+    pub fn stats(&self) -> Arc<ThreadStat> {
+        self.stats.clone()
+    }
+
+    /// Spawn a named worker thread owned by this pipeline.
+    ///
+    /// The queue handles a worker needs must be cloned into the
+    /// closure; the pipeline itself holds none, so each stage's queue
+    /// disconnects as soon as its workers are done.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the operating system refuses to spawn the thread.
+    pub fn spawn<F>(&mut self, name: &str, f: F)
+        where F: FnOnce() -> Result<u64> + Send + 'static
+    {
+        let handle = thread::Builder::new()
+            .name(name.to_string())
+            .spawn(f)
+            .expect("failed to spawn thread");
+        self.threads.push((name.to_string(), handle));
+    }
+
+    /// Number of threads spawned so far.
+    pub fn thread_count(&self) -> usize {
+        self.threads.len()
+    }
+
+    /// Cancel the run; the workers stop at the next object boundary.
+    pub fn cancel(&self) {
+        self.stats.cancel();
+    }
+
+    /// Wait for all workers and collect their results in spawn order.
+    ///
+    /// The first worker that fails or panics cancels the run with
+    /// [`CancelReason::WorkerFailed`]; joining continues so every
+    /// thread is reaped and reported.
+    ///
+    /// [`CancelReason::WorkerFailed`]: ../thread/enum.CancelReason.html
+    pub fn join(self) -> Vec<ThreadResult> {
+        let stats = self.stats;
+        self.threads
+            .into_iter()
+            .map(|(name, handle)| {
+                let result = match handle.join() {
+                    Ok(result) => result,
+                    Err(_) => Err(ErrorKind::WorkerPanicked(name.clone()).into()),
+                };
+                if let Err(ref err) = result {
+                    if !is_cancellation(err) {
+                        error!("thread {} failed: {}", name, err);
+                        stats.cancel_with(CancelReason::WorkerFailed);
+                    }
+                }
+                ThreadResult {
+                    name: name,
+                    result: result,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Cancelled workers report [`ErrorKind::ThreadCancelled`]; that is the
+/// expected reaction to an earlier failure, not a failure of its own.
+///
+/// [`ErrorKind::ThreadCancelled`]: ../error/enum.ErrorKind.html
+fn is_cancellation(err: &::error::MigrationError) -> bool {
+    match *err.kind() {
+        ErrorKind::ThreadCancelled => true,
+        _ => false,
+    }
+}
+
+/// First real failure out of the joined thread results, ignoring
+/// workers that merely reported the cancellation.
+pub fn first_error(results: Vec<ThreadResult>) -> Result<()> {
+    for thread in results {
+        match thread.result {
+            Err(ref err) if is_cancellation(err) => (),
+            Err(err) => return Err(err),
+            Ok(_) => (),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn results_come_back_in_spawn_order() {
+        let mut pipeline = Pipeline::new(Arc::new(ThreadStat::new()));
+        pipeline.spawn("first", || Ok(1));
+        pipeline.spawn("second", || Ok(2));
+        assert_eq!(pipeline.thread_count(), 2);
+
+        let results = pipeline.join();
+        assert_eq!(results[0].name, "first");
+        assert_eq!(*results[0].result.as_ref().unwrap(), 1);
+        assert_eq!(results[1].name, "second");
+        assert_eq!(*results[1].result.as_ref().unwrap(), 2);
+    }
+
+    #[test]
+    fn a_failing_worker_cancels_the_run() {
+        let stats = Arc::new(ThreadStat::new());
+        let mut pipeline = Pipeline::new(stats.clone());
+        pipeline.spawn("broken", || Err(ErrorKind::ChecksumMismatch.into()));
+
+        let results = pipeline.join();
+        assert!(stats.is_cancelled());
+        assert!(first_error(results).is_err());
+    }
+
+    #[test]
+    fn a_panicking_worker_is_reported() {
+        let stats = Arc::new(ThreadStat::new());
+        let mut pipeline = Pipeline::new(stats.clone());
+        pipeline.spawn("explosive", || panic!("boom"));
+
+        let results = pipeline.join();
+        assert!(stats.is_cancelled());
+        match *results[0].result.as_ref().unwrap_err().kind() {
+            ErrorKind::WorkerPanicked(ref name) => assert_eq!(name, "explosive"),
+            ref other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn cancelled_workers_do_not_mask_success() {
+        let results = vec![ThreadResult {
+                               name: "receiver_0".to_string(),
+                               result: Err(ErrorKind::ThreadCancelled.into()),
+                           },
+                           ThreadResult {
+                               name: "storer_0".to_string(),
+                               result: Ok(5),
+                           }];
+        assert!(first_error(results).is_ok());
+    }
+}
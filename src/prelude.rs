@@ -0,0 +1,51 @@
+//! One-stop import for applications embedding the pipeline.
+//!
+//! Re-exports the types an integration typically touches — [`Lo`] and
+//! its buffering traits, the worker types, the configuration structs,
+//! the error types and the backend traits — so embedding starts with a
+//! single
+//!
+//! ```
+//! use lo_migrate::prelude::*;
+//! ```
+//!
+//! instead of a pile of module paths. Functions (schema preparation in
+//! [`db`], stale-upload cleanup in [`thread`]) are deliberately not
+//! re-exported; call those through their module.
+//!
+//! [`Lo`]: ../lo/struct.Lo.html
+//! [`db`]: ../db/index.html
+//! [`thread`]: ../thread/index.html
+
+pub use audit::AuditLog;
+pub use config::{MigrationConfig, NotifyConfig, PgConfig, QueueConfig, ThreadConfig};
+pub use db::{ConnFactory, PooledConn, PooledConnFactory, RunState, UrlConnFactory};
+pub use error::{ErrorKind, MigrationError, Result, Stage};
+pub use estimate::{Estimate, Estimator};
+pub use events::EventStream;
+pub use export::{DirBackend, DirStore, TarBackend, TarStore};
+pub use http_put::{HttpPutBackend, HttpPutStore};
+pub use lo::{AuditTrail, BufferBackend, BufferedData, ColumnMapping, Data, Lo, ScratchBuffer};
+pub use logging::GroupLogger;
+#[cfg(unix)]
+pub use logging::SyslogLogger;
+pub use metrics::{FanoutSink, MetricsSink, NullSink, PrometheusSink, PushgatewayClient,
+                  PushgatewaySink, StdoutSink};
+pub use migrate::{Migration, MigrationBuilder, MigrationReport, RusotoBackend, S3Config};
+pub use notify::{Notifier, NotifierSet, RunStatus, SlackNotifier, SmtpNotifier,
+                 WebhookNotifier};
+pub use object_store::{MemoryObjectStore, ObjectStore, S3ObjectStore, StorageBackend,
+                       UploadMeta, UploadOutcome};
+#[cfg(feature = "otel")]
+pub use otel::OtlpExporter;
+pub use pipeline::{Pipeline, ThreadResult};
+pub use presign::{PresignedBackend, PresignedStore};
+pub use queue::{PriorityWorkQueue, RecvResult, SpillingWorkQueue, TwoLockWorkQueue, WorkQueue,
+                WorkQueueReceiver, WorkQueueSender};
+pub use sigv2::{SigV2Backend, SigV2Store};
+pub use source::{CommitOutcome, DataFormat, LoSource, NiceBinarySource, PendingFilter,
+                 PendingLos, PendingObject, PgLargeObjectSource, PrefetchedSource, SourceTotals};
+pub use tempfiles::{BufferRegistry, TempSpaceGuard};
+pub use thread::{BatchJobGuard, BucketOptions, BucketTotals, CancelReason, CommitMode,
+                 Committer, ConcurrencyGate, Counter, ErrorRecord, Monitor, Observer, Receiver,
+                 Storer, ThreadStat, UploadHeaders, UploadJournal, Verifier};
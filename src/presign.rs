@@ -0,0 +1,385 @@
+//! Presigned-URL upload mode.
+//!
+//! Security-constrained sites do not hand long-lived S3 credentials to
+//! the migration hosts. [`PresignedStore`] uploads every object
+//! through a short-lived presigned `PUT` URL obtained per request:
+//! either from an external signing service, or generated locally with
+//! SigV2 query authentication from the configured credentials, for
+//! stores and policies that only accept presigned requests. Either
+//! way no `Authorization` header is put on the wire.
+//!
+//! A signing service answers `GET <endpoint>?verb=PUT&key=<key>` with
+//! the presigned URL as a plain-text body; it decides the expiry and
+//! may restrict keys and verbs as it sees fit. `exists()` asks for a
+//! `HEAD` URL and treats a refusal to sign one as "not there", so a
+//! PUT-only signer degrades to always uploading — the sha2 column and
+//! the upload journal still keep reruns incremental.
+//!
+//! Like the other hand-rolled HTTP in this crate both legs speak plain
+//! http only. Presigned URLs carry no part protocol, so multipart
+//! uploads are staged like the [`http_put`] backend's and sent as one
+//! `PUT`. [`PresignedBackend`] plugs the store into the
+//! [`StorageBackend`] seam.
+//!
+//! [`PresignedStore`]: struct.PresignedStore.html
+//! [`PresignedBackend`]: struct.PresignedBackend.html
+//! [`StorageBackend`]: ../object_store/trait.StorageBackend.html
+//! [`http_put`]: ../http_put/index.html
+
+use chrono::Utc;
+use error::{ErrorKind, Result};
+use export::composite_etag;
+use http_put::{execute, status_error};
+use object_store::{ObjectStore, Part, StagedUploads, StorageBackend, UploadMeta,
+                   UploadOutcome, base64, verify_checksum};
+use sigv2::{HttpResponse, hmac_sha1};
+use std::io::{self, Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Split a plain-http URL into the `host:port` to connect to and the
+/// path to request; rejects https like the other hand-rolled clients.
+/// The port defaults to 80.
+fn split_url(url: &str, what: &str) -> Result<(String, String)> {
+    let rest = match url.find("://") {
+        Some(at) if &url[..at] == "http" => &url[at + 3..],
+        Some(_) => {
+            return Err(ErrorKind::Config(format!("{} {:?} is not plain http; presigned \
+                                                  uploads speak plain http only, put a \
+                                                  local proxy in front of https targets",
+                                                 what,
+                                                 url))
+                               .into())
+        }
+        None => url,
+    };
+    let (addr, path) = match rest.find('/') {
+        Some(at) => (&rest[..at], &rest[at..]),
+        None => (rest, "/"),
+    };
+    if addr.is_empty() {
+        return Err(ErrorKind::Config(format!("no host in {} {:?}", what, url)).into());
+    }
+    Ok((if addr.contains(':') {
+            addr.to_string()
+        } else {
+            format!("{}:80", addr)
+        },
+        path.to_string()))
+}
+
+/// base64 SigV2 signature for one presigned request.
+fn sign(secret_key: &str, verb: &str, content_type: &str, expires: i64, resource: &str) -> String {
+    let string_to_sign = format!("{}\n\n{}\n{}\n{}", verb, content_type, expires, resource);
+    base64(&hmac_sha1(secret_key.as_bytes(), string_to_sign.as_bytes()))
+}
+
+/// Percent-encode a query string value; the base64 signature carries
+/// `+`, `/` and `=`.
+fn encode_query(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Where the presigned URLs come from.
+#[derive(Clone)]
+enum Signer {
+    /// ask an external signing service for every URL
+    Endpoint { addr: String, path: String },
+    /// sign URLs locally with SigV2 query authentication
+    Local {
+        addr: String,
+        bucket: String,
+        access_key: String,
+        secret_key: String,
+        expiry: Duration,
+    },
+}
+
+/// [`ObjectStore`] uploading through presigned `PUT` URLs.
+///
+/// [`ObjectStore`]: ../object_store/trait.ObjectStore.html
+#[derive(Clone)]
+pub struct PresignedStore {
+    signer: Signer,
+    uploads: Arc<Mutex<StagedUploads>>,
+}
+
+impl PresignedStore {
+    /// Store asking the signing service at `endpoint`, a plain-http
+    /// URL (a query string is kept, so a fixed token can ride along),
+    /// for a presigned URL per request.
+    pub fn with_signer(endpoint: &str) -> Result<Self> {
+        let (addr, path) = split_url(endpoint, "signing endpoint")?;
+        Ok(PresignedStore {
+               signer: Signer::Endpoint {
+                   addr: addr,
+                   path: path,
+               },
+               uploads: Arc::new(Mutex::new(StagedUploads::default())),
+           })
+    }
+
+    /// Store presigning URLs itself for `bucket` behind the plain-http
+    /// `endpoint`; URLs expire after `expiry`.
+    pub fn with_local_signer(endpoint: &str,
+                             bucket: &str,
+                             access_key: &str,
+                             secret_key: &str,
+                             expiry: Duration)
+                             -> Result<Self> {
+        let (addr, _) = split_url(endpoint, "S3 endpoint")?;
+        if bucket.is_empty() {
+            return Err(ErrorKind::Config("no bucket to presign URLs for".to_string()).into());
+        }
+        Ok(PresignedStore {
+               signer: Signer::Local {
+                   addr: addr,
+                   bucket: bucket.to_string(),
+                   access_key: access_key.to_string(),
+                   secret_key: secret_key.to_string(),
+                   expiry: expiry,
+               },
+               uploads: Arc::new(Mutex::new(StagedUploads::default())),
+           })
+    }
+
+    /// Obtain a presigned URL for `verb` on `key`, as the `(addr,
+    /// path)` of the request to send. `content_type` is covered by the
+    /// signature, so the request must carry exactly this value.
+    fn presign(&self, verb: &str, key: &str, content_type: &str) -> Result<(String, String)> {
+        match self.signer {
+            Signer::Endpoint { ref addr, ref path } => {
+                let query = format!("{}{}verb={}&key={}",
+                                    path,
+                                    if path.contains('?') { "&" } else { "?" },
+                                    verb,
+                                    key);
+                let response = execute("presign", addr, "GET", &query, None, "", 0,
+                                       &mut io::empty())?;
+                if response.status / 100 != 2 {
+                    return Err(status_error("presign", &response));
+                }
+                let url = response.body.trim();
+                if url.is_empty() {
+                    return Err(ErrorKind::S3("the signing service answered with an empty \
+                                              body instead of a presigned URL"
+                                                     .to_string())
+                                       .into());
+                }
+                split_url(url, "presigned URL")
+            }
+            Signer::Local {
+                ref addr,
+                ref bucket,
+                ref access_key,
+                ref secret_key,
+                ref expiry,
+            } => {
+                let expires = Utc::now().timestamp() + expiry.as_secs() as i64;
+                let resource = format!("/{}/{}", bucket, key);
+                let signature = sign(secret_key, verb, content_type, expires, &resource);
+                Ok((addr.clone(),
+                    format!("{}?AWSAccessKeyId={}&Expires={}&Signature={}",
+                            resource,
+                            access_key,
+                            expires,
+                            encode_query(&signature))))
+            }
+        }
+    }
+
+    /// The content type to presign and send for `meta`: an external
+    /// signer signed without one, so only the local signer may put the
+    /// header on the wire.
+    fn content_type(&self, meta: &UploadMeta) -> String {
+        match self.signer {
+            Signer::Local { .. } => meta.content_type.clone().unwrap_or_default(),
+            Signer::Endpoint { .. } => String::new(),
+        }
+    }
+
+    fn head(&self, key: &str) -> Result<HttpResponse> {
+        let (addr, path) = self.presign("HEAD", key, "")?;
+        execute("HEAD", &addr, "HEAD", &path, None, "", 0, &mut io::empty())
+    }
+}
+
+impl ObjectStore for PresignedStore {
+    fn exists(&self, key: &str) -> Result<bool> {
+        // a PUT-only signing service refuses to sign HEAD; that counts
+        // as "not there" and the object is uploaded again
+        match self.head(key) {
+            Ok(response) => Ok(response.status == 200),
+            Err(err) => {
+                debug!("presigned HEAD for {} failed ({}); treating the object as missing",
+                       key,
+                       err);
+                Ok(false)
+            }
+        }
+    }
+
+    fn stat(&self, key: &str) -> Result<Option<u64>> {
+        let response = self.head(key)?;
+        match response.status {
+            200 => {
+                Ok(response
+                       .header("Content-Length")
+                       .and_then(|length| length.parse().ok())
+                       .or(Some(0)))
+            }
+            404 => Ok(None),
+            _ => Err(status_error("HEAD", &response)),
+        }
+    }
+
+    fn put(&self, key: &str, data: &[u8], meta: &UploadMeta) -> Result<UploadOutcome> {
+        verify_checksum(meta.checksum_sha256.as_ref().map(String::as_str), data)?;
+        let content_type = self.content_type(meta);
+        let (addr, path) = self.presign("PUT", key, &content_type)?;
+        let response = execute("PUT",
+                               &addr,
+                               "PUT",
+                               &path,
+                               None,
+                               &content_type,
+                               data.len() as u64,
+                               &mut &data[..])?;
+        if response.status / 100 == 2 {
+            Ok(UploadOutcome {
+                e_tag: response.header("ETag").map(str::to_string),
+                version_id: response.header("x-amz-version-id").map(str::to_string),
+            })
+        } else {
+            Err(status_error("PUT", &response))
+        }
+    }
+
+    fn create_multipart(&self, key: &str, meta: &UploadMeta) -> Result<String> {
+        self.uploads
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .create(key, meta)
+    }
+
+    fn upload_part(&self,
+                   key: &str,
+                   upload_id: &str,
+                   part_number: i64,
+                   data: &[u8],
+                   checksum_sha256: Option<&str>)
+                   -> Result<Part> {
+        verify_checksum(checksum_sha256, data)?;
+        self.uploads
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .write_part(key, upload_id, part_number, data)
+    }
+
+    fn complete_multipart(&self,
+                          key: &str,
+                          upload_id: &str,
+                          parts: Vec<Part>)
+                          -> Result<UploadOutcome> {
+        let mut upload = self.uploads
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take(upload_id)?;
+        let e_tag = composite_etag(&parts, &upload.md5s)?;
+        let content_type = self.content_type(&upload.meta);
+        // presigned right before sending, so a long staging phase
+        // cannot outlive the URL
+        let (addr, path) = self.presign("PUT", key, &content_type)?;
+        upload.file.seek(SeekFrom::Start(0))?;
+        let response = execute("PUT",
+                               &addr,
+                               "PUT",
+                               &path,
+                               None,
+                               &content_type,
+                               upload.size,
+                               &mut upload.file)?;
+        if response.status / 100 == 2 {
+            Ok(UploadOutcome {
+                e_tag: Some(e_tag),
+                version_id: response.header("x-amz-version-id").map(str::to_string),
+            })
+        } else {
+            Err(status_error("PUT", &response))
+        }
+    }
+
+    fn abort_multipart(&self, _key: &str, upload_id: &str) -> Result<()> {
+        self.uploads
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .abort(upload_id)
+    }
+}
+
+/// [`StorageBackend`] handing every worker a clone of one
+/// [`PresignedStore`].
+///
+/// [`PresignedStore`]: struct.PresignedStore.html
+/// [`StorageBackend`]: ../object_store/trait.StorageBackend.html
+pub struct PresignedBackend {
+    store: PresignedStore,
+}
+
+impl PresignedBackend {
+    pub fn new(store: PresignedStore) -> Self {
+        PresignedBackend { store: store }
+    }
+}
+
+impl StorageBackend for PresignedBackend {
+    fn store(&self) -> Result<Box<ObjectStore>> {
+        Ok(Box::new(self.store.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_matches_the_reference_example() {
+        // the presigned GET example from the AWS S3 developer guide,
+        // same credentials the SigV2 tests use
+        let signature = sign("wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY",
+                             "GET",
+                             "",
+                             1175139620,
+                             "/johnsmith/photos/puppy.jpg");
+        assert_eq!(encode_query(&signature), "NpgCjnDzrM%2BWFzoENXmpNDUsSn8%3D");
+    }
+
+    #[test]
+    fn query_values_are_percent_encoded() {
+        assert_eq!(encode_query("a-b_c.d~e"), "a-b_c.d~e");
+        assert_eq!(encode_query("a+b/c=d e"), "a%2Bb%2Fc%3Dd%20e");
+    }
+
+    #[test]
+    fn urls_are_split() {
+        let (addr, path) = split_url("http://signer.internal/sign?token=abc", "URL").unwrap();
+        assert_eq!(addr, "signer.internal:80");
+        assert_eq!(path, "/sign?token=abc");
+
+        let (addr, path) = split_url("s3.internal:7480", "URL").unwrap();
+        assert_eq!(addr, "s3.internal:7480");
+        assert_eq!(path, "/");
+
+        assert!(split_url("https://signer.internal/sign", "URL").is_err());
+        assert!(split_url("http:///sign", "URL").is_err());
+    }
+}
@@ -0,0 +1,621 @@
+//! Abstraction over the channels connecting the pipeline stages.
+//!
+//! The workers only talk to the queues through the [`WorkQueueSender`]
+//! and [`WorkQueueReceiver`] traits, and [`Migration::run()`] creates
+//! them through a [`WorkQueue`] factory. The default implementation is
+//! backed by `two_lock_queue`; an alternative channel (or an
+//! instrumented wrapper for tests) plugs in without touching the worker
+//! implementations.
+//!
+//! [`WorkQueueSender`]: trait.WorkQueueSender.html
+//! [`WorkQueueReceiver`]: trait.WorkQueueReceiver.html
+//! [`WorkQueue`]: trait.WorkQueue.html
+//! [`Migration::run()`]: ../migrate/struct.Migration.html#method.run
+
+use error::{ErrorKind, Result};
+use lo::{Data, Lo};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, VecDeque};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use tempfile::NamedTempFileOptions;
+use two_lock_queue::{self, RecvTimeoutError};
+
+/// Outcome of a [`WorkQueueReceiver::recv_timeout()`] call.
+///
+/// [`WorkQueueReceiver::recv_timeout()`]: trait.WorkQueueReceiver.html#tymethod.recv_timeout
+#[derive(Debug)]
+pub enum RecvResult<T> {
+    /// an item was dequeued
+    Item(T),
+    /// the timeout expired with the queue empty
+    TimedOut,
+    /// all senders are gone and the queue is drained
+    Disconnected,
+}
+
+/// Sending half of a bounded inter-stage channel.
+pub trait WorkQueueSender<T>: Send + Sync {
+    /// Block until the item is enqueued. Fails with
+    /// [`ErrorKind::QueueDisconnected`] once all receivers are gone.
+    ///
+    /// [`ErrorKind::QueueDisconnected`]: ../error/enum.ErrorKind.html
+    fn send(&self, item: T) -> Result<()>;
+
+    /// Number of items currently queued, used by the monitor to report
+    /// utilization.
+    fn len(&self) -> usize;
+}
+
+/// Receiving half of a bounded inter-stage channel.
+pub trait WorkQueueReceiver<T>: Send + Sync {
+    /// Dequeue an item, waiting at most `timeout`. The workers use a
+    /// short timeout so they recheck the cancellation flag while idle.
+    fn recv_timeout(&self, timeout: Duration) -> RecvResult<T>;
+
+    /// Number of items currently queued.
+    fn len(&self) -> usize;
+
+    /// Dequeue everything currently queued without waiting, e.g. to
+    /// drop queued objects — and with them their buffer files — after
+    /// a cancellation instead of carrying them until process exit.
+    fn drain(&self) -> Vec<T> {
+        let mut items = Vec::new();
+        loop {
+            match self.recv_timeout(Duration::from_millis(0)) {
+                RecvResult::Item(item) => items.push(item),
+                RecvResult::TimedOut | RecvResult::Disconnected => return items,
+            }
+        }
+    }
+}
+
+impl<T: Send> WorkQueueSender<T> for two_lock_queue::Sender<T> {
+    fn send(&self, item: T) -> Result<()> {
+        two_lock_queue::Sender::send(self, item)
+            .map_err(|_| ErrorKind::QueueDisconnected.into())
+    }
+
+    fn len(&self) -> usize {
+        two_lock_queue::Sender::len(self)
+    }
+}
+
+impl<T: Send> WorkQueueReceiver<T> for two_lock_queue::Receiver<T> {
+    fn recv_timeout(&self, timeout: Duration) -> RecvResult<T> {
+        match two_lock_queue::Receiver::recv_timeout(self, timeout) {
+            Ok(item) => RecvResult::Item(item),
+            Err(RecvTimeoutError::Timeout) => RecvResult::TimedOut,
+            Err(RecvTimeoutError::Disconnected) => RecvResult::Disconnected,
+        }
+    }
+
+    fn len(&self) -> usize {
+        two_lock_queue::Receiver::len(self)
+    }
+}
+
+/// Creates the channels connecting the pipeline stages.
+///
+/// The item type is fixed to [`Lo`]; that is all the pipeline ever
+/// sends.
+///
+/// [`Lo`]: ../lo/struct.Lo.html
+pub trait WorkQueue: Send + Sync {
+    /// Create a bounded channel holding at most `capacity` objects.
+    fn channel(&self,
+               capacity: usize)
+               -> (Arc<WorkQueueSender<Lo>>, Arc<WorkQueueReceiver<Lo>>);
+}
+
+/// Default [`WorkQueue`] backed by `two_lock_queue`.
+///
+/// [`WorkQueue`]: trait.WorkQueue.html
+#[derive(Debug, Default)]
+pub struct TwoLockWorkQueue;
+
+impl WorkQueue for TwoLockWorkQueue {
+    fn channel(&self,
+               capacity: usize)
+               -> (Arc<WorkQueueSender<Lo>>, Arc<WorkQueueReceiver<Lo>>) {
+        let (tx, rx) = two_lock_queue::channel(capacity);
+        (Arc::new(tx), Arc::new(rx))
+    }
+}
+
+/// [`WorkQueue`] that spills object payloads to disk instead of
+/// blocking the sender.
+///
+/// With a bounded store queue, receivers idle as soon as S3 is slower
+/// than Postgres. Channels created here accept objects beyond their
+/// capacity; the payload of every object over capacity is written to a
+/// temporary file first, so only the small [`Lo`] metadata stays in
+/// memory and the pipeline can absorb large bursts without busting the
+/// memory budget. Objects without in-memory data (file-backed or not
+/// yet received) spill for free.
+///
+/// [`WorkQueue`]: trait.WorkQueue.html
+/// [`Lo`]: ../lo/struct.Lo.html
+#[derive(Debug, Default)]
+pub struct SpillingWorkQueue {
+    spill_dir: Option<PathBuf>,
+}
+
+impl SpillingWorkQueue {
+    /// Spill to the system temporary directory.
+    pub fn new() -> Self {
+        SpillingWorkQueue { spill_dir: None }
+    }
+
+    /// Spill to `dir` instead of the system temporary directory, e.g.
+    /// a scratch volume sized for the expected backlog.
+    pub fn with_spill_dir(mut self, dir: PathBuf) -> Self {
+        self.spill_dir = Some(dir);
+        self
+    }
+}
+
+impl WorkQueue for SpillingWorkQueue {
+    fn channel(&self,
+               capacity: usize)
+               -> (Arc<WorkQueueSender<Lo>>, Arc<WorkQueueReceiver<Lo>>) {
+        let shared = Arc::new(SpillShared {
+                                  inner: Mutex::new(SpillInner {
+                                                        queue: VecDeque::new(),
+                                                        sender_gone: false,
+                                                        receiver_gone: false,
+                                                    }),
+                                  available: Condvar::new(),
+                                  capacity: capacity,
+                                  spill_dir: self.spill_dir.clone(),
+                              });
+        (Arc::new(SpillSender(shared.clone())), Arc::new(SpillReceiver(shared)))
+    }
+}
+
+#[derive(Debug)]
+struct SpillInner {
+    queue: VecDeque<Lo>,
+    sender_gone: bool,
+    receiver_gone: bool,
+}
+
+#[derive(Debug)]
+struct SpillShared {
+    inner: Mutex<SpillInner>,
+    available: Condvar,
+    capacity: usize,
+    spill_dir: Option<PathBuf>,
+}
+
+impl SpillShared {
+    /// Move an in-memory payload into a temporary file.
+    fn spill(&self, lo: &mut Lo) -> Result<()> {
+        let data = match lo.take_data() {
+            Data::Vec(data) => data,
+            // file-backed or absent payloads cost no memory, put back
+            other => {
+                lo.set_data(other);
+                return Ok(());
+            }
+        };
+        let mut options = NamedTempFileOptions::new();
+        let options = options.prefix("lo_migrate_spill");
+        let mut file = match self.spill_dir {
+            Some(ref dir) => options.create_in(dir)?,
+            None => options.create()?,
+        };
+        file.write_all(&data)?;
+        lo.set_data(Data::File(file));
+        Ok(())
+    }
+}
+
+struct SpillSender(Arc<SpillShared>);
+
+impl WorkQueueSender<Lo> for SpillSender {
+    fn send(&self, mut item: Lo) -> Result<()> {
+        let mut inner = self.0.inner.lock().unwrap_or_else(|e| e.into_inner());
+        if inner.receiver_gone {
+            return Err(ErrorKind::QueueDisconnected.into());
+        }
+        if inner.queue.len() >= self.0.capacity {
+            self.0.spill(&mut item)?;
+        }
+        inner.queue.push_back(item);
+        self.0.available.notify_one();
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.0.inner.lock().unwrap_or_else(|e| e.into_inner()).queue.len()
+    }
+}
+
+impl Drop for SpillSender {
+    fn drop(&mut self) {
+        self.0.inner.lock().unwrap_or_else(|e| e.into_inner()).sender_gone = true;
+        self.0.available.notify_all();
+    }
+}
+
+struct SpillReceiver(Arc<SpillShared>);
+
+impl WorkQueueReceiver<Lo> for SpillReceiver {
+    fn recv_timeout(&self, timeout: Duration) -> RecvResult<Lo> {
+        let deadline = Instant::now() + timeout;
+        let mut inner = self.0.inner.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if let Some(lo) = inner.queue.pop_front() {
+                return RecvResult::Item(lo);
+            }
+            if inner.sender_gone {
+                return RecvResult::Disconnected;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return RecvResult::TimedOut;
+            }
+            inner = self.0
+                .available
+                .wait_timeout(inner, deadline - now)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.0.inner.lock().unwrap_or_else(|e| e.into_inner()).queue.len()
+    }
+}
+
+impl Drop for SpillReceiver {
+    fn drop(&mut self) {
+        self.0.inner.lock().unwrap_or_else(|e| e.into_inner()).receiver_gone = true;
+    }
+}
+
+/// [`WorkQueue`] handing out the largest queued object first.
+///
+/// A strictly first-in-first-out store queue makes a 100 GiB object
+/// wait behind thousands of small ones, so its hours-long upload only
+/// starts late in the migration and ends up defining the tail. Channels
+/// created here dequeue by object size instead — the next free storer
+/// always picks the biggest queued object, equal sizes stay in arrival
+/// order. Only useful between the receivers and the storers; the other
+/// stages gain nothing from reordering.
+///
+/// [`WorkQueue`]: trait.WorkQueue.html
+#[derive(Debug, Default)]
+pub struct PriorityWorkQueue;
+
+impl WorkQueue for PriorityWorkQueue {
+    fn channel(&self,
+               capacity: usize)
+               -> (Arc<WorkQueueSender<Lo>>, Arc<WorkQueueReceiver<Lo>>) {
+        let shared = Arc::new(PriorityShared {
+                                  inner: Mutex::new(PriorityInner {
+                                                        heap: BinaryHeap::new(),
+                                                        next_seq: 0,
+                                                        sender_gone: false,
+                                                        receiver_gone: false,
+                                                    }),
+                                  available: Condvar::new(),
+                                  space: Condvar::new(),
+                                  capacity: capacity,
+                              });
+        (Arc::new(PrioritySender(shared.clone())), Arc::new(PriorityReceiver(shared)))
+    }
+}
+
+/// Heap entry ordering objects by size, ties broken by arrival order.
+#[derive(Debug)]
+struct PriorityEntry {
+    seq: u64,
+    lo: Lo,
+}
+
+impl PartialEq for PriorityEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.seq == other.seq
+    }
+}
+
+impl Eq for PriorityEntry {}
+
+impl PartialOrd for PriorityEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PriorityEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // larger objects first; among equals the earlier sequence
+        // number wins, keeping same-sized objects in arrival order
+        self.lo
+            .size()
+            .cmp(&other.lo.size())
+            .then(other.seq.cmp(&self.seq))
+    }
+}
+
+#[derive(Debug)]
+struct PriorityInner {
+    heap: BinaryHeap<PriorityEntry>,
+    next_seq: u64,
+    sender_gone: bool,
+    receiver_gone: bool,
+}
+
+#[derive(Debug)]
+struct PriorityShared {
+    inner: Mutex<PriorityInner>,
+    available: Condvar,
+    space: Condvar,
+    capacity: usize,
+}
+
+struct PrioritySender(Arc<PriorityShared>);
+
+impl WorkQueueSender<Lo> for PrioritySender {
+    fn send(&self, item: Lo) -> Result<()> {
+        let mut inner = self.0.inner.lock().unwrap_or_else(|e| e.into_inner());
+        while inner.heap.len() >= self.0.capacity {
+            if inner.receiver_gone {
+                return Err(ErrorKind::QueueDisconnected.into());
+            }
+            inner = self.0
+                .space
+                .wait(inner)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+        if inner.receiver_gone {
+            return Err(ErrorKind::QueueDisconnected.into());
+        }
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+        inner.heap.push(PriorityEntry { seq: seq, lo: item });
+        self.0.available.notify_one();
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.0.inner.lock().unwrap_or_else(|e| e.into_inner()).heap.len()
+    }
+}
+
+impl Drop for PrioritySender {
+    fn drop(&mut self) {
+        self.0.inner.lock().unwrap_or_else(|e| e.into_inner()).sender_gone = true;
+        self.0.available.notify_all();
+    }
+}
+
+struct PriorityReceiver(Arc<PriorityShared>);
+
+impl WorkQueueReceiver<Lo> for PriorityReceiver {
+    fn recv_timeout(&self, timeout: Duration) -> RecvResult<Lo> {
+        let deadline = Instant::now() + timeout;
+        let mut inner = self.0.inner.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            if let Some(entry) = inner.heap.pop() {
+                self.0.space.notify_one();
+                return RecvResult::Item(entry.lo);
+            }
+            if inner.sender_gone {
+                return RecvResult::Disconnected;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return RecvResult::TimedOut;
+            }
+            inner = self.0
+                .available
+                .wait_timeout(inner, deadline - now)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.0.inner.lock().unwrap_or_else(|e| e.into_inner()).heap.len()
+    }
+}
+
+impl Drop for PriorityReceiver {
+    fn drop(&mut self) {
+        self.0.inner.lock().unwrap_or_else(|e| e.into_inner()).receiver_gone = true;
+        self.0.space.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_lo(oid: u32) -> Lo {
+        Lo::new(vec![0; 20], oid, 1, "text/plain".to_string())
+    }
+
+    #[test]
+    fn two_lock_channel_round_trip() {
+        let (tx, rx) = TwoLockWorkQueue.channel(4);
+        tx.send(sample_lo(1)).unwrap();
+        assert_eq!(tx.len(), 1);
+        assert_eq!(rx.len(), 1);
+
+        match rx.recv_timeout(Duration::from_millis(10)) {
+            RecvResult::Item(ref lo) => assert_eq!(lo.oid(), 1),
+            ref other => panic!("unexpected result: {:?}", other),
+        }
+        match rx.recv_timeout(Duration::from_millis(10)) {
+            RecvResult::TimedOut => (),
+            ref other => panic!("unexpected result: {:?}", other),
+        }
+
+        drop(tx);
+        match rx.recv_timeout(Duration::from_millis(10)) {
+            RecvResult::Disconnected => (),
+            ref other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn drain_empties_the_queue_without_blocking() {
+        let (tx, rx) = TwoLockWorkQueue.channel(4);
+        for oid in 0..3 {
+            tx.send(sample_lo(oid)).unwrap();
+        }
+        assert_eq!(rx.drain().len(), 3);
+        assert_eq!(rx.len(), 0);
+        assert!(rx.drain().is_empty());
+    }
+
+    #[test]
+    fn send_fails_once_the_receiver_is_gone() {
+        let (tx, rx) = TwoLockWorkQueue.channel(4);
+        drop(rx);
+        assert!(tx.send(sample_lo(1)).is_err());
+    }
+
+    #[test]
+    fn spilling_channel_never_blocks_the_sender() {
+        let (tx, rx) = SpillingWorkQueue::new().channel(1);
+        for oid in 0..5 {
+            tx.send(sample_lo(oid)).unwrap();
+        }
+        assert_eq!(rx.len(), 5);
+
+        for oid in 0..5 {
+            match rx.recv_timeout(Duration::from_millis(10)) {
+                RecvResult::Item(ref lo) => assert_eq!(lo.oid(), oid),
+                ref other => panic!("unexpected result: {:?}", other),
+            }
+        }
+        drop(tx);
+        match rx.recv_timeout(Duration::from_millis(10)) {
+            RecvResult::Disconnected => (),
+            ref other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn payloads_over_capacity_are_spilled_to_disk() {
+        use std::io::Read;
+
+        let (tx, rx) = SpillingWorkQueue::new().channel(1);
+        let mut first = sample_lo(1);
+        first.set_data(Data::Vec(b"in memory".to_vec()));
+        let mut second = sample_lo(2);
+        second.set_data(Data::Vec(b"on disk".to_vec()));
+        tx.send(first).unwrap();
+        tx.send(second).unwrap();
+
+        let mut first = match rx.recv_timeout(Duration::from_millis(10)) {
+            RecvResult::Item(lo) => lo,
+            ref other => panic!("unexpected result: {:?}", other),
+        };
+        match first.take_data() {
+            Data::Vec(ref data) => assert_eq!(&data[..], b"in memory"),
+            ref other => panic!("expected in-memory data, got {:?}", other),
+        }
+
+        let mut second = match rx.recv_timeout(Duration::from_millis(10)) {
+            RecvResult::Item(lo) => lo,
+            ref other => panic!("unexpected result: {:?}", other),
+        };
+        match second.take_data() {
+            Data::File(ref file) => {
+                let mut data = Vec::new();
+                ::std::fs::File::open(file.path())
+                    .unwrap()
+                    .read_to_end(&mut data)
+                    .unwrap();
+                assert_eq!(&data[..], b"on disk");
+            }
+            ref other => panic!("expected file-backed data, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn spilling_send_fails_once_the_receiver_is_gone() {
+        let (tx, rx) = SpillingWorkQueue::new().channel(4);
+        drop(rx);
+        assert!(tx.send(sample_lo(1)).is_err());
+    }
+
+    fn sized_lo(oid: u32, size: i64) -> Lo {
+        Lo::new(vec![0; 20], oid, size, "text/plain".to_string())
+    }
+
+    #[test]
+    fn priority_channel_hands_out_the_largest_object_first() {
+        let (tx, rx) = PriorityWorkQueue.channel(4);
+        tx.send(sized_lo(1, 100)).unwrap();
+        tx.send(sized_lo(2, 10_000)).unwrap();
+        tx.send(sized_lo(3, 1)).unwrap();
+
+        let mut oids = Vec::new();
+        for _ in 0..3 {
+            match rx.recv_timeout(Duration::from_millis(10)) {
+                RecvResult::Item(ref lo) => oids.push(lo.oid()),
+                ref other => panic!("unexpected result: {:?}", other),
+            }
+        }
+        assert_eq!(oids, [2, 1, 3]);
+    }
+
+    #[test]
+    fn priority_channel_keeps_equal_sizes_in_arrival_order() {
+        let (tx, rx) = PriorityWorkQueue.channel(4);
+        for oid in 0..4 {
+            tx.send(sized_lo(oid, 42)).unwrap();
+        }
+
+        for oid in 0..4 {
+            match rx.recv_timeout(Duration::from_millis(10)) {
+                RecvResult::Item(ref lo) => assert_eq!(lo.oid(), oid),
+                ref other => panic!("unexpected result: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn priority_channel_blocks_the_sender_at_capacity() {
+        use std::sync::mpsc;
+        use std::thread;
+
+        let (tx, rx) = PriorityWorkQueue.channel(1);
+        tx.send(sized_lo(1, 1)).unwrap();
+
+        let (done_tx, done_rx) = mpsc::channel();
+        let sender = thread::spawn(move || {
+                                       tx.send(sized_lo(2, 2)).unwrap();
+                                       done_tx.send(()).unwrap();
+                                   });
+        // the queue is full, the second send must not complete yet
+        assert!(done_rx.recv_timeout(Duration::from_millis(50)).is_err());
+
+        match rx.recv_timeout(Duration::from_millis(10)) {
+            RecvResult::Item(ref lo) => assert_eq!(lo.oid(), 1),
+            ref other => panic!("unexpected result: {:?}", other),
+        }
+        done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        sender.join().unwrap();
+
+        match rx.recv_timeout(Duration::from_millis(10)) {
+            RecvResult::Item(ref lo) => assert_eq!(lo.oid(), 2),
+            ref other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn priority_send_fails_once_the_receiver_is_gone() {
+        let (tx, rx) = PriorityWorkQueue.channel(4);
+        drop(rx);
+        assert!(tx.send(sample_lo(1)).is_err());
+    }
+}
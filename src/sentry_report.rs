@@ -0,0 +1,66 @@
+//! Sentry error reporting, behind the `sentry-report` feature.
+//!
+//! Unattended runs — overnight migration windows in particular — fail
+//! silently unless someone tails the log. With a DSN configured, every
+//! failed object and every worker panic is reported to Sentry with its
+//! oid, stage and error category as tags, so the on-call team is paged
+//! while the window is still open instead of reading about it the next
+//! morning.
+//!
+//! ```no_run
+//! let _guard = lo_migrate::sentry_report::init("https://key@sentry.example.com/42");
+//! // ... run the migration ...
+//! # let stats = lo_migrate::thread::ThreadStat::new();
+//! lo_migrate::sentry_report::report_failures(&stats);
+//! ```
+
+use sentry::{self, Level};
+use sentry::protocol::Event;
+use thread::{ErrorRecord, ThreadStat};
+
+/// Keeps the process-wide Sentry client alive; dropping it flushes
+/// buffered events. Hold it for the whole run.
+pub struct SentryGuard {
+    _guard: sentry::internals::ClientInitGuard,
+}
+
+/// Initialize the Sentry client for `dsn` and hook the panic handler,
+/// so a crashing worker thread is reported even before
+/// [`report_failures()`] runs.
+///
+/// [`report_failures()`]: fn.report_failures.html
+pub fn init(dsn: &str) -> SentryGuard {
+    let guard = sentry::init(dsn);
+    sentry::integrations::panic::register_panic_handler();
+    SentryGuard { _guard: guard }
+}
+
+/// Report every failed object of the run as one Sentry event.
+///
+/// [`ThreadStat`] keeps at most [`ERROR_SAMPLE_CAP`] detail records,
+/// which also caps the number of events sent here; the per-category
+/// counts in the log summary remain exact regardless.
+///
+/// [`ThreadStat`]: ../thread/struct.ThreadStat.html
+/// [`ERROR_SAMPLE_CAP`]: ../thread/constant.ERROR_SAMPLE_CAP.html
+pub fn report_failures(stats: &ThreadStat) {
+    for record in stats.failed_objects() {
+        capture_record(&record);
+    }
+}
+
+fn capture_record(record: &ErrorRecord) {
+    let mut event = Event {
+        message: Some(record.message.clone()),
+        level: Level::Error,
+        ..Default::default()
+    };
+    event.tags.insert("category".to_string(), record.category.to_string());
+    if let Some(stage) = record.stage {
+        event.tags.insert("stage".to_string(), stage.to_string());
+    }
+    if let Some(oid) = record.oid {
+        event.tags.insert("oid".to_string(), oid.to_string());
+    }
+    sentry::capture_event(event);
+}
@@ -0,0 +1,877 @@
+//! Pluggable source of the large objects to migrate.
+//!
+//! The pipeline itself does not care where objects come from; all SQL
+//! specific to the Nice2 schema sits behind the [`LoSource`] trait:
+//! listing pending objects, counting them, opening their data and
+//! committing the sha2 hashes back. [`NiceBinarySource`] is the
+//! `_nice_binary` implementation used by default; applications storing
+//! large objects under a different schema can implement the trait
+//! themselves and reuse the multithreaded pipeline unchanged.
+//!
+//! For scripts that only want to look at pending work, [`PendingLos`]
+//! iterates the rows awaiting migration without any of the pipeline
+//! machinery. [`PgLargeObjectSource`] scans the large-object catalog
+//! directly, for repair runs against a corrupted bookkeeping table.
+//!
+//! [`LoSource`]: trait.LoSource.html
+//! [`NiceBinarySource`]: struct.NiceBinarySource.html
+//! [`PendingLos`]: struct.PendingLos.html
+//! [`PgLargeObjectSource`]: struct.PgLargeObjectSource.html
+
+use error::{ErrorKind, MigrationError, Result};
+use fallible_iterator::FallibleIterator;
+use lo::Lo;
+use postgres::Connection;
+use postgres::transaction::Transaction;
+use postgres::types::ToSql;
+use postgres_large_object::{LargeObjectTransactionExt, Mode};
+use std::collections::{HashSet, VecDeque};
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{Receiver, SyncSender, sync_channel};
+use thread::CommitMode;
+
+/// Number of rows fetched per round trip by the lazy pending query.
+const QUERY_BATCH_SIZE: i32 = 1024;
+
+/// Bytes fetched per `substring()` round trip when streaming a bytea
+/// value; large enough to amortize the query overhead, small enough
+/// not to blow up the connection buffer on huge values.
+const BYTEA_CHUNK_SIZE: i64 = 4 * 1024 * 1024;
+
+/// How the `data` column stores the binary data.
+///
+/// Most Nice installations keep an oid referencing `pg_largeobject`
+/// there, but some store the bytes inline as a `bytea` value; the
+/// receiver streams either through the same hashing and upload
+/// pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataFormat {
+    /// oid of a large object in `pg_largeobject`
+    LargeObject,
+    /// the bytes inline, streamed in 4 MiB `substring()` chunks
+    Bytea,
+}
+
+/// SQL expression selecting the second column of the pending queries.
+///
+/// With inline bytea data there is no oid to report; a placeholder
+/// keeps the column layout identical and the data is streamed by hash
+/// in `open_data()`.
+fn data_expr(format: DataFormat) -> &'static str {
+    match format {
+        DataFormat::LargeObject => "data",
+        DataFormat::Bytea => "0::oid",
+    }
+}
+
+/// One pending object as reported by [`LoSource::each_pending()`].
+///
+/// Raw column values; hash validation and routing stay with the
+/// observer.
+///
+/// [`LoSource::each_pending()`]: trait.LoSource.html#tymethod.each_pending
+#[derive(Debug)]
+pub struct PendingObject {
+    /// sha1 hash identifying the row, as stored (hex encoded)
+    pub hash: String,
+    /// oid of the backing large object; 0 when the data is stored
+    /// inline as bytea
+    pub oid: u32,
+    /// object size in bytes
+    pub size: i64,
+    /// mime type recorded for the object
+    pub mime_type: String,
+    /// original filename, if the source tracks one
+    pub filename: Option<String>,
+    /// already recorded sha2 hash (hex encoded), set when the source
+    /// walks migrated rows for re-verification
+    pub sha2: Option<String>,
+}
+
+/// Row and byte counts reported by [`LoSource::totals()`].
+///
+/// [`LoSource::totals()`]: trait.LoSource.html#tymethod.totals
+#[derive(Debug, Clone, Copy)]
+pub struct SourceTotals {
+    /// all rows known to the source
+    pub count: u64,
+    /// sum of their sizes in bytes
+    pub bytes: u64,
+    /// rows still to be migrated
+    pub remaining: u64,
+    /// sum of the still-to-migrate sizes in bytes
+    pub remaining_bytes: u64,
+}
+
+/// Result of committing one chunk of hashes.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CommitOutcome {
+    /// hashes written back
+    pub committed: u64,
+    /// bytes of object data belonging to the committed hashes
+    pub bytes: u64,
+    /// objects that could not be committed, e.g. because the row is
+    /// gone or the sha2 hash was never computed
+    pub missing: u64,
+}
+
+/// Where the pipeline reads objects from and writes hashes back to.
+///
+/// Implemented by [`NiceBinarySource`] for the Nice2 schema; the worker
+/// threads only ever go through this trait, so a custom implementation
+/// plugs any other schema into the pipeline (see
+/// [`MigrationBuilder::source()`]).
+///
+/// [`NiceBinarySource`]: struct.NiceBinarySource.html
+/// [`MigrationBuilder::source()`]: ../migrate/struct.MigrationBuilder.html#method.source
+pub trait LoSource: Send + Sync {
+    /// Walk all objects still to be migrated, calling `f` once per
+    /// object. An error returned by `f` aborts the walk.
+    fn each_pending(&self,
+                    conn: &Connection,
+                    f: &mut FnMut(PendingObject) -> Result<()>)
+                    -> Result<()>;
+
+    /// Count total and still-to-migrate rows and bytes.
+    fn totals(&self, conn: &Connection) -> Result<SourceTotals>;
+
+    /// Open the object's data for streaming.
+    fn open_data<'a>(&self, trans: &'a Transaction, lo: &Lo) -> Result<Box<Read + 'a>>;
+
+    /// Write the sha2 hashes of `chunk` back in a single transaction.
+    ///
+    /// Objects whose row no longer exists or that carry no sha2 hash
+    /// must not fail the chunk; they are skipped and reported in
+    /// [`CommitOutcome::missing`].
+    ///
+    /// [`CommitOutcome::missing`]: struct.CommitOutcome.html#structfield.missing
+    fn commit_chunk(&self, conn: &Connection, chunk: &[Lo]) -> Result<CommitOutcome>;
+}
+
+/// Narrows which rows [`PendingLos`] yields.
+///
+/// All criteria default to "no restriction" and are combined with AND.
+///
+/// [`PendingLos`]: struct.PendingLos.html
+#[derive(Clone, Debug, Default)]
+pub struct PendingFilter {
+    /// only rows of at least this many bytes
+    pub min_size: Option<i64>,
+    /// only rows of at most this many bytes
+    pub max_size: Option<i64>,
+    /// only rows with exactly this mime type
+    pub mime_type: Option<String>,
+}
+
+/// Fallible iterator over the `_nice_binary` rows awaiting migration.
+///
+/// Decoupled from the queue and thread machinery, so scripts can
+/// enumerate, sample or audit pending work programmatically. Rows are
+/// fetched in batches, keyed on the `hash` primary key in ascending
+/// order; [`position()`] reports the hash last yielded and
+/// [`resume_after()`] continues from one, so a long audit can be
+/// resumed after an interruption.
+///
+/// Pending means `sha2 IS NULL`, matching what a [`CommitMode::Direct`]
+/// run would migrate.
+///
+/// [`position()`]: #method.position
+/// [`resume_after()`]: #method.resume_after
+/// [`CommitMode::Direct`]: ../thread/enum.CommitMode.html
+pub struct PendingLos<'a> {
+    conn: &'a Connection,
+    filter: PendingFilter,
+    data_format: DataFormat,
+    batch: VecDeque<PendingObject>,
+    /// hash of the last row yielded; batches continue after it
+    position: Option<String>,
+    batch_size: i64,
+    exhausted: bool,
+}
+
+impl<'a> PendingLos<'a> {
+    pub fn new(conn: &'a Connection, filter: PendingFilter) -> Self {
+        PendingLos {
+            conn: conn,
+            filter: filter,
+            data_format: DataFormat::LargeObject,
+            batch: VecDeque::new(),
+            position: None,
+            batch_size: i64::from(QUERY_BATCH_SIZE),
+            exhausted: false,
+        }
+    }
+
+    /// Skip all rows up to and including the one with this hash, e.g.
+    /// the [`position()`] of an earlier, interrupted walk.
+    ///
+    /// [`position()`]: #method.position
+    pub fn resume_after(mut self, hash: &str) -> Self {
+        self.position = Some(hash.to_string());
+        self
+    }
+
+    /// How the `data` column stores the binary data; see
+    /// [`DataFormat`].
+    ///
+    /// [`DataFormat`]: enum.DataFormat.html
+    pub fn with_data_format(mut self, format: DataFormat) -> Self {
+        self.data_format = format;
+        self
+    }
+
+    /// Rows fetched per round trip.
+    pub fn with_batch_size(mut self, batch_size: i64) -> Self {
+        assert!(batch_size > 0, "batch size must be positive");
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Hash of the last row yielded, to [`resume_after()`] later.
+    ///
+    /// [`resume_after()`]: #method.resume_after
+    pub fn position(&self) -> Option<&str> {
+        self.position.as_ref().map(|hash| &hash[..])
+    }
+
+    fn fetch_batch(&mut self) -> Result<()> {
+        let mut query = format!("SELECT hash, {}, size, mime_type FROM _nice_binary \
+                                 WHERE sha2 IS NULL",
+                                data_expr(self.data_format));
+        let mut params: Vec<&ToSql> = Vec::new();
+        if let Some(ref position) = self.position {
+            params.push(position);
+            query.push_str(&format!(" AND hash > ${}", params.len()));
+        }
+        if let Some(ref min_size) = self.filter.min_size {
+            params.push(min_size);
+            query.push_str(&format!(" AND size >= ${}", params.len()));
+        }
+        if let Some(ref max_size) = self.filter.max_size {
+            params.push(max_size);
+            query.push_str(&format!(" AND size <= ${}", params.len()));
+        }
+        if let Some(ref mime_type) = self.filter.mime_type {
+            params.push(mime_type);
+            query.push_str(&format!(" AND mime_type = ${}", params.len()));
+        }
+        params.push(&self.batch_size);
+        query.push_str(&format!(" ORDER BY hash LIMIT ${}", params.len()));
+
+        let rows = self.conn.query(&query, &params)?;
+        for row in &rows {
+            let mime_type: Option<String> = row.get(3);
+            self.batch.push_back(PendingObject {
+                                     hash: row.get(0),
+                                     oid: row.get(1),
+                                     size: row.get(2),
+                                     mime_type: mime_type.unwrap_or_default(),
+                                     filename: None,
+                                     sha2: None,
+                                 });
+        }
+        // a short batch means there is nothing beyond it
+        self.exhausted = (rows.len() as i64) < self.batch_size;
+        Ok(())
+    }
+}
+
+impl<'a> FallibleIterator for PendingLos<'a> {
+    type Item = PendingObject;
+    type Error = MigrationError;
+
+    fn next(&mut self) -> Result<Option<PendingObject>> {
+        if self.batch.is_empty() && !self.exhausted {
+            self.fetch_batch()?;
+        }
+        match self.batch.pop_front() {
+            Some(pending) => {
+                self.position = Some(pending.hash.clone());
+                Ok(Some(pending))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// [`LoSource`] for the Nice2 `_nice_binary` table.
+///
+/// [`LoSource`]: trait.LoSource.html
+#[derive(Debug, Clone)]
+pub struct NiceBinarySource {
+    mode: CommitMode,
+    filename_column: Option<String>,
+    reverify: bool,
+    store_version_ids: bool,
+    copy_commit: bool,
+    data_format: DataFormat,
+}
+
+impl NiceBinarySource {
+    pub fn new() -> Self {
+        NiceBinarySource {
+            mode: CommitMode::Direct,
+            filename_column: None,
+            reverify: false,
+            store_version_ids: false,
+            copy_commit: false,
+            data_format: DataFormat::LargeObject,
+        }
+    }
+
+    /// How the `data` column stores the binary data; see
+    /// [`DataFormat`].
+    ///
+    /// [`DataFormat`]: enum.DataFormat.html
+    pub fn with_data_format(mut self, format: DataFormat) -> Self {
+        self.data_format = format;
+        self
+    }
+
+    /// How hashes are written back, see [`CommitMode`]. The pending
+    /// query matches, so rows whose hash already sits in the mapping
+    /// table are not migrated again on resume.
+    ///
+    /// [`CommitMode`]: ../thread/enum.CommitMode.html
+    pub fn with_mode(mut self, mode: CommitMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Also walk rows that already carry a sha2 hash, reporting the
+    /// hash in [`PendingObject::sha2`], so a verifier can check their
+    /// bucket copy; see [`Verifier`]. Only supported with
+    /// [`CommitMode::Direct`].
+    ///
+    /// [`PendingObject::sha2`]: struct.PendingObject.html#structfield.sha2
+    /// [`Verifier`]: ../thread/struct.Verifier.html
+    /// [`CommitMode::Direct`]: ../thread/enum.CommitMode.html
+    pub fn with_reverify(mut self, reverify: bool) -> Self {
+        self.reverify = reverify;
+        self
+    }
+
+    /// Also write the version id a versioning-enabled bucket assigned
+    /// to each upload into the `s3_version_id` column, so a later
+    /// restore can reference the exact uploaded version; see
+    /// [`add_version_id_column`]. Only supported with
+    /// [`CommitMode::Direct`].
+    ///
+    /// [`add_version_id_column`]: ../db/fn.add_version_id_column.html
+    /// [`CommitMode::Direct`]: ../thread/enum.CommitMode.html
+    pub fn with_version_ids(mut self, store: bool) -> Self {
+        self.store_version_ids = store;
+        self
+    }
+
+    /// Commit chunks by staging the hashes into a temporary table via
+    /// `COPY` and applying one joined `UPDATE`, instead of one
+    /// `UPDATE` per object. Markedly faster on tables with hundreds of
+    /// millions of rows, but only pays off with large commit chunks.
+    /// Only supported with [`CommitMode::Direct`].
+    ///
+    /// [`CommitMode::Direct`]: ../thread/enum.CommitMode.html
+    pub fn with_copy_commit(mut self, copy: bool) -> Self {
+        self.copy_commit = copy;
+        self
+    }
+
+    /// Also select the original filename from `column` so the storers
+    /// can set a `Content-Disposition` header on the uploaded objects.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `column` is not a plain lowercase identifier; the name
+    /// is interpolated into the query.
+    pub fn with_filename_column(mut self, column: Option<String>) -> Self {
+        if let Some(ref column) = column {
+            assert!(!column.is_empty() &&
+                    column
+                        .bytes()
+                        .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_'),
+                    "filename column must be a plain lowercase identifier");
+        }
+        self.filename_column = column;
+        self
+    }
+
+    /// Commit one chunk by staging the hashes into a temporary table
+    /// via `COPY` and applying a single joined `UPDATE`.
+    ///
+    /// The per-row path pays one round trip and one index lookup per
+    /// object; staging the whole chunk first lets Postgres resolve the
+    /// join in one pass, which is what makes large chunks on tables
+    /// with hundreds of millions of rows fast.
+    fn commit_chunk_copy(&self, conn: &Connection, chunk: &[Lo]) -> Result<CommitOutcome> {
+        let trans = conn.transaction()?;
+        // ON COMMIT DROP keeps the staging table from leaking into
+        // later transactions on the same pooled connection
+        trans.execute("CREATE TEMPORARY TABLE _lo_migrate_commit \
+                       (hash text PRIMARY KEY, sha2 text NOT NULL, version_id text) \
+                       ON COMMIT DROP",
+                      &[])?;
+
+        let mut outcome = CommitOutcome::default();
+        let mut payload = String::new();
+        let mut staged = Vec::with_capacity(chunk.len());
+        for lo in chunk {
+            // an object that reached the committer without a sha2 hash
+            // is a bug upstream, but must not take the whole chunk (or
+            // an embedding application) down with a panic
+            let sha2 = match lo.sha2_hex() {
+                Some(sha2) => sha2,
+                None => {
+                    warn!("object with hash {} has no sha2 hash, not committed",
+                          lo.sha1_hex());
+                    outcome.missing += 1;
+                    continue;
+                }
+            };
+            payload.push_str(&lo.sha1_hex());
+            payload.push('\t');
+            payload.push_str(&sha2);
+            payload.push('\t');
+            // hex hashes need no escaping; version ids are S3-issued
+            // and URL safe
+            match lo.version_id() {
+                Some(version) if self.store_version_ids => payload.push_str(version),
+                _ => payload.push_str("\\N"),
+            }
+            payload.push('\n');
+            staged.push(lo);
+        }
+
+        if !staged.is_empty() {
+            let stmt = trans.prepare("COPY _lo_migrate_commit (hash, sha2, version_id) \
+                                      FROM STDIN")?;
+            stmt.copy_in(&[], &mut payload.as_bytes())?;
+            let update = if self.store_version_ids {
+                "UPDATE _nice_binary b SET sha2 = s.sha2, s3_version_id = s.version_id \
+                 FROM _lo_migrate_commit s WHERE b.hash = s.hash"
+            } else {
+                "UPDATE _nice_binary b SET sha2 = s.sha2 \
+                 FROM _lo_migrate_commit s WHERE b.hash = s.hash"
+            };
+            let updated = trans.execute(update, &[])?;
+            if updated as usize == staged.len() {
+                for lo in &staged {
+                    outcome.committed += 1;
+                    outcome.bytes += lo.size() as u64;
+                }
+            } else {
+                // some rows vanished mid-migration; name them one by
+                // one like the per-row path does
+                let rows = trans.query("SELECT hash FROM _lo_migrate_commit s \
+                                        WHERE NOT EXISTS (SELECT 1 FROM _nice_binary b \
+                                                          WHERE b.hash = s.hash)",
+                                       &[])?;
+                let missing: HashSet<String> = rows.iter().map(|row| row.get(0)).collect();
+                for lo in &staged {
+                    if missing.contains(&lo.sha1_hex()) {
+                        warn!("row with hash {} no longer exists, sha2 not committed",
+                              lo.sha1_hex());
+                        outcome.missing += 1;
+                    } else {
+                        outcome.committed += 1;
+                        outcome.bytes += lo.size() as u64;
+                    }
+                }
+            }
+        }
+
+        trans.commit()?;
+        Ok(outcome)
+    }
+}
+
+impl Default for NiceBinarySource {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LoSource for NiceBinarySource {
+    fn each_pending(&self,
+                    conn: &Connection,
+                    f: &mut FnMut(PendingObject) -> Result<()>)
+                    -> Result<()> {
+        let trans = conn.transaction()?;
+        let filename = match self.filename_column {
+            Some(ref column) => format!(", {}", column),
+            None => String::new(),
+        };
+        let data = data_expr(self.data_format);
+        let query = match (self.mode, self.reverify) {
+            (CommitMode::Direct, false) => {
+                format!("SELECT hash, {}, size, mime_type{} FROM _nice_binary \
+                         WHERE sha2 IS NULL",
+                        data,
+                        filename)
+            }
+            // in re-verify mode already migrated rows are walked too,
+            // with their sha2 hash so the verifier knows the bucket key
+            (CommitMode::Direct, true) => {
+                format!("SELECT hash, {}, size, mime_type{}, sha2 FROM _nice_binary",
+                        data,
+                        filename)
+            }
+            (CommitMode::MappingTable, false) => {
+                format!("SELECT hash, {}, size, mime_type{} FROM _nice_binary b \
+                         WHERE b.sha2 IS NULL \
+                         AND NOT EXISTS (SELECT 1 FROM _nice_binary_s3 m WHERE m.hash = b.hash)",
+                        data,
+                        filename)
+            }
+            (CommitMode::MappingTable, true) => {
+                return Err(ErrorKind::Config("re-verify is not supported with the mapping \
+                                              table commit mode"
+                                                     .to_string())
+                                   .into())
+            }
+        };
+        let stmt = trans.prepare(&query)?;
+        let rows = stmt.lazy_query(&trans, &[], QUERY_BATCH_SIZE)?;
+
+        let sha2_column = if self.filename_column.is_some() { 5 } else { 4 };
+        for row in rows.iterator() {
+            let row = row?;
+            let mime_type: Option<String> = row.get(3);
+            f(PendingObject {
+                  hash: row.get(0),
+                  oid: row.get(1),
+                  size: row.get(2),
+                  mime_type: mime_type.unwrap_or_default(),
+                  filename: if self.filename_column.is_some() {
+                      row.get(4)
+                  } else {
+                      None
+                  },
+                  sha2: if self.reverify {
+                      row.get(sha2_column)
+                  } else {
+                      None
+                  },
+              })?;
+        }
+        Ok(())
+    }
+
+    fn totals(&self, conn: &Connection) -> Result<SourceTotals> {
+        let rows = conn.query("SELECT count(*), \
+                                      coalesce(sum(size), 0)::bigint, \
+                                      count(*) FILTER (WHERE sha2 IS NULL), \
+                                      coalesce(sum(size) FILTER (WHERE sha2 IS NULL), 0)::bigint \
+                               FROM _nice_binary",
+                              &[])?;
+        let row = rows.get(0);
+        Ok(SourceTotals {
+               count: row.get::<_, i64>(0) as u64,
+               bytes: row.get::<_, i64>(1) as u64,
+               remaining: row.get::<_, i64>(2) as u64,
+               remaining_bytes: row.get::<_, i64>(3) as u64,
+           })
+    }
+
+    fn open_data<'a>(&self, trans: &'a Transaction, lo: &Lo) -> Result<Box<Read + 'a>> {
+        match self.data_format {
+            DataFormat::LargeObject => {
+                let large_object = trans.open_large_object(lo.oid(), Mode::Read)?;
+                Ok(Box::new(large_object))
+            }
+            DataFormat::Bytea => Ok(Box::new(ByteaReader::new(trans, lo.sha1_hex()))),
+        }
+    }
+
+    fn commit_chunk(&self, conn: &Connection, chunk: &[Lo]) -> Result<CommitOutcome> {
+        if self.copy_commit {
+            if self.mode != CommitMode::Direct {
+                return Err(ErrorKind::Config("the COPY commit strategy is only supported \
+                                              with the direct commit mode"
+                                                     .to_string())
+                                   .into());
+            }
+            return self.commit_chunk_copy(conn, chunk);
+        }
+        let trans = conn.transaction()?;
+        let stmt = match (self.mode, self.store_version_ids) {
+            (CommitMode::Direct, false) => {
+                trans.prepare("UPDATE _nice_binary SET sha2 = $1 WHERE hash = $2")?
+            }
+            (CommitMode::Direct, true) => {
+                trans.prepare("UPDATE _nice_binary SET sha2 = $1, s3_version_id = $2 \
+                               WHERE hash = $3")?
+            }
+            (CommitMode::MappingTable, true) => {
+                return Err(ErrorKind::Config("version ids are not supported with the \
+                                              mapping table commit mode"
+                                                     .to_string())
+                                   .into())
+            }
+            (CommitMode::MappingTable, false) => {
+                // the key column repeats the S3 object key so the merge
+                // window does not depend on knowing the key scheme
+                trans.prepare(
+                    "INSERT INTO _nice_binary_s3 (sha2, hash, key) VALUES ($1, $2, $1) \
+                     ON CONFLICT (hash) DO UPDATE SET sha2 = excluded.sha2, key = excluded.key",
+                )?
+            }
+        };
+
+        let mut outcome = CommitOutcome::default();
+        for lo in chunk {
+            // an object that reached the committer without a sha2 hash
+            // is a bug upstream, but must not take the whole chunk (or
+            // an embedding application) down with a panic
+            let sha2 = match lo.sha2_hex() {
+                Some(sha2) => sha2,
+                None => {
+                    warn!("object with hash {} has no sha2 hash, not committed",
+                          lo.sha1_hex());
+                    outcome.missing += 1;
+                    continue;
+                }
+            };
+            let updated = if self.store_version_ids {
+                stmt.execute(&[&sha2, &lo.version_id(), &lo.sha1_hex()])?
+            } else {
+                stmt.execute(&[&sha2, &lo.sha1_hex()])?
+            };
+            if updated == 1 {
+                outcome.committed += 1;
+                outcome.bytes += lo.size() as u64;
+            } else {
+                warn!("row with hash {} no longer exists, sha2 not committed",
+                      lo.sha1_hex());
+                outcome.missing += 1;
+            }
+        }
+
+        trans.commit()?;
+        Ok(outcome)
+    }
+}
+
+/// [`LoSource`] enumerating oids straight from `pg_largeobject_metadata`.
+///
+/// An expert repair mode for databases whose `_nice_binary`
+/// bookkeeping is incomplete or corrupted: every large object in the
+/// database is hashed and uploaded, bookkept or not, and nothing is
+/// written back to any table. The upload journal is the only output;
+/// its entries are keyed by the oid zero-padded to sha1 width, making
+/// the journal an oid -> sha2 manifest of everything uploaded (and a
+/// resume manifest for a rerun, as usual).
+///
+/// Sizes are summed from the `pg_largeobject` pages, which only a
+/// superuser may read.
+///
+/// [`LoSource`]: trait.LoSource.html
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PgLargeObjectSource;
+
+impl PgLargeObjectSource {
+    pub fn new() -> Self {
+        PgLargeObjectSource
+    }
+}
+
+impl LoSource for PgLargeObjectSource {
+    fn each_pending(&self,
+                    conn: &Connection,
+                    f: &mut FnMut(PendingObject) -> Result<()>)
+                    -> Result<()> {
+        let trans = conn.transaction()?;
+        let stmt = trans.prepare("SELECT m.oid, coalesce(sum(octet_length(l.data)), 0)::bigint \
+                                  FROM pg_largeobject_metadata m \
+                                  LEFT JOIN pg_largeobject l ON l.loid = m.oid \
+                                  GROUP BY m.oid ORDER BY m.oid")?;
+        let rows = stmt.lazy_query(&trans, &[], QUERY_BATCH_SIZE)?;
+
+        for row in rows.iterator() {
+            let row = row?;
+            let oid: u32 = row.get(0);
+            f(PendingObject {
+                  // no bookkeeping row, no sha1 hash; the zero-padded
+                  // oid stands in so journal entries are keyed by oid
+                  hash: format!("{:040x}", oid),
+                  oid: oid,
+                  size: row.get(1),
+                  mime_type: String::new(),
+                  filename: None,
+                  sha2: None,
+              })?;
+        }
+        Ok(())
+    }
+
+    fn totals(&self, conn: &Connection) -> Result<SourceTotals> {
+        let rows =
+            conn.query("SELECT (SELECT count(*) FROM pg_largeobject_metadata), \
+                               (SELECT coalesce(sum(octet_length(data)), 0)::bigint \
+                                FROM pg_largeobject)",
+                       &[])?;
+        let row = rows.get(0);
+        let count = row.get::<_, i64>(0) as u64;
+        let bytes = row.get::<_, i64>(1) as u64;
+        Ok(SourceTotals {
+               count: count,
+               bytes: bytes,
+               // without bookkeeping every object counts as pending;
+               // reruns skip uploaded ones via the resume manifest
+               remaining: count,
+               remaining_bytes: bytes,
+           })
+    }
+
+    fn open_data<'a>(&self, trans: &'a Transaction, lo: &Lo) -> Result<Box<Read + 'a>> {
+        let large_object = trans.open_large_object(lo.oid(), Mode::Read)?;
+        Ok(Box::new(large_object))
+    }
+
+    fn commit_chunk(&self, _conn: &Connection, chunk: &[Lo]) -> Result<CommitOutcome> {
+        // there is no bookkeeping row to update; the upload journal is
+        // the authoritative record of what reached the bucket
+        let mut outcome = CommitOutcome::default();
+        for lo in chunk {
+            if lo.sha2().is_some() {
+                outcome.committed += 1;
+                outcome.bytes += lo.size() as u64;
+            } else {
+                outcome.missing += 1;
+            }
+        }
+        Ok(outcome)
+    }
+}
+
+/// Streams a bytea `data` value out of `_nice_binary`.
+///
+/// The value is fetched in `substring()` chunks instead of one query
+/// result, so a multi-gigabyte value never sits in the connection
+/// buffer in full; bytea values are capped at 1 GB, so the 1-based
+/// `int4` substring offsets cannot overflow.
+struct ByteaReader<'a, 'conn: 'a> {
+    trans: &'a Transaction<'conn>,
+    hash: String,
+    /// bytes of the value already fetched
+    offset: i64,
+    buffer: Vec<u8>,
+    /// read position within `buffer`
+    pos: usize,
+    exhausted: bool,
+}
+
+impl<'a, 'conn: 'a> ByteaReader<'a, 'conn> {
+    fn new(trans: &'a Transaction<'conn>, hash: String) -> Self {
+        ByteaReader {
+            trans: trans,
+            hash: hash,
+            offset: 0,
+            buffer: Vec::new(),
+            pos: 0,
+            exhausted: false,
+        }
+    }
+
+    fn fetch_chunk(&mut self) -> io::Result<()> {
+        let from = (self.offset + 1) as i32;
+        let length = BYTEA_CHUNK_SIZE as i32;
+        let rows = self.trans
+            .query("SELECT substring(data from $1 for $2) FROM _nice_binary WHERE hash = $3",
+                   &[&from, &length, &self.hash])
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+        if rows.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                                      format!("row with hash {} vanished while its data \
+                                               was being streamed",
+                                              self.hash)));
+        }
+        let chunk: Option<Vec<u8>> = rows.get(0).get(0);
+        self.buffer = chunk.unwrap_or_default();
+        self.pos = 0;
+        self.offset += self.buffer.len() as i64;
+        self.exhausted = (self.buffer.len() as i64) < BYTEA_CHUNK_SIZE;
+        Ok(())
+    }
+}
+
+impl<'a, 'conn: 'a> Read for ByteaReader<'a, 'conn> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buffer.len() && !self.exhausted {
+            self.fetch_chunk()?;
+        }
+        let available = &self.buffer[self.pos..];
+        let count = available.len().min(buf.len());
+        buf[..count].copy_from_slice(&available[..count]);
+        self.pos += count;
+        Ok(count)
+    }
+}
+
+/// Bounded channel connecting the fetch thread of a
+/// [`PrefetchedSource`] to the observer, sized to hold one cursor
+/// page.
+///
+/// [`PrefetchedSource`]: struct.PrefetchedSource.html
+pub fn prefetch_channel() -> (SyncSender<PendingObject>, Receiver<PendingObject>) {
+    sync_channel(QUERY_BATCH_SIZE as usize)
+}
+
+/// [`LoSource`] replaying rows fetched one page ahead by another
+/// thread.
+///
+/// The pending query of the wrapped source runs on a dedicated thread
+/// with its own connection, pushing every row into a bounded
+/// [`prefetch_channel()`]; this source's `each_pending()` drains that
+/// channel, ignoring its connection argument. While the observer
+/// processes one page the fetch thread already sits in the next cursor
+/// round trip, so on remote databases that latency no longer leaves
+/// the receive queue empty between pages. All other operations go
+/// straight to the wrapped source.
+///
+/// [`LoSource`]: trait.LoSource.html
+/// [`prefetch_channel()`]: fn.prefetch_channel.html
+pub struct PrefetchedSource {
+    inner: Arc<LoSource>,
+    rows: Mutex<Option<Receiver<PendingObject>>>,
+}
+
+impl PrefetchedSource {
+    pub fn new(inner: Arc<LoSource>, rows: Receiver<PendingObject>) -> Self {
+        PrefetchedSource {
+            inner: inner,
+            rows: Mutex::new(Some(rows)),
+        }
+    }
+}
+
+impl LoSource for PrefetchedSource {
+    fn each_pending(&self,
+                    _conn: &Connection,
+                    f: &mut FnMut(PendingObject) -> Result<()>)
+                    -> Result<()> {
+        let rows = self.rows
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+            .expect("a prefetched source can only be walked once");
+        // the iteration ends once the fetch thread is done and its
+        // sender is gone
+        for pending in rows {
+            f(pending)?;
+        }
+        Ok(())
+    }
+
+    fn totals(&self, conn: &Connection) -> Result<SourceTotals> {
+        self.inner.totals(conn)
+    }
+
+    fn open_data<'a>(&self, trans: &'a Transaction, lo: &Lo) -> Result<Box<Read + 'a>> {
+        self.inner.open_data(trans, lo)
+    }
+
+    fn commit_chunk(&self, conn: &Connection, chunk: &[Lo]) -> Result<CommitOutcome> {
+        self.inner.commit_chunk(conn, chunk)
+    }
+}
@@ -0,0 +1,329 @@
+//! Management of the temporary buffer files.
+//!
+//! Receivers buffer large objects in `lo_migrate*` temporary files and
+//! the spilling queue writes `lo_migrate_spill*` overflow files. Both
+//! are removed when their object leaves the pipeline — but a crashed
+//! or killed run leaves them behind, and on a long migration they can
+//! fill up `/tmp`. [`sweep_orphaned_buffers()`] removes such leftovers,
+//! guarded by the naming convention and a minimum age so it never
+//! touches the buffers of a run that is still alive.
+//!
+//! [`sweep_orphaned_buffers()`]: fn.sweep_orphaned_buffers.html
+
+use error::Result;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Prefix shared by all buffer files this crate creates.
+pub const TEMP_PREFIX: &str = "lo_migrate";
+
+/// The file name prefix a worker's buffer files are created with.
+///
+/// Starts with [`TEMP_PREFIX`] and carries the run ID and thread name
+/// when known, e.g. `lo_migrate_r42_receiver_0_` — so the buffers of
+/// concurrent runs on the same host can be told apart at a glance and
+/// a leftover file names the run it belonged to.
+///
+/// [`TEMP_PREFIX`]: constant.TEMP_PREFIX.html
+pub fn buffer_prefix(run_id: Option<i64>, thread: Option<&str>) -> String {
+    let mut prefix = TEMP_PREFIX.to_string();
+    if let Some(run_id) = run_id {
+        prefix.push_str(&format!("_r{}", run_id));
+    }
+    if let Some(thread) = thread {
+        prefix.push('_');
+        prefix.push_str(thread);
+    }
+    prefix.push('_');
+    prefix
+}
+
+/// Tracks the buffer files a running migration currently owns.
+///
+/// Receivers [`register()`] every file-backed buffer they create and
+/// storers [`release()`] it once the object has left the stage, so the
+/// registry always names the files that are legitimately on disk.
+/// [`sweep_orphaned_buffers()`] skips registered files, and
+/// [`remove_remaining()`] is called once the pipeline has shut down —
+/// normally a no-op, after a cancellation it mops up whatever the
+/// dropped queues left behind.
+///
+/// [`register()`]: #method.register
+/// [`release()`]: #method.release
+/// [`remove_remaining()`]: #method.remove_remaining
+/// [`sweep_orphaned_buffers()`]: fn.sweep_orphaned_buffers.html
+#[derive(Debug, Default)]
+pub struct BufferRegistry {
+    files: Mutex<HashSet<PathBuf>>,
+}
+
+impl BufferRegistry {
+    pub fn new() -> Self {
+        BufferRegistry::default()
+    }
+
+    /// Record `path` as a live buffer file of this run.
+    pub fn register(&self, path: &Path) {
+        self.files
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(path.to_path_buf());
+    }
+
+    /// Forget `path`; its object has left the pipeline and the file is
+    /// gone.
+    pub fn release(&self, path: &Path) {
+        self.files
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(path);
+    }
+
+    /// Whether `path` is a live buffer file of this run.
+    pub fn is_tracked(&self, path: &Path) -> bool {
+        self.files
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains(path)
+    }
+
+    /// Number of currently tracked buffer files.
+    pub fn tracked(&self) -> usize {
+        self.files.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    /// Remove any still-registered files from disk and clear the
+    /// registry, returning how many files were actually removed.
+    ///
+    /// Files already gone (the common case — dropped buffers delete
+    /// themselves) are just forgotten; removal failures are logged and
+    /// skipped like in the sweep.
+    pub fn remove_remaining(&self) -> u64 {
+        let mut files = self.files.lock().unwrap_or_else(|e| e.into_inner());
+        let mut removed = 0;
+        for path in files.drain() {
+            match fs::remove_file(&path) {
+                Ok(()) => {
+                    debug!("removed leftover buffer file {:?}", path);
+                    removed += 1;
+                }
+                Err(ref err) if err.kind() == ::std::io::ErrorKind::NotFound => (),
+                Err(err) => warn!("cannot remove buffer file {:?}: {}", path, err),
+            }
+        }
+        removed
+    }
+}
+
+/// Free space in bytes available to unprivileged users on the
+/// filesystem holding `dir`, or `None` where the platform offers no
+/// way to ask (the [`TempSpaceGuard`] is a no-op there).
+///
+/// [`TempSpaceGuard`]: struct.TempSpaceGuard.html
+#[cfg(unix)]
+pub fn free_space(dir: &Path) -> Result<Option<u64>> {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte"))?;
+    let mut stat: ::libc::statvfs = unsafe { ::std::mem::zeroed() };
+    if unsafe { ::libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+}
+
+/// Free space in bytes available on the filesystem holding `dir`;
+/// always `None` on this platform.
+#[cfg(not(unix))]
+pub fn free_space(_dir: &Path) -> Result<Option<u64>> {
+    Ok(None)
+}
+
+/// Pauses receivers while free space in the buffer directory is low.
+///
+/// Without a guard the pipeline keeps buffering until `ENOSPC` kills a
+/// receiver mid-object; queues far upstream of the disk mean that can
+/// happen long after the problem started. Receivers configured with a
+/// guard (see [`Receiver::with_temp_space_guard()`]) check it before
+/// buffering an object to disk and wait until space is available
+/// again — storers keep draining existing buffers meanwhile, which is
+/// exactly what frees the space up.
+///
+/// [`Receiver::with_temp_space_guard()`]: ../thread/struct.Receiver.html#method.with_temp_space_guard
+#[derive(Debug)]
+pub struct TempSpaceGuard {
+    dir: PathBuf,
+    min_free: u64,
+}
+
+impl TempSpaceGuard {
+    /// Guard `dir` (the system temp directory if `None`), requiring at
+    /// least `min_free` bytes before a new buffer file may be created.
+    pub fn new(dir: Option<&Path>, min_free: u64) -> Self {
+        TempSpaceGuard {
+            dir: dir.map_or_else(env::temp_dir, Path::to_path_buf),
+            min_free: min_free,
+        }
+    }
+
+    /// The configured minimum free space in bytes.
+    pub fn min_free(&self) -> u64 {
+        self.min_free
+    }
+
+    /// Whether a new buffer file may currently be created.
+    ///
+    /// On platforms where free space cannot be determined this is
+    /// always `true`.
+    pub fn has_space(&self) -> Result<bool> {
+        Ok(free_space(&self.dir)?.map_or(true, |free| free >= self.min_free))
+    }
+}
+
+/// Remove orphaned buffer files from `dir` (the system temp directory
+/// if `None`), returning how many were removed.
+///
+/// Only files whose name starts with [`TEMP_PREFIX`] and whose last
+/// modification is at least `min_age` ago are touched; a running
+/// migration writes its buffers continuously, so a generous age (hours)
+/// reliably separates leftovers from live buffers. Files registered in
+/// `registry` belong to this process and are never touched, whatever
+/// their age. Files that cannot be inspected or removed are skipped
+/// with a warning rather than failing the sweep.
+///
+/// [`TEMP_PREFIX`]: constant.TEMP_PREFIX.html
+pub fn sweep_orphaned_buffers(dir: Option<&Path>,
+                              min_age: Duration,
+                              registry: Option<&BufferRegistry>)
+                              -> Result<u64> {
+    let dir = match dir {
+        Some(dir) => dir.to_path_buf(),
+        None => env::temp_dir(),
+    };
+
+    let mut removed = 0;
+    for entry in fs::read_dir(&dir)? {
+        let entry = entry?;
+        match entry.file_name().to_str() {
+            Some(name) if name.starts_with(TEMP_PREFIX) => (),
+            _ => continue,
+        }
+        if registry.map_or(false, |registry| registry.is_tracked(&entry.path())) {
+            continue;
+        }
+
+        let stale = entry
+            .metadata()
+            .ok()
+            .and_then(|meta| if meta.is_file() {
+                          meta.modified().ok()
+                      } else {
+                          None
+                      })
+            .and_then(|modified| modified.elapsed().ok())
+            .map_or(false, |age| age >= min_age);
+        if !stale {
+            continue;
+        }
+
+        match fs::remove_file(entry.path()) {
+            Ok(()) => {
+                debug!("removed orphaned buffer file {:?}", entry.path());
+                removed += 1;
+            }
+            Err(err) => warn!("cannot remove buffer file {:?}: {}", entry.path(), err),
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+
+    /// Unique scratch directory per test, removed at the end.
+    fn scratch_dir(test: &str) -> ::std::path::PathBuf {
+        let dir = env::temp_dir().join(format!("lo_migrate_sweep_{}_{}",
+                                               ::std::process::id(),
+                                               test));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn only_our_stale_files_are_removed() {
+        let dir = scratch_dir("prefix");
+        File::create(dir.join("lo_migrateAbC123")).unwrap();
+        File::create(dir.join("lo_migrate_spillXyZ")).unwrap();
+        let mut other = File::create(dir.join("unrelated.txt")).unwrap();
+        other.write_all(b"keep me").unwrap();
+
+        let removed = sweep_orphaned_buffers(Some(&dir), Duration::from_secs(0), None).unwrap();
+        assert_eq!(removed, 2);
+        assert!(dir.join("unrelated.txt").exists());
+        assert!(!dir.join("lo_migrateAbC123").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn registered_files_survive_the_sweep() {
+        let dir = scratch_dir("registry");
+        File::create(dir.join("lo_migrate_r7_receiver_0_AbC")).unwrap();
+        File::create(dir.join("lo_migrateOrphan")).unwrap();
+
+        let registry = BufferRegistry::new();
+        registry.register(&dir.join("lo_migrate_r7_receiver_0_AbC"));
+        assert_eq!(registry.tracked(), 1);
+
+        let removed =
+            sweep_orphaned_buffers(Some(&dir), Duration::from_secs(0), Some(&registry)).unwrap();
+        assert_eq!(removed, 1);
+        assert!(dir.join("lo_migrate_r7_receiver_0_AbC").exists());
+
+        assert_eq!(registry.remove_remaining(), 1);
+        assert!(!dir.join("lo_migrate_r7_receiver_0_AbC").exists());
+        assert_eq!(registry.tracked(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn buffer_prefix_names_the_run_and_thread() {
+        assert_eq!(buffer_prefix(None, None), "lo_migrate_");
+        assert_eq!(buffer_prefix(Some(42), Some("receiver_0")),
+                   "lo_migrate_r42_receiver_0_");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn space_guard_reflects_the_threshold() {
+        // zero bytes are always free; all of them never are
+        assert!(TempSpaceGuard::new(None, 0).has_space().unwrap());
+        assert!(!TempSpaceGuard::new(None, u64::max_value())
+                     .has_space()
+                     .unwrap());
+        assert!(free_space(&env::temp_dir()).unwrap().is_some());
+    }
+
+    #[test]
+    fn young_files_survive_the_age_guard() {
+        let dir = scratch_dir("age");
+        File::create(dir.join("lo_migrateFresh")).unwrap();
+
+        let removed = sweep_orphaned_buffers(Some(&dir), Duration::from_secs(3600), None).unwrap();
+        assert_eq!(removed, 0);
+        assert!(dir.join("lo_migrateFresh").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
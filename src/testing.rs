@@ -0,0 +1,299 @@
+//! Test harness for integrations built on this crate.
+//!
+//! Enabled with the `testing` feature. Grew out of the ad-hoc helpers
+//! the crate's own integration tests use: a throwaway database per
+//! test, the `_nice_binary` schema, SQL fixtures and synthetic large
+//! objects of configurable sizes, so downstream users can test their
+//! own observers, sources and pipelines against a real Postgres.
+//!
+//! ```no_run
+//! use lo_migrate::testing::TestDb;
+//!
+//! let db = TestDb::from_env().unwrap();
+//! let conn = db.connect().unwrap();
+//! lo_migrate::testing::create_nice_binary_table(&conn).unwrap();
+//! lo_migrate::testing::insert_synthetic_binary(&conn, 64 * 1024, "image/png").unwrap();
+//! ```
+//!
+//! The database is dropped again when the [`TestDb`] goes out of
+//! scope.
+//!
+//! [`TestDb`]: struct.TestDb.html
+
+use error::{ErrorKind, Result};
+use lo::{Data, Lo};
+use postgres::{Connection, TlsMode};
+use sha1::{Digest, Sha1};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Environment variable naming the Postgres URL test databases are
+/// created through.
+pub const TEST_PG_ENV: &str = "LO_MIGRATE_TEST_PG";
+
+/// Distinguishes databases created by one process in the same second.
+static NEXT_DB: AtomicUsize = AtomicUsize::new(0);
+
+/// A throwaway database, created on demand and dropped with the value.
+///
+/// The user in the admin URL needs the `CREATEDB` privilege. Each
+/// `TestDb` gets a unique name, so tests touching different databases
+/// can run in parallel.
+pub struct TestDb {
+    admin_url: String,
+    url: String,
+    name: String,
+}
+
+impl TestDb {
+    /// Create a throwaway database via the server `admin_url` points
+    /// at; the database named in the URL is only used to issue the
+    /// `CREATE DATABASE`.
+    pub fn create(admin_url: &str) -> Result<Self> {
+        let name = format!("lo_migrate_test_{}_{}",
+                           ::std::process::id(),
+                           NEXT_DB.fetch_add(1, Ordering::Relaxed));
+        let conn = Connection::connect(admin_url, TlsMode::None)?;
+        // identifiers cannot be bound as parameters; the name is ours
+        // and contains no quoting hazards
+        conn.batch_execute(&format!("CREATE DATABASE {}", name))?;
+        Ok(TestDb {
+            admin_url: admin_url.to_string(),
+            url: replace_database(admin_url, &name),
+            name: name,
+        })
+    }
+
+    /// Create a throwaway database via the URL in `LO_MIGRATE_TEST_PG`.
+    pub fn from_env() -> Result<Self> {
+        let url = ::std::env::var(TEST_PG_ENV)
+            .map_err(|_| {
+                         ErrorKind::Config(format!("{} must point at a Postgres server \
+                                                    test databases may be created on",
+                                                   TEST_PG_ENV))
+                     })?;
+        Self::create(&url)
+    }
+
+    /// URL of the throwaway database, e.g. for
+    /// [`MigrationBuilder::postgres()`].
+    ///
+    /// [`MigrationBuilder::postgres()`]: ../migrate/struct.MigrationBuilder.html#method.postgres
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Name of the throwaway database.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Open a connection to the throwaway database.
+    pub fn connect(&self) -> Result<Connection> {
+        Ok(Connection::connect(&self.url[..], TlsMode::None)?)
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        let dropped = Connection::connect(&self.admin_url[..], TlsMode::None)
+            .and_then(|conn| conn.batch_execute(&format!("DROP DATABASE {}", self.name)));
+        if let Err(err) = dropped {
+            warn!("failed to drop test database {}: {}", self.name, err);
+        }
+    }
+}
+
+/// Swap the database name in a Postgres URL, keeping any parameters.
+fn replace_database(url: &str, name: &str) -> String {
+    // the database name is everything between the last `/` and a
+    // possible `?`; `postgres://host` without a path gets one appended
+    let start = match url.rfind('/') {
+        Some(pos) if !url[..pos].ends_with(':') && !url[..pos].ends_with('/') => pos,
+        _ => return format!("{}/{}", url, name),
+    };
+    match url[start..].find('?') {
+        Some(query) => format!("{}/{}{}", &url[..start], name, &url[start + query..]),
+        None => format!("{}/{}", &url[..start], name),
+    }
+}
+
+/// Create an empty `_nice_binary` table, dropping any previous one.
+pub fn create_nice_binary_table(conn: &Connection) -> Result<()> {
+    conn.batch_execute("DROP TABLE IF EXISTS _nice_binary;
+                        CREATE TABLE _nice_binary (
+                            hash varchar(40) PRIMARY KEY,
+                            data oid,
+                            size bigint NOT NULL,
+                            mime_type varchar(255) NOT NULL,
+                            sha2 varchar(64)
+                        );")?;
+    Ok(())
+}
+
+/// Run a SQL fixture, e.g. a schema dump or seed data.
+pub fn load_fixture(conn: &Connection, sql: &str) -> Result<()> {
+    conn.batch_execute(sql)?;
+    Ok(())
+}
+
+/// Run the SQL fixture stored at `path`.
+pub fn load_fixture_file<P: AsRef<::std::path::Path>>(conn: &Connection, path: P) -> Result<()> {
+    use std::io::Read;
+    let mut sql = String::new();
+    ::std::fs::File::open(path)?.read_to_string(&mut sql)?;
+    load_fixture(conn, &sql)
+}
+
+/// Insert a `_nice_binary` row backed by a real large object
+/// containing `data`.
+///
+/// Returns the sha1 hash used as `hash` column value.
+pub fn insert_binary(conn: &Connection, data: &[u8], mime_type: &str) -> Result<String> {
+    let rows = conn.query("SELECT lo_from_bytea(0, $1)", &[&data])?;
+    let oid: u32 = rows.get(0).get(0);
+
+    let hash = sha1_hex(data);
+    conn.execute("INSERT INTO _nice_binary (hash, data, size, mime_type) \
+                  VALUES ($1, $2, $3, $4)",
+                 &[&hash, &oid, &(data.len() as i64), &mime_type])?;
+    Ok(hash)
+}
+
+/// Insert a synthetic large object of exactly `size` bytes.
+///
+/// The data is deterministic for a given size but differs between
+/// sizes, so rows of different sizes never collide on their hash.
+/// Returns the sha1 hash used as `hash` column value.
+pub fn insert_synthetic_binary(conn: &Connection, size: usize, mime_type: &str) -> Result<String> {
+    insert_binary(conn, &synthetic_data(size), mime_type)
+}
+
+/// Deterministic pseudo-random bytes, seeded by the requested size.
+pub fn synthetic_data(size: usize) -> Vec<u8> {
+    xorshift_data(size as u64, size)
+}
+
+/// Deterministic pseudo-random bytes with an explicit seed, so objects
+/// of the same size can still differ.
+fn xorshift_data(seed: u64, size: usize) -> Vec<u8> {
+    // xorshift* keeps the data incompressible enough to be a realistic
+    // payload without pulling in a rand dependency
+    let mut state = seed ^ 0x2545_f491_4f6c_dd1d;
+    let mut data = Vec::with_capacity(size);
+    while data.len() < size {
+        state ^= state >> 12;
+        state ^= state << 25;
+        state ^= state >> 27;
+        let word = state.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        for shift in 0..8 {
+            if data.len() == size {
+                break;
+            }
+            data.push((word >> (shift * 8)) as u8);
+        }
+    }
+    data
+}
+
+/// Object sizes drawn by [`synthetic_lo()`].
+///
+/// [`synthetic_lo()`]: fn.synthetic_lo.html
+#[derive(Clone, Copy, Debug)]
+pub enum SizeDistribution {
+    /// every object has exactly this size
+    Constant(usize),
+    /// sizes spread deterministically but evenly over `min..=max`
+    Uniform { min: usize, max: usize },
+}
+
+impl SizeDistribution {
+    /// Size of the `n`-th object drawn from the distribution.
+    pub fn size(&self, n: usize) -> usize {
+        match *self {
+            SizeDistribution::Constant(size) => size,
+            SizeDistribution::Uniform { min, max } => {
+                assert!(min <= max);
+                let mut state = n as u64 ^ 0x2545_f491_4f6c_dd1d;
+                state ^= state >> 12;
+                state ^= state << 25;
+                state ^= state >> 27;
+                let word = state.wrapping_mul(0x2545_f491_4f6c_dd1d);
+                min + (word % (max - min + 1) as u64) as usize
+            }
+        }
+    }
+}
+
+/// Build the `n`-th synthetic [`Lo`], fully buffered in memory.
+///
+/// The object's size follows `dist`, its payload and sha1 hash are
+/// deterministic in `n` and distinct between objects. Useful to feed
+/// workers and queues directly, without a database, e.g. in
+/// benchmarks.
+///
+/// [`Lo`]: ../lo/struct.Lo.html
+pub fn synthetic_lo(n: usize, dist: SizeDistribution) -> Lo {
+    let size = dist.size(n);
+    let data = xorshift_data(n as u64, size);
+    let mut digest = Sha1::default();
+    digest.input(&data);
+    let mut lo = Lo::new(digest.result().to_vec(),
+                         n as u32 + 1,
+                         size as i64,
+                         "application/octet-stream".to_string());
+    lo.set_data(Data::Vec(data));
+    lo
+}
+
+/// Hex-encoded sha1 hash as stored in the `hash` column.
+pub fn sha1_hex(data: &[u8]) -> String {
+    let mut digest = Sha1::default();
+    digest.input(data);
+    ::hex::encode(digest.result())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn database_name_replacement() {
+        assert_eq!(replace_database("postgres://user@host/db", "test"),
+                   "postgres://user@host/test");
+        assert_eq!(replace_database("postgres://user@host:5432/db?sslmode=disable", "test"),
+                   "postgres://user@host:5432/test?sslmode=disable");
+        assert_eq!(replace_database("postgres://host", "test"),
+                   "postgres://host/test");
+    }
+
+    #[test]
+    fn synthetic_data_is_deterministic_per_size() {
+        assert_eq!(synthetic_data(100).len(), 100);
+        assert_eq!(synthetic_data(100), synthetic_data(100));
+        assert_ne!(synthetic_data(100)[..8], synthetic_data(101)[..8]);
+    }
+
+    #[test]
+    fn synthetic_los_are_distinct() {
+        let dist = SizeDistribution::Constant(64);
+        let a = synthetic_lo(0, dist);
+        let b = synthetic_lo(1, dist);
+        assert_eq!(a.size(), 64);
+        assert_ne!(a.sha1_hex(), b.sha1_hex());
+    }
+
+    #[test]
+    fn uniform_sizes_stay_in_range() {
+        let dist = SizeDistribution::Uniform { min: 10, max: 20 };
+        for n in 0..100 {
+            let size = dist.size(n);
+            assert!(size >= 10 && size <= 20);
+        }
+    }
+
+    #[test]
+    fn sha1_of_known_input() {
+        assert_eq!(sha1_hex(b"hello world"),
+                   "2aae6c35c94fcfb415dbe95f408b9ce91ee846ed");
+    }
+}
@@ -0,0 +1,246 @@
+//! Committer threads writing sha2 hashes back to `_nice_binary`.
+
+use audit::AuditLog;
+use error::{ErrorKind, MigrationError, Result, Stage};
+use events::EventStream;
+use lo::Lo;
+use metrics::{seconds, MetricsSink};
+use postgres::Connection;
+use postgres::error::{T_R_DEADLOCK_DETECTED, T_R_SERIALIZATION_FAILURE};
+use source::{LoSource, NiceBinarySource};
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use thread::ThreadStat;
+use queue::{RecvResult, WorkQueueReceiver};
+
+/// Interval at which an idle committer rechecks the cancellation flag.
+const RECV_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How often a chunk whose transaction was serialized away or deadlocked
+/// is retried before the committer gives up.
+const MAX_COMMIT_ATTEMPTS: u32 = 5;
+
+/// Backoff before the first retry; doubled on every further attempt.
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(100);
+
+/// Where committed hashes end up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommitMode {
+    /// write `sha2` straight into `_nice_binary`
+    Direct,
+    /// write (hash, sha2, key) into the `_nice_binary_s3` mapping table;
+    /// for operators who cannot touch the production table mid-migration,
+    /// applied to `_nice_binary` later by [`db::apply_mapping_table`]
+    ///
+    /// [`db::apply_mapping_table`]: ../db/fn.apply_mapping_table.html
+    MappingTable,
+}
+
+/// Writes the sha2 hashes of uploaded objects back to `_nice_binary` in
+/// chunks, one transaction per chunk.
+pub struct Committer<'a> {
+    conn: &'a Connection,
+    stats: &'a ThreadStat,
+    source: Arc<LoSource>,
+    metrics: Option<Arc<MetricsSink>>,
+    events: Option<Arc<EventStream>>,
+    audit_log: Option<Arc<AuditLog>>,
+}
+
+impl<'a> Committer<'a> {
+    pub fn new(conn: &'a Connection, stats: &'a ThreadStat) -> Self {
+        Committer {
+            conn: conn,
+            stats: stats,
+            source: Arc::new(NiceBinarySource::new()),
+            metrics: None,
+            events: None,
+            audit_log: None,
+        }
+    }
+
+    /// Record a `commit_seconds` histogram sample per committed chunk.
+    pub fn with_metrics(mut self, metrics: Option<Arc<MetricsSink>>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Publish one event per committed object to `events`; delivery is
+    /// advisory, failures are logged and do not fail the chunk.
+    pub fn with_events(mut self, events: Option<Arc<EventStream>>) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Append one line per committed object to the audit log; see
+    /// [`AuditLog`].
+    ///
+    /// [`AuditLog`]: ../audit/struct.AuditLog.html
+    pub fn with_audit_log(mut self, audit_log: Option<Arc<AuditLog>>) -> Self {
+        self.audit_log = audit_log;
+        self
+    }
+
+    /// Commit hashes through `source` instead of the default
+    /// `_nice_binary` UPDATE; see also [`CommitMode`].
+    ///
+    /// [`CommitMode`]: enum.CommitMode.html
+    pub fn with_source(mut self, source: Arc<LoSource>) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Commit objects from the commit queue until it disconnects.
+    ///
+    /// A chunk is committed once it holds `chunk_size` objects or once
+    /// `flush_timeout` has passed since its first object arrived,
+    /// whichever happens first. Without the timeout, hashes of objects
+    /// already uploaded to S3 could sit uncommitted for a long time when
+    /// the upstream stages are slow, enlarging the window of lost work
+    /// on a crash.
+    pub fn start_worker(&self,
+                        rx: Arc<WorkQueueReceiver<Lo>>,
+                        chunk_size: usize,
+                        flush_timeout: Duration)
+                        -> Result<u64> {
+        self.worker(rx, chunk_size, flush_timeout)
+            .map_err(|err| err.at(Stage::Commit))
+    }
+
+    fn worker(&self,
+              rx: Arc<WorkQueueReceiver<Lo>>,
+              chunk_size: usize,
+              flush_timeout: Duration)
+              -> Result<u64> {
+        let mut count = 0;
+        loop {
+            let chunk = self.receive_next_chunk(&*rx, chunk_size, flush_timeout)?;
+            if chunk.is_empty() {
+                break;
+            }
+            let started = Instant::now();
+            count += self.commit_chunk_with_retry(&chunk)?;
+            if let Some(ref metrics) = self.metrics {
+                metrics.histogram("commit_seconds", seconds(started.elapsed()));
+            }
+        }
+
+        debug!("committer done, {} hashes committed", count);
+        Ok(count)
+    }
+
+    /// Collect up to `chunk_size` objects from the queue, blocking until
+    /// the chunk is full, `flush_timeout` has passed since the chunk's
+    /// first object arrived, or the queue has disconnected.
+    fn receive_next_chunk(&self,
+                          rx: &WorkQueueReceiver<Lo>,
+                          chunk_size: usize,
+                          flush_timeout: Duration)
+                          -> Result<Vec<Lo>> {
+        let mut chunk = Vec::with_capacity(chunk_size);
+        let mut deadline = None;
+        while chunk.len() < chunk_size {
+            if self.stats.is_cancelled() {
+                let dropped = rx.drain().len();
+                if dropped > 0 {
+                    debug!("run cancelled, dropped {} queued objects", dropped);
+                }
+            }
+            self.stats.abort_if_cancelled()?;
+            if let Some(deadline) = deadline {
+                if Instant::now() >= deadline {
+                    debug!("flush timeout expired, committing {} objects early", chunk.len());
+                    break;
+                }
+            }
+            match rx.recv_timeout(RECV_TIMEOUT) {
+                RecvResult::Item(lo) => {
+                    if chunk.is_empty() {
+                        deadline = Some(Instant::now() + flush_timeout);
+                    }
+                    chunk.push(lo);
+                }
+                RecvResult::TimedOut => continue,
+                RecvResult::Disconnected => break,
+            }
+        }
+        Ok(chunk)
+    }
+
+    /// Commit one chunk, retrying serialization failures and deadlocks.
+    ///
+    /// The batch UPDATE can deadlock with concurrent application traffic
+    /// on `_nice_binary` (SQLSTATE 40001/40P01). Those transactions are
+    /// safe to simply run again, so back off exponentially and retry
+    /// instead of killing the committer thread.
+    fn commit_chunk_with_retry(&self, chunk: &[Lo]) -> Result<u64> {
+        let mut attempt = 1;
+        loop {
+            match self.commit_chunk(chunk) {
+                Err(ref err) if is_transient_conflict(err) &&
+                                attempt < MAX_COMMIT_ATTEMPTS => {
+                    let delay = RETRY_BASE_DELAY * (1 << (attempt - 1));
+                    warn!("commit of {} hashes conflicted with concurrent transaction ({}), \
+                           retrying in {:?} (attempt {}/{})",
+                          chunk.len(),
+                          err,
+                          delay,
+                          attempt,
+                          MAX_COMMIT_ATTEMPTS);
+                    sleep(delay);
+                    self.stats.abort_if_cancelled()?;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Write the sha2 hashes of one chunk through the source.
+    fn commit_chunk(&self, chunk: &[Lo]) -> Result<u64> {
+        let outcome = self.source.commit_chunk(self.conn, chunk)?;
+
+        // only count after the transaction went through, a rolled back
+        // (and possibly retried) chunk must not show up in the stats
+        self.stats.add_committed(outcome.committed);
+        self.stats.add_committed_bytes(outcome.bytes);
+        if let Some(lo) = chunk.last() {
+            self.stats.set_last_committed_hash(lo.sha1_hex());
+        }
+        for _ in 0..outcome.missing {
+            self.stats
+                .record_failure_raw("RowMissing",
+                                    Some(Stage::Commit),
+                                    None,
+                                    "row vanished or carried no sha2 hash at commit time"
+                                        .to_string());
+        }
+
+        if let Some(ref audit_log) = self.audit_log {
+            for lo in chunk {
+                audit_log.record(lo)?;
+            }
+        }
+
+        if let Some(ref events) = self.events {
+            for lo in chunk {
+                if let Err(err) = events.emit(lo, "committed") {
+                    warn!("event for object {} not delivered: {}", lo.sha1_hex(), err);
+                }
+            }
+        }
+        Ok(outcome.committed)
+    }
+}
+
+/// Whether the transaction failed in a way that is safe to retry.
+fn is_transient_conflict(err: &MigrationError) -> bool {
+    match *err.kind() {
+        ErrorKind::Postgres(ref err) => {
+            err.code() == Some(&T_R_SERIALIZATION_FAILURE) ||
+            err.code() == Some(&T_R_DEADLOCK_DETECTED)
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,221 @@
+//! Adaptive concurrency control for the worker threads.
+
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often the permit count is re-evaluated.
+const ADJUST_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Throughput regression treated as congestion: dropping below this
+/// fraction of the previous window's rate halves the permits even
+/// without errors.
+const REGRESSION_THRESHOLD: f64 = 0.8;
+
+/// Measurements taken over one adjustment window.
+#[derive(Debug)]
+struct GateState {
+    /// objects allowed in flight at the moment
+    permits: usize,
+    /// permits currently checked out
+    active: usize,
+    /// when the current measurement window started
+    window_started: Instant,
+    /// bytes successfully processed in the window
+    bytes: u64,
+    /// failures in the window
+    errors: u64,
+    /// bytes per second of the previous window, 0.0 before the first
+    last_rate: f64,
+}
+
+/// Limits how many objects one worker stage processes concurrently,
+/// adapting the limit to what the backends sustain.
+///
+/// Worker threads take a [`permit()`] per object and report how the
+/// object went; the gate adjusts the permit count with
+/// additive-increase/multiplicative-decrease, the scheme TCP uses for
+/// its congestion window: after every error-free window one more
+/// permit (up to the thread count), on errors or a clear throughput
+/// regression half the permits (down to one). An overloaded database
+/// or object store thus throttles the pipeline automatically instead
+/// of being hammered by every configured thread.
+///
+/// [`permit()`]: #method.permit
+#[derive(Debug)]
+pub struct ConcurrencyGate {
+    max_permits: usize,
+    adjust_interval: Duration,
+    state: Mutex<GateState>,
+    available: Condvar,
+}
+
+impl ConcurrencyGate {
+    /// Gate starting at and never exceeding `max_permits`, typically
+    /// the stage's thread count.
+    pub fn new(max_permits: usize) -> Self {
+        assert!(max_permits > 0, "at least one permit is needed");
+        ConcurrencyGate {
+            max_permits: max_permits,
+            adjust_interval: ADJUST_INTERVAL,
+            state: Mutex::new(GateState {
+                                  permits: max_permits,
+                                  active: 0,
+                                  window_started: Instant::now(),
+                                  bytes: 0,
+                                  errors: 0,
+                                  last_rate: 0.0,
+                              }),
+            available: Condvar::new(),
+        }
+    }
+
+    /// Re-evaluate the permits every `interval` instead of the 10 s
+    /// default; mainly useful in tests.
+    pub fn with_adjust_interval(mut self, interval: Duration) -> Self {
+        self.adjust_interval = interval;
+        self
+    }
+
+    /// Permits currently granted, between 1 and the configured maximum.
+    pub fn permits(&self) -> usize {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).permits
+    }
+
+    /// Take a permit, giving up after `timeout`.
+    ///
+    /// Callers loop around this with their cancellation check, like
+    /// the queue `recv_timeout()` loops. The permit is returned when
+    /// the guard drops.
+    pub fn permit(&self, timeout: Duration) -> Option<Permit> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        while state.active >= state.permits {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            let (next, _) = self.available
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(|e| e.into_inner());
+            state = next;
+        }
+        state.active += 1;
+        Some(Permit { gate: self })
+    }
+
+    /// Count `bytes` of successfully processed object data.
+    pub fn record_success(&self, bytes: u64) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.bytes += bytes;
+        self.adjust(&mut state);
+    }
+
+    /// Count one failed object.
+    pub fn record_error(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.errors += 1;
+        self.adjust(&mut state);
+    }
+
+    /// Apply AIMD once the current window is over.
+    fn adjust(&self, state: &mut GateState) {
+        let elapsed = state.window_started.elapsed();
+        if elapsed < self.adjust_interval {
+            return;
+        }
+        let rate = state.bytes as f64 /
+                   (elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9);
+
+        if state.errors > 0 || rate < state.last_rate * REGRESSION_THRESHOLD {
+            let halved = (state.permits / 2).max(1);
+            if halved < state.permits {
+                info!("backing off from {} to {} concurrent objects ({} errors, {:.0} B/s \
+                       after {:.0} B/s)",
+                      state.permits,
+                      halved,
+                      state.errors,
+                      rate,
+                      state.last_rate);
+                state.permits = halved;
+            }
+        } else if state.permits < self.max_permits {
+            state.permits += 1;
+            debug!("raising to {} concurrent objects ({:.0} B/s)", state.permits, rate);
+            self.available.notify_one();
+        }
+
+        state.last_rate = rate;
+        state.window_started = Instant::now();
+        state.bytes = 0;
+        state.errors = 0;
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.active -= 1;
+        self.available.notify_one();
+    }
+}
+
+/// A checked-out permit; returned to the gate on drop.
+#[derive(Debug)]
+pub struct Permit<'a> {
+    gate: &'a ConcurrencyGate,
+}
+
+impl<'a> Drop for Permit<'a> {
+    fn drop(&mut self) {
+        self.gate.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permits_bound_the_holders() {
+        let gate = ConcurrencyGate::new(2);
+        let first = gate.permit(Duration::from_millis(10)).unwrap();
+        let _second = gate.permit(Duration::from_millis(10)).unwrap();
+        assert!(gate.permit(Duration::from_millis(10)).is_none());
+
+        drop(first);
+        assert!(gate.permit(Duration::from_millis(10)).is_some());
+    }
+
+    #[test]
+    fn errors_halve_the_permits() {
+        let gate = ConcurrencyGate::new(8).with_adjust_interval(Duration::from_millis(0));
+        assert_eq!(gate.permits(), 8);
+        gate.record_error();
+        assert_eq!(gate.permits(), 4);
+        gate.record_error();
+        gate.record_error();
+        assert_eq!(gate.permits(), 1);
+        // the floor is one permit
+        gate.record_error();
+        assert_eq!(gate.permits(), 1);
+    }
+
+    #[test]
+    fn error_free_windows_raise_the_permits_additively() {
+        let gate = ConcurrencyGate::new(8).with_adjust_interval(Duration::from_millis(0));
+        gate.record_error();
+        gate.record_error();
+        assert_eq!(gate.permits(), 2);
+
+        gate.record_success(1024);
+        assert_eq!(gate.permits(), 3);
+        gate.record_success(1024);
+        assert_eq!(gate.permits(), 4);
+    }
+
+    #[test]
+    fn permits_never_exceed_the_maximum() {
+        let gate = ConcurrencyGate::new(2).with_adjust_interval(Duration::from_millis(0));
+        gate.record_success(1024);
+        gate.record_success(1024);
+        assert_eq!(gate.permits(), 2);
+    }
+}
@@ -0,0 +1,56 @@
+//! Counter thread determining how much work there is.
+
+use error::Result;
+use postgres::Connection;
+use source::{LoSource, NiceBinarySource};
+use std::sync::Arc;
+use thread::ThreadStat;
+
+/// Counts the rows known to the source so the monitor can report
+/// progress as a fraction of the whole table.
+///
+/// Counting hundreds of millions of rows can take a while, hence this
+/// runs in its own thread instead of delaying pipeline start.
+pub struct Counter<'a> {
+    conn: &'a Connection,
+    stats: &'a ThreadStat,
+    source: Arc<LoSource>,
+}
+
+impl<'a> Counter<'a> {
+    pub fn new(conn: &'a Connection, stats: &'a ThreadStat) -> Self {
+        Counter {
+            conn: conn,
+            stats: stats,
+            source: Arc::new(NiceBinarySource::new()),
+        }
+    }
+
+    /// Count `source` instead of the default `_nice_binary` table.
+    pub fn with_source(mut self, source: Arc<LoSource>) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Count total and still-to-migrate rows and bytes and publish them
+    /// in [`ThreadStat`].
+    ///
+    /// Row counts alone are a poor progress measure with object sizes
+    /// varying by five orders of magnitude, so the byte sums are what the
+    /// monitor bases progress and ETA on.
+    ///
+    /// [`ThreadStat`]: struct.ThreadStat.html
+    pub fn count_objects(&self) -> Result<()> {
+        let totals = self.source.totals(self.conn)?;
+        self.stats.set_lo_total(totals.count);
+        self.stats.set_bytes_total(totals.bytes);
+        self.stats.set_lo_remaining(totals.remaining);
+        self.stats.set_bytes_remaining(totals.remaining_bytes);
+        info!("{} of {} objects ({} of {} bytes) still need to be migrated",
+              totals.remaining,
+              totals.count,
+              totals.remaining_bytes,
+              totals.bytes);
+        Ok(())
+    }
+}
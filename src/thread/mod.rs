@@ -0,0 +1,566 @@
+//! Worker threads making up the migration pipeline.
+
+use error::{ErrorKind, MigrationError, Result, Stage};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::panic;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Instant;
+
+mod commit;
+mod concurrency;
+mod counter;
+mod monitor;
+mod observe;
+mod receive;
+mod store;
+mod verify;
+
+pub use self::commit::{CommitMode, Committer};
+pub use self::concurrency::{ConcurrencyGate, Permit};
+pub use self::counter::Counter;
+pub use self::monitor::{BatchJobGuard, Monitor};
+pub use self::observe::Observer;
+pub use self::receive::{DynDigest, Receiver};
+pub use self::store::{BucketOptions, BucketTotals, BufferPool, RateLimiter, Storer,
+                      UploadHeaders, UploadJournal, abort_stale_uploads,
+                      adaptive_chunk_size, bucket_totals, ensure_bucket, write_smoke_test};
+pub use self::verify::Verifier;
+
+/// Why a run was cancelled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CancelReason {
+    /// cancelled from the outside, e.g. by a signal handler or an
+    /// embedding application
+    External,
+    /// a worker thread failed, taking the pipeline down with it
+    WorkerFailed,
+    /// the configured maximum runtime was reached
+    DeadlineReached,
+}
+
+impl fmt::Display for CancelReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+                        CancelReason::External => "cancelled externally",
+                        CancelReason::WorkerFailed => "a worker thread failed",
+                        CancelReason::DeadlineReached => "maximum runtime reached",
+                    })
+    }
+}
+
+/// Detail records kept per run; beyond this only per-category counts
+/// are maintained, so a pathological run cannot eat the heap.
+pub const ERROR_SAMPLE_CAP: usize = 1000;
+
+/// One failed object, as kept for the end-of-run error report.
+#[derive(Clone, Debug)]
+pub struct ErrorRecord {
+    /// error category, e.g. `"ChecksumMismatch"`
+    pub category: &'static str,
+    /// pipeline stage the failure occurred in, if known
+    pub stage: Option<Stage>,
+    /// oid of the affected large object, if known
+    pub oid: Option<u32>,
+    /// full error message
+    pub message: String,
+}
+
+/// Bounded collection of what went wrong during a run.
+#[derive(Debug, Default)]
+struct ErrorLog {
+    /// failures per category; counts everything
+    counts: BTreeMap<&'static str, u64>,
+    /// detail records, capped at [`ERROR_SAMPLE_CAP`]
+    ///
+    /// [`ERROR_SAMPLE_CAP`]: constant.ERROR_SAMPLE_CAP.html
+    samples: Vec<ErrorRecord>,
+    /// failures whose detail record was dropped over the cap
+    dropped: u64,
+}
+
+/// Monotonic `u64` counter built on [`AtomicUsize`].
+///
+/// `AtomicU64` still requires the nightly-only `integer_atomics`
+/// feature, so the counters go through `usize` instead. The migration
+/// runs on 64-bit hosts where `usize` is 64 bits wide; on a 32-bit
+/// target the byte counters would wrap after 4 GiB.
+///
+/// [`AtomicUsize`]: https://doc.rust-lang.org/std/sync/atomic/struct.AtomicUsize.html
+#[derive(Debug)]
+struct AtomicCounter(AtomicUsize);
+
+impl AtomicCounter {
+    fn new() -> Self {
+        AtomicCounter(AtomicUsize::new(0))
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed) as u64
+    }
+
+    fn add(&self, count: u64) {
+        self.0.fetch_add(count as usize, Ordering::Relaxed);
+    }
+}
+
+/// Statistics shared between all worker threads.
+///
+/// One instance is created per run and handed to every worker. Counters
+/// are updated by the workers and read by the monitor thread.
+#[derive(Debug)]
+pub struct ThreadStat {
+    /// number of rows in `_nice_binary`, set by the [`Counter`]
+    ///
+    /// [`Counter`]: struct.Counter.html
+    lo_total: Mutex<Option<u64>>,
+    /// number of rows still lacking a sha2 hash, set by the [`Counter`]
+    ///
+    /// [`Counter`]: struct.Counter.html
+    lo_remaining: Mutex<Option<u64>>,
+    /// sum of `size` over all rows, set by the [`Counter`]
+    ///
+    /// [`Counter`]: struct.Counter.html
+    bytes_total: Mutex<Option<u64>>,
+    /// sum of `size` over the rows still lacking a sha2 hash, set by the
+    /// [`Counter`]
+    ///
+    /// [`Counter`]: struct.Counter.html
+    bytes_remaining: Mutex<Option<u64>>,
+    /// objects seen by the observer
+    lo_observed: AtomicCounter,
+    /// objects read from Postgres
+    lo_received: AtomicCounter,
+    /// already migrated objects found intact in the bucket by the
+    /// re-verify pass
+    lo_verified: AtomicCounter,
+    /// zero-byte objects among them, migrated without any buffering
+    lo_zero_byte: AtomicCounter,
+    /// objects uploaded to S3
+    lo_stored: AtomicCounter,
+    /// hashes committed to `_nice_binary`
+    lo_committed: AtomicCounter,
+    /// bytes of object data belonging to the committed hashes
+    bytes_committed: AtomicCounter,
+    /// objects that could not be migrated
+    lo_failed: AtomicCounter,
+    /// what went wrong, for the end-of-run breakdown
+    errors: Mutex<ErrorLog>,
+    /// `hash` column value of the most recently committed object
+    last_committed_hash: Mutex<Option<String>>,
+    /// cancellation flag, checked by all workers
+    cancelled: AtomicBool,
+    /// why the run was cancelled, set together with the flag
+    cancel_reason: Mutex<Option<CancelReason>>,
+    /// wall-clock deadline after which the run cancels itself
+    deadline: Mutex<Option<Instant>>,
+    /// time the pipeline was started
+    started: Instant,
+}
+
+impl ThreadStat {
+    pub fn new() -> Self {
+        ThreadStat {
+            lo_total: Mutex::new(None),
+            lo_remaining: Mutex::new(None),
+            bytes_total: Mutex::new(None),
+            bytes_remaining: Mutex::new(None),
+            lo_observed: AtomicCounter::new(),
+            lo_received: AtomicCounter::new(),
+            lo_verified: AtomicCounter::new(),
+            lo_zero_byte: AtomicCounter::new(),
+            lo_stored: AtomicCounter::new(),
+            lo_committed: AtomicCounter::new(),
+            bytes_committed: AtomicCounter::new(),
+            lo_failed: AtomicCounter::new(),
+            errors: Mutex::new(ErrorLog::default()),
+            last_committed_hash: Mutex::new(None),
+            cancelled: AtomicBool::new(false),
+            cancel_reason: Mutex::new(None),
+            deadline: Mutex::new(None),
+            started: Instant::now(),
+        }
+    }
+
+    /// total number of rows in `_nice_binary`, if counted yet
+    pub fn lo_total(&self) -> Option<u64> {
+        *self.lo_total.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn set_lo_total(&self, count: u64) {
+        *self.lo_total.lock().unwrap_or_else(|e| e.into_inner()) = Some(count);
+    }
+
+    /// number of rows still to be migrated, if counted yet
+    pub fn lo_remaining(&self) -> Option<u64> {
+        *self.lo_remaining.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn set_lo_remaining(&self, count: u64) {
+        *self.lo_remaining.lock().unwrap_or_else(|e| e.into_inner()) = Some(count);
+    }
+
+    /// total size of all objects in bytes, if counted yet
+    pub fn bytes_total(&self) -> Option<u64> {
+        *self.bytes_total.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn set_bytes_total(&self, bytes: u64) {
+        *self.bytes_total.lock().unwrap_or_else(|e| e.into_inner()) = Some(bytes);
+    }
+
+    /// total size of the objects still to be migrated, if counted yet
+    pub fn bytes_remaining(&self) -> Option<u64> {
+        *self.bytes_remaining.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn set_bytes_remaining(&self, bytes: u64) {
+        *self.bytes_remaining.lock().unwrap_or_else(|e| e.into_inner()) = Some(bytes);
+    }
+
+    pub fn lo_observed(&self) -> u64 {
+        self.lo_observed.get()
+    }
+
+    pub(crate) fn add_observed(&self) {
+        self.lo_observed.add(1);
+    }
+
+    pub fn lo_received(&self) -> u64 {
+        self.lo_received.get()
+    }
+
+    pub(crate) fn add_received(&self) {
+        self.lo_received.add(1);
+    }
+
+    /// already migrated objects the re-verify pass found intact
+    pub fn lo_verified(&self) -> u64 {
+        self.lo_verified.get()
+    }
+
+    pub(crate) fn add_verified(&self) {
+        self.lo_verified.add(1);
+    }
+
+    /// zero-byte objects encountered so far; a subset of
+    /// [`lo_received()`]
+    ///
+    /// [`lo_received()`]: #method.lo_received
+    pub fn lo_zero_byte(&self) -> u64 {
+        self.lo_zero_byte.get()
+    }
+
+    pub(crate) fn add_zero_byte(&self) {
+        self.lo_zero_byte.add(1);
+    }
+
+    pub fn lo_stored(&self) -> u64 {
+        self.lo_stored.get()
+    }
+
+    pub(crate) fn add_stored(&self) {
+        self.lo_stored.add(1);
+    }
+
+    pub fn lo_committed(&self) -> u64 {
+        self.lo_committed.get()
+    }
+
+    pub(crate) fn add_committed(&self, count: u64) {
+        self.lo_committed.add(count);
+    }
+
+    pub fn bytes_committed(&self) -> u64 {
+        self.bytes_committed.get()
+    }
+
+    pub(crate) fn add_committed_bytes(&self, bytes: u64) {
+        self.bytes_committed.add(bytes);
+    }
+
+    pub fn lo_failed(&self) -> u64 {
+        self.lo_failed.get()
+    }
+
+    pub(crate) fn add_failed(&self) {
+        self.lo_failed.add(1);
+    }
+
+    /// Count a failed object and remember what went wrong for the
+    /// end-of-run breakdown.
+    pub(crate) fn record_failure(&self, err: &MigrationError) {
+        self.record_failure_raw(err.kind().category(),
+                                err.stage(),
+                                err.oid(),
+                                err.to_string());
+    }
+
+    /// Like [`record_failure()`], for failures that never materialize
+    /// as a [`MigrationError`], e.g. rows skipped during commit.
+    ///
+    /// [`record_failure()`]: #method.record_failure
+    /// [`MigrationError`]: ../error/struct.MigrationError.html
+    pub(crate) fn record_failure_raw(&self,
+                                     category: &'static str,
+                                     stage: Option<Stage>,
+                                     oid: Option<u32>,
+                                     message: String) {
+        self.add_failed();
+        let mut errors = self.errors.lock().unwrap_or_else(|e| e.into_inner());
+        *errors.counts.entry(category).or_insert(0) += 1;
+        if errors.samples.len() < ERROR_SAMPLE_CAP {
+            errors.samples.push(ErrorRecord {
+                                    category: category,
+                                    stage: stage,
+                                    oid: oid,
+                                    message: message,
+                                });
+        } else {
+            errors.dropped += 1;
+        }
+    }
+
+    /// Failures per category, most frequent first. Counts every
+    /// failure, including those whose detail record was dropped.
+    pub fn error_breakdown(&self) -> Vec<(&'static str, u64)> {
+        let errors = self.errors.lock().unwrap_or_else(|e| e.into_inner());
+        let mut breakdown: Vec<_> = errors
+            .counts
+            .iter()
+            .map(|(&category, &count)| (category, count))
+            .collect();
+        breakdown.sort_by(|a, b| b.1.cmp(&a.1));
+        breakdown
+    }
+
+    /// Detail records of the failed objects, in order of occurrence.
+    /// Bounded: after [`ERROR_SAMPLE_CAP`] records only the per-category
+    /// counts keep growing.
+    ///
+    /// [`ERROR_SAMPLE_CAP`]: constant.ERROR_SAMPLE_CAP.html
+    pub fn failed_objects(&self) -> Vec<ErrorRecord> {
+        self.errors
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .samples
+            .clone()
+    }
+
+    /// `hash` column value of the most recently committed object, used
+    /// as high-water mark in the state table
+    pub fn last_committed_hash(&self) -> Option<String> {
+        self.last_committed_hash
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub(crate) fn set_last_committed_hash(&self, hash: String) {
+        *self.last_committed_hash.lock().unwrap_or_else(|e| e.into_inner()) = Some(hash);
+    }
+
+    /// seconds elapsed since the pipeline was started
+    pub fn runtime(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    /// Tell all worker threads to stop at the next opportunity.
+    ///
+    /// Equivalent to [`cancel_with(CancelReason::External)`].
+    ///
+    /// [`cancel_with(CancelReason::External)`]: #method.cancel_with
+    pub fn cancel(&self) {
+        self.cancel_with(CancelReason::External);
+    }
+
+    /// Cancel the run, recording why. The first reason sticks; later
+    /// calls only ensure the flag is set.
+    pub fn cancel_with(&self, reason: CancelReason) {
+        {
+            let mut current = self.cancel_reason.lock().unwrap_or_else(|e| e.into_inner());
+            if current.is_none() {
+                info!("cancelling run: {}", reason);
+                *current = Some(reason);
+            }
+        }
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Why the run was cancelled, if it was.
+    pub fn cancel_reason(&self) -> Option<CancelReason> {
+        *self.cancel_reason.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Cancel the run once `deadline` passes, so unattended runs stop
+    /// and report cleanly instead of running into the next maintenance
+    /// window.
+    pub fn set_deadline(&self, deadline: Instant) {
+        *self.deadline.lock().unwrap_or_else(|e| e.into_inner()) = Some(deadline);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        if self.cancelled.load(Ordering::Relaxed) {
+            return true;
+        }
+        let deadline = *self.deadline.lock().unwrap_or_else(|e| e.into_inner());
+        match deadline {
+            Some(deadline) if Instant::now() >= deadline => {
+                self.cancel_with(CancelReason::DeadlineReached);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Fail with [`ErrorKind::ThreadCancelled`] if the run has been
+    /// cancelled. Called by the workers between objects.
+    ///
+    /// [`ErrorKind::ThreadCancelled`]: ../error/enum.ErrorKind.html
+    pub fn abort_if_cancelled(&self) -> Result<()> {
+        if self.is_cancelled() {
+            Err(ErrorKind::ThreadCancelled.into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Default for ThreadStat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Run a per-object operation, converting a panic into
+/// [`ErrorKind::ObjectPanicked`] so only the one object fails instead
+/// of the whole worker thread.
+///
+/// The workers wrap exactly the calls that touch one object's data —
+/// a stray `assert!` or index bug there takes the object down, not the
+/// run. Panics outside these calls still unwind the thread and are
+/// reported by the pipeline as [`ErrorKind::WorkerPanicked`].
+///
+/// [`ErrorKind::ObjectPanicked`]: ../error/enum.ErrorKind.html
+/// [`ErrorKind::WorkerPanicked`]: ../error/enum.ErrorKind.html
+pub(crate) fn catch_object_panic<T, F>(op: F) -> Result<T>
+    where F: FnOnce() -> Result<T>
+{
+    match panic::catch_unwind(panic::AssertUnwindSafe(op)) {
+        Ok(result) => result,
+        Err(payload) => {
+            let message = if let Some(msg) = payload.downcast_ref::<&str>() {
+                (*msg).to_string()
+            } else if let Some(msg) = payload.downcast_ref::<String>() {
+                msg.clone()
+            } else {
+                "panic payload of unknown type".to_string()
+            };
+            Err(ErrorKind::ObjectPanicked(message).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters() {
+        let stats = ThreadStat::new();
+        assert_eq!(stats.lo_observed(), 0);
+        stats.add_observed();
+        stats.add_observed();
+        assert_eq!(stats.lo_observed(), 2);
+        stats.add_committed(50);
+        assert_eq!(stats.lo_committed(), 50);
+    }
+
+    #[test]
+    fn failures_are_counted_and_categorized() {
+        use error::MigrationError;
+
+        let stats = ThreadStat::new();
+        stats.record_failure(&MigrationError::from(ErrorKind::ChecksumMismatch)
+                                  .at(Stage::Store)
+                                  .for_object(42, "abcd".to_string()));
+        stats.record_failure(&MigrationError::from(ErrorKind::ChecksumMismatch));
+        stats.record_failure_raw("RowMissing", Some(Stage::Commit), None, "gone".to_string());
+
+        assert_eq!(stats.lo_failed(), 3);
+        assert_eq!(stats.error_breakdown(),
+                   vec![("ChecksumMismatch", 2), ("RowMissing", 1)]);
+
+        let records = stats.failed_objects();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].oid, Some(42));
+        assert_eq!(records[0].stage, Some(Stage::Store));
+        assert!(records[0].message.contains("abcd"));
+    }
+
+    #[test]
+    fn totals_unset_by_default() {
+        let stats = ThreadStat::new();
+        assert_eq!(stats.lo_total(), None);
+        assert_eq!(stats.lo_remaining(), None);
+        stats.set_lo_total(100);
+        stats.set_lo_remaining(42);
+        assert_eq!(stats.lo_total(), Some(100));
+        assert_eq!(stats.lo_remaining(), Some(42));
+    }
+
+    #[test]
+    fn cancellation() {
+        let stats = ThreadStat::new();
+        assert!(stats.abort_if_cancelled().is_ok());
+        assert_eq!(stats.cancel_reason(), None);
+        stats.cancel();
+        assert!(stats.is_cancelled());
+        assert_eq!(stats.cancel_reason(), Some(CancelReason::External));
+        match stats.abort_if_cancelled() {
+            Err(ref err) => {
+                match *err.kind() {
+                    ErrorKind::ThreadCancelled => (),
+                    ref other => panic!("unexpected error: {:?}", other),
+                }
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn first_cancel_reason_sticks() {
+        let stats = ThreadStat::new();
+        stats.cancel_with(CancelReason::DeadlineReached);
+        stats.cancel_with(CancelReason::WorkerFailed);
+        assert_eq!(stats.cancel_reason(), Some(CancelReason::DeadlineReached));
+    }
+
+    #[test]
+    fn a_panic_becomes_a_per_object_error() {
+        let result = catch_object_panic(|| -> Result<()> { panic!("boom: oid 42") });
+        match result {
+            Err(ref err) => {
+                match *err.kind() {
+                    ErrorKind::ObjectPanicked(ref msg) => assert!(msg.contains("boom")),
+                    ref other => panic!("unexpected error: {:?}", other),
+                }
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        assert_eq!(catch_object_panic(|| Ok(7)).unwrap(), 7);
+    }
+
+    #[test]
+    fn expired_deadline_cancels() {
+        use std::time::Duration;
+        let stats = ThreadStat::new();
+        stats.set_deadline(Instant::now() + Duration::from_secs(3600));
+        assert!(!stats.is_cancelled());
+        stats.set_deadline(Instant::now() - Duration::from_secs(1));
+        assert!(stats.is_cancelled());
+        assert_eq!(stats.cancel_reason(), Some(CancelReason::DeadlineReached));
+    }
+}
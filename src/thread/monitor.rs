@@ -0,0 +1,229 @@
+//! Monitor thread periodically logging progress.
+
+use db::RunState;
+use error::{ErrorKind, Result};
+use lo::Lo;
+use metrics::MetricsSink;
+use postgres::Connection;
+use std::sync::{Arc, Weak};
+use std::thread::sleep;
+use std::time::Duration;
+use queue::{WorkQueueReceiver, WorkQueueSender};
+use thread::{CancelReason, ThreadStat};
+
+/// Periodically logs counters, queue utilization and an estimate of the
+/// remaining time.
+///
+/// The monitor only holds [`Weak`] references to the queues so it never
+/// keeps a queue alive once all workers are done; it exits once all
+/// queues are gone.
+///
+/// [`Weak`]: https://doc.rust-lang.org/std/sync/struct.Weak.html
+pub struct Monitor<'a> {
+    pub stats: &'a ThreadStat,
+    pub receive_queue: Weak<WorkQueueSender<Lo>>,
+    pub receive_queue_size: usize,
+    pub store_queue: Weak<WorkQueueReceiver<Lo>>,
+    pub store_queue_size: usize,
+    pub commit_queue: Weak<WorkQueueReceiver<Lo>>,
+    pub commit_queue_size: usize,
+    /// state table row to keep up to date, if state tracking is on
+    pub state: Option<(&'a Connection, RunState)>,
+    /// sink the counters and queue gauges are published into each tick
+    pub metrics: Option<Arc<MetricsSink>>,
+}
+
+impl<'a> Monitor<'a> {
+    /// Log progress every `interval` until the pipeline has shut down.
+    pub fn start_worker(&self, interval: Duration) {
+        loop {
+            sleep(interval);
+
+            let receive_queue = self.receive_queue.upgrade();
+            let store_queue = self.store_queue.upgrade();
+            let commit_queue = self.commit_queue.upgrade();
+            if receive_queue.is_none() && store_queue.is_none() && commit_queue.is_none() {
+                break;
+            }
+
+            info!("observed: {}, received: {}, stored: {}, committed: {}, failed: {}{}",
+                  self.stats.lo_observed(),
+                  self.stats.lo_received(),
+                  self.stats.lo_stored(),
+                  self.stats.lo_committed(),
+                  self.stats.lo_failed(),
+                  self.progress());
+            info!("queue utilization: receive: {}, store: {}, commit: {}",
+                  Self::utilization(&receive_queue.map(|q| q.len()), self.receive_queue_size),
+                  Self::utilization(&store_queue.map(|q| q.len()), self.store_queue_size),
+                  Self::utilization(&commit_queue.map(|q| q.len()), self.commit_queue_size));
+
+            if let Some(ref metrics) = self.metrics {
+                metrics.counter("lo_observed_total", self.stats.lo_observed());
+                metrics.counter("lo_received_total", self.stats.lo_received());
+                metrics.counter("lo_zero_byte_total", self.stats.lo_zero_byte());
+                metrics.counter("lo_stored_total", self.stats.lo_stored());
+                metrics.counter("lo_committed_total", self.stats.lo_committed());
+                metrics.counter("lo_failed_total", self.stats.lo_failed());
+                metrics.counter("bytes_committed_total", self.stats.bytes_committed());
+                let lengths = [("receive_queue_length", self.receive_queue.upgrade().map(|q| q.len())),
+                               ("store_queue_length", self.store_queue.upgrade().map(|q| q.len())),
+                               ("commit_queue_length", self.commit_queue.upgrade().map(|q| q.len()))];
+                for &(name, len) in &lengths {
+                    if let Some(len) = len {
+                        metrics.gauge(name, len as f64);
+                    }
+                }
+            }
+
+            if let Some((conn, ref state)) = self.state {
+                if let Err(err) = state.update(conn, self.stats) {
+                    warn!("failed to update _lo_migrate_state: {}", err);
+                }
+            }
+        }
+        debug!("all queues gone, monitor exiting");
+    }
+
+    /// Progress and estimated remaining time, if the counter has
+    /// published totals already.
+    ///
+    /// Based on bytes rather than object counts: sizes vary by five
+    /// orders of magnitude, so a row count says little about how much
+    /// work is actually left.
+    fn progress(&self) -> String {
+        let remaining = match self.stats.bytes_remaining() {
+            Some(bytes) => bytes,
+            None => return String::new(),
+        };
+        let committed = self.stats.bytes_committed();
+        if remaining == 0 || committed == 0 {
+            return String::new();
+        }
+
+        let percent = committed as f64 / remaining as f64 * 100.0;
+        let runtime = self.stats.runtime();
+        let eta_secs = (runtime as f64 / committed as f64 *
+                        (remaining - committed.min(remaining)) as f64) as u64;
+        format!(" ({} of {}, {:.2}%, about {} remaining)",
+                format_bytes(committed),
+                format_bytes(remaining),
+                percent,
+                format_duration(eta_secs))
+    }
+
+    fn utilization(len: &Option<usize>, size: usize) -> String {
+        match *len {
+            Some(len) => format!("{}/{}", len, size),
+            None => "gone".to_string(),
+        }
+    }
+}
+
+
+/// Periodically re-checks that the Nice cleanup batch job stays
+/// disabled.
+///
+/// [`check_batch_job_is_disabled()`] runs once at startup — but if an
+/// operator re-enables `DeleteUnreferencedBinariesBatchJob` mid-run,
+/// large objects can vanish between observation and receipt. This
+/// guard re-runs the check periodically and cancels the migration with
+/// a clear error should the job become active. Like the [`Monitor`] it
+/// only holds [`Weak`] queue references and exits once all queues are
+/// gone.
+///
+/// [`check_batch_job_is_disabled()`]: ../db/fn.check_batch_job_is_disabled.html
+/// [`Monitor`]: struct.Monitor.html
+/// [`Weak`]: https://doc.rust-lang.org/std/sync/struct.Weak.html
+pub struct BatchJobGuard<'a> {
+    pub conn: &'a Connection,
+    pub stats: &'a ThreadStat,
+    pub receive_queue: Weak<WorkQueueSender<Lo>>,
+    pub commit_queue: Weak<WorkQueueReceiver<Lo>>,
+}
+
+impl<'a> BatchJobGuard<'a> {
+    /// Re-check every `interval` until the pipeline has shut down.
+    pub fn start_worker(&self, interval: Duration) -> Result<u64> {
+        loop {
+            sleep(interval);
+
+            if self.stats.is_cancelled() ||
+               (self.receive_queue.upgrade().is_none() &&
+                self.commit_queue.upgrade().is_none()) {
+                break;
+            }
+
+            match ::db::check_batch_job_is_disabled(self.conn) {
+                Ok(()) => (),
+                Err(ref err) if is_batch_job_enabled(err) => {
+                    error!("DeleteUnreferencedBinariesBatchJob was re-enabled mid-run; \
+                            large objects can vanish under the receivers, cancelling \
+                            the migration");
+                    self.stats.cancel_with(CancelReason::WorkerFailed);
+                    return Err(ErrorKind::BatchJobEnabled.into());
+                }
+                // a transient query failure must not kill the run; the
+                // next tick tries again
+                Err(err) => warn!("cannot re-check batch job state: {}", err),
+            }
+        }
+        debug!("pipeline has shut down, batch job guard exiting");
+        Ok(0)
+    }
+}
+
+fn is_batch_job_enabled(err: &::error::MigrationError) -> bool {
+    match *err.kind() {
+        ErrorKind::BatchJobEnabled => true,
+        _ => false,
+    }
+}
+
+/// Render a byte count as a short human readable string.
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.2} {}", value, UNITS[unit])
+    }
+}
+
+/// Render a duration in seconds as a short human readable string.
+fn format_duration(secs: u64) -> String {
+    if secs >= 3600 {
+        format!("{}h {}min", secs / 3600, secs % 3600 / 60)
+    } else if secs >= 60 {
+        format!("{}min {}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_bytes, format_duration};
+
+    #[test]
+    fn bytes() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1024), "1.00 KiB");
+        assert_eq!(format_bytes(5 * 1024 * 1024 * 1024), "5.00 GiB");
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(format_duration(0), "0s");
+        assert_eq!(format_duration(59), "59s");
+        assert_eq!(format_duration(61), "1min 1s");
+        assert_eq!(format_duration(7265), "2h 1min");
+    }
+}
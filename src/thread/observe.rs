@@ -0,0 +1,319 @@
+//! Observer thread walking the source for objects still to migrate.
+
+use error::{ErrorKind, MigrationError, Result, Stage};
+use hex::FromHex;
+use lo::Lo;
+use postgres::Connection;
+use queue::WorkQueueSender;
+use source::{LoSource, NiceBinarySource};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use thread::ThreadStat;
+
+/// Walks the [`LoSource`] and enqueues a [`Lo`] for every object still
+/// to be migrated.
+///
+/// [`LoSource`]: ../source/trait.LoSource.html
+/// [`Lo`]: ../lo/struct.Lo.html
+pub struct Observer<'a> {
+    conn: &'a Connection,
+    stats: &'a ThreadStat,
+    source: Arc<LoSource>,
+    /// sha1 -> sha2 of objects already known to sit in the bucket
+    known_hashes: HashMap<String, Vec<u8>>,
+    skip_larger_than: Option<i64>,
+    verify_tx: Option<Arc<WorkQueueSender<Lo>>>,
+    audit_hashes: bool,
+    audit_csv: Option<PathBuf>,
+}
+
+/// Defects of one `hash` column value, in audit order.
+///
+/// The checks run on the trimmed value, since the rest of the pipeline
+/// tolerates surrounding whitespace.
+fn hash_defects(hash: &str) -> Vec<&'static str> {
+    let hash = hash.trim();
+    let mut defects = Vec::new();
+    if hash.len() != 40 {
+        defects.push("wrong length");
+    }
+    if !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        defects.push("non-hex characters");
+    } else if hash.bytes().any(|b| b.is_ascii_uppercase()) {
+        defects.push("upper-case hex digits");
+    }
+    defects
+}
+
+/// Tally of malformed `hash` values seen while walking the source,
+/// optionally exporting the offending rows to CSV.
+struct HashAudit {
+    checked: u64,
+    flawed: u64,
+    wrong_length: u64,
+    non_hex: u64,
+    upper_case: u64,
+    csv: Option<BufWriter<File>>,
+}
+
+impl HashAudit {
+    fn open(csv: Option<&PathBuf>) -> Result<Self> {
+        let csv = match csv {
+            Some(path) => {
+                let mut csv = BufWriter::new(File::create(path)?);
+                writeln!(csv, "oid,defects,hash")?;
+                Some(csv)
+            }
+            None => None,
+        };
+        Ok(HashAudit {
+               checked: 0,
+               flawed: 0,
+               wrong_length: 0,
+               non_hex: 0,
+               upper_case: 0,
+               csv: csv,
+           })
+    }
+
+    fn inspect(&mut self, oid: u32, hash: &str) -> Result<()> {
+        self.checked += 1;
+        let defects = hash_defects(hash);
+        if defects.is_empty() {
+            return Ok(());
+        }
+        self.flawed += 1;
+        for defect in &defects {
+            match *defect {
+                "wrong length" => self.wrong_length += 1,
+                "non-hex characters" => self.non_hex += 1,
+                _ => self.upper_case += 1,
+            }
+        }
+        if let Some(ref mut csv) = self.csv {
+            writeln!(csv,
+                     "{},\"{}\",\"{}\"",
+                     oid,
+                     defects.join("; "),
+                     hash.replace('"', "\"\""))?;
+        }
+        Ok(())
+    }
+
+    /// Flush the CSV export and log the summary.
+    fn finish(mut self) -> Result<()> {
+        if let Some(ref mut csv) = self.csv {
+            csv.flush()?;
+        }
+        if self.flawed == 0 {
+            info!("hash audit: all {} hashes are well-formed", self.checked);
+        } else {
+            warn!("hash audit: {} of {} hashes are malformed ({} with the wrong length, {} \
+                   with non-hex characters, {} with upper-case digits)",
+                  self.flawed,
+                  self.checked,
+                  self.wrong_length,
+                  self.non_hex,
+                  self.upper_case);
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Observer<'a> {
+    pub fn new(conn: &'a Connection, stats: &'a ThreadStat) -> Self {
+        Observer {
+            conn: conn,
+            stats: stats,
+            source: Arc::new(NiceBinarySource::new()),
+            known_hashes: HashMap::new(),
+            skip_larger_than: None,
+            verify_tx: None,
+            audit_hashes: false,
+            audit_csv: None,
+        }
+    }
+
+    /// Walk `source` instead of the default `_nice_binary` table.
+    pub fn with_source(mut self, source: Arc<LoSource>) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Send rows that already carry a sha2 hash to this queue — the
+    /// verify queue of a re-verify run — instead of the receive queue.
+    /// The source must report the hash in [`PendingObject::sha2`].
+    ///
+    /// [`PendingObject::sha2`]: ../source/struct.PendingObject.html#structfield.sha2
+    pub fn with_verify_queue(mut self, verify_tx: Option<Arc<WorkQueueSender<Lo>>>) -> Self {
+        self.verify_tx = verify_tx;
+        self
+    }
+
+    /// Divert objects larger than `bytes` to the failure report
+    /// without enqueuing them, so a handful of pathological blobs can
+    /// be dealt with manually instead of blocking the automated run.
+    pub fn with_skip_larger_than(mut self, bytes: Option<i64>) -> Self {
+        self.skip_larger_than = bytes;
+        self
+    }
+
+    /// Validate every `hash` column value while walking the source and
+    /// log a summary count of malformed ones — wrong length, non-hex
+    /// characters or upper-case digits. With `csv` set, the offending
+    /// rows are additionally exported to that file as
+    /// `oid,defects,hash`.
+    ///
+    /// The audit only reports; malformed rows are still handled as
+    /// before (skipped with a recorded failure if the hash cannot be
+    /// decoded at all).
+    pub fn with_hash_audit(mut self, audit: bool, csv: Option<PathBuf>) -> Self {
+        self.audit_hashes = audit || csv.is_some();
+        self.audit_csv = csv;
+        self
+    }
+
+    /// Preload sha1 -> sha2 pairs of objects that are already in the
+    /// bucket, e.g. from a previous run's manifest.
+    ///
+    /// Matching rows are marked "commit only": their sha2 is taken from
+    /// the map and they go straight to the committers instead of being
+    /// re-streamed through receivers and storers.
+    pub fn with_known_hashes(mut self, known_hashes: HashMap<String, Vec<u8>>) -> Self {
+        self.known_hashes = known_hashes;
+        self
+    }
+
+    /// Enqueue all pending objects, returning the number observed.
+    ///
+    /// The receive queue is disconnected when the returned sender is
+    /// dropped, signalling the receivers that no more objects follow.
+    /// Rows found in the preloaded known-hash map are sent to
+    /// `commit_tx` instead, skipping receive and store.
+    pub fn start_worker(&self,
+                        tx: Arc<WorkQueueSender<Lo>>,
+                        commit_tx: Option<Arc<WorkQueueSender<Lo>>>)
+                        -> Result<u64> {
+        self.worker(tx, commit_tx).map_err(|err| err.at(Stage::Observe))
+    }
+
+    fn worker(&self,
+              tx: Arc<WorkQueueSender<Lo>>,
+              commit_tx: Option<Arc<WorkQueueSender<Lo>>>)
+              -> Result<u64> {
+        let mut count = 0;
+        let mut audit = if self.audit_hashes {
+            Some(HashAudit::open(self.audit_csv.as_ref())?)
+        } else {
+            None
+        };
+        self.source
+            .each_pending(self.conn, &mut |pending| {
+                self.stats.abort_if_cancelled()?;
+
+                if let Some(ref mut audit) = audit {
+                    audit.inspect(pending.oid, &pending.hash)?;
+                }
+
+                let mut lo = match Lo::from_hash_hex(&pending.hash,
+                                                     pending.oid,
+                                                     pending.size,
+                                                     pending.mime_type) {
+                    Ok(lo) => lo,
+                    Err(err) => {
+                        warn!("hash {:?} in the source is not a valid sha1 hash, row skipped",
+                              pending.hash);
+                        self.stats.record_failure(&err.at(Stage::Observe));
+                        return Ok(());
+                    }
+                };
+                lo.set_filename(pending.filename);
+                debug!("observed large object: {:?}", lo);
+
+                if let Some(limit) = self.skip_larger_than {
+                    if lo.size() > limit {
+                        warn!("object {} is {} bytes, larger than the skip limit of {} \
+                               bytes; skipped for manual handling",
+                              lo.sha1_hex(),
+                              lo.size(),
+                              limit);
+                        self.stats
+                            .record_failure_raw("ObjectSkipped",
+                                                Some(Stage::Observe),
+                                                Some(lo.oid()),
+                                                format!("object {} skipped: {} bytes exceed \
+                                                         the --skip-larger-than limit",
+                                                        lo.sha1_hex(),
+                                                        lo.size()));
+                        return Ok(());
+                    }
+                }
+
+                if let Some(sha2) = pending.sha2 {
+                    // the row is already migrated; route it to the
+                    // verifiers, who requeue it if its bucket copy is
+                    // missing or damaged
+                    let verify_tx = self.verify_tx
+                        .as_ref()
+                        .expect("source reported a sha2 hash outside a re-verify run");
+                    match Vec::from_hex(sha2.trim()) {
+                        Ok(sha2) => lo.set_sha2(sha2),
+                        Err(_) => {
+                            warn!("sha2 {:?} of row {} is not a valid hash, row skipped",
+                                  sha2,
+                                  pending.hash);
+                            self.stats
+                                .record_failure(&MigrationError::from(ErrorKind::InvalidHash)
+                                                     .at(Stage::Observe)
+                                                     .for_object(lo.oid(), lo.sha1_hex()));
+                            return Ok(());
+                        }
+                    }
+                    verify_tx.send(lo)?;
+                } else {
+                    match (self.known_hashes.get(pending.hash.trim()), &commit_tx) {
+                        (Some(sha2), &Some(ref commit_tx)) => {
+                            debug!("object {} already in the bucket, sending straight to commit",
+                                   pending.hash);
+                            lo.set_sha2(sha2.clone());
+                            commit_tx.send(lo)?;
+                        }
+                        _ => tx.send(lo)?,
+                    }
+                }
+                self.stats.add_observed();
+                count += 1;
+                Ok(())
+            })?;
+
+        if let Some(audit) = audit {
+            audit.finish()?;
+        }
+        info!("observer done, {} objects enqueued", count);
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::hash_defects;
+
+    #[test]
+    fn well_formed_hashes_have_no_defects() {
+        assert!(hash_defects(&"ab".repeat(20)).is_empty());
+        // surrounding whitespace is tolerated by the whole pipeline
+        assert!(hash_defects(&format!(" {} \n", "ab".repeat(20))).is_empty());
+    }
+
+    #[test]
+    fn malformed_hashes_are_classified() {
+        assert_eq!(hash_defects("abcd"), vec!["wrong length"]);
+        assert_eq!(hash_defects(&"AB".repeat(20)), vec!["upper-case hex digits"]);
+        assert_eq!(hash_defects(&"zz".repeat(20)), vec!["non-hex characters"]);
+        assert_eq!(hash_defects("0xgg"),
+                   vec!["wrong length", "non-hex characters"]);
+    }
+}
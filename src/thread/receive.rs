@@ -0,0 +1,551 @@
+//! Receiver threads reading large object data out of Postgres.
+
+use digest::{Digest, FixedOutput, Input};
+use error::{ErrorKind, Result, Stage};
+use lo::{BufferBackend, Data, Lo};
+use metrics::{seconds, MetricsSink};
+use postgres::Connection;
+use postgres::error::LOCK_NOT_AVAILABLE;
+use source::{LoSource, NiceBinarySource};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use tempfile::NamedTempFileOptions;
+use tempfiles::{self, BufferRegistry, TempSpaceGuard};
+use thread::{BufferPool, ConcurrencyGate, Permit, ThreadStat};
+use queue::{RecvResult, WorkQueueReceiver, WorkQueueSender};
+use std::time::{Duration, Instant};
+
+/// Size of the read buffer used when streaming a large object.
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Interval at which an idle receiver rechecks the cancellation flag.
+const RECV_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Interval at which a paused receiver rechecks the free space.
+const SPACE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often a locked large object is attempted before it is reported
+/// as failed.
+const MAX_LOCK_ATTEMPTS: u32 = 3;
+
+/// How long a locked large object is set aside before the next attempt.
+const LOCK_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// A large object set aside because another session held a conflicting
+/// lock; retried once `due` has passed.
+struct Deferred {
+    due: Instant,
+    attempts: u32,
+    lo: Lo,
+}
+
+/// Whether the error is Postgres giving up on a lock after
+/// `lock_timeout` expired (SQLSTATE 55P03).
+fn is_lock_timeout(err: &::error::MigrationError) -> bool {
+    match *err.kind() {
+        ErrorKind::Postgres(ref err) => err.code() == Some(&LOCK_NOT_AVAILABLE),
+        _ => false,
+    }
+}
+
+/// Object-safe view of the digest traits.
+///
+/// [`Receiver::start_worker()`] fixes the hash algorithm in the
+/// caller's type signature; where the algorithm is only known at
+/// runtime (e.g. chosen from configuration), a `Box<DynDigest>` can be
+/// handed to [`start_worker_dyn()`] instead. Every type implementing
+/// the digest traits implements `DynDigest` via the blanket impl.
+///
+/// [`Receiver::start_worker()`]: struct.Receiver.html#method.start_worker
+/// [`start_worker_dyn()`]: struct.Receiver.html#method.start_worker_dyn
+pub trait DynDigest: Send {
+    /// Feed `data` into the hash.
+    fn input(&mut self, data: &[u8]);
+
+    /// Finish the hash, resetting the state for the next object.
+    fn finish(&mut self) -> Vec<u8>;
+}
+
+impl<D> DynDigest for D
+    where D: Digest + Input + FixedOutput + Default + Send
+{
+    fn input(&mut self, data: &[u8]) {
+        Input::process(self, data);
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        ::std::mem::replace(self, D::default()).fixed_result().to_vec()
+    }
+}
+
+/// Reads large object data from Postgres, hashing it on the way, and
+/// hands the buffered object on to the storers.
+pub struct Receiver<'a> {
+    conn: &'a Connection,
+    stats: &'a ThreadStat,
+    source: Arc<LoSource>,
+    buffer_backend: Option<Arc<BufferBackend>>,
+    metrics: Option<Arc<MetricsSink>>,
+    space_guard: Option<Arc<TempSpaceGuard>>,
+    registry: Option<Arc<BufferRegistry>>,
+    run_id: Option<i64>,
+    lock_timeout: Option<Duration>,
+    /// mime type pattern -> in-memory threshold, first match wins
+    memory_rules: Vec<(String, i64)>,
+    buffer_pool: Option<Arc<BufferPool>>,
+    gate: Option<Arc<ConcurrencyGate>>,
+    audit_trail: bool,
+}
+
+impl<'a> Receiver<'a> {
+    pub fn new(conn: &'a Connection, stats: &'a ThreadStat) -> Self {
+        Receiver {
+            conn: conn,
+            stats: stats,
+            source: Arc::new(NiceBinarySource::new()),
+            buffer_backend: None,
+            metrics: None,
+            space_guard: None,
+            registry: None,
+            run_id: None,
+            lock_timeout: None,
+            memory_rules: Vec::new(),
+            buffer_pool: None,
+            gate: None,
+            audit_trail: false,
+        }
+    }
+
+    /// Stamp every object with a receive timestamp, duration and the
+    /// worker name for the audit log; see [`AuditLog`].
+    ///
+    /// [`AuditLog`]: ../audit/struct.AuditLog.html
+    pub fn with_audit_trail(mut self, audit: bool) -> Self {
+        self.audit_trail = audit;
+        self
+    }
+
+    /// Take the buffers for in-memory objects out of `pool` instead of
+    /// allocating one per object; the storers return them once the
+    /// object is uploaded. See [`BufferPool`].
+    ///
+    /// [`BufferPool`]: struct.BufferPool.html
+    pub fn with_buffer_pool(mut self, pool: Option<Arc<BufferPool>>) -> Self {
+        self.buffer_pool = pool;
+        self
+    }
+
+    /// Take a permit from `gate` before working on an object, so the
+    /// effective receiver parallelism follows the gate instead of the
+    /// thread count; see [`ConcurrencyGate`].
+    ///
+    /// [`ConcurrencyGate`]: struct.ConcurrencyGate.html
+    pub fn with_concurrency_gate(mut self, gate: Option<Arc<ConcurrencyGate>>) -> Self {
+        self.gate = gate;
+        self
+    }
+
+    /// Record a `receive_seconds` histogram sample per received object.
+    pub fn with_metrics(mut self, metrics: Option<Arc<MetricsSink>>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Read object data through `source` instead of the default large
+    /// object lookup.
+    pub fn with_source(mut self, source: Arc<LoSource>) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Buffer objects exceeding the in-memory limit in `backend`'s
+    /// scratch space instead of `mkstemp` temporary files; see
+    /// [`BufferBackend`].
+    ///
+    /// [`BufferBackend`]: ../lo/trait.BufferBackend.html
+    pub fn with_buffer_backend(mut self, backend: Option<Arc<BufferBackend>>) -> Self {
+        self.buffer_backend = backend;
+        self
+    }
+
+    /// Pause before buffering an object to disk while `guard` reports
+    /// the buffer directory low on space; see [`TempSpaceGuard`].
+    ///
+    /// [`TempSpaceGuard`]: ../tempfiles/struct.TempSpaceGuard.html
+    pub fn with_temp_space_guard(mut self, guard: Option<Arc<TempSpaceGuard>>) -> Self {
+        self.space_guard = guard;
+        self
+    }
+
+    /// Track file-backed buffers in `registry`; see [`BufferRegistry`].
+    ///
+    /// [`BufferRegistry`]: ../tempfiles/struct.BufferRegistry.html
+    pub fn with_buffer_registry(mut self, registry: Option<Arc<BufferRegistry>>) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    /// Include this `_lo_migrate_state` run ID in buffer file names, so
+    /// concurrent runs on the same host can be told apart.
+    pub fn with_run_id(mut self, run_id: Option<i64>) -> Self {
+        self.run_id = run_id;
+        self
+    }
+
+    /// Give up on a large object after waiting this long for a
+    /// conflicting lock held by the live application, instead of
+    /// stalling the receiver behind application traffic indefinitely.
+    /// The object is set aside and retried a bounded number of times.
+    pub fn with_lock_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    /// Override the in-memory threshold per mime type; a rule's pattern
+    /// matches exactly or, with a trailing `*`, by prefix, first match
+    /// wins. Lets e.g. `video/*` always stream to disk while small
+    /// documents stay in memory regardless of the global threshold.
+    pub fn with_memory_rules(mut self, rules: Vec<(String, i64)>) -> Self {
+        self.memory_rules = rules;
+        self
+    }
+
+    /// In-memory threshold applying to `lo`, considering the per-mime
+    /// rules before the global value.
+    fn max_in_memory_for(&self, lo: &Lo, max_in_memory: i64) -> i64 {
+        self.memory_rules
+            .iter()
+            .find(|&&(ref pattern, _)| ::thread::store::mime_matches(pattern, lo.mime_type()))
+            .map_or(max_in_memory, |&(_, threshold)| threshold)
+    }
+
+    /// Process objects from the receive queue until it disconnects.
+    ///
+    /// Objects up to `max_in_memory` bytes are buffered in memory, larger
+    /// ones in a temporary file. `D` selects the hash written to the
+    /// `sha2` column; in practice this is [`Sha256`]. Hashing is
+    /// statically dispatched; see [`start_worker_dyn()`] when the
+    /// algorithm is only known at runtime.
+    ///
+    /// [`Sha256`]: https://docs.rs/sha2
+    /// [`start_worker_dyn()`]: #method.start_worker_dyn
+    pub fn start_worker<D>(&self,
+                           rx: Arc<WorkQueueReceiver<Lo>>,
+                           tx: Arc<WorkQueueSender<Lo>>,
+                           max_in_memory: i64)
+                           -> Result<u64>
+        where D: Digest + Input + FixedOutput + Default + Send
+    {
+        let mut digest = D::default();
+        self.worker(rx, tx, max_in_memory, &mut digest)
+            .map_err(|err| err.at(Stage::Receive))
+    }
+
+    /// Like [`start_worker()`], hashing through a boxed [`DynDigest`]
+    /// chosen at runtime.
+    ///
+    /// [`start_worker()`]: #method.start_worker
+    /// [`DynDigest`]: trait.DynDigest.html
+    pub fn start_worker_dyn(&self,
+                            rx: Arc<WorkQueueReceiver<Lo>>,
+                            tx: Arc<WorkQueueSender<Lo>>,
+                            max_in_memory: i64,
+                            mut digest: Box<DynDigest>)
+                            -> Result<u64> {
+        self.worker(rx, tx, max_in_memory, &mut *digest)
+            .map_err(|err| err.at(Stage::Receive))
+    }
+
+    fn worker<D>(&self,
+                 rx: Arc<WorkQueueReceiver<Lo>>,
+                 tx: Arc<WorkQueueSender<Lo>>,
+                 max_in_memory: i64,
+                 digest: &mut D)
+                 -> Result<u64>
+        where D: DynDigest + ?Sized
+    {
+        let mut count = 0;
+        let mut deferred: VecDeque<Deferred> = VecDeque::new();
+        loop {
+            if self.stats.is_cancelled() {
+                let dropped = rx.drain().len() + deferred.len();
+                deferred.clear();
+                if dropped > 0 {
+                    debug!("run cancelled, dropped {} queued objects", dropped);
+                }
+            }
+            self.stats.abort_if_cancelled()?;
+
+            // locked objects whose retry delay has passed come first;
+            // fresh objects keep flowing while the others wait
+            let due = deferred
+                .front()
+                .map_or(false, |entry| entry.due <= Instant::now());
+            let (lo, attempts) = if due {
+                let entry = deferred.pop_front().expect("checked non-empty above");
+                (entry.lo, entry.attempts)
+            } else {
+                match rx.recv_timeout(RECV_TIMEOUT) {
+                    RecvResult::Item(lo) => (lo, 1),
+                    RecvResult::TimedOut => continue,
+                    RecvResult::Disconnected => break,
+                }
+            };
+
+            count += self.process(lo, attempts, max_in_memory, digest, &tx, &mut deferred)?;
+        }
+
+        // the queue is gone; give the set-aside objects their remaining
+        // attempts before reporting done
+        while let Some(entry) = deferred.pop_front() {
+            self.stats.abort_if_cancelled()?;
+            let now = Instant::now();
+            if entry.due > now {
+                ::std::thread::sleep(entry.due - now);
+            }
+            count += self.process(entry.lo,
+                                  entry.attempts,
+                                  max_in_memory,
+                                  digest,
+                                  &tx,
+                                  &mut deferred)?;
+        }
+
+        debug!("receiver done, {} objects received", count);
+        Ok(count)
+    }
+
+    /// Wait for a permit from the concurrency gate, if one is
+    /// configured, rechecking the cancellation flag while waiting.
+    fn acquire_permit(&self) -> Result<Option<Permit>> {
+        match self.gate {
+            Some(ref gate) => {
+                loop {
+                    self.stats.abort_if_cancelled()?;
+                    if let Some(permit) = gate.permit(RECV_TIMEOUT) {
+                        return Ok(Some(permit));
+                    }
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Receive one object and route it on: into the store queue on
+    /// success, back into the deferred list when another session holds
+    /// a conflicting lock, into the failure report otherwise. Returns
+    /// how many objects were passed on (0 or 1).
+    fn process<D>(&self,
+                  mut lo: Lo,
+                  attempts: u32,
+                  max_in_memory: i64,
+                  digest: &mut D,
+                  tx: &Arc<WorkQueueSender<Lo>>,
+                  deferred: &mut VecDeque<Deferred>)
+                  -> Result<u64>
+        where D: DynDigest + ?Sized
+    {
+        let _permit = self.acquire_permit()?;
+        let started = Instant::now();
+        match ::thread::catch_object_panic(|| self.receive_data(&mut lo, max_in_memory, digest)) {
+            Ok(()) => {
+                if let Some(ref metrics) = self.metrics {
+                    metrics.histogram("receive_seconds", seconds(started.elapsed()));
+                }
+                if self.audit_trail {
+                    let trail = lo.audit_trail_mut();
+                    trail.received_at = Some(::chrono::Utc::now());
+                    trail.receive_seconds = seconds(started.elapsed());
+                    trail.received_by = ::audit::worker_name();
+                }
+                self.stats.add_received();
+                if let Some(ref gate) = self.gate {
+                    gate.record_success(lo.size() as u64);
+                }
+                tx.send(lo)?;
+                Ok(1)
+            }
+            Err(ref err) if is_lock_timeout(err) && attempts < MAX_LOCK_ATTEMPTS => {
+                warn!("large object {} is locked by another session, retrying in {:?} \
+                       (attempt {}/{})",
+                      lo.oid(),
+                      LOCK_RETRY_DELAY,
+                      attempts,
+                      MAX_LOCK_ATTEMPTS);
+                deferred.push_back(Deferred {
+                                       due: Instant::now() + LOCK_RETRY_DELAY,
+                                       attempts: attempts + 1,
+                                       lo: lo,
+                                   });
+                Ok(0)
+            }
+            Err(err) => {
+                if let ErrorKind::ObjectPanicked(_) = *err.kind() {
+                    // the panic may have left half an object in the
+                    // digest; finish() resets it for the next one
+                    let _ = digest.finish();
+                }
+                let err = err.at(Stage::Receive).for_object(lo.oid(), lo.sha1_hex());
+                warn!("failed to read large object: {}", err);
+                self.stats.record_failure(&err);
+                if let Some(ref gate) = self.gate {
+                    gate.record_error();
+                }
+                Ok(0)
+            }
+        }
+    }
+
+    /// Stream the object's data into a buffer, computing the sha2 hash
+    /// on the way.
+    ///
+    /// `digest` is left reset and ready for the next object even on
+    /// error.
+    fn receive_data<D>(&self, lo: &mut Lo, max_in_memory: i64, digest: &mut D) -> Result<()>
+        where D: DynDigest + ?Sized
+    {
+        let max_in_memory = self.max_in_memory_for(lo, max_in_memory);
+        let result = self.stream_data(lo, max_in_memory, digest);
+        let sha2 = digest.finish();
+        result.map(|data| {
+                       lo.set_sha2(sha2);
+                       lo.set_data(data);
+                   })
+    }
+
+    /// Wait until the buffer directory has space for another temporary
+    /// file, if a [`TempSpaceGuard`] is configured and `lo` is going to
+    /// need one.
+    ///
+    /// Waiting here — before the transaction is opened — keeps the
+    /// storers draining existing buffers, which is what frees the
+    /// space up again. Cancellation still interrupts the wait.
+    ///
+    /// [`TempSpaceGuard`]: ../tempfiles/struct.TempSpaceGuard.html
+    fn wait_for_temp_space(&self, lo: &Lo, max_in_memory: i64) -> Result<()> {
+        let guard = match self.space_guard {
+            Some(ref guard) if lo.size() > max_in_memory && self.buffer_backend.is_none() => guard,
+            _ => return Ok(()),
+        };
+
+        let mut warned = false;
+        while !guard.has_space()? {
+            if !warned {
+                warn!("less than {} bytes free in the buffer directory, pausing receiver",
+                      guard.min_free());
+                warned = true;
+            }
+            self.stats.abort_if_cancelled()?;
+            ::std::thread::sleep(SPACE_POLL_INTERVAL);
+        }
+        if warned {
+            info!("buffer directory has space again, resuming receiver");
+        }
+        Ok(())
+    }
+
+    fn stream_data<D>(&self, lo: &Lo, max_in_memory: i64, digest: &mut D) -> Result<Data>
+        where D: DynDigest + ?Sized
+    {
+        self.wait_for_temp_space(lo, max_in_memory)?;
+
+        let trans = self.conn.transaction()?;
+        if let Some(timeout) = self.lock_timeout {
+            // SET LOCAL takes no bind parameters; the value is a
+            // computed integer, so formatting it in is safe
+            let millis = timeout.as_secs() * 1000 + u64::from(timeout.subsec_nanos()) / 1_000_000;
+            trans.batch_execute(&format!("SET LOCAL lock_timeout = '{}ms'", millis))?;
+        }
+        let mut large_object = self.source.open_data(&trans, lo)?;
+
+        if lo.size() == 0 {
+            // nothing to buffer, but the source is still read once so a
+            // row whose bookkept size is wrong fails loudly instead of
+            // being committed with the empty-input hash
+            let mut buffer = [0; 1];
+            if large_object.read(&mut buffer)? != 0 {
+                return Err(ErrorKind::Io(io::Error::new(io::ErrorKind::InvalidData,
+                                                        "object has data although its \
+                                                         bookkept size is 0"))
+                                   .into());
+            }
+            self.stats.add_zero_byte();
+            Ok(Data::Vec(Vec::new()))
+        } else if lo.size() <= max_in_memory {
+            let mut data = match self.buffer_pool {
+                Some(ref pool) => pool.take(lo.size() as usize),
+                None => Vec::with_capacity(lo.size() as usize),
+            };
+            let mut buffer = [0; READ_BUFFER_SIZE];
+            loop {
+                let read = large_object.read(&mut buffer)?;
+                if read == 0 {
+                    break;
+                }
+                digest.input(&buffer[..read]);
+                data.extend_from_slice(&buffer[..read]);
+            }
+            Ok(Data::Vec(data))
+        } else if let Some(ref backend) = self.buffer_backend {
+            let mut scratch = backend.create(lo.size())?;
+            let mut buffer = [0; READ_BUFFER_SIZE];
+            loop {
+                let read = large_object.read(&mut buffer)?;
+                if read == 0 {
+                    break;
+                }
+                digest.input(&buffer[..read]);
+                scratch.write_all(&buffer[..read])?;
+            }
+            scratch.into_data()
+        } else {
+            let prefix =
+                tempfiles::buffer_prefix(self.run_id, ::std::thread::current().name());
+            let mut file = NamedTempFileOptions::new().prefix(&prefix).create()?;
+            if let Some(ref registry) = self.registry {
+                registry.register(file.path());
+            }
+            let result = (|| -> Result<()> {
+                let mut buffer = [0; READ_BUFFER_SIZE];
+                let mut written = 0;
+                loop {
+                    let read = large_object.read(&mut buffer)?;
+                    if read == 0 {
+                        break;
+                    }
+                    digest.input(&buffer[..read]);
+                    file.write_all(&buffer[..read])?;
+                    written += read as u64;
+                }
+
+                // flush to stable storage before the hand-off: a machine
+                // crash or filesystem hiccup must not let the storer upload
+                // a truncated buffer out of the page cache
+                file.flush()?;
+                let buffer_file = file.reopen()?;
+                buffer_file.sync_all()?;
+                let len = buffer_file.metadata()?.len();
+                if len != written {
+                    return Err(ErrorKind::Io(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                            format!("buffer file holds {} \
+                                                                     bytes but {} were \
+                                                                     written",
+                                                                    len,
+                                                                    written)))
+                                       .into());
+                }
+                Ok(())
+            })();
+            if let Err(err) = result {
+                // the file is deleted when it drops below; don't leave
+                // its path in the registry
+                if let Some(ref registry) = self.registry {
+                    registry.release(file.path());
+                }
+                return Err(err);
+            }
+            Ok(Data::File(file))
+        }
+    }
+}
@@ -0,0 +1,1933 @@
+//! Storer threads uploading buffered objects to S3.
+
+use chrono::{DateTime, Utc};
+use error::{ErrorKind, Result, Stage};
+use hex;
+use lo::{BufferedData, Data, Lo};
+use manifest;
+use md5;
+use metrics::{seconds, MetricsSink};
+use memmap::Mmap;
+use object_store::{ObjectStore, Part, UploadMeta, base64, sha256_checksum};
+use rusoto_s3::{AbortMultipartUploadRequest, CreateBucketConfiguration, CreateBucketRequest,
+                HeadBucketError, HeadBucketRequest, ListMultipartUploadsRequest, S3};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use tempfiles::BufferRegistry;
+use thread::{ConcurrencyGate, Permit, ThreadStat};
+use queue::{RecvResult, WorkQueueReceiver, WorkQueueSender};
+
+/// Interval at which an idle storer rechecks the cancellation flag.
+const RECV_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Backoff in milliseconds before the first retry of a failed upload
+/// request; doubled on every further attempt, plus jitter.
+const RETRY_BASE_DELAY_MS: u64 = 500;
+
+/// Exponential backoff delay for retry `attempt`, with up to 50%
+/// jitter so storer threads hitting the same outage do not hammer the
+/// recovering store in lockstep. The jitter is derived from the clock
+/// instead of pulling in a rand dependency.
+fn retry_delay(attempt: u32) -> Duration {
+    let base_ms = RETRY_BASE_DELAY_MS << (attempt - 1).min(6);
+    let nanos = ::std::time::SystemTime::now()
+        .duration_since(::std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    let jitter_ms = base_ms * u64::from(nanos % 512) / 1024;
+    Duration::from_millis(base_ms + jitter_ms)
+}
+
+/// Run `op`, retrying transient failures (5xx, timeouts, throttling)
+/// up to `attempts` times with exponential backoff and jitter.
+///
+/// Fatal errors — denied access, a missing bucket, malformed requests
+/// — fail immediately: retrying those only delays the failure report.
+/// The split keeps a brief object-store restart from translating into
+/// thousands of failed objects.
+fn retry_transient<T, F>(what: &str, attempts: u32, mut op: F) -> Result<T>
+    where F: FnMut() -> Result<T>
+{
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Err(err) => {
+                if !err.is_transient() || attempt >= attempts {
+                    return Err(err);
+                }
+                let delay = retry_delay(attempt);
+                warn!("{} failed ({}), retrying in {:?} (attempt {}/{})",
+                      what,
+                      err,
+                      delay,
+                      attempt,
+                      attempts);
+                sleep(delay);
+                attempt += 1;
+            }
+            ok => return ok,
+        }
+    }
+}
+
+/// Abort incomplete multipart uploads older than `max_age`.
+///
+/// Crashed or aborted runs leave incomplete multipart uploads behind
+/// that silently accrue storage cost until aborted. Only uploads whose
+/// key looks like one of our sha2 keys are touched, so other tools'
+/// in-flight uploads in a shared bucket are left alone. Returns the
+/// number of uploads aborted.
+pub fn abort_stale_uploads<S>(client: &S, bucket: &str, max_age: Duration) -> Result<u64>
+    where S: S3
+{
+    let cutoff = Utc::now() -
+                 chrono::Duration::from_std(max_age)
+                     .map_err(|e| ErrorKind::S3(format!("invalid max age: {}", e)))?;
+    let mut aborted = 0;
+    let mut key_marker = None;
+    let mut upload_id_marker = None;
+
+    loop {
+        let request = ListMultipartUploadsRequest {
+            bucket: bucket.to_string(),
+            key_marker: key_marker.clone(),
+            upload_id_marker: upload_id_marker.clone(),
+            ..Default::default()
+        };
+        let output = client
+            .list_multipart_uploads(request)
+            .sync()
+            .map_err(|e| ErrorKind::S3(format!("ListMultipartUploads failed: {}", e)))?;
+
+        for upload in output.uploads.unwrap_or_default() {
+            let (key, upload_id) = match (upload.key, upload.upload_id) {
+                (Some(key), Some(upload_id)) => (key, upload_id),
+                _ => continue,
+            };
+            if !is_sha2_key(&key) {
+                continue;
+            }
+            let initiated = upload
+                .initiated
+                .as_ref()
+                .and_then(|ts| DateTime::parse_from_rfc3339(ts).ok());
+            match initiated {
+                Some(initiated) if initiated.with_timezone(&Utc) < cutoff => (),
+                _ => continue,
+            }
+
+            info!("aborting stale multipart upload of {} started {}",
+                  key,
+                  upload.initiated.as_ref().map(|i| &i[..]).unwrap_or("?"));
+            let abort = AbortMultipartUploadRequest {
+                bucket: bucket.to_string(),
+                key: key,
+                upload_id: upload_id,
+                ..Default::default()
+            };
+            client
+                .abort_multipart_upload(abort)
+                .sync()
+                .map_err(|e| {
+                    ErrorKind::S3(format!("AbortMultipartUpload failed: {}", e))
+                })?;
+            aborted += 1;
+        }
+
+        if output.is_truncated != Some(true) {
+            break;
+        }
+        key_marker = output.next_key_marker;
+        upload_id_marker = output.next_upload_id_marker;
+    }
+    Ok(aborted)
+}
+
+/// How `--create-bucket` sets up a bucket that does not exist yet.
+///
+/// Only consulted when [`ensure_bucket()`] actually creates the
+/// bucket; an existing bucket is left exactly as it is.
+///
+/// [`ensure_bucket()`]: fn.ensure_bucket.html
+#[derive(Clone, Debug, Default)]
+pub struct BucketOptions {
+    /// `LocationConstraint` for the new bucket; `us-east-1` must be
+    /// requested without one, which `None` expresses
+    pub location: Option<String>,
+    /// canned ACL, e.g. `private`
+    pub acl: Option<String>,
+    /// default encryption algorithm, `AES256` or `aws:kms`
+    pub encryption: Option<String>,
+}
+
+/// Check that the target bucket exists and is accessible, optionally
+/// creating it.
+///
+/// Meant to run before any workers are spawned: a missing bucket or
+/// wrong credentials otherwise surface as every storer thread failing
+/// on its first object, well into the run. With `create` a missing
+/// bucket is created as `options` describe; access errors other than
+/// "not found" (wrong credentials, denied by policy) always fail.
+pub fn ensure_bucket<S>(client: &S, bucket: &str, create: bool, options: &BucketOptions)
+                        -> Result<()>
+    where S: S3
+{
+    let request = HeadBucketRequest { bucket: bucket.to_string() };
+    let err = match client.head_bucket(request).sync() {
+        Ok(()) => return Ok(()),
+        Err(err) => err,
+    };
+
+    let missing = match err {
+        HeadBucketError::NoSuchBucket(_) => true,
+        HeadBucketError::Unknown(ref response) => response.status.as_u16() == 404,
+        _ => false,
+    };
+    if !missing {
+        return Err(ErrorKind::S3(format!("cannot access bucket {}: {}", bucket, err)).into());
+    }
+    if !create {
+        return Err(ErrorKind::S3(format!("bucket {} does not exist; create it or rerun \
+                                          with --create-bucket",
+                                         bucket))
+                           .into());
+    }
+
+    let request = CreateBucketRequest {
+        acl: options.acl.clone(),
+        bucket: bucket.to_string(),
+        create_bucket_configuration: options
+            .location
+            .clone()
+            .map(|location| {
+                     CreateBucketConfiguration { location_constraint: Some(location) }
+                 }),
+        ..Default::default()
+    };
+    client
+        .create_bucket(request)
+        .sync()
+        .map_err(|e| ErrorKind::S3(format!("cannot create bucket {}: {}", bucket, e)))?;
+    info!("created bucket {}", bucket);
+
+    if let Some(ref algorithm) = options.encryption {
+        use rusoto_s3::{PutBucketEncryptionRequest, ServerSideEncryptionByDefault,
+                        ServerSideEncryptionConfiguration, ServerSideEncryptionRule};
+        let request = PutBucketEncryptionRequest {
+            bucket: bucket.to_string(),
+            server_side_encryption_configuration: ServerSideEncryptionConfiguration {
+                rules: vec![ServerSideEncryptionRule {
+                                apply_server_side_encryption_by_default:
+                                    Some(ServerSideEncryptionByDefault {
+                                             kms_master_key_id: None,
+                                             sse_algorithm: algorithm.clone(),
+                                         }),
+                            }],
+            },
+            ..Default::default()
+        };
+        client
+            .put_bucket_encryption(request)
+            .sync()
+            .map_err(|e| {
+                         ErrorKind::S3(format!("cannot set default encryption on bucket \
+                                                {}: {}",
+                                               bucket,
+                                               e))
+                     })?;
+        info!("defaulted bucket {} to {} encryption", bucket, algorithm);
+    }
+    Ok(())
+}
+
+/// Key of the canary object written by [`write_smoke_test()`].
+///
+/// [`write_smoke_test()`]: fn.write_smoke_test.html
+const CANARY_KEY: &str = "lo-migrate-write-test";
+
+/// Confirm that writes to the bucket actually work by uploading and
+/// deleting a small canary object.
+///
+/// A bucket can HEAD fine and still reject writes — denied `PutObject`
+/// in the IAM policy, a bucket policy demanding SSE headers, and the
+/// like — which otherwise shows up as every upload failing once the
+/// pipeline is running. The canary PUT exercises the same request path
+/// the storers use; the object is deleted again afterwards, and a
+/// failing DELETE is only logged since it does not affect the
+/// migration.
+pub fn write_smoke_test<S>(client: &S, bucket: &str) -> Result<()>
+    where S: S3
+{
+    use rusoto_s3::{DeleteObjectRequest, PutObjectRequest};
+
+    let request = PutObjectRequest {
+        bucket: bucket.to_string(),
+        key: CANARY_KEY.to_string(),
+        body: Some(b"lo-migrate write test".to_vec().into()),
+        content_type: Some("text/plain".to_string()),
+        ..Default::default()
+    };
+    client
+        .put_object(request)
+        .sync()
+        .map_err(|e| {
+                     ErrorKind::S3(format!("cannot write to bucket {}: {}; check the \
+                                            credentials, bucket policy and SSE settings",
+                                           bucket,
+                                           e))
+                 })?;
+
+    let request = DeleteObjectRequest {
+        bucket: bucket.to_string(),
+        key: CANARY_KEY.to_string(),
+        ..Default::default()
+    };
+    if let Err(err) = client.delete_object(request).sync() {
+        warn!("cannot delete canary object {} from bucket {}: {}",
+              CANARY_KEY,
+              bucket,
+              err);
+    }
+    debug!("write smoke test against bucket {} passed", bucket);
+    Ok(())
+}
+
+/// Key count and byte total of a bucket listing.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BucketTotals {
+    pub objects: u64,
+    pub bytes: u64,
+}
+
+/// Count the keys and bytes in the bucket by paging through
+/// `ListObjectsV2`, excluding the smoke test canary.
+///
+/// A cheap reconciliation signal: comparing key count and byte total
+/// against the database flags missing or surplus objects early,
+/// without fetching or hashing a single object.
+pub fn bucket_totals<S>(client: &S, bucket: &str) -> Result<BucketTotals>
+    where S: S3
+{
+    use rusoto_s3::ListObjectsV2Request;
+
+    let mut totals = BucketTotals::default();
+    let mut continuation_token = None;
+    loop {
+        let request = ListObjectsV2Request {
+            bucket: bucket.to_string(),
+            continuation_token: continuation_token.clone(),
+            ..Default::default()
+        };
+        let output = client
+            .list_objects_v2(request)
+            .sync()
+            .map_err(|e| ErrorKind::S3(format!("ListObjectsV2 failed: {}", e)))?;
+
+        for object in output.contents.unwrap_or_default() {
+            if object.key.as_ref().map(String::as_str) == Some(CANARY_KEY) {
+                continue;
+            }
+            totals.objects += 1;
+            totals.bytes += object.size.unwrap_or(0) as u64;
+        }
+        if output.is_truncated != Some(true) {
+            break;
+        }
+        continuation_token = output.next_continuation_token;
+    }
+    Ok(totals)
+}
+
+/// Whether `key` looks like one of the sha2 hex keys this tool writes.
+fn is_sha2_key(key: &str) -> bool {
+    key.len() == 64 && key.bytes().all(|b| b.is_ascii_hexdigit() && !b.is_ascii_uppercase())
+}
+
+/// ETag S3 is expected to return for a multipart upload whose parts had
+/// the given MD5 digests: the MD5 of the concatenated part digests,
+/// suffixed with the number of parts.
+fn composite_etag(part_md5s: &[[u8; 16]]) -> String {
+    let mut concatenated = Vec::with_capacity(part_md5s.len() * 16);
+    for digest in part_md5s {
+        concatenated.extend_from_slice(digest);
+    }
+    format!("{}-{}", hex::encode(&md5::compute(&concatenated).0), part_md5s.len())
+}
+
+/// Compare the ETag returned by S3 against the expected one, ignoring
+/// the quoting and hex case servers differ on.
+fn etag_matches(returned: &str, expected: &str) -> bool {
+    returned.trim_matches('"').eq_ignore_ascii_case(expected)
+}
+
+/// Journal of objects that made it to the bucket.
+///
+/// Storers append a `<sha1> <sha2> [version id]` line for every
+/// successful upload *before* the object enters the commit queue. If a run crashes
+/// between upload and commit, the affected objects sit in the bucket
+/// without their hash in the database; without the journal they could
+/// only be found by a full reconciliation. The format matches the
+/// resume manifest, so the journal can be fed straight back into the
+/// next run via `--resume-manifest`.
+#[derive(Debug)]
+pub struct UploadJournal {
+    file: Mutex<BufWriter<File>>,
+}
+
+impl UploadJournal {
+    /// Open (or create) the journal at `path`, appending to an existing
+    /// file so a restarted run keeps earlier records. A freshly created
+    /// journal starts with the [`manifest`] format header.
+    ///
+    /// [`manifest`]: ../manifest/index.html
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        let mut file = BufWriter::new(file);
+        if file.get_ref().metadata()?.len() == 0 {
+            manifest::write_header(&mut file)?;
+            file.flush()?;
+        }
+        Ok(UploadJournal { file: Mutex::new(file) })
+    }
+
+    /// Record an uploaded object. The line is flushed to the OS before
+    /// this returns so it survives a crash of the process.
+    pub fn record(&self, lo: &Lo) -> Result<()> {
+        let sha2 = lo.sha2_hex().ok_or(ErrorKind::Sha2NotComputed)?;
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        // the version id is a trailing field the manifest parser of
+        // older releases skips over, so the format version stays at 1
+        match lo.version_id() {
+            Some(version_id) => writeln!(file, "{} {} {}", lo.sha1_hex(), sha2, version_id)?,
+            None => writeln!(file, "{} {}", lo.sha1_hex(), sha2)?,
+        }
+        file.flush()?;
+        Ok(())
+    }
+}
+
+/// Caching headers attached to every uploaded object.
+///
+/// Migrated binaries are typically served through a CDN afterwards;
+/// retrofitting headers would require a copy-in-place of every object,
+/// so they are set during the upload. A global `Cache-Control` value
+/// can be overridden per mime type, where a rule's pattern either
+/// matches exactly or, with a trailing `*`, by prefix (`image/*`).
+#[derive(Clone, Debug, Default)]
+pub struct UploadHeaders {
+    cache_control: Option<String>,
+    expires: Option<String>,
+    /// mime type pattern -> Cache-Control value, first match wins
+    rules: Vec<(String, String)>,
+    default_content_type: Option<String>,
+    sniff_content_type: bool,
+    normalize_content_type: bool,
+    send_checksums: bool,
+}
+
+impl UploadHeaders {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `Cache-Control` value for objects no rule matches.
+    pub fn with_cache_control(mut self, value: Option<String>) -> Self {
+        self.cache_control = value;
+        self
+    }
+
+    /// `Expires` value set on all objects.
+    pub fn with_expires(mut self, value: Option<String>) -> Self {
+        self.expires = value;
+        self
+    }
+
+    /// Add a per-mime-type `Cache-Control` rule, tried before the
+    /// global value in the order added.
+    pub fn with_rule(mut self, pattern: String, value: String) -> Self {
+        self.rules.push((pattern, value));
+        self
+    }
+
+    /// `Cache-Control` value for an object of the given mime type.
+    pub fn cache_control_for(&self, mime_type: &str) -> Option<String> {
+        self.rules
+            .iter()
+            .find(|&&(ref pattern, _)| mime_matches(pattern, mime_type))
+            .map(|&(_, ref value)| value.clone())
+            .or_else(|| self.cache_control.clone())
+    }
+
+    /// `Expires` value, the same for all objects.
+    pub fn expires(&self) -> Option<String> {
+        self.expires.clone()
+    }
+
+    /// `Content-Type` for objects whose `mime_type` column is NULL or
+    /// empty; without one such objects are uploaded without the header.
+    pub fn with_default_content_type(mut self, value: Option<String>) -> Self {
+        self.default_content_type = value;
+        self
+    }
+
+    /// Guess the content type of objects without one from their leading
+    /// magic bytes, falling back to the default only when nothing is
+    /// recognized.
+    pub fn with_content_type_sniffing(mut self, sniff: bool) -> Self {
+        self.sniff_content_type = sniff;
+        self
+    }
+
+    /// Normalize mime types before uploading: lowercase them, correct
+    /// well-known legacy spellings (`octet/stream`, `image/jpg`, ...)
+    /// and drop values that are not valid `type/subtype` syntax, which
+    /// some S3-compatible stores reject. Only the uploaded header is
+    /// affected; the `mime_type` column keeps its original value.
+    pub fn with_content_type_normalization(mut self, normalize: bool) -> Self {
+        self.normalize_content_type = normalize;
+        self
+    }
+
+    pub(crate) fn normalize_content_type(&self) -> bool {
+        self.normalize_content_type
+    }
+
+    /// Attach the sha256 of every upload as its checksum, so stores
+    /// supporting additional checksums verify the transfer server-side
+    /// (and older stores at least get a `Content-MD5`). The hash is the
+    /// one the receiver already computed; nothing is hashed twice for
+    /// single-request uploads.
+    pub fn with_checksums(mut self, send: bool) -> Self {
+        self.send_checksums = send;
+        self
+    }
+
+    pub(crate) fn send_checksums(&self) -> bool {
+        self.send_checksums
+    }
+
+    /// `Content-Type` for an object whose row carries none, given the
+    /// first bytes of its data.
+    pub fn content_type_for_data(&self, head: &[u8]) -> Option<String> {
+        let sniffed = if self.sniff_content_type {
+            sniff_mime(head)
+        } else {
+            None
+        };
+        sniffed
+            .map(str::to_string)
+            .or_else(|| self.default_content_type.clone())
+    }
+}
+
+/// Whether `mime` is a syntactically valid `type/subtype`, using the
+/// token syntax of RFC 6838.
+fn valid_mime(mime: &str) -> bool {
+    fn token(part: &str) -> bool {
+        !part.is_empty() &&
+        part.bytes().all(|b| match b {
+                             b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' => true,
+                             b'!' | b'#' | b'$' | b'&' | b'-' | b'^' | b'_' | b'.' | b'+' => true,
+                             _ => false,
+                         })
+    }
+
+    let mut parts = mime.splitn(2, '/');
+    match (parts.next(), parts.next()) {
+        (Some(main), Some(sub)) => token(main) && token(sub),
+        _ => false,
+    }
+}
+
+/// Lowercase `mime` and correct well-known legacy spellings.
+fn normalize_mime(mime: &str) -> String {
+    const LEGACY: &[(&str, &str)] = &[("octet/stream", "application/octet-stream"),
+                                      ("application/octetstream", "application/octet-stream"),
+                                      ("image/jpg", "image/jpeg"),
+                                      ("image/pjpeg", "image/jpeg"),
+                                      ("text/xml", "application/xml")];
+    let lowered = mime.trim().to_ascii_lowercase();
+    match LEGACY.iter().find(|&&(legacy, _)| legacy == lowered) {
+        Some(&(_, corrected)) => corrected.to_string(),
+        None => lowered,
+    }
+}
+
+/// How many leading bytes are enough for [`sniff_mime()`].
+///
+/// [`sniff_mime()`]: fn.sniff_mime.html
+const SNIFF_LEN: usize = 16;
+
+/// Guess a mime type from the file's magic bytes.
+///
+/// Covers the formats that dominate typical Nice document stores;
+/// anything unrecognized falls back to the configured default.
+fn sniff_mime(head: &[u8]) -> Option<&'static str> {
+    const MAGIC: &[(&[u8], &str)] = &[(b"%PDF-", "application/pdf"),
+                                      (b"\x89PNG\r\n\x1a\n", "image/png"),
+                                      (b"\xff\xd8\xff", "image/jpeg"),
+                                      (b"GIF87a", "image/gif"),
+                                      (b"GIF89a", "image/gif"),
+                                      (b"PK\x03\x04", "application/zip"),
+                                      (b"\x1f\x8b", "application/gzip"),
+                                      (b"II*\x00", "image/tiff"),
+                                      (b"MM\x00*", "image/tiff")];
+    MAGIC
+        .iter()
+        .find(|&&(magic, _)| head.starts_with(magic))
+        .map(|&(_, mime)| mime)
+}
+
+/// Whether a mime type pattern (`image/png` or `image/*`) matches.
+pub(crate) fn mime_matches(pattern: &str, mime_type: &str) -> bool {
+    if pattern.ends_with('*') {
+        mime_type.starts_with(&pattern[..pattern.len() - 1])
+    } else {
+        pattern == mime_type
+    }
+}
+
+/// Upper capacities of the [`BufferPool`] size classes; one further
+/// class holds everything larger.
+///
+/// [`BufferPool`]: struct.BufferPool.html
+const BUFFER_CLASS_CAPS: [usize; 3] = [64 * 1024, 1024 * 1024, 16 * 1024 * 1024];
+
+/// Size class a buffer of `capacity` bytes belongs to.
+fn buffer_class(capacity: usize) -> usize {
+    BUFFER_CLASS_CAPS
+        .iter()
+        .position(|&cap| capacity <= cap)
+        .unwrap_or(BUFFER_CLASS_CAPS.len())
+}
+
+/// Pool of reusable byte buffers shared by the worker threads.
+///
+/// The receivers' in-memory object buffers and the storers' staging
+/// buffers are returned here once their object is uploaded, instead of
+/// being freed and reallocated for every object. With many worker
+/// threads this noticeably cuts allocator pressure and peak RSS.
+///
+/// Buffers are kept in capacity classes (up to each of
+/// [`BUFFER_CLASS_CAPS`], plus one class above), so a request for a
+/// small buffer does not pin a multi-megabyte allocation and a large
+/// request is not served a buffer that would immediately regrow.
+///
+/// [`BUFFER_CLASS_CAPS`]: constant.BUFFER_CLASS_CAPS.html
+#[derive(Debug)]
+pub struct BufferPool {
+    classes: [Mutex<Vec<Vec<u8>>>; 4],
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    /// Pool keeping at most `max_buffers` buffers around per size class.
+    pub fn new(max_buffers: usize) -> Self {
+        BufferPool {
+            classes: [Mutex::new(Vec::new()),
+                      Mutex::new(Vec::new()),
+                      Mutex::new(Vec::new()),
+                      Mutex::new(Vec::new())],
+            max_buffers: max_buffers,
+        }
+    }
+
+    /// Get an empty buffer for `size_hint` bytes, reusing a pooled
+    /// allocation of the matching size class if one fits.
+    pub fn take(&self, size_hint: usize) -> Vec<u8> {
+        {
+            let mut buffers = self.classes[buffer_class(size_hint)]
+                .lock()
+                .unwrap_or_else(|e| e.into_inner());
+            if let Some(index) = buffers
+                   .iter()
+                   .position(|buffer| buffer.capacity() >= size_hint) {
+                return buffers.swap_remove(index);
+            }
+        }
+        Vec::with_capacity(size_hint)
+    }
+
+    /// Return a no longer needed buffer to the pool.
+    pub fn put(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() == 0 {
+            return;
+        }
+        let mut buffers = self.classes[buffer_class(buffer.capacity())]
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        if buffers.len() < self.max_buffers {
+            buffer.clear();
+            buffers.push(buffer);
+        }
+    }
+}
+
+/// Limits the average upload rate of a single storer thread.
+///
+/// Keeps one thread that happens to pick up a giant object from
+/// monopolizing the uplink and starving the other storers' small-object
+/// throughput. Accounting is per uploaded buffer (object or part): after
+/// each upload the limiter sleeps long enough to keep the average rate
+/// at or below the configured limit.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_sec: Option<u64>,
+    started: Instant,
+    bytes: u64,
+}
+
+impl RateLimiter {
+    /// Limit to `bytes_per_sec`, or no limit at all with `None`.
+    pub fn new(bytes_per_sec: Option<u64>) -> Self {
+        RateLimiter {
+            bytes_per_sec: bytes_per_sec,
+            started: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// Account `bytes` of upload and sleep if we are ahead of the limit.
+    pub fn throttle(&mut self, bytes: u64) {
+        let limit = match self.bytes_per_sec {
+            Some(limit) => limit,
+            None => return,
+        };
+        self.bytes += bytes;
+        let expected = Duration::from_millis(self.bytes * 1000 / limit);
+        let elapsed = self.started.elapsed();
+        if expected > elapsed {
+            sleep(expected - elapsed);
+        }
+    }
+}
+
+/// Parts allowed per multipart upload (S3 limit).
+const S3_MAX_PARTS: i64 = 10_000;
+
+/// Largest single PUT body and largest multipart part (S3 limit).
+const S3_MAX_UPLOAD_SIZE: i64 = 5 * 1024 * 1024 * 1024;
+
+/// Largest object S3 accepts at all: 10,000 parts of 5 GiB.
+const S3_MAX_OBJECT_SIZE: i64 = S3_MAX_PARTS * S3_MAX_UPLOAD_SIZE;
+
+/// Smallest part S3 accepts (all but the last part).
+const S3_MIN_PART_SIZE: i64 = 5 * 1024 * 1024;
+
+/// Part count [`adaptive_chunk_size()`] aims for.
+///
+/// [`adaptive_chunk_size()`]: fn.adaptive_chunk_size.html
+const TARGET_PART_COUNT: i64 = 100;
+
+/// Largest part [`adaptive_chunk_size()`] picks on its own; parts are
+/// staged or copied whole, so this bounds per-part memory.
+///
+/// [`adaptive_chunk_size()`]: fn.adaptive_chunk_size.html
+const MAX_ADAPTIVE_PART_SIZE: i64 = 256 * 1024 * 1024;
+
+/// Part size used for an object of `size` bytes.
+///
+/// Objects up to the configured chunk size are uploaded in a single
+/// request and keep the configured value. Larger objects get a part
+/// size derived from their total size — about [`TARGET_PART_COUNT`]
+/// parts, never below the 5 MiB S3 part minimum and never above
+/// [`MAX_ADAPTIVE_PART_SIZE`] — instead of the one global value:
+/// modest objects stage smaller parts, huge objects make fewer
+/// requests. Where the 10,000-part limit forces larger parts it wins
+/// over the upper bound.
+pub fn adaptive_chunk_size(size: i64, configured: usize) -> usize {
+    if size <= configured as i64 {
+        return configured;
+    }
+    let target = (size + TARGET_PART_COUNT - 1) / TARGET_PART_COUNT;
+    let bounded = target.max(S3_MIN_PART_SIZE).min(MAX_ADAPTIVE_PART_SIZE);
+    let for_part_limit = (size + S3_MAX_PARTS - 1) / S3_MAX_PARTS;
+    bounded.max(for_part_limit) as usize
+}
+
+/// Uploads buffered objects to S3 and hands them on to the committers.
+pub struct Storer<'a> {
+    stats: &'a ThreadStat,
+    rate_limit: Option<u64>,
+    part_attempts: u32,
+    pool: Arc<BufferPool>,
+    headers: UploadHeaders,
+    journal: Option<Arc<UploadJournal>>,
+    metrics: Option<Arc<MetricsSink>>,
+    registry: Option<Arc<BufferRegistry>>,
+    max_object_size: Option<i64>,
+    gate: Option<Arc<ConcurrencyGate>>,
+    audit_trail: bool,
+}
+
+impl<'a> Storer<'a> {
+    pub fn new(stats: &'a ThreadStat) -> Self {
+        Storer {
+            stats: stats,
+            rate_limit: None,
+            part_attempts: 3,
+            pool: Arc::new(BufferPool::new(4)),
+            headers: UploadHeaders::new(),
+            journal: None,
+            metrics: None,
+            registry: None,
+            max_object_size: None,
+            gate: None,
+            audit_trail: false,
+        }
+    }
+
+    /// Stamp every object with a store timestamp, duration and the
+    /// worker name for the audit log; see [`AuditLog`].
+    ///
+    /// [`AuditLog`]: ../audit/struct.AuditLog.html
+    pub fn with_audit_trail(mut self, audit: bool) -> Self {
+        self.audit_trail = audit;
+        self
+    }
+
+    /// Release consumed file-backed buffers from `registry`; see
+    /// [`BufferRegistry`].
+    ///
+    /// [`BufferRegistry`]: ../tempfiles/struct.BufferRegistry.html
+    pub fn with_buffer_registry(mut self, registry: Option<Arc<BufferRegistry>>) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    /// Record a `store_seconds` histogram sample per uploaded object.
+    pub fn with_metrics(mut self, metrics: Option<Arc<MetricsSink>>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Journal every successful upload before it enters the commit
+    /// queue; see [`UploadJournal`].
+    ///
+    /// [`UploadJournal`]: struct.UploadJournal.html
+    pub fn with_journal(mut self, journal: Option<Arc<UploadJournal>>) -> Self {
+        self.journal = journal;
+        self
+    }
+
+    /// Caching headers to set on every uploaded object.
+    pub fn with_headers(mut self, headers: UploadHeaders) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Share a buffer pool with the other storer threads instead of the
+    /// small thread-local default pool.
+    pub fn with_buffer_pool(mut self, pool: Arc<BufferPool>) -> Self {
+        self.pool = pool;
+        self
+    }
+
+    /// Take a permit from `gate` before working on an object, so the
+    /// effective storer parallelism follows the gate instead of the
+    /// thread count; see [`ConcurrencyGate`].
+    ///
+    /// [`ConcurrencyGate`]: struct.ConcurrencyGate.html
+    pub fn with_concurrency_gate(mut self, gate: Option<Arc<ConcurrencyGate>>) -> Self {
+        self.gate = gate;
+        self
+    }
+
+    /// Cap this thread's average upload rate at `bytes_per_sec`.
+    pub fn with_rate_limit(mut self, bytes_per_sec: Option<u64>) -> Self {
+        self.rate_limit = bytes_per_sec;
+        self
+    }
+
+    /// How often a single multipart part is attempted before the whole
+    /// upload is aborted.
+    pub fn with_part_attempts(mut self, attempts: u32) -> Self {
+        assert!(attempts > 0, "at least one attempt is needed");
+        self.part_attempts = attempts;
+        self
+    }
+
+    /// Fail objects larger than `bytes` up front instead of uploading
+    /// them; the hard S3 limits are enforced regardless.
+    pub fn with_max_object_size(mut self, bytes: Option<i64>) -> Self {
+        self.max_object_size = bytes;
+        self
+    }
+
+    /// Check `lo` against the configured maximum and the hard S3 limits
+    /// before any upload work is done.
+    ///
+    /// Failing here routes the object into the regular failure report
+    /// with a reason a person can act on, instead of a cryptic S3 error
+    /// from deep inside the multipart machinery — possibly hours into
+    /// the upload.
+    fn check_size(&self, lo: &Lo, chunk_size: usize) -> Result<()> {
+        let size = lo.size();
+        if let Some(max) = self.max_object_size {
+            if size > max {
+                return Err(ErrorKind::ObjectTooLarge(format!("object is {} bytes, above \
+                                                              the configured maximum of \
+                                                              {} bytes",
+                                                             size,
+                                                             max))
+                                   .into());
+            }
+        }
+        if size > S3_MAX_OBJECT_SIZE {
+            return Err(ErrorKind::ObjectTooLarge(format!("object is {} bytes, above the \
+                                                          5 TiB S3 object limit",
+                                                         size))
+                               .into());
+        }
+        if size <= chunk_size as i64 {
+            // single PUT
+            if size > S3_MAX_UPLOAD_SIZE {
+                return Err(ErrorKind::ObjectTooLarge(format!("object is {} bytes, above \
+                                                              the 5 GiB single-upload \
+                                                              limit; lower the upload \
+                                                              chunk size to upload it \
+                                                              in parts",
+                                                             size))
+                                   .into());
+            }
+        } else {
+            // multipart
+            let parts = (size + chunk_size as i64 - 1) / chunk_size as i64;
+            if parts > S3_MAX_PARTS {
+                let needed = (size + S3_MAX_PARTS - 1) / S3_MAX_PARTS;
+                let needed_mib = (needed + 1024 * 1024 - 1) / (1024 * 1024);
+                return Err(ErrorKind::ObjectTooLarge(format!("object needs {} parts of \
+                                                              {} bytes but S3 allows \
+                                                              10,000; raise the upload \
+                                                              chunk size to at least \
+                                                              {} MiB",
+                                                             parts,
+                                                             chunk_size,
+                                                             needed_mib))
+                                   .into());
+            }
+            if chunk_size as i64 > S3_MAX_UPLOAD_SIZE {
+                return Err(ErrorKind::ObjectTooLarge(format!("the upload chunk size of \
+                                                              {} bytes is above the \
+                                                              5 GiB S3 part limit",
+                                                             chunk_size))
+                                   .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop everything still queued after a cancellation, deleting the
+    /// buffer files right away instead of carrying them until process
+    /// exit. Queued objects have no multipart upload in flight yet, so
+    /// dropping them leaves nothing to abort.
+    fn drain_queue(&self, rx: &Arc<WorkQueueReceiver<Lo>>) {
+        let mut dropped = 0;
+        for mut lo in rx.drain() {
+            // dropping the temp file handle deletes the file
+            if let Data::File(file) = lo.take_data() {
+                if let Some(ref registry) = self.registry {
+                    registry.release(file.path());
+                }
+            }
+            dropped += 1;
+        }
+        if dropped > 0 {
+            debug!("run cancelled, dropped {} queued objects", dropped);
+        }
+    }
+
+    /// Process objects from the store queue until it disconnects.
+    ///
+    /// Objects whose file-backed buffer exceeds `chunk_size` are uploaded
+    /// as multipart uploads with parts of `chunk_size` bytes.
+    pub fn start_worker(&self,
+                        rx: Arc<WorkQueueReceiver<Lo>>,
+                        tx: Arc<WorkQueueSender<Lo>>,
+                        store: &ObjectStore,
+                        chunk_size: usize)
+                        -> Result<u64> {
+        self.worker(rx, tx, store, chunk_size)
+            .map_err(|err| err.at(Stage::Store))
+    }
+
+    fn worker(&self,
+              rx: Arc<WorkQueueReceiver<Lo>>,
+              tx: Arc<WorkQueueSender<Lo>>,
+              store: &ObjectStore,
+              chunk_size: usize)
+              -> Result<u64> {
+        let mut count = 0;
+        let mut limiter = RateLimiter::new(self.rate_limit);
+        loop {
+            if self.stats.is_cancelled() {
+                self.drain_queue(&rx);
+            }
+            self.stats.abort_if_cancelled()?;
+            let mut lo = match rx.recv_timeout(RECV_TIMEOUT) {
+                RecvResult::Item(lo) => lo,
+                RecvResult::TimedOut => continue,
+                RecvResult::Disconnected => break,
+            };
+
+            let _permit = self.acquire_permit()?;
+
+            // store() consumes the buffer either way; remember the
+            // path so the registry entry can be released afterwards
+            let buffer_path = match *lo.data() {
+                Data::File(ref file) => Some(file.path().to_path_buf()),
+                _ => None,
+            };
+
+            // pick the part size per object; the configured value only
+            // decides up to where a single request is used
+            let chunk_size = adaptive_chunk_size(lo.size(), chunk_size);
+
+            let started = Instant::now();
+            let stored = ::thread::catch_object_panic(|| {
+                self.check_size(&lo, chunk_size)
+                    .and_then(|_| {
+                                  lo.store(store,
+                                           chunk_size,
+                                           &mut limiter,
+                                           self.part_attempts,
+                                           &self.pool,
+                                           &self.headers)
+                              })
+            });
+            if let (&Some(ref registry), Some(ref path)) = (&self.registry, buffer_path) {
+                registry.release(path);
+            }
+            match stored {
+                Ok(()) => {
+                    if let Some(ref metrics) = self.metrics {
+                        metrics.histogram("store_seconds", seconds(started.elapsed()));
+                    }
+                    if self.audit_trail {
+                        let trail = lo.audit_trail_mut();
+                        trail.stored_at = Some(Utc::now());
+                        trail.store_seconds = seconds(started.elapsed());
+                        trail.stored_by = ::audit::worker_name();
+                    }
+                    if let Some(ref journal) = self.journal {
+                        journal.record(&lo)?;
+                    }
+                    self.stats.add_stored();
+                    if let Some(ref gate) = self.gate {
+                        gate.record_success(lo.size() as u64);
+                    }
+                    count += 1;
+                    tx.send(lo)?;
+                }
+                Err(err) => {
+                    let err = err.at(Stage::Store).for_object(lo.oid(), lo.sha1_hex());
+                    warn!("failed to upload object: {}", err);
+                    self.stats.record_failure(&err);
+                    if let Some(ref gate) = self.gate {
+                        gate.record_error();
+                    }
+                }
+            };
+        }
+
+        debug!("storer done, {} objects uploaded", count);
+        Ok(count)
+    }
+
+    /// Wait for a permit from the concurrency gate, if one is
+    /// configured, rechecking the cancellation flag while waiting.
+    fn acquire_permit(&self) -> Result<Option<Permit>> {
+        match self.gate {
+            Some(ref gate) => {
+                loop {
+                    self.stats.abort_if_cancelled()?;
+                    if let Some(permit) = gate.permit(RECV_TIMEOUT) {
+                        return Ok(Some(permit));
+                    }
+                }
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Lo {
+    /// Upload the buffered data to S3, keyed by the object's sha2 hash.
+    ///
+    /// The buffer is consumed; on success the object carries
+    /// [`Data::None`] and only the metadata needed by the committer.
+    /// An object without data or sha2 hash fails with a typed error
+    /// instead of panicking, so one malformed object cannot take down
+    /// an embedding application.
+    ///
+    /// [`Data::None`]: ../lo/enum.Data.html
+    pub fn store(&mut self,
+                 store: &ObjectStore,
+                 chunk_size: usize,
+                 limiter: &mut RateLimiter,
+                 part_attempts: u32,
+                 pool: &BufferPool,
+                 headers: &UploadHeaders)
+                 -> Result<()> {
+        let key = self.sha2_hex().ok_or(ErrorKind::Sha2NotComputed)?;
+
+        if headers.normalize_content_type() && !self.mime_type().trim().is_empty() {
+            let normalized = normalize_mime(self.mime_type());
+            if !valid_mime(&normalized) {
+                // invalid content types are rejected by some stores;
+                // dropping the value routes it through the fallback below
+                warn!("object {} has invalid mime type {:?}, ignoring it",
+                      key,
+                      self.mime_type());
+                self.set_mime_type(String::new());
+            } else if normalized != self.mime_type() {
+                debug!("normalizing mime type {:?} of object {} to {:?}",
+                       self.mime_type(),
+                       key,
+                       normalized);
+                self.set_mime_type(normalized);
+            }
+        }
+
+        // rows with a NULL or empty mime_type would upload without a
+        // usable Content-Type; substitute the sniffed or configured one
+        if self.mime_type().trim().is_empty() {
+            let head = self.peek_data(SNIFF_LEN)?;
+            if let Some(content_type) = headers.content_type_for_data(&head) {
+                debug!("object {} carries no mime type, using {}", key, content_type);
+                self.set_mime_type(content_type);
+            }
+        }
+
+        // zero-byte objects carry an empty buffer; upload the empty
+        // body directly instead of routing it through the size-based
+        // buffering and multipart branches below
+        if self.size() == 0 {
+            match self.take_data() {
+                Data::None => return Err(ErrorKind::NoDataAttached.into()),
+                _ => {
+                    let version_id = self.upload_in_one_go(store, &key, &[], limiter,
+                                                           part_attempts, headers)?;
+                    self.set_version_id(version_id);
+                    return Ok(());
+                }
+            }
+        }
+
+        let version_id = match self.take_data() {
+            Data::Vec(data) => {
+                // the buffer is shared with the request body instead of
+                // being copied into it; the copy used to double peak
+                // RSS for every memory-buffered object
+                let data = Arc::new(data);
+                let version_id = self.upload_shared(store, &key, &data, limiter,
+                                                    part_attempts, headers)?;
+                reclaim_buffer(data, pool);
+                version_id
+            }
+            Data::File(file) => {
+                if self.size() > chunk_size as i64 {
+                    self.upload_multipart(store,
+                                          &key,
+                                          file.path(),
+                                          chunk_size,
+                                          limiter,
+                                          part_attempts,
+                                          headers)?
+                } else {
+                    let mut data = pool.take(self.size() as usize);
+                    file.reopen()?.read_to_end(&mut data)?;
+                    let data = Arc::new(data);
+                    let version_id = self.upload_shared(store, &key, &data, limiter,
+                                                        part_attempts, headers)?;
+                    reclaim_buffer(data, pool);
+                    version_id
+                }
+            }
+            Data::Custom(mut reader) => {
+                reader.seek(::std::io::SeekFrom::Start(0))?;
+                if self.size() > chunk_size as i64 {
+                    self.upload_multipart_from_reader(store,
+                                                      &key,
+                                                      &mut *reader,
+                                                      chunk_size,
+                                                      limiter,
+                                                      part_attempts,
+                                                      pool,
+                                                      headers)?
+                } else {
+                    let mut data = pool.take(self.size() as usize);
+                    reader.read_to_end(&mut data)?;
+                    let data = Arc::new(data);
+                    let version_id = self.upload_shared(store, &key, &data, limiter,
+                                                        part_attempts, headers)?;
+                    reclaim_buffer(data, pool);
+                    version_id
+                }
+            }
+            Data::None => return Err(ErrorKind::NoDataAttached.into()),
+        };
+        self.set_version_id(version_id);
+        Ok(())
+    }
+
+    /// Headers attached to this object's upload.
+    fn upload_meta(&self, headers: &UploadHeaders) -> UploadMeta {
+        let mime_type = self.mime_type().trim();
+        UploadMeta {
+            // no Content-Type at all beats an empty one
+            content_type: if mime_type.is_empty() {
+                None
+            } else {
+                Some(mime_type.to_string())
+            },
+            content_disposition: self.content_disposition(),
+            cache_control: headers.cache_control_for(mime_type),
+            expires: headers.expires(),
+            checksum_sha256: if headers.send_checksums() {
+                self.sha2().map(base64)
+            } else {
+                None
+            },
+        }
+    }
+
+    /// `Content-Disposition` header advertising the original filename,
+    /// if the observer attached one.
+    fn content_disposition(&self) -> Option<String> {
+        self.filename().map(|name| {
+            let escaped = name.replace('\\', "\\\\").replace('"', "\\\"");
+            format!("attachment; filename=\"{}\"", escaped)
+        })
+    }
+
+    fn upload_in_one_go(&self,
+                        store: &ObjectStore,
+                        key: &str,
+                        data: &[u8],
+                        limiter: &mut RateLimiter,
+                        attempts: u32,
+                        headers: &UploadHeaders)
+                        -> Result<Option<String>> {
+        let meta = self.upload_meta(headers);
+        let outcome = retry_transient(&format!("upload of {}", key),
+                                      attempts,
+                                      || store.put(key, data, &meta))?;
+        limiter.throttle(data.len() as u64);
+        Ok(outcome.version_id)
+    }
+
+    /// [`upload_in_one_go()`] out of a buffer shared with the request
+    /// body; sharing instead of borrowing keeps the retries and lets
+    /// the store skip the copy into the request.
+    ///
+    /// [`upload_in_one_go()`]: #method.upload_in_one_go
+    fn upload_shared(&self,
+                     store: &ObjectStore,
+                     key: &str,
+                     data: &Arc<Vec<u8>>,
+                     limiter: &mut RateLimiter,
+                     attempts: u32,
+                     headers: &UploadHeaders)
+                     -> Result<Option<String>> {
+        let meta = self.upload_meta(headers);
+        let outcome = retry_transient(&format!("upload of {}", key),
+                                      attempts,
+                                      || store.put_shared(key, data, &meta))?;
+        limiter.throttle(data.len() as u64);
+        Ok(outcome.version_id)
+    }
+
+    fn upload_multipart(&self,
+                        store: &ObjectStore,
+                        key: &str,
+                        path: &::std::path::Path,
+                        chunk_size: usize,
+                        limiter: &mut RateLimiter,
+                        part_attempts: u32,
+                        headers: &UploadHeaders)
+                        -> Result<Option<String>> {
+        let upload_id = store.create_multipart(key, &self.upload_meta(headers))?;
+        let produced = self.upload_parts(store,
+                                         key,
+                                         &upload_id,
+                                         path,
+                                         chunk_size,
+                                         limiter,
+                                         part_attempts,
+                                         headers.send_checksums());
+        complete_or_abort(store, key, &upload_id, produced)
+    }
+
+    /// Multipart upload whose parts are read sequentially out of a
+    /// [`BufferedData`] reader, used for [`Data::Custom`] buffers that
+    /// have no file to mmap.
+    ///
+    /// [`BufferedData`]: ../lo/trait.BufferedData.html
+    /// [`Data::Custom`]: ../lo/enum.Data.html
+    fn upload_multipart_from_reader(&self,
+                                    store: &ObjectStore,
+                                    key: &str,
+                                    reader: &mut BufferedData,
+                                    chunk_size: usize,
+                                    limiter: &mut RateLimiter,
+                                    part_attempts: u32,
+                                    pool: &BufferPool,
+                                    headers: &UploadHeaders)
+                                    -> Result<Option<String>> {
+        let upload_id = store.create_multipart(key, &self.upload_meta(headers))?;
+        let produced = self.upload_parts_from_reader(store,
+                                                     key,
+                                                     &upload_id,
+                                                     reader,
+                                                     chunk_size,
+                                                     limiter,
+                                                     part_attempts,
+                                                     pool,
+                                                     headers.send_checksums());
+        complete_or_abort(store, key, &upload_id, produced)
+    }
+
+    fn upload_parts_from_reader(&self,
+                                store: &ObjectStore,
+                                key: &str,
+                                upload_id: &str,
+                                reader: &mut BufferedData,
+                                chunk_size: usize,
+                                limiter: &mut RateLimiter,
+                                part_attempts: u32,
+                                pool: &BufferPool,
+                                send_checksums: bool)
+                                -> Result<(Vec<Part>, Vec<[u8; 16]>)> {
+        let mut parts = Vec::new();
+        let mut part_md5s = Vec::new();
+        let mut buffer = pool.take(chunk_size);
+        let mut part_number = 1;
+        loop {
+            read_chunk(reader, &mut buffer, chunk_size)?;
+            if buffer.is_empty() {
+                break;
+            }
+            let len = buffer.len();
+            let checksum = if send_checksums {
+                Some(sha256_checksum(&buffer))
+            } else {
+                None
+            };
+            let md5 = md5::compute(&buffer).0;
+            // each part is shared with the request body instead of
+            // being copied into it and reclaimed for the next part
+            let shared = Arc::new(buffer);
+            let part = self.upload_shared_part_with_retry(store,
+                                                          key,
+                                                          upload_id,
+                                                          part_number,
+                                                          &shared,
+                                                          checksum
+                                                              .as_ref()
+                                                              .map(String::as_str),
+                                                          part_attempts)?;
+            buffer = Arc::try_unwrap(shared).unwrap_or_else(|shared| (*shared).clone());
+            limiter.throttle(len as u64);
+            parts.push(part);
+            part_md5s.push(md5);
+            if len < chunk_size {
+                break;
+            }
+            part_number += 1;
+        }
+        pool.put(buffer);
+        Ok((parts, part_md5s))
+    }
+
+    fn upload_parts(&self,
+                    store: &ObjectStore,
+                    key: &str,
+                    upload_id: &str,
+                    path: &::std::path::Path,
+                    chunk_size: usize,
+                    limiter: &mut RateLimiter,
+                    part_attempts: u32,
+                    send_checksums: bool)
+                    -> Result<(Vec<Part>, Vec<[u8; 16]>)> {
+        let file = ::std::fs::File::open(path)?;
+        // safe: the buffer file is private to this Lo and nobody resizes
+        // it once the receiver is done writing
+        let mmap = unsafe { Mmap::map(&file)? };
+
+        // parts are sliced straight out of the mapping, the only copy
+        // left is the one into the request body
+        let mut parts = Vec::new();
+        let mut part_md5s = Vec::new();
+        for (index, data) in mmap.chunks(chunk_size).enumerate() {
+            let part_number = index as i64 + 1;
+            let checksum = if send_checksums {
+                Some(sha256_checksum(data))
+            } else {
+                None
+            };
+            let part = self.upload_part_with_retry(store,
+                                                   key,
+                                                   upload_id,
+                                                   part_number,
+                                                   data,
+                                                   checksum.as_ref().map(String::as_str),
+                                                   part_attempts)?;
+            limiter.throttle(data.len() as u64);
+            parts.push(part);
+            part_md5s.push(md5::compute(data).0);
+        }
+        Ok((parts, part_md5s))
+    }
+
+    /// Upload one part, retrying transient failures with backoff.
+    ///
+    /// Transient 500s from Ceph/MinIO are common; aborting the whole
+    /// multipart upload on the first one would throw away all parts
+    /// uploaded so far, so each part gets `part_attempts` tries first.
+    /// Fatal errors (denied access, missing bucket) are not retried.
+    fn upload_part_with_retry(&self,
+                              store: &ObjectStore,
+                              key: &str,
+                              upload_id: &str,
+                              part_number: i64,
+                              data: &[u8],
+                              checksum_sha256: Option<&str>,
+                              part_attempts: u32)
+                              -> Result<Part> {
+        retry_transient(&format!("upload of part {} of {}", part_number, key),
+                        part_attempts,
+                        || store.upload_part(key, upload_id, part_number, data, checksum_sha256))
+    }
+
+    /// [`upload_part_with_retry()`] out of a buffer shared with the
+    /// request body.
+    ///
+    /// [`upload_part_with_retry()`]: #method.upload_part_with_retry
+    fn upload_shared_part_with_retry(&self,
+                                     store: &ObjectStore,
+                                     key: &str,
+                                     upload_id: &str,
+                                     part_number: i64,
+                                     data: &Arc<Vec<u8>>,
+                                     checksum_sha256: Option<&str>,
+                                     part_attempts: u32)
+                                     -> Result<Part> {
+        retry_transient(&format!("upload of part {} of {}", part_number, key),
+                        part_attempts,
+                        || {
+                            store.upload_part_shared(key,
+                                                     upload_id,
+                                                     part_number,
+                                                     data,
+                                                     checksum_sha256)
+                        })
+    }
+}
+
+/// Return an upload's body buffer to the pool.
+///
+/// Once the request is done its body has dropped the shared reference,
+/// so this normally succeeds; a buffer a backend still holds on to is
+/// simply not recycled.
+fn reclaim_buffer(data: Arc<Vec<u8>>, pool: &BufferPool) {
+    if let Ok(buffer) = Arc::try_unwrap(data) {
+        pool.put(buffer);
+    }
+}
+
+/// Complete `upload_id` from the produced parts, or abort it so the
+/// failed upload does not linger and accrue storage cost.
+///
+/// On completion the returned ETag is validated: the composite ETag is
+/// derived from the part contents, so a mismatch means the store did
+/// not assemble the parts we sent and the object's data cannot be
+/// trusted.
+fn complete_or_abort(store: &ObjectStore,
+                     key: &str,
+                     upload_id: &str,
+                     produced: Result<(Vec<Part>, Vec<[u8; 16]>)>)
+                     -> Result<Option<String>> {
+    match produced {
+        Ok((parts, part_md5s)) => {
+            let outcome = store.complete_multipart(key, upload_id, parts)?;
+            let expected = composite_etag(&part_md5s);
+            match outcome.e_tag {
+                Some(ref e_tag) if etag_matches(e_tag, &expected) => Ok(outcome.version_id),
+                Some(e_tag) => {
+                    warn!("ETag of {} is {} but {} was expected", key, e_tag, expected);
+                    Err(ErrorKind::ChecksumMismatch.into())
+                }
+                None => {
+                    warn!("no ETag returned for {}, upload cannot be validated", key);
+                    Err(ErrorKind::ChecksumMismatch.into())
+                }
+            }
+        }
+        Err(err) => {
+            if let Err(abort_err) = store.abort_multipart(key, upload_id) {
+                warn!("failed to abort multipart upload {}: {}", upload_id, abort_err);
+            }
+            Err(err)
+        }
+    }
+}
+
+/// Fill `buffer` with the next up-to-`chunk_size` bytes from `reader`.
+fn read_chunk(reader: &mut BufferedData, buffer: &mut Vec<u8>, chunk_size: usize) -> Result<()> {
+    buffer.resize(chunk_size, 0);
+    let mut filled = 0;
+    while filled < chunk_size {
+        let read = reader.read(&mut buffer[filled..])?;
+        if read == 0 {
+            break;
+        }
+        filled += read;
+    }
+    buffer.truncate(filled);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BufferPool, RateLimiter};
+    use std::time::Instant;
+
+    #[test]
+    fn sha2_key_detection() {
+        use super::is_sha2_key;
+        assert!(is_sha2_key(&"ab".repeat(32)));
+        assert!(!is_sha2_key(&"AB".repeat(32)));
+        assert!(!is_sha2_key(&"ab".repeat(31)));
+        assert!(!is_sha2_key(&"zz".repeat(32)));
+        assert!(!is_sha2_key("some/other/key"));
+    }
+
+    #[test]
+    fn content_disposition_escapes_filename() {
+        use lo::Lo;
+        let mut lo = Lo::new(vec![0; 20], 1, 1, "text/plain".to_string());
+        assert_eq!(lo.content_disposition(), None);
+        lo.set_filename(Some("weird \"name\".txt".to_string()));
+        assert_eq!(lo.content_disposition().unwrap(),
+                   "attachment; filename=\"weird \\\"name\\\".txt\"");
+    }
+
+    #[test]
+    fn composite_etag_of_known_digests() {
+        use super::composite_etag;
+        // md5 of 16 zero bytes
+        assert_eq!(composite_etag(&[[0; 16]]),
+                   "4ae71336e44bf9bf79d2752e234818a5-1");
+        assert!(composite_etag(&[[0; 16], [0xff; 16]]).ends_with("-2"));
+    }
+
+    #[test]
+    fn etag_comparison_ignores_quoting_and_case() {
+        use super::etag_matches;
+        assert!(etag_matches("\"abc123-2\"", "abc123-2"));
+        assert!(etag_matches("ABC123-2", "abc123-2"));
+        assert!(!etag_matches("\"abc123-2\"", "abc123-3"));
+    }
+
+    #[test]
+    fn journal_records_match_the_manifest_format() {
+        use super::UploadJournal;
+        use lo::Lo;
+        use std::io::Read;
+
+        let file = ::tempfile::NamedTempFile::new().unwrap();
+        let journal = UploadJournal::open(file.path()).unwrap();
+
+        let mut lo = Lo::new(vec![0xab; 20], 1, 1, "text/plain".to_string());
+        lo.set_sha2(vec![0xcd; 32]);
+        journal.record(&lo).unwrap();
+
+        // an upload into a versioned bucket records the version id as
+        // the trailing field
+        let mut versioned = Lo::new(vec![0x12; 20], 2, 1, "text/plain".to_string());
+        versioned.set_sha2(vec![0x34; 32]);
+        versioned.set_version_id(Some("3sL4kqtJlcpXroDTDmJ".to_string()));
+        journal.record(&versioned).unwrap();
+
+        let mut content = String::new();
+        file.reopen().unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content,
+                   format!("#lo-migrate-manifest v1\n{} {}\n{} {} 3sL4kqtJlcpXroDTDmJ\n",
+                           "ab".repeat(20),
+                           "cd".repeat(32),
+                           "12".repeat(20),
+                           "34".repeat(32)));
+
+        // and a manifest parser accepts the journal as-is
+        let known = ::manifest::read(content.as_bytes()).unwrap();
+        assert_eq!(known[&"ab".repeat(20)], vec![0xcd; 32]);
+        assert_eq!(known[&"12".repeat(20)], vec![0x34; 32]);
+    }
+
+    #[test]
+    fn cache_control_rules_override_global_value() {
+        use super::UploadHeaders;
+        let headers = UploadHeaders::new()
+            .with_cache_control(Some("no-cache".to_string()))
+            .with_rule("image/*".to_string(), "public, max-age=86400".to_string())
+            .with_rule("application/pdf".to_string(), "private".to_string());
+
+        assert_eq!(headers.cache_control_for("image/png").unwrap(),
+                   "public, max-age=86400");
+        assert_eq!(headers.cache_control_for("application/pdf").unwrap(), "private");
+        assert_eq!(headers.cache_control_for("text/plain").unwrap(), "no-cache");
+        assert!(UploadHeaders::new().cache_control_for("text/plain").is_none());
+    }
+
+    #[test]
+    fn magic_bytes_are_sniffed() {
+        use super::sniff_mime;
+        assert_eq!(sniff_mime(b"%PDF-1.4 ...").unwrap(), "application/pdf");
+        assert_eq!(sniff_mime(b"\x89PNG\r\n\x1a\n....").unwrap(), "image/png");
+        assert_eq!(sniff_mime(b"GIF89a......").unwrap(), "image/gif");
+        assert!(sniff_mime(b"plain text").is_none());
+        assert!(sniff_mime(b"").is_none());
+    }
+
+    #[test]
+    fn content_type_fallback_prefers_sniffing_over_default() {
+        use super::UploadHeaders;
+        let headers = UploadHeaders::new()
+            .with_default_content_type(Some("application/octet-stream".to_string()))
+            .with_content_type_sniffing(true);
+
+        assert_eq!(headers.content_type_for_data(b"%PDF-1.4").unwrap(),
+                   "application/pdf");
+        assert_eq!(headers.content_type_for_data(b"plain text").unwrap(),
+                   "application/octet-stream");
+
+        let no_sniff = UploadHeaders::new()
+            .with_default_content_type(Some("application/octet-stream".to_string()));
+        assert_eq!(no_sniff.content_type_for_data(b"%PDF-1.4").unwrap(),
+                   "application/octet-stream");
+
+        assert!(UploadHeaders::new().content_type_for_data(b"plain text").is_none());
+    }
+
+    #[test]
+    fn mime_syntax_is_validated() {
+        use super::valid_mime;
+        assert!(valid_mime("application/octet-stream"));
+        assert!(valid_mime("application/vnd.ms-excel"));
+        assert!(valid_mime("image/svg+xml"));
+        assert!(!valid_mime("octetstream"));
+        assert!(!valid_mime("application/"));
+        assert!(!valid_mime("/plain"));
+        assert!(!valid_mime("text/pla in"));
+        assert!(!valid_mime(""));
+    }
+
+    #[test]
+    fn legacy_mime_spellings_are_normalized() {
+        use super::normalize_mime;
+        assert_eq!(normalize_mime("octet/stream"), "application/octet-stream");
+        assert_eq!(normalize_mime("Image/JPG"), "image/jpeg");
+        assert_eq!(normalize_mime(" Text/Plain "), "text/plain");
+        assert_eq!(normalize_mime("application/pdf"), "application/pdf");
+    }
+
+    #[test]
+    fn adaptive_chunk_size_scales_with_the_object() {
+        use super::adaptive_chunk_size;
+        const MIB: i64 = 1024 * 1024;
+        let configured = (50 * MIB) as usize;
+
+        // single-request objects keep the configured value
+        assert_eq!(adaptive_chunk_size(10 * MIB, configured), configured);
+        assert_eq!(adaptive_chunk_size(50 * MIB, configured), configured);
+
+        // a modest multipart object stages 5 MiB parts, not 50 MiB ones
+        assert_eq!(adaptive_chunk_size(60 * MIB, configured), (5 * MIB) as usize);
+
+        // mid-sized objects aim for about 100 parts
+        let chunk = adaptive_chunk_size(10 * 1024 * MIB, configured) as i64;
+        assert!(chunk >= 100 * MIB && chunk <= 103 * MIB, "{}", chunk);
+
+        // huge objects are capped at 256 MiB parts
+        assert_eq!(adaptive_chunk_size(1024 * 1024 * MIB, configured),
+                   (256 * MIB) as usize);
+
+        // unless the 10,000-part limit forces larger parts
+        let size = 5 * 1024 * 1024 * MIB;
+        let chunk = adaptive_chunk_size(size, configured) as i64;
+        assert!((size + chunk - 1) / chunk <= 10_000);
+        assert!(chunk > 256 * MIB);
+    }
+
+    #[test]
+    fn buffer_pool_reuses_allocations() {
+        let pool = BufferPool::new(2);
+        let mut buffer = pool.take(1024);
+        buffer.extend_from_slice(&[0; 1024]);
+        let capacity = buffer.capacity();
+        pool.put(buffer);
+
+        let buffer = pool.take(1024);
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.capacity(), capacity);
+    }
+
+    #[test]
+    fn buffer_pool_respects_limit() {
+        let pool = BufferPool::new(1);
+        pool.put(Vec::with_capacity(1024));
+        // same size class and the pool is full, dropped
+        pool.put(Vec::with_capacity(2048));
+
+        assert_eq!(pool.take(512).capacity(), 1024);
+        assert_eq!(pool.take(512).capacity(), 512);
+    }
+
+    #[test]
+    fn buffer_pool_size_classes_keep_requests_apart() {
+        let pool = BufferPool::new(4);
+        pool.put(Vec::with_capacity(8 * 1024 * 1024));
+        pool.put(Vec::with_capacity(1024));
+
+        // a small request is not served the 8 MiB allocation
+        assert_eq!(pool.take(100).capacity(), 1024);
+        // a large request reuses it
+        assert_eq!(pool.take(4 * 1024 * 1024).capacity(), 8 * 1024 * 1024);
+        // nothing pooled fits, allocate fresh
+        assert_eq!(pool.take(32 * 1024 * 1024).capacity(), 32 * 1024 * 1024);
+    }
+
+    fn uploadable_lo(data: &[u8]) -> ::lo::Lo {
+        use lo::{BufferedData, Data, Lo};
+        let mut lo = Lo::new(vec![0xab; 20], 1, data.len() as i64, "text/plain".to_string());
+        lo.set_sha2(vec![0xcd; 32]);
+        lo.set_data(Data::Vec(data.to_vec()));
+        lo
+    }
+
+    fn file_backed_lo(data: &[u8]) -> ::lo::Lo {
+        use lo::Data;
+        use std::io::Write;
+        let mut file = ::tempfile::NamedTempFile::new().unwrap();
+        file.write_all(data).unwrap();
+        let mut lo = uploadable_lo(data);
+        lo.set_data(Data::File(file));
+        lo
+    }
+
+    #[test]
+    fn small_object_uploads_in_one_go() {
+        use lo::Data;
+        use object_store::MemoryObjectStore;
+
+        let store = MemoryObjectStore::new();
+        let mut lo = uploadable_lo(b"hello world");
+        lo.store(&store,
+                   1024,
+                   &mut RateLimiter::new(None),
+                   1,
+                   &BufferPool::new(1),
+                   &super::UploadHeaders::new())
+            .unwrap();
+
+        let object = store.object(&"cd".repeat(32)).unwrap();
+        assert_eq!(&object.data[..], b"hello world");
+        assert_eq!(object.meta.content_type.as_ref().unwrap(), "text/plain");
+        match *lo.data() {
+            Data::None => (),
+            ref other => panic!("data was not consumed: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn size_guard_rejects_oversized_objects_up_front() {
+        use super::Storer;
+        use error::ErrorKind;
+        use lo::Lo;
+        use thread::ThreadStat;
+
+        let stats = ThreadStat::new();
+        let chunk = 50 * 1024 * 1024;
+        let sized = |size| Lo::new(vec![0xab; 20], 1, size, "text/plain".to_string());
+
+        let storer = Storer::new(&stats);
+        // within limits: 100 GiB in 50 MiB parts is 2048 parts
+        assert!(storer.check_size(&sized(100 * 1024 * 1024 * 1024), chunk).is_ok());
+        // 10,000 parts of 50 MiB allow just under 500 GB, 5 TiB does not fit
+        let err = storer
+            .check_size(&sized(5 * 1024 * 1024 * 1024 * 1024), chunk)
+            .unwrap_err();
+        match *err.kind() {
+            ErrorKind::ObjectTooLarge(ref msg) => {
+                assert!(msg.contains("raise the upload chunk size"), "{}", msg)
+            }
+            ref other => panic!("unexpected error: {:?}", other),
+        }
+
+        // a configured maximum wins over the S3 limits
+        let storer = Storer::new(&stats).with_max_object_size(Some(1024));
+        let err = storer.check_size(&sized(2048), chunk).unwrap_err();
+        match *err.kind() {
+            ErrorKind::ObjectTooLarge(ref msg) => {
+                assert!(msg.contains("configured maximum"), "{}", msg)
+            }
+            ref other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn zero_byte_object_uploads_an_empty_body() {
+        use object_store::MemoryObjectStore;
+
+        let store = MemoryObjectStore::new();
+        let mut lo = uploadable_lo(b"");
+        // chunk size below the multipart threshold on purpose: a
+        // zero-byte object must never take the multipart path
+        lo.store(&store,
+                   4,
+                   &mut RateLimiter::new(None),
+                   1,
+                   &BufferPool::new(1),
+                   &super::UploadHeaders::new())
+            .unwrap();
+
+        assert!(store.object(&"cd".repeat(32)).unwrap().data.is_empty());
+        assert_eq!(store.pending_uploads(), 0);
+    }
+
+    #[test]
+    fn large_object_uploads_in_chunks() {
+        use object_store::MemoryObjectStore;
+
+        let store = MemoryObjectStore::new();
+        let mut lo = file_backed_lo(b"0123456789");
+        // 10 bytes with 4-byte chunks: parts of 4, 4 and 2 bytes
+        lo.store(&store,
+                   4,
+                   &mut RateLimiter::new(None),
+                   1,
+                   &BufferPool::new(1),
+                   &super::UploadHeaders::new())
+            .unwrap();
+
+        assert_eq!(&store.object(&"cd".repeat(32)).unwrap().data[..], b"0123456789");
+        assert_eq!(store.pending_uploads(), 0);
+    }
+
+    #[test]
+    fn custom_buffer_uploads_small_objects_in_one_go() {
+        use lo::Data;
+        use object_store::MemoryObjectStore;
+        use std::io::Cursor;
+
+        let store = MemoryObjectStore::new();
+        let mut lo = uploadable_lo(b"hello world");
+        lo.set_data(Data::Custom(Box::new(Cursor::new(b"hello world".to_vec()))));
+        lo.store(&store,
+                   1024,
+                   &mut RateLimiter::new(None),
+                   1,
+                   &BufferPool::new(1),
+                   &super::UploadHeaders::new())
+            .unwrap();
+
+        assert_eq!(&store.object(&"cd".repeat(32)).unwrap().data[..], b"hello world");
+    }
+
+    #[test]
+    fn custom_buffer_uploads_large_objects_in_chunks() {
+        use lo::Data;
+        use object_store::MemoryObjectStore;
+        use std::io::Cursor;
+
+        let store = MemoryObjectStore::new();
+        let mut lo = uploadable_lo(b"0123456789");
+        lo.set_data(Data::Custom(Box::new(Cursor::new(b"0123456789".to_vec()))));
+        lo.store(&store,
+                   4,
+                   &mut RateLimiter::new(None),
+                   1,
+                   &BufferPool::new(1),
+                   &super::UploadHeaders::new())
+            .unwrap();
+
+        assert_eq!(&store.object(&"cd".repeat(32)).unwrap().data[..], b"0123456789");
+        assert_eq!(store.pending_uploads(), 0);
+    }
+
+    #[test]
+    fn failed_part_aborts_the_upload() {
+        use object_store::MemoryObjectStore;
+
+        let store = MemoryObjectStore::new().with_failing_part(2);
+        let mut lo = file_backed_lo(b"0123456789");
+        let result = lo.store(&store,
+                              4,
+                              &mut RateLimiter::new(None),
+                              1,
+                              &BufferPool::new(1),
+                              &super::UploadHeaders::new());
+
+        assert!(result.is_err());
+        // the failed upload must not linger and accrue storage cost
+        assert_eq!(store.pending_uploads(), 0);
+        assert_eq!(store.object_count(), 0);
+    }
+
+    #[test]
+    fn rate_limiter_enforces_average_rate() {
+        let started = Instant::now();
+        let mut limiter = RateLimiter::new(Some(1000));
+        limiter.throttle(100);
+        limiter.throttle(100);
+        // 200 bytes at 1000 bytes/s must take at least 200 ms
+        assert!(started.elapsed().as_millis() >= 200);
+    }
+
+    #[test]
+    fn unlimited_rate_limiter_never_sleeps() {
+        let started = Instant::now();
+        let mut limiter = RateLimiter::new(None);
+        limiter.throttle(u64::max_value());
+        assert!(started.elapsed().as_secs() < 1);
+    }
+
+    #[test]
+    fn transient_errors_are_retried() {
+        use super::retry_transient;
+        use error::ErrorKind;
+        use std::cell::Cell;
+
+        let calls = Cell::new(0);
+        let result = retry_transient("test request", 3, || {
+            calls.set(calls.get() + 1);
+            if calls.get() < 3 {
+                Err(ErrorKind::S3Transient("503 slow down".to_string()).into())
+            } else {
+                Ok(42)
+            }
+        });
+        assert_eq!(result.unwrap(), 42);
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn fatal_errors_are_not_retried() {
+        use super::retry_transient;
+        use error::ErrorKind;
+        use std::cell::Cell;
+
+        let calls = Cell::new(0);
+        let result: ::error::Result<()> = retry_transient("test request", 3, || {
+            calls.set(calls.get() + 1);
+            Err(ErrorKind::S3("403 access denied".to_string()).into())
+        });
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+    }
+}
@@ -0,0 +1,143 @@
+//! Verifier threads checking already migrated objects against the bucket.
+
+use error::{ErrorKind, Result, Stage};
+use lo::Lo;
+use object_store::ObjectStore;
+use queue::{RecvResult, WorkQueueReceiver, WorkQueueSender};
+use std::sync::Arc;
+use std::time::Duration;
+use thread::ThreadStat;
+
+/// Interval at which an idle verifier rechecks the cancellation flag.
+const RECV_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// HEAD-checks objects that already carry a sha2 hash against the
+/// bucket, requeueing missing or size-mismatched ones for a fresh
+/// migration.
+///
+/// Only runs in re-verify mode (see [`MigrationBuilder::reverify()`]),
+/// where the observer routes rows with a non-NULL `sha2` here instead
+/// of into the receive queue. An intact bucket copy means the row is
+/// done; anything else goes back through receive, store and commit
+/// like a never-migrated object.
+///
+/// [`MigrationBuilder::reverify()`]: ../migrate/struct.MigrationBuilder.html#method.reverify
+pub struct Verifier<'a> {
+    stats: &'a ThreadStat,
+}
+
+impl<'a> Verifier<'a> {
+    pub fn new(stats: &'a ThreadStat) -> Self {
+        Verifier { stats: stats }
+    }
+
+    /// Process objects from the verify queue until it disconnects,
+    /// returning the number found intact. Damaged or missing objects
+    /// are sent into `requeue` — the receive queue — so the regular
+    /// pipeline re-migrates them.
+    pub fn start_worker(&self,
+                        rx: Arc<WorkQueueReceiver<Lo>>,
+                        requeue: Arc<WorkQueueSender<Lo>>,
+                        store: &ObjectStore)
+                        -> Result<u64> {
+        self.worker(rx, requeue, store)
+            .map_err(|err| err.at(Stage::Verify))
+    }
+
+    fn worker(&self,
+              rx: Arc<WorkQueueReceiver<Lo>>,
+              requeue: Arc<WorkQueueSender<Lo>>,
+              store: &ObjectStore)
+              -> Result<u64> {
+        let mut intact = 0;
+        loop {
+            self.stats.abort_if_cancelled()?;
+            let lo = match rx.recv_timeout(RECV_TIMEOUT) {
+                RecvResult::Item(lo) => lo,
+                RecvResult::TimedOut => continue,
+                RecvResult::Disconnected => break,
+            };
+
+            match ::thread::catch_object_panic(|| self.check(&lo, store)) {
+                Ok(true) => {
+                    self.stats.add_verified();
+                    intact += 1;
+                }
+                Ok(false) => {
+                    info!("re-migrating object {}", lo.sha1_hex());
+                    requeue.send(lo)?;
+                }
+                Err(err) => {
+                    let err = err.at(Stage::Verify).for_object(lo.oid(), lo.sha1_hex());
+                    warn!("failed to verify object: {}", err);
+                    self.stats.record_failure(&err);
+                }
+            }
+        }
+
+        debug!("verifier done, {} objects intact", intact);
+        Ok(intact)
+    }
+
+    /// Whether the bucket holds an object of the bookkept size under
+    /// the row's sha2 key.
+    fn check(&self, lo: &Lo, store: &ObjectStore) -> Result<bool> {
+        let key = lo.sha2_hex().ok_or(ErrorKind::Sha2NotComputed)?;
+        match store.stat(&key)? {
+            Some(length) if length == lo.size() as u64 => Ok(true),
+            Some(length) => {
+                warn!("object {} holds {} bytes in the bucket but {} in the database",
+                      key,
+                      length,
+                      lo.size());
+                Ok(false)
+            }
+            None => {
+                warn!("object {} is missing from the bucket", key);
+                Ok(false)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Verifier;
+    use lo::Lo;
+    use object_store::{MemoryObjectStore, ObjectStore, UploadMeta};
+    use thread::ThreadStat;
+
+    fn migrated_lo(size: i64) -> Lo {
+        let mut lo = Lo::new(vec![0xab; 20], 42, size, "text/plain".to_string());
+        lo.set_sha2(vec![0xcd; 32]);
+        lo
+    }
+
+    #[test]
+    fn intact_objects_pass_the_check() {
+        let stats = ThreadStat::new();
+        let store = MemoryObjectStore::new();
+        store
+            .put(&"cd".repeat(32), b"12345", &UploadMeta::default())
+            .unwrap();
+
+        let verifier = Verifier::new(&stats);
+        assert!(verifier.check(&migrated_lo(5), &store).unwrap());
+    }
+
+    #[test]
+    fn missing_and_mismatched_objects_fail_the_check() {
+        let stats = ThreadStat::new();
+        let store = MemoryObjectStore::new();
+        let verifier = Verifier::new(&stats);
+
+        // not in the bucket at all
+        assert!(!verifier.check(&migrated_lo(5), &store).unwrap());
+
+        // wrong size
+        store
+            .put(&"cd".repeat(32), b"123", &UploadMeta::default())
+            .unwrap();
+        assert!(!verifier.check(&migrated_lo(5), &store).unwrap());
+    }
+}
@@ -0,0 +1,63 @@
+//! Helpers shared by the integration tests.
+//!
+//! The tests need a throwaway Postgres database; point `LO_MIGRATE_TEST_PG`
+//! at it, e.g. `postgres://postgres@localhost/lo_migrate_test`, and run the
+//! ignored tests with `cargo test -- --ignored`.
+//!
+//! Downstream users get a richer version of these helpers from the
+//! `lo_migrate::testing` module (gated behind the `testing` feature);
+//! the copies here stay minimal so a plain `cargo test` compiles
+//! without extra features.
+
+extern crate postgres;
+extern crate sha1;
+
+use self::postgres::{Connection, TlsMode};
+use self::sha1::{Digest, Sha1};
+
+/// Connect to the test database named by `LO_MIGRATE_TEST_PG`.
+pub fn connect() -> Connection {
+    let url = ::std::env::var("LO_MIGRATE_TEST_PG")
+        .expect("LO_MIGRATE_TEST_PG must point at a throwaway test database");
+    Connection::connect(&url[..], TlsMode::None).expect("failed to connect to test database")
+}
+
+/// Create an empty `_nice_binary` table, dropping any previous one.
+pub fn create_schema(conn: &Connection) {
+    conn.batch_execute(
+        "DROP TABLE IF EXISTS _nice_binary;
+         CREATE TABLE _nice_binary (
+             hash varchar(40) PRIMARY KEY,
+             data oid,
+             size bigint NOT NULL,
+             mime_type varchar(255) NOT NULL,
+             sha2 varchar(64)
+         );",
+    ).expect("failed to create test schema");
+}
+
+/// Insert a row backed by a real large object containing `data`.
+///
+/// Returns the sha1 hash used as `hash` column value.
+pub fn insert_lo(conn: &Connection, data: &[u8], mime_type: &str) -> String {
+    let rows = conn.query("SELECT lo_from_bytea(0, $1)", &[&data])
+        .expect("failed to create large object");
+    let oid: u32 = rows.get(0).get(0);
+
+    let hash = sha1_hex(data);
+    conn.execute(
+        "INSERT INTO _nice_binary (hash, data, size, mime_type) VALUES ($1, $2, $3, $4)",
+        &[&hash, &oid, &(data.len() as i64), &mime_type],
+    ).expect("failed to insert _nice_binary row");
+    hash
+}
+
+fn sha1_hex(data: &[u8]) -> String {
+    let mut digest = Sha1::default();
+    digest.input(data);
+    digest
+        .result()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
@@ -0,0 +1,329 @@
+//! Integration tests running parts of the pipeline against a real
+//! Postgres database. See `common/mod.rs` for the required setup.
+
+extern crate fallible_iterator;
+extern crate hex;
+extern crate lo_migrate;
+extern crate sha2;
+extern crate two_lock_queue;
+
+mod common;
+
+use lo_migrate::db::{self, ConnFactory, PooledConnFactory, RunState};
+use lo_migrate::source::{LoSource, NiceBinarySource};
+use lo_migrate::thread::{CommitMode, Committer, Counter, Observer, Receiver, ThreadStat};
+use lo_migrate::lo::{ColumnMapping, Data, Lo};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[test]
+#[ignore]
+fn observer_enqueues_pending_rows() {
+    let conn = common::connect();
+    common::create_schema(&conn);
+    common::insert_lo(&conn, b"hello world", "text/plain");
+    common::insert_lo(&conn, b"other data", "application/octet-stream");
+
+    let stats = ThreadStat::new();
+    let (tx, rx) = two_lock_queue::channel(16);
+    let count = Observer::new(&conn, &stats)
+        .start_worker(Arc::new(tx), None)
+        .unwrap();
+
+    assert_eq!(count, 2);
+    assert_eq!(stats.lo_observed(), 2);
+    assert_eq!(rx.try_recv().unwrap().size(), 11);
+}
+
+#[test]
+#[ignore]
+fn observer_sends_known_hashes_straight_to_commit() {
+    let conn = common::connect();
+    common::create_schema(&conn);
+    let hash = common::insert_lo(&conn, b"hello world", "text/plain");
+    common::insert_lo(&conn, b"other data", "application/octet-stream");
+
+    let mut known = HashMap::new();
+    known.insert(hash.clone(), Sha256::digest(b"hello world").to_vec());
+
+    let stats = ThreadStat::new();
+    let (tx, rx) = two_lock_queue::channel(16);
+    let (commit_tx, commit_rx) = two_lock_queue::channel(16);
+    Observer::new(&conn, &stats)
+        .with_known_hashes(known)
+        .start_worker(Arc::new(tx), Some(Arc::new(commit_tx)))
+        .unwrap();
+
+    // the known object skipped receive/store and carries its sha2 already
+    let lo = commit_rx.try_recv().unwrap();
+    assert_eq!(lo.sha1_hex(), hash);
+    assert_eq!(lo.sha2_hex().unwrap(),
+               hex::encode(Sha256::digest(b"hello world")));
+    assert!(commit_rx.try_recv().is_err());
+
+    // the unknown one goes down the regular path
+    assert_eq!(rx.try_recv().unwrap().size(), 10);
+    assert!(rx.try_recv().is_err());
+}
+
+#[test]
+#[ignore]
+fn receiver_hashes_and_buffers_data() {
+    let conn = common::connect();
+    common::create_schema(&conn);
+    common::insert_lo(&conn, b"hello world", "text/plain");
+
+    let stats = ThreadStat::new();
+    let (obs_tx, obs_rx) = two_lock_queue::channel(16);
+    Observer::new(&conn, &stats)
+        .start_worker(Arc::new(obs_tx), None)
+        .unwrap();
+
+    let (store_tx, store_rx) = two_lock_queue::channel(16);
+    Receiver::new(&conn, &stats)
+        .start_worker::<Sha256>(Arc::new(obs_rx), Arc::new(store_tx), 1024)
+        .unwrap();
+
+    let mut lo = store_rx.try_recv().unwrap();
+    assert_eq!(lo.sha2_hex().unwrap(),
+               hex::encode(Sha256::digest(b"hello world")));
+    match lo.take_data() {
+        Data::Vec(ref data) => assert_eq!(&data[..], b"hello world"),
+        ref other => panic!("expected in-memory data, got {:?}", other),
+    }
+}
+
+#[test]
+#[ignore]
+fn committer_writes_sha2_back() {
+    let conn = common::connect();
+    common::create_schema(&conn);
+    let hash = common::insert_lo(&conn, b"hello world", "text/plain");
+
+    let stats = ThreadStat::new();
+    let (obs_tx, obs_rx) = two_lock_queue::channel(16);
+    Observer::new(&conn, &stats)
+        .start_worker(Arc::new(obs_tx), None)
+        .unwrap();
+    let (store_tx, store_rx) = two_lock_queue::channel(16);
+    Receiver::new(&conn, &stats)
+        .start_worker::<Sha256>(Arc::new(obs_rx), Arc::new(store_tx), 1024)
+        .unwrap();
+
+    let committed = Committer::new(&conn, &stats)
+        .start_worker(Arc::new(store_rx), 10, Duration::from_secs(30))
+        .unwrap();
+    assert_eq!(committed, 1);
+
+    let rows = conn.query("SELECT sha2 FROM _nice_binary WHERE hash = $1", &[&hash])
+        .unwrap();
+    let sha2: String = rows.get(0).get(0);
+    assert_eq!(sha2, hex::encode(Sha256::digest(b"hello world")));
+}
+
+#[test]
+#[ignore]
+fn lo_from_row_with_custom_mapping() {
+    let conn = common::connect();
+    common::create_schema(&conn);
+    let hash = common::insert_lo(&conn, b"hello world", "text/plain");
+
+    // columns deliberately out of the default order
+    let rows = conn.query("SELECT mime_type, size, data, hash FROM _nice_binary", &[])
+        .unwrap();
+    let mapping = ColumnMapping {
+        hash: 3,
+        oid: 2,
+        size: 1,
+        mime_type: 0,
+        filename: None,
+    };
+    let lo = Lo::from_row(&rows.get(0), &mapping).unwrap();
+    assert_eq!(lo.sha1_hex(), hash);
+    assert_eq!(lo.size(), 11);
+    assert_eq!(lo.mime_type(), "text/plain");
+}
+
+#[test]
+#[ignore]
+fn pending_los_iterates_filters_and_resumes() {
+    use fallible_iterator::FallibleIterator;
+    use lo_migrate::source::{PendingFilter, PendingLos};
+
+    let conn = common::connect();
+    common::create_schema(&conn);
+    common::insert_lo(&conn, b"hello world", "text/plain");
+    common::insert_lo(&conn, b"other data", "application/octet-stream");
+    common::insert_lo(&conn, b"third", "text/plain");
+
+    // all pending rows come back, ordered by hash
+    let hashes: Vec<_> = PendingLos::new(&conn, PendingFilter::default())
+        .map(|pending| pending.hash)
+        .collect()
+        .unwrap();
+    assert_eq!(hashes.len(), 3);
+    assert!(hashes[0] < hashes[1] && hashes[1] < hashes[2]);
+
+    // filters narrow the result
+    let filter = PendingFilter {
+        mime_type: Some("text/plain".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(PendingLos::new(&conn, filter).count().unwrap(), 2);
+
+    // resuming after the first row skips it
+    let mut iter = PendingLos::new(&conn, PendingFilter::default()).with_batch_size(1);
+    iter.next().unwrap().unwrap();
+    let position = iter.position().unwrap().to_string();
+    let rest: Vec<_> = PendingLos::new(&conn, PendingFilter::default())
+        .resume_after(&position)
+        .map(|pending| pending.hash)
+        .collect()
+        .unwrap();
+    assert_eq!(rest, hashes[1..].to_vec());
+}
+
+#[test]
+#[ignore]
+fn counter_reports_totals() {
+    let conn = common::connect();
+    common::create_schema(&conn);
+    common::insert_lo(&conn, b"hello world", "text/plain");
+    common::insert_lo(&conn, b"other data", "application/octet-stream");
+
+    let stats = ThreadStat::new();
+    Counter::new(&conn, &stats).count_objects().unwrap();
+    assert_eq!(stats.lo_total(), Some(2));
+    assert_eq!(stats.lo_remaining(), Some(2));
+    assert_eq!(stats.bytes_total(), Some(21));
+    assert_eq!(stats.bytes_remaining(), Some(21));
+}
+
+#[test]
+#[ignore]
+fn mapping_table_mode() {
+    let conn = common::connect();
+    common::create_schema(&conn);
+    conn.batch_execute("DROP TABLE IF EXISTS _nice_binary_s3").unwrap();
+    let hash = common::insert_lo(&conn, b"hello world", "text/plain");
+
+    db::create_mapping_table(&conn).unwrap();
+
+    let source: Arc<LoSource> =
+        Arc::new(NiceBinarySource::new().with_mode(CommitMode::MappingTable));
+    let stats = ThreadStat::new();
+    let (obs_tx, obs_rx) = two_lock_queue::channel(16);
+    Observer::new(&conn, &stats)
+        .with_source(source.clone())
+        .start_worker(Arc::new(obs_tx), None)
+        .unwrap();
+    let (store_tx, store_rx) = two_lock_queue::channel(16);
+    Receiver::new(&conn, &stats)
+        .start_worker::<Sha256>(Arc::new(obs_rx), Arc::new(store_tx), 1024)
+        .unwrap();
+    Committer::new(&conn, &stats)
+        .with_source(source.clone())
+        .start_worker(Arc::new(store_rx), 10, Duration::from_secs(30))
+        .unwrap();
+
+    // hash landed in the mapping table, the production table is untouched
+    let rows = conn.query("SELECT sha2 FROM _nice_binary WHERE hash = $1", &[&hash])
+        .unwrap();
+    assert_eq!(rows.get(0).get::<_, Option<String>>(0), None);
+    let rows = conn.query("SELECT sha2 FROM _nice_binary_s3 WHERE hash = $1", &[&hash])
+        .unwrap();
+    let expected = hex::encode(Sha256::digest(b"hello world"));
+    assert_eq!(rows.get(0).get::<_, String>(0), expected);
+
+    // a resumed observer no longer sees the row
+    let (obs_tx, obs_rx) = two_lock_queue::channel(16);
+    let observed = Observer::new(&conn, &stats)
+        .with_source(source)
+        .start_worker(Arc::new(obs_tx), None)
+        .unwrap();
+    assert_eq!(observed, 0);
+    drop(obs_rx);
+
+    // the merge step applies the mapping to _nice_binary
+    assert_eq!(db::apply_mapping_table(&conn).unwrap(), 1);
+    let rows = conn.query("SELECT sha2 FROM _nice_binary WHERE hash = $1", &[&hash])
+        .unwrap();
+    assert_eq!(rows.get(0).get::<_, String>(0), expected);
+}
+
+#[test]
+#[ignore]
+fn duplicate_content_blocks_finalize() {
+    let conn = common::connect();
+    common::create_schema(&conn);
+    common::insert_lo(&conn, b"hello world", "text/plain");
+
+    // a second row with different sha1 but identical content
+    conn.execute(
+        "INSERT INTO _nice_binary (hash, data, size, mime_type, sha2) \
+         SELECT 'ff' || substring(hash from 3), data, size, mime_type, sha2 \
+         FROM _nice_binary",
+        &[],
+    ).unwrap();
+    conn.execute("UPDATE _nice_binary SET sha2 = 'abc'", &[]).unwrap();
+
+    let duplicates = db::find_duplicate_sha2(&conn).unwrap();
+    assert_eq!(duplicates.len(), 1);
+    assert_eq!(duplicates[0].sha2, "abc");
+    assert_eq!(duplicates[0].hashes.len(), 2);
+
+    match db::add_constraints(&conn, false) {
+        Err(ref err) => {
+            match *err.kind() {
+                lo_migrate::error::ErrorKind::DuplicateContent => (),
+                ref other => panic!("unexpected error: {:?}", other),
+            }
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+#[ignore]
+fn pooled_conn_factory_hands_out_usable_connections() {
+    let conn = common::connect();
+    common::create_schema(&conn);
+    common::insert_lo(&conn, b"hello world", "text/plain");
+
+    let url = std::env::var("LO_MIGRATE_TEST_PG").unwrap();
+    let factory = PooledConnFactory::new(&url, 2).unwrap();
+
+    let stats = ThreadStat::new();
+    let (tx, rx) = two_lock_queue::channel(16);
+    let pooled = factory.connection().unwrap();
+    let count = Observer::new(&pooled, &stats)
+        .start_worker(Arc::new(tx), None)
+        .unwrap();
+
+    assert_eq!(count, 1);
+    assert_eq!(rx.try_recv().unwrap().size(), 11);
+}
+
+#[test]
+#[ignore]
+fn run_state_is_persisted() {
+    let conn = common::connect();
+    conn.batch_execute("DROP TABLE IF EXISTS _lo_migrate_state").unwrap();
+
+    let stats = ThreadStat::new();
+    let state = RunState::create(&conn).unwrap();
+    state.update(&conn, &stats).unwrap();
+    state.set_finalized(&conn).unwrap();
+
+    let rows = conn.query(
+        "SELECT committed, last_committed_hash, finalized FROM _lo_migrate_state \
+         WHERE run_id = $1",
+        &[&state.run_id()],
+    ).unwrap();
+    let row = rows.get(0);
+    assert_eq!(row.get::<_, i64>(0), 0);
+    assert_eq!(row.get::<_, Option<String>>(1), None);
+    assert!(row.get::<_, bool>(2));
+}